@@ -0,0 +1,148 @@
+use crate::{
+    speculative::{probs, rows},
+    CausalLM, DecodingMeta, ForwardError, QueryContext,
+};
+use common::{upos, utok};
+use std::{cmp::Ordering, ops::Deref};
+use tensor::Tensor;
+
+/// 对比搜索：每步从概率最高的 `k` 个候选词中选择使
+/// `(1 - alpha) * p(v) - alpha * max_j cos(h_v, h_j)` 最大的词，最多生成 `max_tokens` 个词。
+///
+/// `h_v` 是候选词经过前向传播后的隐藏状态，`h_j` 是上下文中已有的各词的隐藏状态，
+/// 后者惩罚与上文相似的表示以减少重复。`alpha` 为 0 时退化为贪心解码；遇到结束符时停止。
+pub fn contrastive_search<M>(
+    model: &M,
+    prompt: &[utok],
+    k: usize,
+    alpha: f32,
+    max_tokens: usize,
+) -> Result<Vec<utok>, ForwardError>
+where
+    M: CausalLM,
+    M::Storage: Deref<Target = [u8]>,
+{
+    assert!(!prompt.is_empty() && k > 0);
+    assert!((0. ..=1.).contains(&alpha), "alpha {alpha} out of [0, 1]");
+
+    let eos = model.eos_token();
+    let mut cache = model.new_cache();
+    let (mut context, mut next) = forward(model, vec![&mut cache], prompt, 0)?;
+    let mut next = probs(next.pop().unwrap(), 1.);
+
+    let mut ans = Vec::new();
+    while ans.len() < max_tokens {
+        let mut candidates = (0..next.len()).collect::<Vec<_>>();
+        candidates.sort_by(|&i, &j| next[j].partial_cmp(&next[i]).unwrap_or(Ordering::Equal));
+        candidates.truncate(k);
+        let tokens = candidates.iter().map(|&i| i as utok).collect::<Vec<_>>();
+
+        // 所有候选词共享上下文的缓存，一次前向得到各自的隐藏状态
+        let pos = (prompt.len() + ans.len()) as upos;
+        let mut caches = tokens
+            .iter()
+            .map(|_| model.duplicate_cache(&cache, pos))
+            .collect::<Vec<_>>();
+        let (hidden, logits) = forward(model, caches.iter_mut().collect(), &tokens, pos)?;
+
+        let score = |i: usize| {
+            let degeneration = context
+                .iter()
+                .map(|h| cosine(&hidden[i], h))
+                .fold(f32::NEG_INFINITY, f32::max);
+            (1. - alpha) * next[candidates[i]] - alpha * degeneration
+        };
+        let best = (0..tokens.len())
+            .map(|i| (i, score(i)))
+            .fold((0, f32::NEG_INFINITY), |a, b| if b.1 > a.1 { b } else { a })
+            .0;
+
+        let token = tokens[best];
+        ans.push(token);
+        if token == eos {
+            break;
+        }
+        cache = caches.swap_remove(best);
+        context.push(hidden[best].clone());
+        next = probs(logits[best].clone(), 1.);
+    }
+    Ok(ans)
+}
+
+/// 推理 `tokens`，每个缓存对应一个请求，各请求的词数相同且都从 `start` 开始。
+///
+/// 返回所有词的隐藏状态和每个请求最后一个词的 logits。
+fn forward<M>(
+    model: &M,
+    caches: Vec<&mut Tensor<M::Storage>>,
+    tokens: &[utok],
+    start: upos,
+) -> Result<(Vec<Vec<f32>>, Vec<Vec<f32>>), ForwardError>
+where
+    M: CausalLM,
+    M::Storage: Deref<Target = [u8]>,
+{
+    let num_seq = caches.len();
+    let n = tokens.len() / num_seq;
+    let queries = caches.into_iter().map(|cache| QueryContext {
+        cache: Some(cache),
+        range: start..start + n as upos,
+        adapter: None,
+        mask: None,
+    });
+
+    let token_embedded = model.token_embed(tokens.iter().copied());
+    let hidden_state = model.forward(queries, token_embedded)?;
+    let hidden = rows(&hidden_state);
+    let decoding = (0..num_seq).map(|_| DecodingMeta {
+        num_query: n,
+        num_decode: 1,
+    });
+    let logits = rows(&model.decode(decoding, hidden_state));
+    Ok((hidden, logits))
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm = |x: &[f32]| x.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let denom = norm(a) * norm(b);
+    if denom > 0. {
+        dot / denom
+    } else {
+        0.
+    }
+}
+
+#[test]
+fn test_contrastive() {
+    use crate::mock::{Bigram, VOC};
+
+    let model = Bigram {
+        table: [
+            [0., 3., 1., 0.],
+            [0., 3., 1., 0.],
+            [0., 1., 0., 2.],
+            [0., 1., 2., 0.],
+        ],
+    };
+
+    // alpha 为 0 时与贪心解码一致
+    let mut greedy = vec![0];
+    for _ in 0..6 {
+        let row = &model.table[*greedy.last().unwrap() as usize];
+        let next = (0..VOC).fold(0, |a, b| if row[b] > row[a] { b } else { a });
+        greedy.push(next as utok);
+    }
+    for k in [1, 2, VOC] {
+        assert_eq!(
+            contrastive_search(&model, &[0], k, 0., 6).unwrap(),
+            greedy[1..]
+        );
+    }
+
+    // 独热的隐藏状态使出现过的词相似度为 1，退化惩罚优先选择新词
+    assert_eq!(
+        contrastive_search(&model, &[0], 2, 0.9, 4).unwrap(),
+        [1, 2, 3, 2]
+    );
+}
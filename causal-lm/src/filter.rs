@@ -0,0 +1,246 @@
+use common::f16;
+use std::cmp::Ordering;
+
+/// 截断候选词的过滤器，按 [`SampleMeta::filter_order`](crate::SampleMeta::filter_order) 的顺序依次执行。
+///
+/// 每个过滤器都在前面的过滤器留下的候选词上按温度缩放、重新归一化后计算，因此顺序会影响结果。
+/// 例如先 top-k 后 top-p 时，累计概率只在概率最大的 k 个词中计算；
+/// 先 top-p 后 top-k 时，先按完整分布截出累计概率达到 p 的词，再保留其中最多 k 个。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum SampleFilter {
+    /// 保留概率最大的 `top_k` 个词。
+    TopK,
+    /// 保留累计概率达到 `top_p` 的概率最大的词。
+    TopP,
+    /// 局部典型采样。
+    Typical,
+    /// 无尾采样。
+    TailFree,
+    /// 最小概率采样。
+    MinP,
+}
+
+impl SampleFilter {
+    /// 默认顺序：温度之后依次执行 top-k、top-p、典型采样、无尾采样和最小概率采样。
+    pub const DEFAULT_ORDER: [Self; 5] = [
+        Self::TopK,
+        Self::TopP,
+        Self::Typical,
+        Self::TailFree,
+        Self::MinP,
+    ];
+}
+
+/// 只保留 logits 最大的 `k` 个词，`k` 为 0 时不截断。
+pub(crate) fn top_k(logits: &mut [f16], k: usize) {
+    let mut order = (0..logits.len())
+        .filter(|&i| logits[i].is_finite())
+        .collect::<Vec<_>>();
+    if k == 0 || k >= order.len() {
+        return;
+    }
+    order.select_nth_unstable_by(k, |&i, &j| {
+        logits[j].partial_cmp(&logits[i]).unwrap_or(Ordering::Equal)
+    });
+    mask(logits, &order[k..]);
+}
+
+/// 按概率从大到小保留词，直到累计概率达到 `p`。
+pub(crate) fn top_p(logits: &mut [f16], temperature: f32, p: f32) {
+    if p >= 1. {
+        return;
+    }
+    let logp = log_softmax(logits, temperature);
+    let mut order = candidates(&logp);
+    order.sort_unstable_by(|&i, &j| logp[j].partial_cmp(&logp[i]).unwrap_or(Ordering::Equal));
+    let mut cum = 0.;
+    let keep = order
+        .iter()
+        .position(|&i| {
+            cum += logp[i].exp();
+            cum >= p
+        })
+        .map_or(order.len(), |i| i + 1);
+    mask(logits, &order[keep..]);
+}
+
+/// 局部典型采样：保留惊异度最接近分布熵的词，直到累计概率达到 `p`。
+pub(crate) fn typical(logits: &mut [f16], temperature: f32, p: f32) {
+    if p >= 1. {
+        return;
+    }
+    let logp = log_softmax(logits, temperature);
+    // 概率为 0 的词对熵没有贡献
+    let entropy = -logp
+        .iter()
+        .filter(|x| x.is_finite())
+        .map(|&x| x.exp() * x)
+        .sum::<f32>();
+
+    let mut order = candidates(&logp);
+    order.sort_unstable_by(|&i, &j| {
+        let di = (-logp[i] - entropy).abs();
+        let dj = (-logp[j] - entropy).abs();
+        di.partial_cmp(&dj).unwrap_or(Ordering::Equal)
+    });
+    let mut cum = 0.;
+    let keep = order
+        .iter()
+        .position(|&i| {
+            cum += logp[i].exp();
+            cum >= p
+        })
+        .map_or(order.len(), |i| i + 1);
+    mask(logits, &order[keep..]);
+}
+
+/// 无尾采样：按排序后概率的二阶差分截断长尾，`z` 为保留的差分累计比例。
+pub(crate) fn tail_free(logits: &mut [f16], temperature: f32, z: f32) {
+    if z >= 1. {
+        return;
+    }
+    let logp = log_softmax(logits, temperature);
+    let mut order = candidates(&logp);
+    if order.len() <= 2 {
+        return;
+    }
+    order.sort_unstable_by(|&i, &j| logp[j].partial_cmp(&logp[i]).unwrap_or(Ordering::Equal));
+
+    let probs = order.iter().map(|&i| logp[i].exp()).collect::<Vec<_>>();
+    let d1 = probs.windows(2).map(|w| w[0] - w[1]).collect::<Vec<_>>();
+    let d2 = d1
+        .windows(2)
+        .map(|w| (w[0] - w[1]).abs())
+        .collect::<Vec<_>>();
+    let sum = d2.iter().sum::<f32>();
+    // 概率均匀分布时没有可判断的尾部
+    if sum <= 0. {
+        return;
+    }
+    let mut cum = 0.;
+    let keep = d2
+        .iter()
+        .position(|&x| {
+            cum += x / sum;
+            cum > z
+        })
+        .map_or(order.len(), |i| i.max(1));
+    mask(logits, &order[keep..]);
+}
+
+/// 最小概率采样：只保留概率不低于 `p` 倍最大概率的词。
+pub(crate) fn min_p(logits: &mut [f16], temperature: f32, p: f32) {
+    if p <= 0. {
+        return;
+    }
+    let logp = log_softmax(logits, temperature);
+    let max = logp.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    // 在对数空间比较，避免小概率下溢
+    let threshold = max + p.ln();
+    let tail = candidates(&logp)
+        .into_iter()
+        .filter(|&i| logp[i] < threshold)
+        .collect::<Vec<_>>();
+    mask(logits, &tail);
+}
+
+/// 在 f32 下计算带温度的 log softmax，避免小概率在 f16 下下溢。
+pub(crate) fn log_softmax(logits: &[f16], temperature: f32) -> Vec<f32> {
+    let t = if temperature > 0. { temperature } else { 1. };
+    let x = logits.iter().map(|x| x.to_f32() / t).collect::<Vec<_>>();
+    let max = x.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if !max.is_finite() {
+        return x;
+    }
+    let lse = max + x.iter().map(|x| (x - max).exp()).sum::<f32>().ln();
+    x.into_iter().map(|x| x - lse).collect()
+}
+
+/// 尚未被屏蔽的词。
+pub(crate) fn candidates(logp: &[f32]) -> Vec<usize> {
+    (0..logp.len()).filter(|&i| logp[i].is_finite()).collect()
+}
+
+pub(crate) fn mask(logits: &mut [f16], tokens: &[usize]) {
+    for &i in tokens {
+        logits[i] = f16::NEG_INFINITY;
+    }
+}
+
+#[cfg(test)]
+fn count(logits: &[f16]) -> usize {
+    logits.iter().filter(|x| x.is_finite()).count()
+}
+
+#[test]
+fn test_typical() {
+    let row = (0..32)
+        .map(|i| f16::from_f32(-(i as f32) / 4.))
+        .collect::<Vec<_>>();
+    let mut last = row.len();
+    for p in [1., 0.95, 0.8, 0.5, 0.2, 0.] {
+        let mut logits = row.clone();
+        typical(&mut logits, 1., p);
+        let n = count(&logits);
+        assert!((1..=last).contains(&n), "p = {p}: {n} > {last}");
+        last = n;
+    }
+    assert!(last < row.len());
+
+    // 极小概率不产生 NaN
+    let mut logits = [0., -60., -65000., f32::NEG_INFINITY].map(f16::from_f32);
+    typical(&mut logits, 1., 0.5);
+    assert!(logits.iter().all(|x| !x.is_nan()));
+    assert_eq!(count(&logits), 1);
+}
+
+#[test]
+fn test_tail_free() {
+    let row = (0..32)
+        .map(|i| f16::from_f32(-((i * i) as f32) / 16.))
+        .collect::<Vec<_>>();
+    let mut last = row.len();
+    for z in [1., 0.99, 0.9, 0.7, 0.5, 0.1] {
+        let mut logits = row.clone();
+        tail_free(&mut logits, 1., z);
+        let n = count(&logits);
+        assert!((1..=last).contains(&n), "z = {z}: {n} > {last}");
+        last = n;
+    }
+    assert!(last < row.len());
+
+    // 全部相等时保留所有词
+    let mut logits = [f16::ONE; 8];
+    tail_free(&mut logits, 1., 0.5);
+    assert_eq!(count(&logits), 8);
+}
+
+#[test]
+fn test_min_p() {
+    let probs = [0.5f32, 0.2, 0.15, 0.1, 0.04, 0.01];
+    let row = probs.map(|p| f16::from_f32(p.ln()));
+    let max = probs[0];
+    for p in [0., 0.05, 0.25, 0.35, 0.5] {
+        let mut logits = row;
+        min_p(&mut logits, 1., p);
+        for (x, &prob) in logits.iter().zip(&probs) {
+            // 阈值附近留出 f16 的舍入误差
+            if prob > p * max * 1.01 {
+                assert!(x.is_finite(), "p = {p}, prob = {prob}");
+            } else if prob < p * max * 0.99 {
+                assert!(!x.is_finite(), "p = {p}, prob = {prob}");
+            }
+        }
+    }
+
+    // 阈值高于除最大值外所有词时退化为贪心
+    let mut logits = row;
+    min_p(&mut logits, 1., 0.9);
+    assert_eq!(count(&logits), 1);
+    assert!(logits[0].is_finite());
+
+    // 温度改变分布形状，阈值在温度之后生效
+    let mut logits = row;
+    min_p(&mut logits, 4., 0.5);
+    assert_eq!(count(&logits), 5);
+}
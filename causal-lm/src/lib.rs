@@ -1,17 +1,36 @@
 #![doc = include_str!("../README.md")]
 #![deny(warnings, missing_docs)]
 
+mod contrastive;
 mod decoding;
+mod filter;
+mod loss;
+mod mirostat;
+#[cfg(test)]
+mod mock;
+mod ngram;
 mod query_context;
+mod speculative;
 
-use common::{upos, utok};
+use common::{f16, upos, utok};
 use digit_layout::types::U32;
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    iter::repeat,
+    path::Path,
+    sync::{Arc, Mutex},
+};
 use tensor::{udim, Tensor};
 
+pub use contrastive::contrastive_search;
 pub use decoding::DecodingMeta;
+pub use filter::SampleFilter;
+pub use loss::cross_entropy;
+pub use mirostat::{Mirostat, MirostatState};
+pub use ngram::NgramBlocker;
 pub use operators::random_sample::SampleArgs;
-pub use query_context::QueryContext;
+pub use query_context::{ForwardError, QueryContext, SeqLenError};
+pub use speculative::{speculative_generate, Speculation};
 
 /// 从文件系统加载的模型。
 pub trait Model: Sized {
@@ -29,6 +48,7 @@ pub trait Model: Sized {
 ///
 /// - 创建缓存张量（[`new_cache`](CausalLM::new_cache)）；
 /// - 复制缓存张量（[`duplicate_cache`](CausalLM::duplicate_cache)）；
+/// - 淘汰缓存中的位置（[`evict_cache`](CausalLM::evict_cache)）；
 /// - 以及对输入序列计算词嵌入（[`token_embed`](CausalLM::token_embed)）；
 /// - 对词嵌入计算前向传播（[`forward`](CausalLM::forward)）；
 /// - 解码词嵌入张量得到概率密度（[`decode`](CausalLM::decode)）；
@@ -53,18 +73,31 @@ pub trait CausalLM: Model {
     ///
     /// 有效部分：`.., .., .., ..pos, ..`
     fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage>;
+    /// 从有效长度为 `pos` 的缓存中丢弃 `sink..sink + n` 的位置，得到有效长度为 `pos - n` 的缓存。
+    ///
+    /// 前 `sink` 个位置作为注意力汇聚点保留，之后的位置依次前移并重新编码到新的位置上。
+    /// 模型不支持时返回 `None`，默认不支持。
+    fn evict_cache(
+        &self,
+        _cache: &Tensor<Self::Storage>,
+        _pos: upos,
+        _sink: upos,
+        _n: upos,
+    ) -> Option<Tensor<Self::Storage>> {
+        None
+    }
     /// 对所有词执行词嵌入（`num_tokens x hidden_size`）。
     ///
     /// 词嵌入是上下文无关的，对于每个词独立进行，因此多个请求的查询序列可以 flatten 同时计算。
     fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage>;
     /// 对词嵌入张量执行 Transformer 计算（`num_t   okens x hidden_size`）。
     ///
-    /// 需要输入每个请求的上下文。
+    /// 需要输入每个请求的上下文，查询越过 K-V 缓存的边界时返回错误。
     fn forward<'a>(
         &self,
         queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+    ) -> Result<Tensor<Self::Storage>, ForwardError>
     where
         Self: 'a;
     /// 对词嵌入张量执行解码计算（`num_decoding_tokens` x `vocab_size`）。
@@ -84,11 +117,195 @@ pub trait CausalLM: Model {
 }
 
 /// 解码的要求。
+#[derive(Clone, Default)]
 pub struct SampleMeta {
     /// 解码的长度。
     pub num_decode: usize,
     /// 采样参数。
     pub args: SampleArgs,
+    /// 采样前按词序号叠加到 logits 上的偏置，`-inf` 表示禁止采样该词，超出词表的词被忽略。
+    pub logit_bias: HashMap<utok, f32>,
+    /// 局部典型采样保留的累计概率，`None` 表示不启用。
+    pub typical_p: Option<f32>,
+    /// 无尾采样保留的二阶差分累计比例，`None` 表示不启用。
+    pub tfs_z: Option<f32>,
+    /// 最小概率采样的相对阈值，`None` 表示不启用。
+    pub min_p: Option<f32>,
+    /// 截断过滤器的执行顺序，`None` 表示 [`SampleFilter::DEFAULT_ORDER`]。
+    ///
+    /// 不在列表中的过滤器不执行。
+    pub filter_order: Option<Vec<SampleFilter>>,
+    /// 存在惩罚，出现过的词的 logits 减去该值一次。
+    pub presence_penalty: f32,
+    /// 频率惩罚，出现过的词的 logits 按出现次数减去该值的倍数。
+    pub frequency_penalty: f32,
+    /// 该序列中已经出现的词及其次数，用于存在惩罚和频率惩罚。
+    pub token_counts: HashMap<utok, usize>,
+    /// Mirostat 采样的状态，`None` 表示不启用。
+    ///
+    /// 状态在同一序列的各解码步之间共享，每步采样后由 [`observe`](Self::observe) 更新。
+    /// 启用时应关闭 top-k 和 top-p。
+    pub mirostat: Option<Arc<Mutex<MirostatState>>>,
+    /// 禁止重复 n 元组的状态，`None` 表示不启用。
+    ///
+    /// 与 Mirostat 的状态一样在解码步之间共享，采样到的词由 [`observe`](Self::observe) 追加到历史。
+    pub no_repeat_ngram: Option<Arc<Mutex<NgramBlocker>>>,
+}
+
+impl SampleMeta {
+    /// 在随机采样之前处理一行 logits。
+    ///
+    /// 依次叠加偏置、减去存在惩罚和频率惩罚、屏蔽重复的 n 元组，
+    /// 再按 [`filter_order`](Self::filter_order) 执行截断过滤器，最后执行 Mirostat 的截断，
+    /// 各截断都按 `args.temperature` 缩放后的分布计算；
+    /// 之后由采样算子以 [`sample_args`](Self::sample_args) 在剩余的候选词上采样。
+    pub fn process(&self, logits: &mut [f16]) {
+        for (&token, &bias) in &self.logit_bias {
+            let Some(x) = logits.get_mut(token as usize) else {
+                continue;
+            };
+            *x = f16::from_f32(x.to_f32() + bias);
+        }
+        if self.presence_penalty != 0. || self.frequency_penalty != 0. {
+            for (&token, &count) in &self.token_counts {
+                if count == 0 {
+                    continue;
+                }
+                let Some(x) = logits.get_mut(token as usize) else {
+                    continue;
+                };
+                let penalty = self.presence_penalty + self.frequency_penalty * count as f32;
+                *x = f16::from_f32(x.to_f32() - penalty);
+            }
+        }
+        if let Some(blocker) = &self.no_repeat_ngram {
+            blocker.lock().unwrap().block(logits);
+        }
+        let temperature = self.args.temperature;
+        let order = self
+            .filter_order
+            .as_deref()
+            .unwrap_or(&SampleFilter::DEFAULT_ORDER);
+        for filter in order {
+            match filter {
+                SampleFilter::TopK => filter::top_k(logits, self.args.top_k),
+                SampleFilter::TopP => filter::top_p(logits, temperature, self.args.top_p),
+                SampleFilter::Typical => {
+                    if let Some(p) = self.typical_p {
+                        filter::typical(logits, temperature, p);
+                    }
+                }
+                SampleFilter::TailFree => {
+                    if let Some(z) = self.tfs_z {
+                        filter::tail_free(logits, temperature, z);
+                    }
+                }
+                SampleFilter::MinP => {
+                    if let Some(p) = self.min_p {
+                        filter::min_p(logits, temperature, p);
+                    }
+                }
+            }
+        }
+        if let Some(state) = &self.mirostat {
+            state.lock().unwrap().truncate(logits, temperature);
+        }
+    }
+
+    /// 采样前是否需要 [`process`](Self::process)。
+    ///
+    /// 不需要时采样算子直接以 `args` 采样的结果与处理后相同，
+    /// 不支持逐行处理 logits 的后端可以据此只为需要的行把 logits 搬到主机上处理。
+    pub fn needs_process(&self) -> bool {
+        let greedy = self.args.temperature <= 0. || self.args.top_k <= 1;
+        let penalized = (self.presence_penalty != 0. || self.frequency_penalty != 0.)
+            && self.token_counts.values().any(|&n| n > 0);
+        let filtered = self.typical_p.is_some()
+            || self.tfs_z.is_some()
+            || self.min_p.is_some()
+            || self
+                .filter_order
+                .as_ref()
+                .is_some_and(|order| *order != SampleFilter::DEFAULT_ORDER);
+        !self.logit_bias.is_empty()
+            || penalized
+            || self.no_repeat_ngram.is_some()
+            || self.mirostat.is_some()
+            || (!greedy && filtered)
+    }
+
+    /// 交给采样算子的参数，词表大小为 `voc`。
+    ///
+    /// 随机采样时 top-k 和 top-p 已经由 [`process`](Self::process) 按顺序执行，算子只按温度采样；
+    /// 贪心采样的结果与截断顺序无关，保持原参数。
+    pub fn sample_args(&self, voc: usize) -> SampleArgs {
+        let args = self.args;
+        if args.temperature <= 0. || args.top_k <= 1 {
+            args
+        } else {
+            SampleArgs {
+                temperature: args.temperature,
+                top_p: 1.,
+                top_k: voc,
+            }
+        }
+    }
+
+    /// 采样得到 `token` 后调用，用 [`process`](Self::process) 处理过的 `logits`
+    /// 更新需要在解码步之间延续的状态。
+    pub fn observe(&self, logits: &[f16], token: utok) {
+        if let Some(state) = &self.mirostat {
+            state
+                .lock()
+                .unwrap()
+                .update(logits, token, self.args.temperature);
+        }
+        if let Some(blocker) = &self.no_repeat_ngram {
+            blocker.lock().unwrap().push(token);
+        }
+    }
+}
+
+/// 展开每个解码位置交给采样算子的参数。
+///
+/// 每个解码位置携带所属序列的采样参数，同一批次中的序列可以各不相同；
+/// 需要 [`process`](SampleMeta::process) 的序列取处理后的 [`sample_args`](SampleMeta::sample_args)。
+pub fn sample_args(metas: &[SampleMeta], voc: usize) -> Vec<SampleArgs> {
+    metas
+        .iter()
+        .flat_map(|meta| {
+            let args = if meta.needs_process() {
+                meta.sample_args(voc)
+            } else {
+                meta.args
+            };
+            repeat(args).take(meta.num_decode)
+        })
+        .collect()
+}
+
+/// 为不能在设备上逐行处理的后端，在主机上处理形状为 `[rows, voc]` 的 `logits` 中需要处理的行。
+///
+/// 同一序列的多行先全部处理，采样后再由 [`observe_rows`] 依次更新状态。
+pub fn process_rows(metas: &[SampleMeta], logits: &mut [f16], voc: usize) {
+    let mut rows = logits.chunks_exact_mut(voc);
+    for meta in metas {
+        for row in rows.by_ref().take(meta.num_decode) {
+            if meta.needs_process() {
+                meta.process(row);
+            }
+        }
+    }
+}
+
+/// 用 [`process_rows`] 处理过的 `logits` 和采样结果更新各序列的状态。
+pub fn observe_rows(metas: &[SampleMeta], logits: &[f16], voc: usize, tokens: &[utok]) {
+    let mut rows = logits.chunks_exact(voc).zip(tokens);
+    for meta in metas {
+        for (row, &token) in rows.by_ref().take(meta.num_decode) {
+            meta.observe(row, token);
+        }
+    }
 }
 
 /// 生成位置张量。
@@ -104,6 +321,33 @@ pub fn pos<'a, S: 'a>(
     Tensor::new(U32, &[ans.len() as _], ans)
 }
 
+/// 生成滑动窗口注意力的加性掩码，形状为 `[seq_len, att_len]`。
+///
+/// 位于 `pos + i` 的查询只能看到位置 `(pos + i - window, pos + i]` 内的键；
+/// 窗口覆盖全部注意力长度时返回 `None`，此时与因果注意力完全一致。
+pub fn sliding_window_mask(
+    pos: upos,
+    seq_len: udim,
+    att_len: udim,
+    window: udim,
+) -> Option<Vec<f32>> {
+    if att_len <= window {
+        return None;
+    }
+    let mut ans = Vec::with_capacity((seq_len * att_len) as usize);
+    for i in 0..seq_len {
+        let p = pos + i;
+        ans.extend((0..att_len).map(|j| {
+            if j <= p && p - j < window {
+                0.
+            } else {
+                f32::NEG_INFINITY
+            }
+        }));
+    }
+    Some(ans)
+}
+
 /// 测试模型实现。
 pub fn test_impl<M>(meta: M::Meta, prompt: &[utok])
 where
@@ -133,8 +377,10 @@ where
         let queries = [QueryContext {
             cache: Some(&mut cache),
             range: pos..pos + prompt.len() as upos,
+            adapter: None,
+            mask: None,
         }];
-        let hidden_state = CausalLM::forward(&model, queries, token_embedded);
+        let hidden_state = CausalLM::forward(&model, queries, token_embedded).unwrap();
 
         let decoding = [DecodingMeta {
             num_query: prompt.len(),
@@ -145,6 +391,7 @@ where
         let args = [SampleMeta {
             num_decode: 1,
             args: SampleArgs::ARG_MAX,
+            ..Default::default()
         }];
         let tokens = CausalLM::sample(&model, args, logits);
 
@@ -153,3 +400,143 @@ where
         prompt = tokens;
     }
 }
+
+#[test]
+fn test_logit_bias() {
+    let meta = SampleMeta {
+        logit_bias: HashMap::from([(1, f32::NEG_INFINITY), (2, 100.), (7, 1.)]),
+        ..Default::default()
+    };
+    let mut logits = [1., 2., 3., 4.].map(f16::from_f32);
+    meta.process(&mut logits);
+    assert_eq!(logits, [1., f32::NEG_INFINITY, 103., 4.].map(f16::from_f32));
+}
+
+#[test]
+fn test_penalties() {
+    let meta = SampleMeta {
+        presence_penalty: 0.5,
+        frequency_penalty: 0.25,
+        token_counts: HashMap::from([(0, 1), (2, 2), (3, 0)]),
+        ..Default::default()
+    };
+    let mut logits = [1., 2., 3., 4.].map(f16::from_f32);
+    meta.process(&mut logits);
+    // 出现两次的词减去一次存在惩罚和两倍频率惩罚
+    assert_eq!(logits, [0.25, 2., 2., 4.].map(f16::from_f32));
+
+    let meta = SampleMeta {
+        frequency_penalty: 0.5,
+        ..meta
+    };
+    let mut logits = [1., 2., 3., 4.].map(f16::from_f32);
+    meta.process(&mut logits);
+    assert_eq!(logits, [0., 2., 1.5, 4.].map(f16::from_f32));
+}
+
+#[test]
+fn test_no_repeat_ngram() {
+    let meta = SampleMeta {
+        no_repeat_ngram: Some(Arc::new(Mutex::new(NgramBlocker::new(2)))),
+        ..Default::default()
+    };
+    for token in [1, 2, 3] {
+        meta.observe(&[], token);
+    }
+    let mut logits = [1., 2., 3., 4.].map(f16::from_f32);
+    meta.process(&mut logits);
+    assert_eq!(logits, [1., 2., 3., 4.].map(f16::from_f32));
+
+    // 再次生成 1 之后，2 会重复二元组 (1, 2)
+    meta.observe(&[], 1);
+    meta.process(&mut logits);
+    assert_eq!(logits, [1., 2., f32::NEG_INFINITY, 4.].map(f16::from_f32));
+}
+
+#[test]
+fn test_sliding_window_mask() {
+    assert_eq!(sliding_window_mask(0, 4, 4, 4), None);
+
+    const X: f32 = f32::NEG_INFINITY;
+    let mask = sliding_window_mask(2, 2, 4, 2).unwrap();
+    assert_eq!(mask, [X, 0., 0., X, X, X, 0., 0.]);
+}
+
+#[test]
+fn test_filter_order() {
+    use SampleFilter::{TopK, TopP};
+
+    let meta = |filter_order| SampleMeta {
+        args: SampleArgs {
+            temperature: 1.,
+            top_p: 0.5,
+            top_k: 2,
+        },
+        filter_order,
+        ..Default::default()
+    };
+    let count = |meta: SampleMeta| {
+        let mut logits = [0.4f32, 0.3, 0.2, 0.1].map(|p| f16::from_f32(p.ln()));
+        meta.process(&mut logits);
+        logits.iter().filter(|x| x.is_finite()).count()
+    };
+    // 前两个词归一化后第一个词的概率已超过 0.5
+    assert_eq!(count(meta(Some(vec![TopK, TopP]))), 1);
+    // 完整分布中需要前两个词才能达到 0.5
+    assert_eq!(count(meta(Some(vec![TopP, TopK]))), 2);
+    // 默认先 top-k 后 top-p
+    assert_eq!(count(meta(None)), 1);
+
+    // 随机采样时截断已经完成，算子只按温度采样
+    let args = meta(None).sample_args(4);
+    assert_eq!((args.top_p, args.top_k), (1., 4));
+
+    // 默认顺序的 top-k 和 top-p 可以直接交给算子，其他顺序需要预先处理
+    assert!(!meta(None).needs_process());
+    assert!(meta(Some(vec![TopP, TopK])).needs_process());
+    assert!(!SampleMeta {
+        args: SampleArgs::ARG_MAX,
+        ..meta(Some(vec![TopP, TopK]))
+    }
+    .needs_process());
+}
+
+#[test]
+fn test_process_rows() {
+    let hot = SampleArgs {
+        temperature: 1.,
+        top_p: 0.5,
+        top_k: 2,
+    };
+    let metas = [
+        SampleMeta {
+            num_decode: 2,
+            args: hot,
+            ..Default::default()
+        },
+        SampleMeta {
+            num_decode: 1,
+            args: hot,
+            logit_bias: HashMap::from([(0, f32::NEG_INFINITY)]),
+            no_repeat_ngram: Some(Arc::new(Mutex::new(NgramBlocker::new(2)))),
+            ..Default::default()
+        },
+    ];
+    // 只有需要处理的序列由算子按温度在处理后的候选词上采样
+    let args = sample_args(&metas, 4);
+    assert_eq!(args.len(), 3);
+    assert_eq!((args[0].top_p, args[0].top_k), (0.5, 2));
+    assert_eq!((args[2].top_p, args[2].top_k), (1., 4));
+
+    let origin = [1., 2., 3., 4.].map(f16::from_f32);
+    let mut logits = origin.repeat(3);
+    process_rows(&metas, &mut logits, 4);
+    assert_eq!(logits[..8], origin.repeat(2));
+    assert_eq!(logits[8], f16::NEG_INFINITY);
+
+    // 每个序列只记录自己的行采样到的词
+    observe_rows(&metas, &logits, 4, &[3, 3, 2]);
+    let mut blocker = metas[1].no_repeat_ngram.as_ref().unwrap().lock().unwrap();
+    blocker.extend([3, 2]);
+    assert_eq!(blocker.banned(), &[3]);
+}
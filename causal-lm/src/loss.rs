@@ -0,0 +1,90 @@
+use common::{bf16, f16, utok};
+use digit_layout::types::{BF16, F16, F32};
+use std::ops::Deref;
+use tensor::{reslice, Tensor};
+
+/// 计算 logits 相对目标词的平均交叉熵：`-mean(log_softmax(logits)[target])`。
+///
+/// `logits` 形状为 `num_tokens x vocab_size`，每行对应 `targets` 中的一个词，在 f32 下计算。
+/// 等于 `ignore_index` 的目标不参与计算；所有目标都被忽略时返回 0。
+pub fn cross_entropy<T>(logits: &Tensor<T>, targets: &[utok], ignore_index: Option<utok>) -> f32
+where
+    T: Deref<Target = [u8]>,
+{
+    let &[nt, voc] = logits.shape() else {
+        panic!("logits must be 2-dimensional")
+    };
+    assert_eq!(nt as usize, targets.len());
+    assert!(logits.is_contiguous());
+
+    let voc = voc as usize;
+    let data = logits.as_slice();
+    let row: Box<dyn Fn(usize) -> Vec<f32> + '_> = match logits.data_layout() {
+        F16 => Box::new(|i| {
+            reslice::<u8, f16>(data)[i * voc..][..voc]
+                .iter()
+                .map(|x| x.to_f32())
+                .collect()
+        }),
+        BF16 => Box::new(|i| {
+            reslice::<u8, bf16>(data)[i * voc..][..voc]
+                .iter()
+                .map(|x| x.to_f32())
+                .collect()
+        }),
+        F32 => Box::new(|i| reslice::<u8, f32>(data)[i * voc..][..voc].to_vec()),
+        dt => panic!("unsupported logits data type: {dt:?}"),
+    };
+
+    let mut sum = 0.;
+    let mut count = 0;
+    for (i, &target) in targets.iter().enumerate() {
+        if Some(target) == ignore_index {
+            continue;
+        }
+        let logits = row(i);
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let log_sum_exp = logits.iter().map(|&x| (x - max).exp()).sum::<f32>().ln() + max;
+        sum += log_sum_exp - logits[target as usize];
+        count += 1;
+    }
+    if count == 0 {
+        0.
+    } else {
+        sum / count as f32
+    }
+}
+
+#[test]
+fn test() {
+    const IGNORE: utok = utok::MAX;
+
+    let mut data = vec![
+        1.0f32, 2., 3., 4., //
+        0.5, 0.5, 0.5, 0.5, //
+        9., -9., 9., -9., //
+    ];
+    let targets = [3, 1, IGNORE];
+
+    let reference = |row: &[f32], target: usize| {
+        let sum = row.iter().map(|x| x.exp()).sum::<f32>();
+        -(row[target].exp() / sum).ln()
+    };
+    let expected = (reference(&data[0..4], 3) + reference(&data[4..8], 1)) / 2.;
+
+    let loss = cross_entropy(
+        &Tensor::new(F32, &[3, 4], reslice::<f32, u8>(&data)),
+        &targets,
+        Some(IGNORE),
+    );
+    assert!((loss - expected).abs() < 1e-6);
+
+    // 被忽略的位置不影响结果
+    data[8..].copy_from_slice(&[100., 0., -100., 0.]);
+    let loss_ = cross_entropy(
+        &Tensor::new(F32, &[3, 4], reslice::<f32, u8>(&data)),
+        &targets,
+        Some(IGNORE),
+    );
+    assert_eq!(loss, loss_);
+}
@@ -0,0 +1,150 @@
+use crate::filter::{candidates, log_softmax, mask};
+use common::{f16, utok};
+use std::{cmp::Ordering, f32::consts::LOG2_E};
+
+/// Mirostat 采样的版本。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mirostat {
+    /// 假设概率服从 Zipf 分布，由 `mu` 估计保留的词数。
+    V1,
+    /// 直接屏蔽惊异度超过 `mu` 的词。
+    V2,
+}
+
+/// Mirostat 采样的状态，每个序列独立保存，在解码步之间延续。
+///
+/// 每步先按 [`truncate`](Self::truncate) 截断，采样后按 [`update`](Self::update) 更新 `mu`，
+/// 使采样到的词的惊异度（以比特计）趋近目标值 `tau`。
+#[derive(Clone, Debug)]
+pub struct MirostatState {
+    /// 算法版本。
+    pub version: Mirostat,
+    /// 目标惊异度。
+    pub tau: f32,
+    /// `mu` 的学习率。
+    pub eta: f32,
+    /// 当前允许的最大惊异度。
+    pub mu: f32,
+}
+
+impl MirostatState {
+    /// 创建新的状态，`mu` 初始化为 `2 * tau`。
+    #[inline]
+    pub fn new(version: Mirostat, tau: f32, eta: f32) -> Self {
+        Self {
+            version,
+            tau,
+            eta,
+            mu: 2. * tau,
+        }
+    }
+
+    /// 按当前的 `mu` 屏蔽惊异度过高的词，至少保留概率最大的词。
+    pub fn truncate(&self, logits: &mut [f16], temperature: f32) {
+        let logp = log_softmax(logits, temperature);
+        let mut order = candidates(&logp);
+        if order.is_empty() {
+            return;
+        }
+        order.sort_unstable_by(|&i, &j| logp[j].partial_cmp(&logp[i]).unwrap_or(Ordering::Equal));
+        let keep = match self.version {
+            Mirostat::V1 => self.estimate_k(&logp, &order),
+            Mirostat::V2 => order
+                .iter()
+                .position(|&i| -logp[i] * LOG2_E > self.mu)
+                .unwrap_or(order.len())
+                .max(1),
+        };
+        mask(logits, &order[keep..]);
+    }
+
+    /// 用截断后的 `logits` 计算采样到的 `token` 的惊异度，更新 `mu`。
+    pub fn update(&mut self, logits: &[f16], token: utok, temperature: f32) {
+        let logp = log_softmax(logits, temperature);
+        let surprise = -logp[token as usize] * LOG2_E;
+        if surprise.is_finite() {
+            self.mu -= self.eta * (surprise - self.tau);
+        }
+    }
+
+    /// 用前 100 个词拟合 Zipf 指数，估计惊异度不超过 `mu` 的词数。
+    fn estimate_k(&self, logp: &[f32], order: &[usize]) -> usize {
+        const M: usize = 100;
+        let n = order.len();
+        let (mut num, mut den) = (0., 0.);
+        for i in 0..M.min(n) - 1 {
+            let t = ((i + 2) as f32 / (i + 1) as f32).ln();
+            let b = logp[order[i]] - logp[order[i + 1]];
+            num += t * b;
+            den += t * t;
+        }
+        if den == 0. {
+            return n;
+        }
+        let s = num / den;
+        let e = s - 1.;
+        let k = (e * self.mu.exp2() / (1. - (n as f32).powf(-e))).powf(s.recip());
+        // 指数接近 1 或分布均匀时估计失效，不截断
+        if k.is_nan() {
+            n
+        } else {
+            (k.round() as usize).clamp(1, n)
+        }
+    }
+}
+
+#[test]
+fn test_mirostat() {
+    use crate::speculative::probs;
+
+    const N: usize = 1000;
+    const TAU: f32 = 3.;
+    const STEPS: usize = 4000;
+
+    // 平稳的 Zipf 分布
+    let zipf = (0..N)
+        .map(|i| f16::from_f32(-1.1 * ((i + 1) as f32).ln()))
+        .collect::<Vec<_>>();
+
+    for version in [Mirostat::V1, Mirostat::V2] {
+        let mut state = MirostatState::new(version, TAU, 0.1);
+        let mut seed = 1u64;
+        let mut surprise = 0.;
+        let mut mu = 0.;
+        for step in 0..STEPS {
+            let mut logits = zipf.clone();
+            state.truncate(&mut logits, 1.);
+            let logits_ = logits.iter().map(|x| x.to_f32()).collect::<Vec<_>>();
+            let p = probs(logits_, 1.);
+
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let mut u = (seed >> 40) as f32 / (1u64 << 24) as f32;
+            let token = p
+                .iter()
+                .position(|&p| {
+                    u -= p;
+                    u < 0.
+                })
+                .unwrap_or_else(|| p.iter().rposition(|&p| p > 0.).unwrap());
+
+            state.update(&logits, token as _, 1.);
+            if step >= STEPS / 2 {
+                surprise += -p[token].log2();
+                mu += state.mu;
+            }
+        }
+        let surprise = surprise / (STEPS / 2) as f32;
+        let mu = mu / (STEPS / 2) as f32;
+        // 观察到的惊异度收敛到 tau，mu 稳定在 2 * tau 附近
+        assert!(
+            (surprise - TAU).abs() < 0.1,
+            "{version:?}: surprise = {surprise}"
+        );
+        assert!(
+            (mu - 2. * TAU).abs() < 0.15 * 2. * TAU,
+            "{version:?}: mu = {mu}"
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{CausalLM, DecodingMeta, ForwardError, Model, QueryContext, SampleMeta};
+use common::{upos, utok, Blob};
+use digit_layout::types::F32;
+use std::path::Path;
+use tensor::{reslice, reslice_mut, Tensor};
+
+pub(crate) const VOC: usize = 4;
+
+/// 只依赖前一个词的模型，隐藏状态为当前词的独热向量，`table` 的第 `i` 行是词 `i` 之后的 logits。
+pub(crate) struct Bigram {
+    pub table: [[f32; VOC]; VOC],
+}
+
+impl Model for Bigram {
+    type Meta = ();
+    type Error = ();
+    fn load(_: impl AsRef<Path>, _: Self::Meta) -> Result<Self, Self::Error> {
+        Err(())
+    }
+}
+
+impl CausalLM for Bigram {
+    type Storage = Blob;
+
+    fn max_seq_len(&self) -> upos {
+        upos::MAX
+    }
+    fn bos_token(&self) -> utok {
+        0
+    }
+    fn eos_token(&self) -> utok {
+        utok::MAX
+    }
+    fn new_cache(&self) -> Tensor<Self::Storage> {
+        Tensor::alloc(F32, &[1], Blob::new)
+    }
+    fn duplicate_cache(&self, _: &Tensor<Self::Storage>, _: upos) -> Tensor<Self::Storage> {
+        self.new_cache()
+    }
+    fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage> {
+        let tokens = queries.into_iter().collect::<Vec<_>>();
+        let mut x = Tensor::alloc(F32, &[tokens.len() as _, VOC as _], Blob::new);
+        let x_ = reslice_mut::<u8, f32>(x.physical_mut());
+        x_.fill(0.);
+        for (row, &t) in x_.chunks_exact_mut(VOC).zip(&tokens) {
+            row[t as usize] = 1.;
+        }
+        x
+    }
+    fn forward<'a>(
+        &self,
+        _: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
+        token_embedded: Tensor<Self::Storage>,
+    ) -> Result<Tensor<Self::Storage>, ForwardError> {
+        Ok(token_embedded)
+    }
+    fn decode(
+        &self,
+        decoding: impl IntoIterator<Item = DecodingMeta>,
+        mut hidden_state: Tensor<Self::Storage>,
+    ) -> Tensor<Self::Storage> {
+        let range = DecodingMeta::select(&mut hidden_state, decoding, |dst, src| {
+            dst.copy_from_slice(src)
+        });
+        let x =
+            &reslice::<u8, f32>(hidden_state.physical())[range.start * VOC..][..range.len() * VOC];
+        let mut logits = Tensor::alloc(F32, &[range.len() as _, VOC as _], Blob::new);
+        for (row, x) in reslice_mut::<u8, f32>(logits.physical_mut())
+            .chunks_exact_mut(VOC)
+            .zip(x.chunks_exact(VOC))
+        {
+            let t = x.iter().position(|&x| x == 1.).unwrap();
+            row.copy_from_slice(&self.table[t]);
+        }
+        logits
+    }
+    fn sample(
+        &self,
+        _: impl IntoIterator<Item = SampleMeta>,
+        _: Tensor<Self::Storage>,
+    ) -> Vec<utok> {
+        unimplemented!()
+    }
+}
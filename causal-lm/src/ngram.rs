@@ -0,0 +1,103 @@
+use common::{f16, utok};
+use std::collections::HashMap;
+
+/// 禁止重复 n 元组，每个序列独立保存，在解码步之间延续。
+///
+/// 以前 `n - 1` 个词为键记录已经出现过的后继词，
+/// 采样前屏蔽会与历史中某个 n 元组完全相同的候选词。`n` 为 0 或 1 时不启用。
+#[derive(Clone, Default, Debug)]
+pub struct NgramBlocker {
+    n: usize,
+    /// 最近的至多 `n - 1` 个词。
+    prefix: Vec<utok>,
+    /// 前缀到出现过的后继词。
+    seen: HashMap<Vec<utok>, Vec<utok>>,
+}
+
+impl NgramBlocker {
+    /// 创建禁止重复 `n` 元组的状态。
+    #[inline]
+    pub fn new(n: usize) -> Self {
+        Self {
+            n,
+            ..Default::default()
+        }
+    }
+
+    /// 是否启用。
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.n > 1
+    }
+
+    /// 向历史追加一个词。
+    pub fn push(&mut self, token: utok) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self.prefix.len() == self.n - 1 {
+            let next = self.seen.entry(self.prefix.clone()).or_default();
+            if !next.contains(&token) {
+                next.push(token);
+            }
+            self.prefix.remove(0);
+        }
+        self.prefix.push(token);
+    }
+
+    /// 向历史追加多个词。
+    #[inline]
+    pub fn extend(&mut self, tokens: impl IntoIterator<Item = utok>) {
+        for token in tokens {
+            self.push(token);
+        }
+    }
+
+    /// 下一个词不能取的词。
+    pub fn banned(&self) -> &[utok] {
+        if !self.is_enabled() || self.prefix.len() < self.n - 1 {
+            return &[];
+        }
+        self.seen.get(&self.prefix).map_or(&[], |v| v)
+    }
+
+    /// 把下一个词不能取的词的 logits 设为 `-inf`。
+    pub fn block(&self, logits: &mut [f16]) {
+        for &token in self.banned() {
+            if let Some(x) = logits.get_mut(token as usize) {
+                *x = f16::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_ngram() {
+    let mut blocker = NgramBlocker::new(2);
+    blocker.extend([1, 2, 3, 1]);
+    // 二元组 (1, 2) 已经出现过
+    assert_eq!(blocker.banned(), &[2]);
+    let mut logits = [0.; 5].map(f16::from_f32);
+    blocker.block(&mut logits);
+    assert_eq!(
+        logits,
+        [0., 0., f32::NEG_INFINITY, 0., 0.].map(f16::from_f32)
+    );
+
+    blocker.extend([4, 1]);
+    assert_eq!(blocker.banned(), &[2, 4]);
+    blocker.push(3);
+    assert_eq!(blocker.banned(), &[1]);
+
+    let mut blocker = NgramBlocker::new(3);
+    blocker.extend([1, 2, 1]);
+    assert!(blocker.banned().is_empty());
+    blocker.push(2);
+    assert_eq!(blocker.banned(), &[1]);
+
+    for n in [0, 1] {
+        let mut blocker = NgramBlocker::new(n);
+        blocker.extend([1, 1, 1]);
+        assert!(blocker.banned().is_empty());
+    }
+}
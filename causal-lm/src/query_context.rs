@@ -1,5 +1,8 @@
 ﻿use common::upos;
-use std::ops::{DerefMut, Range};
+use std::{
+    error, fmt,
+    ops::{DerefMut, Range},
+};
 use tensor::{slice, split, udim, LocalSplitable, Tensor};
 
 /// 查询 Transformer 的的信息。
@@ -8,6 +11,11 @@ pub struct QueryContext<'a, Storage> {
     pub cache: Option<&'a mut Tensor<Storage>>,
     /// 查询在上下文中的位置。
     pub range: Range<upos>,
+    /// 查询使用的 LoRA 适配器序号，`None` 表示不使用适配器。
+    pub adapter: Option<usize>,
+    /// 加到注意力分数上的掩码，形状为 `[seq_len, att_len]`，列与上下文中的位置（包括已缓存的部分）对齐，
+    /// `-inf` 表示完全屏蔽；`None` 表示使用因果掩码。
+    pub mask: Option<&'a [f32]>,
 }
 
 impl<'a, Storage> QueryContext<'a, Storage> {
@@ -26,6 +34,74 @@ impl<'a, Storage> QueryContext<'a, Storage> {
     pub const fn att_len(&self) -> udim {
         self.range.end
     }
+    /// 检查注意力长度不超过 `max_seq_len`，否则查询将越过 K-V 缓存的边界。
+    #[inline]
+    pub const fn check_seq_len(&self, max_seq_len: upos) -> Result<(), SeqLenError> {
+        if self.range.end <= max_seq_len {
+            Ok(())
+        } else {
+            Err(SeqLenError {
+                att_len: self.range.end,
+                max_seq_len,
+            })
+        }
+    }
+    /// 检查查询不越过自身 K-V 缓存的边界，没有缓存时不检查。
+    #[inline]
+    pub fn check_cache(&self) -> Result<(), SeqLenError> {
+        match &self.cache {
+            Some(cache) => self.check_seq_len(cache.shape()[3]),
+            None => Ok(()),
+        }
+    }
+}
+
+/// 查询的注意力长度超过模型的最大序列长度。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct SeqLenError {
+    /// 查询需要的注意力长度。
+    pub att_len: upos,
+    /// 模型的最大序列长度。
+    pub max_seq_len: upos,
+}
+
+impl error::Error for SeqLenError {}
+impl fmt::Display for SeqLenError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "sequence length {} exceeds max sequence length {}",
+            self.att_len, self.max_seq_len
+        )
+    }
+}
+
+/// 前向传播无法执行的原因。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ForwardError {
+    /// 查询越过 K-V 缓存的边界。
+    SeqLen(SeqLenError),
+    /// 查询使用了模型不支持的特性，如自定义注意力掩码。
+    Unsupported(&'static str),
+}
+
+impl From<SeqLenError> for ForwardError {
+    #[inline]
+    fn from(e: SeqLenError) -> Self {
+        Self::SeqLen(e)
+    }
+}
+
+impl error::Error for ForwardError {}
+impl fmt::Display for ForwardError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::SeqLen(e) => write!(f, "{e}"),
+            Self::Unsupported(msg) => write!(f, "{msg}"),
+        }
+    }
 }
 
 type KVCache<'a, T> = (
@@ -61,3 +137,31 @@ where
         })
     }
 }
+
+#[test]
+fn test_check_seq_len() {
+    let query = |range| QueryContext::<Vec<u8>> {
+        cache: None,
+        range,
+        adapter: None,
+        mask: None,
+    };
+    assert_eq!(query(0..16).check_seq_len(16), Ok(()));
+    assert_eq!(query(12..16).check_seq_len(16), Ok(()));
+    // 提示词比最大序列长度更长
+    let e = query(0..20).check_seq_len(16).unwrap_err();
+    assert_eq!(
+        e,
+        SeqLenError {
+            att_len: 20,
+            max_seq_len: 16,
+        }
+    );
+    assert_eq!(
+        e.to_string(),
+        "sequence length 20 exceeds max sequence length 16"
+    );
+    assert_eq!(ForwardError::from(e).to_string(), e.to_string());
+    // 没有缓存时不检查
+    assert_eq!(query(0..20).check_cache(), Ok(()));
+}
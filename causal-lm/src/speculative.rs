@@ -0,0 +1,260 @@
+use crate::{CausalLM, DecodingMeta, ForwardError, QueryContext};
+use common::{bf16, f16, upos, utok};
+use digit_layout::types::{BF16, F16, F32};
+use std::ops::Deref;
+use tensor::{reslice, Tensor};
+
+/// 投机解码的结果。
+#[derive(Clone, Default, Debug)]
+pub struct Speculation {
+    /// 生成的词，不含提示词。
+    pub tokens: Vec<utok>,
+    /// 草稿模型提出的词数。
+    pub proposed: usize,
+    /// 被目标模型接受的草稿词数。
+    pub accepted: usize,
+}
+
+/// 投机解码：草稿模型每轮提出 `k` 个词，目标模型用一次前向验证，最多生成 `max_tokens` 个词。
+///
+/// 第 `i` 个草稿词以 `min(1, p(x) / q(x))` 的概率被接受，第一次拒绝时从 `max(0, p - q)` 重新采样；
+/// 全部接受时再从目标模型采样一个词。这样输出的分布与直接从目标模型采样一致。
+/// `temperature` 为 0 时两个模型都按贪心采样；遇到目标模型的结束符时停止。
+pub fn speculative_generate<T, D>(
+    target: &T,
+    draft: &D,
+    prompt: &[utok],
+    k: usize,
+    max_tokens: usize,
+    temperature: f32,
+    seed: u64,
+) -> Result<Speculation, ForwardError>
+where
+    T: CausalLM,
+    T::Storage: Deref<Target = [u8]>,
+    D: CausalLM,
+    D::Storage: Deref<Target = [u8]>,
+{
+    assert!(!prompt.is_empty() && k > 0);
+
+    let eos = target.eos_token();
+    let mut rng = XorShift(seed.max(1));
+    let mut ans = Speculation::default();
+    let mut seq = prompt.to_vec();
+    let mut target_cache = target.new_cache();
+    let mut draft_cache = draft.new_cache();
+    // 两个模型的缓存中已经有效的词数
+    let mut target_fed = 0;
+    let mut draft_fed = 0;
+
+    while ans.tokens.len() < max_tokens {
+        // 草稿模型逐个提出 k 个词
+        let mut proposal = Vec::with_capacity(k);
+        let mut q = Vec::with_capacity(k);
+        let mut input = seq[draft_fed..].to_vec();
+        let mut pos = draft_fed;
+        for _ in 0..k {
+            let row = logits(draft, &mut draft_cache, &input, pos, 1)?
+                .pop()
+                .unwrap();
+            pos += input.len();
+            let dist = probs(row, temperature);
+            let token = sample(&dist, &mut rng);
+            proposal.push(token);
+            q.push(dist);
+            input = vec![token];
+        }
+
+        // 目标模型一次计算所有草稿位置的分布
+        let mut input = seq[target_fed..].to_vec();
+        input.extend_from_slice(&proposal);
+        let p = logits(target, &mut target_cache, &input, target_fed, k + 1)?
+            .into_iter()
+            .map(|row| probs(row, temperature))
+            .collect::<Vec<_>>();
+
+        let mut accepted = 0;
+        let mut next = None;
+        for (i, &token) in proposal.iter().enumerate() {
+            let (pt, qt) = (p[i][token as usize], q[i][token as usize]);
+            if rng.next() * qt < pt {
+                accepted += 1;
+            } else {
+                let residual = p[i]
+                    .iter()
+                    .zip(&q[i])
+                    .map(|(p, q)| (p - q).max(0.))
+                    .collect::<Vec<_>>();
+                next = Some(sample(&residual, &mut rng));
+                break;
+            }
+        }
+        let next = next.unwrap_or_else(|| sample(&p[k], &mut rng));
+        ans.proposed += k;
+        ans.accepted += accepted;
+
+        // 草稿模型缓存了除最后一个草稿词外的所有词，目标模型缓存了所有草稿词
+        let len = seq.len();
+        draft_fed = len + accepted.min(k - 1);
+        target_fed = len + accepted;
+        seq.extend_from_slice(&proposal[..accepted]);
+        seq.push(next);
+
+        for &token in &seq[len..] {
+            ans.tokens.push(token);
+            if token == eos || ans.tokens.len() == max_tokens {
+                return Ok(ans);
+            }
+        }
+    }
+    Ok(ans)
+}
+
+/// 推理 `tokens`，返回最后 `num_decode` 个位置的 logits。
+fn logits<M>(
+    model: &M,
+    cache: &mut Tensor<M::Storage>,
+    tokens: &[utok],
+    pos: usize,
+    num_decode: usize,
+) -> Result<Vec<Vec<f32>>, ForwardError>
+where
+    M: CausalLM,
+    M::Storage: Deref<Target = [u8]>,
+{
+    let token_embedded = model.token_embed(tokens.iter().copied());
+    let queries = [QueryContext {
+        cache: Some(cache),
+        range: pos as upos..(pos + tokens.len()) as upos,
+        adapter: None,
+        mask: None,
+    }];
+    let hidden_state = model.forward(queries, token_embedded)?;
+    let decoding = [DecodingMeta {
+        num_query: tokens.len(),
+        num_decode,
+    }];
+    Ok(rows(&model.decode(decoding, hidden_state)))
+}
+
+/// 把 `[n, d]` 的张量按行转换为 f32。
+pub(crate) fn rows<S: Deref<Target = [u8]>>(tensor: &Tensor<S>) -> Vec<Vec<f32>> {
+    let &[_, d] = tensor.shape() else { panic!() };
+    let data = tensor.as_slice();
+    let values: Vec<f32> = match tensor.data_layout() {
+        F16 => reslice::<u8, f16>(data)
+            .iter()
+            .map(|x| x.to_f32())
+            .collect(),
+        BF16 => reslice::<u8, bf16>(data)
+            .iter()
+            .map(|x| x.to_f32())
+            .collect(),
+        F32 => reslice::<u8, f32>(data).to_vec(),
+        dt => panic!("unsupported data type: {dt:?}"),
+    };
+    values.chunks_exact(d as _).map(<[f32]>::to_vec).collect()
+}
+
+/// 带温度的 softmax，温度为 0 时为最大值处的独热分布。
+pub(crate) fn probs(mut logits: Vec<f32>, temperature: f32) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if temperature <= 0. {
+        let i = logits.iter().position(|&x| x == max).unwrap();
+        logits.fill(0.);
+        logits[i] = 1.;
+        return logits;
+    }
+    let mut sum = 0.;
+    for x in &mut logits {
+        *x = ((*x - max) / temperature).exp();
+        sum += *x;
+    }
+    for x in &mut logits {
+        *x /= sum;
+    }
+    logits
+}
+
+/// 按未归一化的权重采样。
+fn sample(weights: &[f32], rng: &mut XorShift) -> utok {
+    let mut u = rng.next() * weights.iter().sum::<f32>();
+    for (i, &w) in weights.iter().enumerate() {
+        if u < w {
+            return i as _;
+        }
+        u -= w;
+    }
+    // 舍入误差导致没有命中时取最后一个概率非零的词
+    weights.iter().rposition(|&w| w > 0.).unwrap_or(0) as _
+}
+
+/// 产生 `[0, 1)` 均匀分布随机数的 xorshift 生成器。
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+#[test]
+fn test_speculative() {
+    use crate::mock::{Bigram, VOC};
+
+    let target = Bigram {
+        table: [
+            [0.5, 1.5, -1., 0.],
+            [2., 0., 0.5, -0.5],
+            [0., 0., 0., 1.],
+            [1., -2., 1., 0.5],
+        ],
+    };
+    let draft = Bigram {
+        table: [
+            [1., 0., 0., 0.],
+            [0., 1., 0., 0.],
+            [0.5, 0.5, 0., 0.],
+            [0., 0., 2., 0.],
+        ],
+    };
+
+    // 草稿模型与目标模型相同时全部接受
+    for temperature in [0., 0.7, 1.] {
+        let ans = speculative_generate(&target, &target, &[0, 1], 4, 17, temperature, 7).unwrap();
+        assert_eq!(ans.tokens.len(), 17);
+        assert_eq!(ans.accepted, ans.proposed);
+    }
+
+    // 贪心解码时结果与草稿模型无关
+    let expected = speculative_generate(&target, &target, &[2], 3, 12, 0., 1)
+        .unwrap()
+        .tokens;
+    let ans = speculative_generate(&target, &draft, &[2], 3, 12, 0., 1).unwrap();
+    assert_eq!(ans.tokens, expected);
+    assert!(ans.accepted < ans.proposed);
+
+    // 前两个词的边缘分布与直接从目标模型采样一致
+    const N: usize = 20000;
+    let p = |row: usize| probs(target.table[row].to_vec(), 1.);
+    let p1 = p(0);
+    let p2 = (0..VOC)
+        .map(|x| (0..VOC).map(|a| p1[a] * p(a)[x]).sum::<f32>())
+        .collect::<Vec<_>>();
+
+    let mut count = [[0usize; VOC]; 2];
+    for seed in 0..N as u64 {
+        let ans = speculative_generate(&target, &draft, &[0], 3, 2, 1., seed * 7919 + 1).unwrap();
+        count[0][ans.tokens[0] as usize] += 1;
+        count[1][ans.tokens[1] as usize] += 1;
+    }
+    for (count, expected) in count.iter().zip([p1, p2]) {
+        for (&c, e) in count.iter().zip(expected) {
+            let freq = c as f32 / N as f32;
+            assert!((freq - e).abs() < 0.02, "{freq} vs {e}");
+        }
+    }
+}
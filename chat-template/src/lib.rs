@@ -117,3 +117,49 @@ fn test() {
         .unwrap();
     assert_eq!(result, "<用户>Hello, who are you?<AI>");
 }
+
+#[test]
+fn test_tinyllama() {
+    const TINYLLAMA: &str = "{% for message in messages %}{% if message['role'] == 'user' %}{{ '<|user|>\n' + message['content'] + eos_token }}{% elif message['role'] == 'system' %}{{ '<|system|>\n' + message['content'] + eos_token }}{% elif message['role'] == 'assistant' %}{{ '<|assistant|>\n' + message['content'] + eos_token }}{% endif %}{% if loop.last and add_generation_prompt %}{{ '<|assistant|>\n' }}{% endif %}{% endfor %}";
+    let template = ChatTemplate::new(TINYLLAMA.into());
+
+    // 与 llama-cpu 中 test_infer 使用的提示词一致
+    let result = template
+        .render(
+            &[Message {
+                role: "user",
+                content: "Say \"Hi\" to me.",
+            }],
+            "<s>",
+            "</s>",
+            true,
+        )
+        .unwrap();
+    assert_eq!(result, "<|user|>\nSay \"Hi\" to me.</s><|assistant|>\n");
+
+    let result = template
+        .render(
+            &[
+                Message {
+                    role: "system",
+                    content: "You are a friendly chatbot.",
+                },
+                Message {
+                    role: "user",
+                    content: "Hi",
+                },
+                Message {
+                    role: "assistant",
+                    content: "Hello!",
+                },
+            ],
+            "<s>",
+            "</s>",
+            false,
+        )
+        .unwrap();
+    assert_eq!(
+        result,
+        "<|system|>\nYou are a friendly chatbot.</s><|user|>\nHi</s><|assistant|>\nHello!</s>"
+    );
+}
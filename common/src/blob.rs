@@ -19,9 +19,16 @@ impl Blob {
     /// Creates a new `Blob` with the given size.
     ///
     /// The allocated block of memory may or may not be initialized.
+    /// A zero-sized `Blob` does not allocate.
     #[inline]
     pub fn new(size: usize) -> Self {
         const ALIGN: usize = align_of::<usize>();
+        if size == 0 {
+            return Self {
+                ptr: NonNull::<usize>::dangling().cast(),
+                len: 0,
+            };
+        }
         let layout = Layout::from_size_align(size, ALIGN).unwrap();
         Self {
             ptr: NonNull::new(unsafe { alloc(layout) }).unwrap(),
@@ -33,6 +40,9 @@ impl Blob {
 impl Drop for Blob {
     #[inline]
     fn drop(&mut self) {
+        if self.len == 0 {
+            return;
+        }
         const ALIGN: usize = align_of::<usize>();
         let layout = Layout::from_size_align(self.len, ALIGN).unwrap();
         unsafe { dealloc(self.ptr.as_ptr(), layout) }
@@ -11,11 +11,13 @@ pub type utok = u32;
 pub type upos = u32;
 
 mod blob;
+mod pool;
 pub mod safe_tensors;
 pub mod test_model;
 
 pub use blob::Blob;
 pub use half::{bf16, f16};
+pub use pool::{BlobPool, PooledBlob};
 
 /// 加载 safetensors 文件可能产生的错误。
 #[derive(Debug)]
@@ -24,4 +26,17 @@ pub enum FileLoadError {
     Io(std::io::Error),
     /// Json 解析错误。
     Json(serde_json::Error),
+    /// 不支持的数据类型。
+    UnsupportedDtype(String),
+    /// 数据校验失败，列出所有不符合预期的张量或文件。
+    Mismatch(Vec<String>),
+    /// 模型文件中缺少需要的张量。
+    MissingTensor {
+        /// 缺少的张量名。
+        name: String,
+        /// 查找过的文件。
+        files: Vec<std::path::PathBuf>,
+    },
+    /// 模型使用了当前设备不支持的特性。
+    Unsupported(String),
 }
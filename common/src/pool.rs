@@ -0,0 +1,195 @@
+use crate::Blob;
+use std::{
+    collections::HashMap,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+};
+
+/// 按大小级别回收 [`Blob`] 的内存池，可以在线程间共享。
+///
+/// 请求的大小向上取整到 2 的幂，从池中取出的 [`PooledBlob`] 释放时归还到所属级别，
+/// 之后同一级别的请求直接复用。解码循环中随注意力长度缓慢增长的缓冲区因此只在跨越级别时重新分配。
+/// 池中空闲内存的总字节数不超过上限，超出时释放归还的内存。
+#[derive(Clone, Default)]
+pub struct BlobPool(Arc<Internal>);
+
+struct Internal {
+    free: Mutex<Free>,
+    limit: usize,
+    allocated: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Free {
+    blobs: HashMap<usize, Vec<Blob>>,
+    bytes: usize,
+}
+
+impl Default for Internal {
+    fn default() -> Self {
+        Self {
+            free: Default::default(),
+            limit: BlobPool::DEFAULT_LIMIT,
+            allocated: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl BlobPool {
+    /// 默认的空闲内存上限。
+    pub const DEFAULT_LIMIT: usize = 1 << 30;
+
+    /// 创建空的内存池。
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 创建空闲内存总量不超过 `limit` 字节的内存池。
+    #[inline]
+    pub fn with_limit(limit: usize) -> Self {
+        Self(Arc::new(Internal {
+            limit,
+            ..Default::default()
+        }))
+    }
+
+    /// 取出一块 `size` 字节的内存，池中没有同一级别的空闲内存时新分配一块。
+    ///
+    /// 复用的内存保留上次使用时的内容。
+    pub fn take(&self, size: usize) -> PooledBlob {
+        let class = size.next_power_of_two();
+        let blob = {
+            let mut free = self.0.free.lock().unwrap();
+            let blob = free.blobs.get_mut(&class).and_then(Vec::pop);
+            if blob.is_some() {
+                free.bytes -= class;
+            }
+            blob
+        };
+        let blob = blob.unwrap_or_else(|| {
+            self.0.allocated.fetch_add(1, Relaxed);
+            Blob::new(class)
+        });
+        PooledBlob {
+            blob: ManuallyDrop::new(blob),
+            len: size,
+            pool: self.clone(),
+        }
+    }
+
+    /// 池创建以来实际分配的内存块数。
+    #[inline]
+    pub fn allocated(&self) -> usize {
+        self.0.allocated.load(Relaxed)
+    }
+
+    /// 池中空闲的内存块数。
+    pub fn idle(&self) -> usize {
+        let free = self.0.free.lock().unwrap();
+        free.blobs.values().map(Vec::len).sum()
+    }
+
+    /// 池中空闲内存的总字节数。
+    #[inline]
+    pub fn idle_bytes(&self) -> usize {
+        self.0.free.lock().unwrap().bytes
+    }
+
+    /// 释放池中所有空闲的内存块。
+    pub fn clear(&self) {
+        let mut free = self.0.free.lock().unwrap();
+        free.blobs.clear();
+        free.bytes = 0;
+    }
+}
+
+/// 从 [`BlobPool`] 中取出的内存，释放时归还到池中。
+pub struct PooledBlob {
+    blob: ManuallyDrop<Blob>,
+    len: usize,
+    pool: BlobPool,
+}
+
+impl Drop for PooledBlob {
+    #[inline]
+    fn drop(&mut self) {
+        let blob = unsafe { ManuallyDrop::take(&mut self.blob) };
+        let class = blob.len();
+        let mut free = self.pool.0.free.lock().unwrap();
+        // 超出上限的内存直接释放
+        if free.bytes + class <= self.pool.0.limit {
+            free.bytes += class;
+            free.blobs.entry(class).or_default().push(blob);
+        }
+    }
+}
+
+impl Deref for PooledBlob {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.blob[..self.len]
+    }
+}
+
+impl DerefMut for PooledBlob {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.blob[..self.len]
+    }
+}
+
+#[test]
+fn test_pool() {
+    use std::thread;
+
+    let pool = BlobPool::new();
+    // 模拟批量解码：每步多个线程各自申请几块不同大小的缓冲区，用完后释放
+    const THREADS: usize = 4;
+    const SIZES: [usize; 3] = [0, 64, 4096];
+    for step in 0..100 {
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    let mut bufs = SIZES.map(|size| pool.take(size));
+                    for (buf, size) in bufs.iter_mut().zip(SIZES) {
+                        assert_eq!(buf.len(), size);
+                        buf.fill(step as _);
+                    }
+                });
+            }
+        });
+        assert_eq!(pool.idle(), pool.allocated());
+    }
+    assert!(pool.allocated() <= THREADS * SIZES.len());
+
+    pool.clear();
+    assert_eq!(pool.idle(), 0);
+}
+
+#[test]
+fn test_size_class() {
+    // 缓慢增长的请求只在跨越 2 的幂时重新分配
+    let pool = BlobPool::new();
+    for size in 1..=1000 {
+        let buf = pool.take(size);
+        assert_eq!(buf.len(), size);
+    }
+    assert_eq!(pool.allocated(), 11);
+    assert_eq!(pool.idle(), 11);
+
+    // 超出上限的内存归还时直接释放
+    let pool = BlobPool::with_limit(1024);
+    drop([pool.take(1000), pool.take(600), pool.take(16)]);
+    assert_eq!(pool.idle_bytes(), 1024);
+    assert_eq!(pool.idle(), 1);
+    drop(pool.take(1000));
+    assert_eq!(pool.allocated(), 3);
+    assert!(pool.idle_bytes() <= 1024);
+}
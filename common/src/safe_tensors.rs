@@ -1,14 +1,15 @@
 ﻿//! safetensors 文件的加载和访问。
 
-use crate::FileLoadError::{self, Io, Json};
+use crate::FileLoadError::{self, Io, Json, Mismatch};
 use memmap2::Mmap;
+use sha2::{Digest, Sha256};
 use std::{
     collections::{hash_map, HashMap},
-    fs::File,
+    fs::{self, File},
     io::{Error as IoError, ErrorKind::NotFound},
     mem::size_of_val,
     ops::Deref,
-    path::Path,
+    path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
 };
@@ -19,6 +20,7 @@ pub use safetensors::{tensor::TensorInfo, Dtype};
 pub struct SafeTensors {
     tensors: HashMap<String, (usize, TensorInfo)>, // name -> (file_index, tensor_info)
     files: Vec<(Mmap, String)>,                    // file_index -> (mmap, format)
+    headers: Vec<(PathBuf, HashMap<String, String>)>, // file_index -> (path, metadata)
 }
 
 /// safetensors 文件中的张量映射。
@@ -62,6 +64,7 @@ impl SafeTensors {
 
     /// 加载单个 `.safetensors` 文件。
     pub fn single_file(path: impl AsRef<Path>) -> Result<Self, FileLoadError> {
+        let path = path.as_ref();
         let file = File::open(path).map_err(Io)?;
         let file = unsafe { Mmap::map(&file) }.map_err(Io)?;
         let header = load_header(&file)?;
@@ -72,6 +75,7 @@ impl SafeTensors {
                 .map(|(name, info)| (name, (0, info)))
                 .collect(),
             files: vec![(file, header.metadata.format)],
+            headers: vec![(path.into(), header.metadata.extra)],
         })
     }
 
@@ -92,6 +96,7 @@ impl SafeTensors {
         let mut tensors = HashMap::new();
         let mut file_map = HashMap::new();
         let mut files = Vec::new();
+        let mut headers = Vec::new();
         // 迭代所有张量
         for (name, filename) in index.weight_map {
             match file_map.entry(filename) {
@@ -103,7 +108,8 @@ impl SafeTensors {
                 // 张量在新文件中
                 Entry::Vacant(e) => {
                     // 打开文件
-                    let file = File::open(dir.join(e.key())).map_err(Io)?;
+                    let path = dir.join(e.key());
+                    let file = File::open(&path).map_err(Io)?;
                     let file = unsafe { Mmap::map(&file) }.map_err(Io)?;
                     let header = load_header(&file)?;
                     // 迭代文件中的张量
@@ -117,11 +123,72 @@ impl SafeTensors {
                     // 记录文件映射
                     e.insert(i);
                     files.push((file, header.metadata.format));
+                    headers.push((path, header.metadata.extra));
                 }
             };
         }
 
-        Ok(Self { tensors, files })
+        Ok(Self {
+            tensors,
+            files,
+            headers,
+        })
+    }
+
+    /// 校验所有张量的数据。
+    ///
+    /// 总是检查每个张量的数据区大小与形状和数据类型一致且未超出文件范围；
+    /// 若文件旁有 `<文件名>.sha256`，校验整个文件的哈希；
+    /// 若文件元信息中有 `sha256.<张量名>`，校验该张量数据的哈希。
+    pub fn verify(&self) -> Result<(), FileLoadError> {
+        let mut errors = Vec::new();
+
+        let mut names = self.tensors.keys().collect::<Vec<_>>();
+        names.sort_unstable();
+        for name in names {
+            let (i, info) = &self.tensors[name];
+            let (begin, end) = info.data_offsets;
+            let expected = info.shape.iter().product::<usize>() * dtype_size(info.dtype)?;
+            if end < begin || end - begin != expected {
+                errors.push(format!(
+                    "{name}: expected {expected} bytes, header declares {}",
+                    end.saturating_sub(begin),
+                ));
+                continue;
+            }
+            let available = data_region(&self.files[*i].0).len();
+            if end > available {
+                errors.push(format!(
+                    "{name}: data ends at byte {end}, but only {available} bytes in file",
+                ));
+                continue;
+            }
+            if let Some(hash) = self.headers[*i].1.get(&format!("sha256.{name}")) {
+                let data = self.get_internal(*i, info).data;
+                if !hash.eq_ignore_ascii_case(&format!("{:x}", Sha256::digest(data))) {
+                    errors.push(format!("{name}: sha256 mismatch"));
+                }
+            }
+        }
+
+        for ((file, _), (path, _)) in self.files.iter().zip(&self.headers) {
+            let mut sidecar = path.clone().into_os_string();
+            sidecar.push(".sha256");
+            let Ok(text) = fs::read_to_string(sidecar) else {
+                continue;
+            };
+            // 兼容 `sha256sum` 的输出格式
+            let expected = text.split_whitespace().next().unwrap_or_default();
+            if !expected.eq_ignore_ascii_case(&format!("{:x}", Sha256::digest(&file[..]))) {
+                errors.push(format!("{}: sha256 mismatch", path.display()));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Mismatch(errors))
+        }
     }
 
     /// 共享自身。
@@ -141,6 +208,18 @@ impl SafeTensors {
         })
     }
 
+    /// 获取共享的张量，不存在时返回带有张量名和所有文件路径的错误。
+    pub fn require_tensor(
+        self: &Pin<Arc<Self>>,
+        name: &str,
+    ) -> Result<SharedTensor, FileLoadError> {
+        self.share_tensor(name)
+            .ok_or_else(|| FileLoadError::MissingTensor {
+                name: name.into(),
+                files: self.headers.iter().map(|(path, _)| path.clone()).collect(),
+            })
+    }
+
     /// 检查张量是否存在。
     #[inline]
     pub fn contains(&self, name: &str) -> bool {
@@ -178,12 +257,11 @@ impl SafeTensors {
 
     fn get_internal<'a>(&'a self, i: usize, info: &'a TensorInfo) -> SafeTensor<'a> {
         let (file, format) = &self.files[i];
-        let header_len = unsafe { *file.as_ptr().cast::<u64>() };
         let (begin, end) = info.data_offsets;
         SafeTensor {
             dtype: info.dtype,
             shape: &info.shape,
-            data: &file[size_of_val(&header_len)..][header_len as _..][begin..end],
+            data: &data_region(file)[begin..end],
             format,
         }
     }
@@ -276,6 +354,7 @@ pub struct SafeTensorsHeader {
 fn default_metadata() -> SafeTensorsHeaderMetadata {
     SafeTensorsHeaderMetadata {
         format: "pt".into(),
+        extra: HashMap::new(),
     }
 }
 
@@ -283,6 +362,8 @@ fn default_metadata() -> SafeTensorsHeaderMetadata {
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct SafeTensorsHeaderMetadata {
     pub format: String,
+    #[serde(flatten, skip_serializing_if = "HashMap::is_empty")]
+    pub extra: HashMap<String, String>,
 }
 
 fn load_header(file: &Mmap) -> Result<SafeTensorsHeader, FileLoadError> {
@@ -291,6 +372,23 @@ fn load_header(file: &Mmap) -> Result<SafeTensorsHeader, FileLoadError> {
     serde_json::from_slice(header).map_err(Json)
 }
 
+/// 文件中头部之后的数据区。
+#[inline]
+fn data_region(file: &Mmap) -> &[u8] {
+    let header_len = unsafe { *file.as_ptr().cast::<u64>() };
+    &file[size_of_val(&header_len)..][header_len as _..]
+}
+
+fn dtype_size(dtype: Dtype) -> Result<usize, FileLoadError> {
+    match dtype {
+        Dtype::BOOL | Dtype::U8 | Dtype::I8 | Dtype::F8_E5M2 | Dtype::F8_E4M3 => Ok(1),
+        Dtype::I16 | Dtype::U16 | Dtype::F16 | Dtype::BF16 => Ok(2),
+        Dtype::I32 | Dtype::U32 | Dtype::F32 => Ok(4),
+        Dtype::I64 | Dtype::U64 | Dtype::F64 => Ok(8),
+        dtype => Err(FileLoadError::UnsupportedDtype(format!("{dtype:?}"))),
+    }
+}
+
 #[test]
 fn test() {
     let Some(model_dir) = crate::test_model::find() else {
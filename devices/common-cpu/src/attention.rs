@@ -0,0 +1,143 @@
+use crate::{softmax::softmax, CpuKernels, KernelsA, ThisThread};
+use common::Blob;
+use std::ops::{Deref, DerefMut};
+use tensor::{udim, SliceDim, Tensor};
+
+impl CpuKernels {
+    /// 分块计算缩放点积注意力，每次只为 `tile` 行查询分配注意力分数。
+    ///
+    /// `q`/`o` 形状为 `[nh, seq, dh]`，`k`/`v` 形状为 `[nkvh, att, dh]`；
+    /// `causal` 时第 `i` 行查询只能看到前 `att - seq + i + 1` 个键。
+    pub fn attention<T, U, V, W>(
+        &self,
+        o: &mut Tensor<T>,
+        q: &Tensor<U>,
+        k: &Tensor<V>,
+        v: &Tensor<W>,
+        causal: bool,
+        tile: udim,
+    ) where
+        T: DerefMut<Target = [u8]>,
+        U: Deref<Target = [u8]>,
+        V: Deref<Target = [u8]>,
+        W: Deref<Target = [u8]>,
+    {
+        let &[nh, seq, dh] = q.shape() else { panic!() };
+        let &[nkvh, att, _] = k.shape() else { panic!() };
+        assert_eq!(o.shape(), q.shape());
+        assert_eq!(k.shape(), &[nkvh, att, dh]);
+        assert_eq!(v.shape(), k.shape());
+        assert_eq!(nh % nkvh, 0);
+        assert!(att >= seq || !causal);
+
+        let dt = q.data_layout();
+        let head_group = nh / nkvh;
+        let head_div = (dh as f32).sqrt().recip();
+        let tile = tile.clamp(1, seq.max(1));
+
+        let mut q_buf = Blob::new((nh * tile * dh) as usize * dt.nbytes());
+        let mut att_buf = Blob::new((nh * tile * att) as usize * dt.nbytes());
+
+        let mut start = 0;
+        while start < seq {
+            let len = tile.min(seq - start);
+            // 因果掩码下这一块查询能看到的键数量
+            let att_len = if causal { att - seq + start + len } else { att };
+
+            let rows = &[ALL, range(start, len), ALL];
+            let keys = &[ALL, range(0, att_len), ALL];
+            let shape_q0 = &[nh, len, dh];
+            let shape_q1 = &[nkvh, head_group * len, dh];
+            let shape_att0 = &[nkvh, head_group * len, att_len];
+            let shape_att1 = &[nh, len, att_len];
+
+            let mut q_tile = Tensor::new(dt, shape_q0, &mut q_buf[..]);
+            let q_src = q.as_ref().slice(rows).map_physical(|u| &**u);
+            self.reform(&mut q_tile, &q_src, &ThisThread);
+
+            let q_tile = q_tile.reshape(shape_q1);
+            let k_att = k
+                .as_ref()
+                .slice(keys)
+                .transpose(&[0, 2, 1])
+                .map_physical(|u| &**u);
+            let v_att = v.as_ref().slice(keys).map_physical(|u| &**u);
+
+            let mut att_tile = Tensor::new(dt, shape_att0, &mut att_buf[..]);
+            self.mat_mul(&mut att_tile, 0., &q_tile, &k_att, head_div, &ThisThread);
+            let mut att_tile = att_tile.reshape(shape_att1);
+            if causal {
+                // 截断后的块恰好满足算子的因果掩码约定
+                self.softmax(&mut att_tile, &ThisThread);
+            } else {
+                softmax(&mut att_tile, None);
+            }
+
+            let mut x = q_tile;
+            let att_tile = att_tile.reshape(shape_att0);
+            self.mat_mul(&mut x, 0., &att_tile, &v_att, 1., &ThisThread);
+
+            let mut o_dst = o.as_mut().slice(rows).map_physical(|u| &mut **u);
+            self.reform(&mut o_dst, &x.reshape(shape_q0), &ThisThread);
+
+            start += len;
+        }
+    }
+}
+
+const ALL: SliceDim = range(0, udim::MAX);
+
+#[inline]
+const fn range(start: udim, len: udim) -> SliceDim {
+    SliceDim {
+        start,
+        step: 1,
+        len,
+    }
+}
+
+#[test]
+fn test_attention() {
+    use common::f16;
+    use digit_layout::types::F16;
+    use tensor::reslice_mut;
+
+    let (nh, seq, dh) = (2, 16, 64);
+    let tensor = |seed: usize| {
+        let mut t = Tensor::alloc(F16, &[nh, seq, dh], Blob::new);
+        for (i, x) in reslice_mut::<u8, f16>(t.physical_mut())
+            .iter_mut()
+            .enumerate()
+        {
+            *x = f16::from_f32((((i + seed) * 7) % 23) as f32 / 16. - 0.7);
+        }
+        t
+    };
+    let (q, k, v) = (tensor(0), tensor(5), tensor(11));
+    let kernels = CpuKernels::default();
+
+    // 一次性计算整个注意力分数矩阵的多步实现
+    let mut att = Tensor::alloc(F16, &[nh, seq, seq], Blob::new);
+    let k_t = k.as_ref().transpose(&[0, 2, 1]).map_physical(|u| &**u);
+    kernels.mat_mul(
+        &mut att,
+        0.,
+        &q,
+        &k_t,
+        (dh as f32).sqrt().recip(),
+        &ThisThread,
+    );
+    kernels.softmax(&mut att, &ThisThread);
+    let mut expected = Tensor::alloc(F16, &[nh, seq, dh], Blob::new);
+    kernels.mat_mul(&mut expected, 0., &att, &v, 1., &ThisThread);
+    let expected = reslice_mut::<u8, f16>(expected.physical_mut()).to_vec();
+
+    for tile in [1, 3, 4, 16] {
+        let mut o = Tensor::alloc(F16, &[nh, seq, dh], Blob::new);
+        kernels.attention(&mut o, &q, &k, &v, true, tile);
+        let o = reslice_mut::<u8, f16>(o.physical_mut());
+        for (a, b) in o.iter().zip(&expected) {
+            assert!((a.to_f32() - b.to_f32()).abs() < 1e-2, "tile = {tile}");
+        }
+    }
+}
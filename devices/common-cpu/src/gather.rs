@@ -16,11 +16,24 @@ where
     debug_assert_eq!(table.shape()[1], d);
     debug_assert!(x.is_contiguous());
     debug_assert!(table.is_contiguous());
+    let voc = table.shape()[0];
     let d = d as usize * x.data_layout().nbytes();
 
     let x = x.as_mut_slice();
     let table = table.as_slice();
     for (i, t) in tokens.into_iter().enumerate() {
+        // 损坏的词序列不能越界读取词表
+        assert!(t < voc, "token {t} out of range for vocab size {voc}");
         slice!(x; d; [i]).copy_from_slice(&slice!(table; d; [t]))
     }
 }
+
+#[test]
+#[should_panic(expected = "token 4 out of range for vocab size 4")]
+fn test_out_of_range() {
+    use digit_layout::types::F16;
+
+    let table = Tensor::alloc(F16, &[4, 8], |len| vec![0u8; len]);
+    let mut x = Tensor::alloc(F16, &[2, 8], |len| vec![0u8; len]);
+    gather(&mut x, &table, [3, 4]);
+}
@@ -0,0 +1,104 @@
+use crate::CpuKernels;
+use common::f16;
+use digit_layout::types::F16;
+use std::{
+    iter::zip,
+    ops::{Deref, DerefMut},
+    slice::{from_raw_parts, from_raw_parts_mut},
+};
+use tensor::{reslice, Tensor};
+
+impl CpuKernels {
+    /// 对形状为 `[n, d]` 的 `x` 逐行做层归一化，结果写入 `y`。
+    ///
+    /// 每行减去均值、除以标准差后乘 `w`，有 `b` 时再加上偏置。行之间可以有间隔，行内必须连续。
+    pub fn layer_norm<T, U, V>(
+        &self,
+        y: &mut Tensor<T>,
+        x: &Tensor<U>,
+        w: &Tensor<V>,
+        b: Option<&Tensor<V>>,
+        epsilon: f32,
+    ) where
+        T: DerefMut<Target = [u8]>,
+        U: Deref<Target = [u8]>,
+        V: Deref<Target = [u8]>,
+    {
+        let &[n, d] = x.shape() else { panic!() };
+        assert_eq!(y.shape(), &[n, d]);
+        assert_eq!(w.shape(), &[d]);
+        assert!([y.data_layout(), x.data_layout(), w.data_layout()]
+            .iter()
+            .all(|&dt| dt == F16));
+        let &[sy, 1] = y.strides() else { panic!() };
+        let &[sx, 1] = x.strides() else { panic!() };
+
+        let w: &[f16] = reslice(w.as_slice());
+        let b: Option<&[f16]> = b.map(|b| {
+            assert_eq!(b.shape(), &[d]);
+            assert_eq!(b.data_layout(), F16);
+            reslice(b.as_slice())
+        });
+        let d = d as usize;
+        let y_base = y.base_mut().cast::<f16>();
+        let x_base = x.base().cast::<f16>();
+        // 先把一行读入缓冲区，`y` 和 `x` 可以是同一块存储
+        let mut buf = vec![0.; d];
+        for i in 0..n as isize {
+            let x = unsafe { from_raw_parts(x_base.offset(i * sx as isize), d) };
+            for (y, x) in zip(&mut buf, x) {
+                *y = x.to_f32();
+            }
+            let mean = buf.iter().sum::<f32>() / d as f32;
+            let var = buf.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / d as f32;
+            let k = (var + epsilon).sqrt().recip();
+
+            let y = unsafe { from_raw_parts_mut(y_base.offset(i * sy as isize), d) };
+            for (j, (y, (x, w))) in zip(y, zip(&buf, w)).enumerate() {
+                let bias = b.map_or(0., |b| b[j].to_f32());
+                *y = f16::from_f32((x - mean) * k * w.to_f32() + bias);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_layer_norm() {
+    use common::Blob;
+    use tensor::reslice_mut;
+
+    const EPSILON: f32 = 1e-5;
+    fn tensor(shape: &[u32], data: &[f32]) -> Tensor<Blob> {
+        let mut t = Tensor::alloc(F16, shape, Blob::new);
+        for (x, y) in zip(reslice_mut::<u8, f16>(t.physical_mut()), data) {
+            *x = f16::from_f32(*y);
+        }
+        t
+    }
+
+    let x = (0..16)
+        .map(|i| ((i * 7 % 16) as f32 - 6.) / 4.)
+        .collect::<Vec<_>>();
+    let w = (0..8).map(|i| 0.5 + i as f32 / 8.).collect::<Vec<_>>();
+    let b = (0..8).map(|i| i as f32 / 16. - 0.25).collect::<Vec<_>>();
+
+    let kernels = CpuKernels::default();
+    let x_ = tensor(&[2, 8], &x);
+    let w_ = tensor(&[8], &w);
+    let b_ = tensor(&[8], &b);
+    for bias in [None, Some(&b_)] {
+        let mut y = Tensor::alloc(F16, &[2, 8], Blob::new);
+        kernels.layer_norm(&mut y, &x_, &w_, bias, EPSILON);
+        let y: &[f16] = reslice(y.as_slice());
+
+        for (x, y) in zip(x.chunks_exact(8), y.chunks_exact(8)) {
+            let mean = x.iter().sum::<f32>() / 8.;
+            let var = x.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / 8.;
+            for j in 0..8 {
+                let b = bias.map_or(0., |_| b[j]);
+                let expected = (x[j] - mean) / (var + EPSILON).sqrt() * w[j] + b;
+                assert!((y[j].to_f32() - expected).abs() < 1e-2);
+            }
+        }
+    }
+}
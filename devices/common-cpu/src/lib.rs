@@ -5,7 +5,13 @@ macro_rules! slice {
     };
 }
 
+mod attention;
 mod gather;
+mod layer_norm;
+mod mat_mul;
+mod q4;
+mod rope;
+mod softmax;
 
 use common::{f16, utok};
 use common_devices::{Operators, SliceOn};
@@ -20,6 +26,7 @@ use operators::{
     rope::common_cpu as rope,
     Operator, QueueOf,
 };
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use std::ops::{Deref, DerefMut};
 use tensor::Tensor;
 
@@ -27,6 +34,8 @@ pub extern crate tensor;
 
 pub use common_devices::{Kernels, KernelsA, KernelsB};
 pub use operators::common_cpu::{Handle as Cpu, ThisThread};
+pub use q4::{BlockQ4, MatrixQ4, Q4_BLOCK};
+pub use rope::RopeCache;
 
 pub struct CpuKernels {
     reform: reform::Operator,
@@ -36,9 +45,32 @@ pub struct CpuKernels {
     softmax: softmax::Operator,
     mlp: mlp::Operator,
     sample: random_sample::Operator,
+    pool: Option<ThreadPool>,
 }
 
 impl CpuKernels {
+    /// 创建使用独立线程池的算子库，在 [`install`](Self::install) 中执行的计算最多占用 `threads` 个线程。
+    pub fn with_threads(threads: usize) -> Self {
+        Self {
+            pool: Some(
+                ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .unwrap(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    /// 在算子库的线程池中执行 `f`，未指定线程数时使用全局线程池。
+    #[inline]
+    pub fn install<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        match &self.pool {
+            Some(pool) => pool.install(f),
+            None => f(),
+        }
+    }
+
     pub fn sample(&self, temperature: f32, top_p: f32, top_k: usize, logits: &[f16]) -> utok {
         let mut kv_pair = KVPair::new(0, f16::ZERO);
         let mut args = Args::<Cpu>::new(F16, logits.len());
@@ -64,6 +96,7 @@ impl Default for CpuKernels {
             softmax: softmax::Operator::new(&Cpu),
             mlp: mlp::Operator::new(&Cpu),
             sample: random_sample::Operator::new(&Cpu),
+            pool: None,
         }
     }
 }
@@ -122,3 +155,33 @@ impl KernelsB for CpuKernels {
         gather::gather(x, table, tokens);
     }
 }
+
+#[test]
+fn test_mat_mul_threads() {
+    use common::Blob;
+    use tensor::reslice_mut;
+
+    fn fill(shape: &[u32], f: impl Fn(usize) -> f32) -> Tensor<Blob> {
+        let mut t = Tensor::alloc(F16, shape, Blob::new);
+        for (i, x) in reslice_mut::<u8, f16>(t.physical_mut())
+            .iter_mut()
+            .enumerate()
+        {
+            *x = f16::from_f32(f(i));
+        }
+        t
+    }
+
+    let a = fill(&[64, 96], |i| ((i * 7) % 13) as f32 / 16. - 0.4);
+    let b = fill(&[96, 80], |i| ((i * 5) % 11) as f32 / 16. - 0.3);
+    let mat_mul = |kernels: &CpuKernels| {
+        let mut c = fill(&[64, 80], |_| 0.);
+        kernels.install(|| kernels.mat_mul(&mut c, 0., &a, &b, 1., &ThisThread));
+        c.as_slice().to_vec()
+    };
+
+    let global = mat_mul(&CpuKernels::default());
+    for threads in [1, 2, 4] {
+        assert_eq!(mat_mul(&CpuKernels::with_threads(threads)), global);
+    }
+}
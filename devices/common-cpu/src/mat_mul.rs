@@ -0,0 +1,114 @@
+use crate::{CpuKernels, KernelsA, ThisThread};
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+};
+use std::ops::{Deref, DerefMut};
+use tensor::{udim, Tensor};
+
+impl CpuKernels {
+    /// 批量矩阵乘 `c = beta * c + alpha * a x b`，形状为 `[h, m, k] x [h, k, n] -> [h, m, n]`。
+    ///
+    /// 各批次并行计算，每个批次调用一次二维的 [`mat_mul`](KernelsA::mat_mul)。
+    /// `c` 必须连续，以便按批次切分成互不重叠的可变切片。
+    pub fn mat_mul_batched<T, U, V>(
+        &self,
+        c: &mut Tensor<T>,
+        beta: f32,
+        a: &Tensor<U>,
+        b: &Tensor<V>,
+        alpha: f32,
+    ) where
+        T: DerefMut<Target = [u8]>,
+        U: Deref<Target = [u8]>,
+        V: Deref<Target = [u8]>,
+    {
+        let &[h, m, k] = a.shape() else {
+            panic!("a must be [h, m, k], got {:?}", a.shape())
+        };
+        let &[hb, kb, n] = b.shape() else {
+            panic!("b must be [h, k, n], got {:?}", b.shape())
+        };
+        assert_eq!(hb, h, "batch of b {hb} mismatch with batch of a {h}");
+        assert_eq!(kb, k, "b has {kb} rows, expected {k}");
+        assert_eq!(c.shape(), &[h, m, n]);
+        assert!(c.is_contiguous(), "c must be contiguous");
+
+        // a、b 只读，各批次共享底层切片
+        let batches = |t: Tensor<&[u8]>, rows: udim, cols: udim| {
+            t.split(0, &vec![1; h as usize])
+                .into_iter()
+                .map(|t| t.reshape(&[rows, cols]))
+                .collect::<Vec<_>>()
+        };
+        let a_ = batches(a.as_ref().map_physical(|u| &**u), m, k);
+        let b_ = batches(b.as_ref().map_physical(|u| &**u), k, n);
+
+        let dt = c.data_layout();
+        let batch_bytes = (m * n) as usize * dt.nbytes();
+        if batch_bytes == 0 {
+            return;
+        }
+        c.as_mut_slice()
+            .par_chunks_mut(batch_bytes)
+            .zip(a_)
+            .zip(b_)
+            .for_each(|((c_, a_), b_)| {
+                let mut c_ = Tensor::new(dt, &[m, n], c_);
+                self.mat_mul(&mut c_, beta, &a_, &b_, alpha, &ThisThread);
+            });
+    }
+}
+
+#[test]
+fn test_mat_mul_batched() {
+    use common::{f16, Blob};
+    use digit_layout::types::F16;
+    use tensor::{reslice, reslice_mut, slice};
+
+    fn fill(shape: &[udim], f: impl Fn(usize) -> f32) -> Tensor<Blob> {
+        let mut t = Tensor::alloc(F16, shape, Blob::new);
+        for (i, x) in reslice_mut::<u8, f16>(t.physical_mut())
+            .iter_mut()
+            .enumerate()
+        {
+            *x = f16::from_f32(f(i));
+        }
+        t
+    }
+
+    let (h, m, k, n) = (4, 3, 5, 6);
+    let a = fill(&[h, m, k], |i| ((i * 7) % 13) as f32 / 16. - 0.4);
+    // 转置后的 b 也能按批次计算
+    let b = fill(&[h, n, k], |i| ((i * 5) % 11) as f32 / 16. - 0.3).transpose(&[0, 2, 1]);
+    let c0 = |i| ((i * 3) % 7) as f32 / 8.;
+
+    let kernels = CpuKernels::default();
+    let mut batched = fill(&[h, m, n], c0);
+    kernels.mat_mul_batched(&mut batched, 0.5, &a, &b, 2.);
+
+    let mut expected = fill(&[h, m, n], c0);
+    for i in 0..h {
+        let batch = [slice![i =>=> 1], slice![=>], slice![=>]];
+        let mut c = expected
+            .as_mut()
+            .slice(&batch)
+            .reshape(&[m, n])
+            .map_physical(|u| &mut **u);
+        let a = a
+            .as_ref()
+            .slice(&batch)
+            .reshape(&[m, k])
+            .map_physical(|u| &**u);
+        let b = b
+            .as_ref()
+            .slice(&batch)
+            .reshape(&[k, n])
+            .map_physical(|u| &**u);
+        kernels.mat_mul(&mut c, 0.5, &a, &b, 2., &ThisThread);
+    }
+    assert_eq!(
+        reslice::<u8, f16>(batched.as_slice()),
+        reslice::<u8, f16>(expected.as_slice()),
+    );
+}
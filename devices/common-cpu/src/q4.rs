@@ -0,0 +1,261 @@
+use crate::CpuKernels;
+use common::f16;
+use digit_layout::types::F16;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::ops::{Deref, DerefMut};
+use tensor::{reslice, udim, Tensor};
+
+/// 每个量化块包含的权重数。
+pub const Q4_BLOCK: usize = 32;
+
+/// 对称 4 位量化块：`x = (q - 8) * scale`，每个字节低 4 位在前。
+///
+/// 按字节紧密排列，可以直接视作文件中的字节。
+#[derive(Clone, Copy, Default, Debug)]
+#[repr(C, packed)]
+pub struct BlockQ4 {
+    pub scale: f16,
+    pub qs: [u8; Q4_BLOCK / 2],
+}
+
+impl BlockQ4 {
+    fn quantize(x: &[f32; Q4_BLOCK]) -> Self {
+        let max = x.iter().fold(0f32, |m, x| m.max(x.abs()));
+        let scale = max / 7.;
+        let inv = if scale == 0. { 0. } else { scale.recip() };
+        let q = |x: f32| ((x * inv).round() as i32 + 8).clamp(0, 15) as u8;
+        let mut qs = [0; Q4_BLOCK / 2];
+        for (i, q2) in qs.iter_mut().enumerate() {
+            *q2 = q(x[2 * i]) | q(x[2 * i + 1]) << 4;
+        }
+        Self {
+            scale: f16::from_f32(scale),
+            qs,
+        }
+    }
+
+    fn dequantize(&self, y: &mut [f32; Q4_BLOCK]) {
+        let scale = self.scale.to_f32();
+        for (i, q2) in self.qs.iter().enumerate() {
+            y[2 * i] = ((q2 & 0xf) as i32 - 8) as f32 * scale;
+            y[2 * i + 1] = ((q2 >> 4) as i32 - 8) as f32 * scale;
+        }
+    }
+}
+
+/// 按列分块量化的 `[k, n]` 矩阵，用作 [`CpuKernels::mat_mul_q4`] 的右操作数。
+///
+/// 第 `j` 列的 `k / Q4_BLOCK` 个块连续存放，对应原始权重 `[n, k]` 的第 `j` 行。
+pub struct MatrixQ4<B = Vec<BlockQ4>> {
+    k: udim,
+    n: udim,
+    blocks: B,
+}
+
+impl MatrixQ4 {
+    /// 量化形状为 `[k, n]` 的 f16 矩阵，`k` 必须是 [`Q4_BLOCK`] 的整数倍。
+    pub fn quantize<T>(b: &Tensor<T>) -> Self
+    where
+        T: Deref<Target = [u8]>,
+    {
+        let &[k, n] = b.shape() else { panic!() };
+        assert_eq!(b.data_layout(), F16);
+        assert_eq!(k as usize % Q4_BLOCK, 0);
+        let &[sk, sn] = b.strides() else {
+            unreachable!()
+        };
+        let base = b.base().cast::<f16>() as usize;
+
+        let blocks = (0..n)
+            .into_par_iter()
+            .flat_map_iter(|j| {
+                (0..k as usize / Q4_BLOCK).map(move |blk| {
+                    let mut x = [0.; Q4_BLOCK];
+                    for (i, x) in x.iter_mut().enumerate() {
+                        let offset =
+                            (blk * Q4_BLOCK + i) as isize * sk as isize + j as isize * sn as isize;
+                        *x = unsafe { (base as *const f16).offset(offset).read_unaligned() }
+                            .to_f32();
+                    }
+                    BlockQ4::quantize(&x)
+                })
+            })
+            .collect();
+        Self { k, n, blocks }
+    }
+}
+
+impl<'a> MatrixQ4<&'a [BlockQ4]> {
+    /// 把 [`as_bytes`](MatrixQ4::as_bytes) 得到的字节视作 `[k, n]` 的量化矩阵，不复制数据。
+    pub fn from_bytes(k: udim, n: udim, bytes: &'a [u8]) -> Self {
+        assert_eq!(k as usize % Q4_BLOCK, 0);
+        let blocks = reslice::<u8, BlockQ4>(bytes);
+        assert_eq!(blocks.len() * Q4_BLOCK, k as usize * n as usize);
+        Self { k, n, blocks }
+    }
+}
+
+impl<B: Deref<Target = [BlockQ4]>> MatrixQ4<B> {
+    #[inline]
+    pub fn shape(&self) -> [udim; 2] {
+        [self.k, self.n]
+    }
+
+    /// 量化后占用的字节数。
+    #[inline]
+    pub fn nbytes(&self) -> usize {
+        self.blocks.len() * size_of::<BlockQ4>()
+    }
+
+    /// 量化块的字节，与 [`from_bytes`](MatrixQ4::from_bytes) 互逆。
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        reslice(&self.blocks)
+    }
+
+    #[inline]
+    fn column(&self, j: usize) -> &[BlockQ4] {
+        let len = self.k as usize / Q4_BLOCK;
+        &self.blocks[j * len..][..len]
+    }
+}
+
+impl CpuKernels {
+    /// `c = beta * c + alpha * a x b`，`a` 为 `[m, k]` 的 f16 激活，`b` 为量化权重。
+    ///
+    /// 逐块反量化后与 f32 激活做点积，不展开完整的 f16 权重。
+    pub fn mat_mul_q4<T, U, B>(
+        &self,
+        c: &mut Tensor<T>,
+        beta: f32,
+        a: &Tensor<U>,
+        b: &MatrixQ4<B>,
+        alpha: f32,
+    ) where
+        T: DerefMut<Target = [u8]>,
+        U: Deref<Target = [u8]>,
+        B: Deref<Target = [BlockQ4]> + Sync,
+    {
+        let &[m, k] = a.shape() else { panic!() };
+        let [_, n] = b.shape();
+        assert_eq!(b.shape(), [k, n]);
+        assert_eq!(c.shape(), &[m, n]);
+        assert_eq!(a.data_layout(), F16);
+        assert_eq!(c.data_layout(), F16);
+        let (m, k, n) = (m as usize, k as usize, n as usize);
+
+        let a = read(a);
+        let ab = self.install(|| {
+            (0..n)
+                .into_par_iter()
+                .map(|j| {
+                    let mut w = [0.; Q4_BLOCK];
+                    let mut col = vec![0f32; m];
+                    for (blk, block) in b.column(j).iter().enumerate() {
+                        block.dequantize(&mut w);
+                        for (i, y) in col.iter_mut().enumerate() {
+                            let x = &a[i * k + blk * Q4_BLOCK..][..Q4_BLOCK];
+                            *y += x.iter().zip(&w).map(|(x, w)| x * w).sum::<f32>();
+                        }
+                    }
+                    col
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let &[sm, sn] = c.strides() else {
+            unreachable!()
+        };
+        let base = c.base_mut().cast::<f16>();
+        for (j, col) in ab.into_iter().enumerate() {
+            for (i, y) in col.into_iter().enumerate() {
+                let ptr =
+                    unsafe { base.offset(i as isize * sm as isize + j as isize * sn as isize) };
+                let c0 = if beta == 0. {
+                    0.
+                } else {
+                    beta * unsafe { ptr.read_unaligned() }.to_f32()
+                };
+                unsafe { ptr.write_unaligned(f16::from_f32(c0 + alpha * y)) };
+            }
+        }
+    }
+}
+
+/// 把 `[m, k]` 的 f16 矩阵按行展开为 f32。
+fn read<T: Deref<Target = [u8]>>(a: &Tensor<T>) -> Vec<f32> {
+    let &[m, k] = a.shape() else { unreachable!() };
+    let &[sm, sk] = a.strides() else {
+        unreachable!()
+    };
+    let base = a.base().cast::<f16>();
+    (0..m as isize)
+        .flat_map(|i| {
+            (0..k as isize).map(move |j| {
+                unsafe {
+                    base.offset(i * sm as isize + j * sk as isize)
+                        .read_unaligned()
+                }
+                .to_f32()
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_mat_mul_q4() {
+    use crate::ThisThread;
+    use common::Blob;
+    use tensor::reslice_mut;
+
+    fn fill(shape: &[udim], f: impl Fn(usize) -> f32) -> Tensor<Blob> {
+        let mut t = Tensor::alloc(F16, shape, Blob::new);
+        for (i, x) in reslice_mut::<u8, f16>(t.physical_mut())
+            .iter_mut()
+            .enumerate()
+        {
+            *x = f16::from_f32(f(i));
+        }
+        t
+    }
+
+    let (m, k, n) = (8, 256, 96);
+    let a = fill(&[m, k], |i| ((i * 7) % 13) as f32 / 16. - 0.4);
+    // 与 llama 的权重一致，以 `[n, k]` 存储并转置为 `[k, n]`
+    let w = fill(&[n, k], |i| ((i * 5) % 11) as f32 / 32. - 0.15);
+    let b = w.as_ref().transpose(&[1, 0]).map_physical(|u| &**u);
+    let q4 = MatrixQ4::quantize(&b);
+    let kernels = CpuKernels::default();
+
+    let mut expected = fill(&[m, n], |_| 0.);
+    kernels.mat_mul(&mut expected, 0., &a, &b, 1., &ThisThread);
+
+    let mut c = fill(&[m, n], |_| 1.);
+    kernels.mat_mul_q4(&mut c, 0., &a, &q4, 1.);
+
+    let expected = reslice_mut::<u8, f16>(expected.physical_mut());
+    let c = reslice_mut::<u8, f16>(c.physical_mut());
+    // 相对误差按 L2 范数计算
+    let (err, norm) = c
+        .iter()
+        .zip(&*expected)
+        .fold((0., 0.), |(err, norm), (x, y)| {
+            let (x, y) = (x.to_f32(), y.to_f32());
+            (err + (x - y).powi(2), norm + y.powi(2))
+        });
+    assert!(
+        (err / norm).sqrt() < 0.05,
+        "relative error: {}",
+        (err / norm).sqrt()
+    );
+
+    // 每 32 个权重占 18 字节，f16 需要 64 字节
+    assert_eq!(size_of::<BlockQ4>(), 18);
+    assert_eq!(q4.nbytes() * 64, w.bytes_size() * 18);
+
+    // 从字节重建的矩阵与原矩阵的乘积相同
+    let view = MatrixQ4::from_bytes(k, n, q4.as_bytes());
+    let mut c_ = fill(&[m, n], |_| 1.);
+    kernels.mat_mul_q4(&mut c_, 0., &a, &view, 1.);
+    assert_eq!(reslice::<u8, f16>(c_.physical()), &*c);
+}
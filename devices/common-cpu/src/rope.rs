@@ -0,0 +1,236 @@
+use common::f16;
+use digit_layout::types::{F16, U32};
+use std::{
+    iter::zip,
+    ops::{Deref, DerefMut},
+    slice::from_raw_parts_mut,
+    sync::RwLock,
+};
+use tensor::{reslice, udim, Tensor};
+
+/// 旋转位置编码的 cos/sin 表，按位置索引，位置范围增长时才追加计算。
+pub struct RopeCache {
+    theta: f32,
+    dh: udim,
+    table: RwLock<Vec<(f32, f32)>>,
+}
+
+impl RopeCache {
+    /// 创建缓存并预先计算 `0..max_seq_len` 的旋转因子。
+    pub fn new(theta: f32, dh: udim, max_seq_len: udim) -> Self {
+        assert_eq!(dh % 2, 0);
+        let ans = Self {
+            theta,
+            dh,
+            table: RwLock::new(Vec::new()),
+        };
+        ans.reserve(max_seq_len);
+        ans
+    }
+
+    /// 已缓存的位置数量。
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.table.read().unwrap().len() / (self.dh as usize / 2)
+    }
+
+    /// 缓存是否为空。
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 保证缓存覆盖 `0..len` 的位置。
+    pub fn reserve(&self, len: udim) {
+        let half = self.dh as usize / 2;
+        if self.len() >= len as usize {
+            return;
+        }
+        let mut table = self.table.write().unwrap();
+        // 其他线程可能已经完成扩展
+        for pos in table.len() / half..len as usize {
+            table.extend((0..half).map(|k| rotation(pos, k, half, self.theta)));
+        }
+    }
+
+    /// 对形状为 `[n, nh, dh]` 的 `t` 原地施加旋转位置编码，`pos` 是形状为 `[n]` 的位置。
+    pub fn rotary_embedding<T, U>(&self, t: &mut Tensor<T>, pos: &Tensor<U>)
+    where
+        T: DerefMut<Target = [u8]>,
+        U: Deref<Target = [u8]>,
+    {
+        let &[n, nh, dh] = t.shape() else { panic!() };
+        let &[sn, sh, sd] = t.strides() else {
+            unreachable!()
+        };
+        assert_eq!(t.data_layout(), F16);
+        assert_eq!(pos.data_layout(), U32);
+        assert_eq!(pos.shape(), &[n]);
+        assert_eq!(dh, self.dh);
+        assert_eq!(sd, 1);
+
+        let pos: &[u32] = reslice(pos.as_slice());
+        if let Some(&max) = pos.iter().max() {
+            self.reserve(max + 1);
+        }
+
+        let half = dh as usize / 2;
+        let table = self.table.read().unwrap();
+        let base = t.base_mut().cast::<f16>();
+        for (i, &p) in pos.iter().enumerate() {
+            let rotation = &table[p as usize * half..][..half];
+            for h in 0..nh as isize {
+                let offset = i as isize * sn as isize + h * sh as isize;
+                let head = unsafe { from_raw_parts_mut(base.offset(offset), dh as usize) };
+                for (pair, &(cos, sin)) in zip(head.chunks_exact_mut(2), rotation) {
+                    let [a, b] = pair else { unreachable!() };
+                    let (x, y) = (a.to_f32(), b.to_f32());
+                    *a = f16::from_f32(x * cos - y * sin);
+                    *b = f16::from_f32(x * sin + y * cos);
+                }
+            }
+        }
+    }
+
+    /// 对形状为 `[n, nh, dh]` 的 `t` 原地撤销 `delta` 个位置的旋转，位置 `p` 的编码变为 `p - delta` 的编码。
+    pub fn rotate_back<T>(&self, t: &mut Tensor<T>, delta: udim)
+    where
+        T: DerefMut<Target = [u8]>,
+    {
+        let &[n, nh, dh] = t.shape() else { panic!() };
+        let &[sn, sh, sd] = t.strides() else {
+            unreachable!()
+        };
+        assert_eq!(t.data_layout(), F16);
+        assert_eq!(dh, self.dh);
+        assert_eq!(sd, 1);
+        self.reserve(delta + 1);
+
+        let half = dh as usize / 2;
+        let table = self.table.read().unwrap();
+        let rotation = &table[delta as usize * half..][..half];
+        let base = t.base_mut().cast::<f16>();
+        for i in 0..n as isize {
+            for h in 0..nh as isize {
+                let offset = i * sn as isize + h * sh as isize;
+                let head = unsafe { from_raw_parts_mut(base.offset(offset), dh as usize) };
+                // 旋转角取反
+                for (pair, &(cos, sin)) in zip(head.chunks_exact_mut(2), rotation) {
+                    let [a, b] = pair else { unreachable!() };
+                    let (x, y) = (a.to_f32(), b.to_f32());
+                    *a = f16::from_f32(x * cos + y * sin);
+                    *b = f16::from_f32(y * cos - x * sin);
+                }
+            }
+        }
+    }
+}
+
+/// 位置 `pos` 上第 `k` 对分量的旋转因子 `(cos, sin)`。
+#[inline]
+pub fn rotation(pos: usize, k: usize, half: usize, theta: f32) -> (f32, f32) {
+    let (sin, cos) = (pos as f32 / theta.powf(k as f32 / half as f32)).sin_cos();
+    (cos, sin)
+}
+
+#[test]
+fn test_rope_cache() {
+    use common::Blob;
+    use tensor::reslice_mut;
+
+    const THETA: f32 = 1e4;
+    let (n, nh, dh) = (64, 8, 64);
+    let pos = (0..n).map(|i| i * 3).collect::<Vec<u32>>();
+    let pos = Tensor::new(U32, &[n], reslice::<u32, u8>(&pos));
+
+    let mut x = Tensor::alloc(F16, &[n, nh, dh], Blob::new);
+    for (i, x) in reslice_mut::<u8, f16>(x.physical_mut())
+        .iter_mut()
+        .enumerate()
+    {
+        *x = f16::from_f32(((i * 7) % 17) as f32 / 8. - 1.);
+    }
+
+    // 逐次计算旋转因子的参照实现
+    let mut expected = x.as_slice().to_vec();
+    let half = dh as usize / 2;
+    for (i, token) in reslice_mut::<u8, f16>(&mut expected)
+        .chunks_exact_mut((nh * dh) as usize)
+        .enumerate()
+    {
+        for head in token.chunks_exact_mut(dh as usize) {
+            for (k, pair) in head.chunks_exact_mut(2).enumerate() {
+                let (cos, sin) = rotation(i * 3, k, half, THETA);
+                let (a, b) = (pair[0].to_f32(), pair[1].to_f32());
+                pair[0] = f16::from_f32(a * cos - b * sin);
+                pair[1] = f16::from_f32(a * sin + b * cos);
+            }
+        }
+    }
+
+    let cache = RopeCache::new(THETA, dh, 16);
+    assert_eq!(cache.len(), 16);
+
+    cache.rotary_embedding(&mut x, &pos);
+    assert_eq!(cache.len(), (n as usize - 1) * 3 + 1);
+    assert_eq!(x.as_slice(), expected);
+
+    // 缓存已覆盖全部位置，不再增长
+    let mut y = x.as_ref().map_physical(|u| u.to_vec());
+    cache.rotary_embedding(&mut y, &pos);
+    assert_eq!(cache.len(), (n as usize - 1) * 3 + 1);
+}
+
+#[test]
+fn test_rotate_back() {
+    use common::Blob;
+    use tensor::reslice_mut;
+
+    const THETA: f32 = 1e4;
+    let (n, nh, dh) = (4, 2, 8);
+    let mut x = Tensor::alloc(F16, &[n, nh, dh], Blob::new);
+    for (i, x) in reslice_mut::<u8, f16>(x.physical_mut())
+        .iter_mut()
+        .enumerate()
+    {
+        *x = f16::from_f32(((i * 5) % 13) as f32 / 8. - 0.75);
+    }
+    let pos = |p: &[u32]| Tensor::new(U32, &[n], reslice::<u32, u8>(p).to_vec());
+
+    let cache = RopeCache::new(THETA, dh, 16);
+    // 在位置 9.. 编码后回退 7 个位置，应与直接在位置 2.. 编码相同
+    let mut shifted = x.as_ref().map_physical(|u| u.to_vec());
+    cache.rotary_embedding(&mut shifted, &pos(&[9, 10, 11, 12]));
+    cache.rotate_back(&mut shifted, 7);
+    let mut expected = x.as_ref().map_physical(|u| u.to_vec());
+    cache.rotary_embedding(&mut expected, &pos(&[2, 3, 4, 5]));
+    assert!(shifted.approx_eq(&expected, 1e-2, 0.));
+}
+
+#[test]
+fn test_rope_kernel() {
+    use crate::{CpuKernels, KernelsA, ThisThread};
+    use tensor::reslice_mut;
+
+    const THETA: f32 = 1e4;
+    let (n, nh, dh) = (16, 4, 32);
+    // xorshift 生成的伪随机输入
+    let mut x = vec![0u8; (n * nh * dh) as usize * 2];
+    let mut seed = 1u32;
+    for x in reslice_mut::<u8, f16>(&mut x) {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        *x = f16::from_f32(seed as f32 / u32::MAX as f32 * 2. - 1.);
+    }
+    let x = Tensor::new(F16, &[n, nh, dh], x);
+    let pos = (0..n).map(|i| i * 5 + 1).collect::<Vec<u32>>();
+    let pos = Tensor::new(U32, &[n], reslice::<u32, u8>(&pos));
+
+    // 与算子库的实现结果相同
+    let mut expected = x.clone();
+    CpuKernels::default().rope(&mut expected, &pos, THETA, &ThisThread);
+    let mut cached = x;
+    RopeCache::new(THETA, dh, 0).rotary_embedding(&mut cached, &pos);
+    assert!(cached.approx_eq(&expected, 1e-2, 1e-2));
+}
@@ -0,0 +1,143 @@
+use crate::CpuKernels;
+use common::f16;
+use digit_layout::types::F16;
+use std::ops::DerefMut;
+use tensor::{reslice_mut, Tensor};
+
+impl CpuKernels {
+    /// 在形状为 `[nh, seq, att]` 的注意力分数上加形状为 `[seq, att]` 的 `mask` 后逐行 softmax。
+    pub fn softmax_with_mask<T>(&self, att: &mut Tensor<T>, mask: &[f32])
+    where
+        T: DerefMut<Target = [u8]>,
+    {
+        let &[_, seq, att_len] = att.shape() else {
+            panic!()
+        };
+        assert_eq!(mask.len(), (seq * att_len) as usize);
+        softmax(att, Some(mask));
+    }
+
+    /// 与 [`softmax_with_mask`](Self::softmax_with_mask) 相同，但 `mask` 中为 `false` 的位置被屏蔽。
+    ///
+    /// 完全被屏蔽的行（如批次中的填充行）输出全 0。
+    pub fn softmax_masked<T>(&self, att: &mut Tensor<T>, mask: &[bool])
+    where
+        T: DerefMut<Target = [u8]>,
+    {
+        let mask = mask
+            .iter()
+            .map(|&visible| if visible { 0. } else { f32::NEG_INFINITY })
+            .collect::<Vec<_>>();
+        self.softmax_with_mask(att, &mask);
+    }
+}
+
+/// 逐行 softmax，`mask` 的各行依次加到每个头的对应行上。
+pub(crate) fn softmax<T>(att: &mut Tensor<T>, mask: Option<&[f32]>)
+where
+    T: DerefMut<Target = [u8]>,
+{
+    let &[.., att_len] = att.shape() else {
+        unreachable!()
+    };
+    assert_eq!(att.data_layout(), F16);
+    let att_len = att_len as usize;
+
+    let mut buf = vec![0.; att_len];
+    for (i, row) in reslice_mut::<u8, f16>(att.as_mut_slice())
+        .chunks_exact_mut(att_len)
+        .enumerate()
+    {
+        for (y, x) in buf.iter_mut().zip(&*row) {
+            *y = x.to_f32();
+        }
+        if let Some(mask) = mask {
+            let rows = mask.len() / att_len;
+            let mask = &mask[i % rows * att_len..][..att_len];
+            for (y, m) in buf.iter_mut().zip(mask) {
+                *y += m;
+            }
+        }
+
+        let max = buf.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        // 整行被屏蔽时没有可见的位置，输出 0 而不是 NaN
+        if max == f32::NEG_INFINITY {
+            row.fill(f16::ZERO);
+            continue;
+        }
+        let mut sum = 0.;
+        for y in &mut buf {
+            *y = (*y - max).exp();
+            sum += *y;
+        }
+        for (x, y) in row.iter_mut().zip(&buf) {
+            *x = f16::from_f32(y / sum);
+        }
+    }
+}
+
+#[test]
+fn test_softmax_with_mask() {
+    use crate::{KernelsA, ThisThread};
+    use common::Blob;
+
+    fn tensor(shape: &[u32], data: &[f32]) -> Tensor<Blob> {
+        let mut t = Tensor::alloc(F16, shape, Blob::new);
+        for (x, y) in reslice_mut::<u8, f16>(t.physical_mut())
+            .iter_mut()
+            .zip(data)
+        {
+            *x = f16::from_f32(*y);
+        }
+        t
+    }
+    fn values(t: &Tensor<Blob>) -> Vec<f32> {
+        let data: &[f16] = tensor::reslice(t.as_slice());
+        data.iter().map(|x| x.to_f32()).collect()
+    }
+
+    let kernels = CpuKernels::default();
+    let mask = [0., f32::NEG_INFINITY, 0., 0., 0., 0.];
+    let mut att = tensor(&[1, 2, 3], &[0.5, 2., -1., 0.5, 2., -1.]);
+    kernels.softmax_with_mask(&mut att, &mask);
+
+    let att_ = values(&att);
+    assert_eq!(att_[1], 0.);
+    assert!((att_[..3].iter().sum::<f32>() - 1.).abs() < 1e-3);
+    assert!((att_[3..].iter().sum::<f32>() - 1.).abs() < 1e-3);
+
+    // 被屏蔽的第 1 个位置对第 0 行输出没有贡献
+    let output = |v: &Tensor<Blob>| {
+        let mut o = Tensor::alloc(F16, &[1, 2, 2], Blob::new);
+        kernels.mat_mul(&mut o, 0., &att, v, 1., &ThisThread);
+        values(&o)
+    };
+    let a = output(&tensor(&[1, 3, 2], &[1., 2., 3., 4., 5., 6.]));
+    let b = output(&tensor(&[1, 3, 2], &[1., 2., 100., -100., 5., 6.]));
+    assert_eq!(a[..2], b[..2]);
+    assert_ne!(a[2..], b[2..]);
+}
+
+#[test]
+fn test_softmax_masked() {
+    use common::Blob;
+
+    let mut att = Tensor::alloc(F16, &[2, 2, 3], Blob::new);
+    for (i, x) in reslice_mut::<u8, f16>(att.physical_mut())
+        .iter_mut()
+        .enumerate()
+    {
+        *x = f16::from_f32(i as f32 / 4.);
+    }
+    // 第 1 行完全被屏蔽
+    let mask = [true, false, true, false, false, false];
+    CpuKernels::default().softmax_masked(&mut att, &mask);
+
+    let att: &[f16] = tensor::reslice(att.as_slice());
+    assert!(att.iter().all(|x| !x.is_nan()));
+    for head in att.chunks_exact(6) {
+        assert_eq!(head[1], f16::ZERO);
+        assert!((head[..3].iter().map(|x| x.to_f32()).sum::<f32>() - 1.).abs() < 1e-3);
+        assert!(head[3..].iter().all(|x| *x == f16::ZERO));
+    }
+}
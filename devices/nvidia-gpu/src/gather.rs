@@ -16,11 +16,13 @@ where
     debug_assert_eq!(table.shape()[1], d);
     debug_assert!(x.is_contiguous());
     debug_assert!(table.is_contiguous());
+    let voc = table.shape()[0];
     let d = d as usize * x.data_layout().nbytes();
 
     let x = &mut **x.physical_mut();
     let table = table.as_slice();
     for (i, t) in tokens.into_iter().enumerate() {
+        assert!(t < voc, "token {t} out of range for vocab size {voc}");
         let dst = &mut x[d * i..][..d];
         let src = &table[d * t as usize..][..d];
         stream.memcpy_h2d(dst, src);
@@ -2,7 +2,7 @@
 
 mod resource;
 
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
+use causal_lm::{CausalLM, DecodingMeta, ForwardError, Model, QueryContext, SampleMeta};
 use common::{upos, utok, FileLoadError};
 use common_cn::Tensor;
 use std::path::Path;
@@ -48,7 +48,7 @@ impl CausalLM for Transformer {
         &self,
         _queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         _token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+    ) -> Result<Tensor<Self::Storage>, ForwardError>
     where
         Self: 'a,
     {
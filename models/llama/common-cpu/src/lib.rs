@@ -1,15 +1,242 @@
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
-use common::{f16, upos, utok, Blob, FileLoadError};
+use causal_lm::{CausalLM, DecodingMeta, ForwardError, Model, QueryContext, SampleMeta};
+use common::{bf16, f16, upos, utok, Blob, BlobPool, FileLoadError, PooledBlob};
 use common_cpu::{
-    tensor::{reslice, slice, udim, Tensor},
-    CpuKernels, Kernels, KernelsA, KernelsB, ThisThread,
+    tensor::{reslice, reslice_mut, slice, udim, Tensor},
+    CpuKernels, Kernels, KernelsA, KernelsB, MatrixQ4, RopeCache, ThisThread,
+};
+use digit_layout::types::{BF16, F16, F32, U8};
+use llama::{
+    q4_row_bytes, ComputeConst, ComputeStream, Handle, InferenceConfig, LayerStorage, LoraLayer,
+    LoraWeight, QueueOf, SliceOn, Storage, Weight,
+};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::{
+    ops::{Deref, DerefMut},
+    path::Path,
+    slice::from_raw_parts,
 };
-use llama::{ComputeConst, ComputeStream, Handle, LayerStorage, QueueOf, SliceOn, Storage, Weight};
-use std::{iter::repeat, ops::Deref, path::Path, slice::from_raw_parts};
 
 pub struct Transformer {
     s: Storage,
+    adapters: Vec<Vec<LoraLayer<Weight>>>,
+    rope: RopeCache,
     kernels: CpuKernels,
+    pool: BlobPool,
+}
+
+impl Transformer {
+    /// 限制推理计算最多占用 `threads` 个线程，默认使用全局线程池。
+    #[inline]
+    pub fn with_threads(self, threads: usize) -> Self {
+        Self {
+            kernels: CpuKernels::with_threads(threads),
+            ..self
+        }
+    }
+
+    /// 模型的结构参数。
+    #[inline]
+    pub fn config(&self) -> &InferenceConfig {
+        &self.s.config
+    }
+
+    /// StreamingLLM 式的注意力汇聚：有效长度为 `pos` 的缓存超过 `sink + window` 时，
+    /// 保留前 `sink` 个位置和最近的 `window` 个位置，淘汰中间的部分，返回新的有效长度。
+    ///
+    /// 后续的词从返回的位置继续推理，位置始终不超过 `sink + window`。
+    pub fn slide_cache(
+        &self,
+        cache: &mut Tensor<Blob>,
+        pos: upos,
+        sink: upos,
+        window: upos,
+    ) -> upos {
+        let n = pos.saturating_sub(sink + window);
+        if n == 0 {
+            return pos;
+        }
+        *cache = self.evict_cache(cache, pos, sink, n).unwrap();
+        sink + window
+    }
+
+    /// 前向传播中激活值缓冲区的内存池。
+    #[inline]
+    pub fn blob_pool(&self) -> &BlobPool {
+        &self.pool
+    }
+
+    /// 把 `queries` 的词嵌入写入预先分配的 `out`，`out` 的形状必须是 `[nt, d]`。
+    pub fn token_embed_into(
+        &self,
+        queries: impl IntoIterator<Item = utok>,
+        out: &mut Tensor<Blob>,
+    ) {
+        let dt = self.s.config.dt;
+        let d = self.s.config.d;
+
+        let tokens = queries.into_iter().collect::<Vec<_>>();
+        let nt = tokens.len() as udim;
+        assert_eq!(out.data_layout(), dt, "output data type mismatch");
+        assert_eq!(
+            out.shape(),
+            &[nt, d],
+            "output shape {:?} mismatch, expected [{nt}, {d}]",
+            out.shape(),
+        );
+        if nt == 0 {
+            return;
+        }
+        self.kernels
+            .gather(out, &self.s.embed_tokens, tokens, &ThisThread);
+    }
+
+    /// 注册一个 LoRA 适配器，返回适配器序号。
+    pub fn add_adapter(&mut self, layers: Vec<LoraLayer<Weight>>) -> usize {
+        assert_eq!(layers.len(), self.s.layers.len());
+        self.adapters.push(layers);
+        self.adapters.len() - 1
+    }
+
+    /// 与 [`CausalLM::forward`] 相同的前向传播，同时收集每一层输出的隐藏状态。
+    ///
+    /// 每层的隐藏状态都复制到新分配的 `[nt, d]` 张量中，层数多时占用大量内存，只在需要时调用。
+    /// 最后一层的隐藏状态与返回值相同。
+    pub fn forward_with_hidden_states<'a>(
+        &self,
+        queries: impl IntoIterator<Item = QueryContext<'a, Blob>>,
+        token_embedded: Tensor<Blob>,
+    ) -> Result<(Tensor<Blob>, Vec<Tensor<Blob>>), ForwardError> {
+        let queries = queries.into_iter().collect::<Vec<_>>();
+        let mut states = Vec::with_capacity(self.s.layers.len());
+        let x = self.kernels.install(|| {
+            <Self as ComputeStream>::forward_inspect(self, queries, token_embedded, |_, x| {
+                let mut state = Tensor::alloc(x.data_layout(), x.shape(), Blob::new);
+                state.physical_mut().copy_from_slice(x.as_slice());
+                states.push(state);
+            })
+        })?;
+        Ok((x, states))
+    }
+
+    /// 计算 `tokens` 经过最后一层归一化的隐藏状态，不经过输出层。
+    ///
+    /// 不池化时返回 `[n, d]`，否则按 `pooling` 合并为 `[d]`；`tokens` 超过最大序列长度时返回错误。
+    pub fn embed(
+        &self,
+        tokens: &[utok],
+        pooling: Option<Pooling>,
+    ) -> Result<Tensor<Blob>, ForwardError> {
+        let mut cache = self.new_cache();
+        let token_embedded = CausalLM::token_embed(self, tokens.iter().copied());
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: 0..tokens.len() as upos,
+            adapter: None,
+            mask: None,
+        }];
+        let mut x = CausalLM::forward(self, queries, token_embedded)?;
+
+        let x_ = x
+            .as_ref()
+            .map_physical(|u| unsafe { from_raw_parts(u.as_ptr(), u.len()) });
+        self.normalize(
+            &mut x,
+            &x_,
+            &self.s.lm_layernorm,
+            self.s.lm_layernorm_bias.as_ref(),
+            self.s.config.norm,
+            self.s.config.epsilon,
+        );
+
+        Ok(match pooling {
+            Some(pooling) => pool(&x, pooling),
+            None => x,
+        })
+    }
+}
+
+/// 隐藏状态合并为句向量的方式。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Pooling {
+    /// 取最后一个词的隐藏状态。
+    LastToken,
+    /// 取所有词隐藏状态的平均。
+    Mean,
+    /// 取第一个词的隐藏状态。
+    Cls,
+}
+
+/// 把 `[n, d]` 的隐藏状态池化为 `[d]`，结果与输入的数据类型相同。
+fn pool(x: &Tensor<Blob>, pooling: Pooling) -> Tensor<Blob> {
+    let &[n, d] = x.shape() else { panic!() };
+    assert!(n > 0);
+    let dt = x.data_layout();
+    let mut rows = x.as_slice().chunks_exact(d as usize * dt.nbytes());
+
+    let mut ans = Tensor::alloc(dt, &[d], Blob::new);
+    let y = ans.physical_mut();
+    match pooling {
+        Pooling::LastToken => y.copy_from_slice(rows.last().unwrap()),
+        Pooling::Cls => y.copy_from_slice(rows.next().unwrap()),
+        // 在 f32 上累加，避免半精度求和损失精度
+        Pooling::Mean => match dt {
+            F16 => mean(x.as_slice(), y, n, f16::to_f32, f16::from_f32),
+            BF16 => mean(x.as_slice(), y, n, bf16::to_f32, bf16::from_f32),
+            F32 => mean(x.as_slice(), y, n, |x: f32| x, |x| x),
+            _ => panic!("unsupported data type: {dt:?}"),
+        },
+    }
+    ans
+}
+
+/// 对 `n` 行元素类型为 `T` 的数据逐列求平均。
+fn mean<T: Copy>(
+    x: &[u8],
+    y: &mut [u8],
+    n: udim,
+    to_f32: impl Fn(T) -> f32,
+    from_f32: impl Fn(f32) -> T,
+) {
+    let y = reslice_mut::<u8, T>(y);
+    let mut sum = vec![0f32; y.len()];
+    for row in reslice::<u8, T>(x).chunks_exact(y.len()) {
+        for (s, &x) in sum.iter_mut().zip(row) {
+            *s += to_f32(x);
+        }
+    }
+    for (y, s) in y.iter_mut().zip(sum) {
+        *y = from_f32(s / n as f32);
+    }
+}
+
+/// 把投影权重量化为 4 位，输入宽度不是 32 的整数倍的投影保持 f16。
+///
+/// 量化后的模型只能在 CPU 上推理。
+pub fn quantize_q4(mut s: Storage) -> Storage {
+    assert_eq!(s.config.dt, F16);
+    for layer in &mut s.layers {
+        let LayerStorage {
+            att_qkv,
+            att_o,
+            mlp_gate_up,
+            mlp_down,
+            ..
+        } = layer;
+        for w in [att_qkv, att_o, mlp_gate_up, mlp_down] {
+            let &[k, n] = w.shape() else { unreachable!() };
+            let Some(row) = q4_row_bytes(k) else {
+                continue;
+            };
+            if w.data_layout() != F16 {
+                continue;
+            }
+            let q4 = MatrixQ4::quantize(w);
+            let mut t = Tensor::alloc(U8, &[n, row], Blob::new);
+            t.physical_mut().copy_from_slice(q4.as_bytes());
+            *w = t.map_physical(Weight::from).transpose(&[1, 0]);
+        }
+    }
+    s
 }
 
 impl Model for Transformer {
@@ -18,9 +245,21 @@ impl Model for Transformer {
 
     #[inline]
     fn load(model_dir: impl AsRef<Path>, _meta: Self::Meta) -> Result<Self, Self::Error> {
+        // 以 .gguf 结尾的路径按 GGUF 文件加载，否则视为 safetensors 模型目录
+        let model_dir = model_dir.as_ref();
+        let s = if model_dir.extension().is_some_and(|ext| ext == "gguf") {
+            llama::Storage::load_gguf(model_dir)?
+        } else {
+            llama::Storage::load_safetensors(model_dir)?
+        };
+        let config = &s.config;
+        let rope = RopeCache::new(config.theta, config.dh, config.max_seq_len);
         Ok(Self {
-            s: llama::Storage::load_safetensors(model_dir)?,
+            s,
+            adapters: Vec::new(),
+            rope,
             kernels: Default::default(),
+            pool: Default::default(),
         })
     }
 }
@@ -28,12 +267,13 @@ impl Model for Transformer {
 impl ComputeStream for Transformer {
     type Handle = common_cpu::Cpu;
     type Storage = Blob;
-    type Buf<'m> = Blob;
+    type Buf<'m> = PooledBlob;
     type Pos<'m> = &'m [u8];
+    const ATTENTION_MASK: bool = true;
 
     #[inline]
     fn malloc(&self, len: usize) -> Self::Buf<'_> {
-        Blob::new(len)
+        self.pool.take(len)
     }
     #[inline]
     fn map_pos<'p>(&self, pos: &'p [u32]) -> Self::Pos<'p>
@@ -59,9 +299,13 @@ impl ComputeStream for Transformer {
         ComputeConst {
             nh: self.s.config.nh,
             nkvh: self.s.config.nkvh,
+            dh: self.s.config.dh,
             di: self.s.config.di,
             epsilon: self.s.config.epsilon,
             theta: self.s.config.theta,
+            sliding_window: self.s.config.sliding_window,
+            norm: self.s.config.norm,
+            attention_scale: self.s.config.attention_scale(),
         }
     }
 
@@ -78,6 +322,126 @@ impl ComputeStream for Transformer {
     ) -> impl Iterator<Item = impl llama::LLamaLayer<Byte = <Self::Handle as Handle>::Byte>> {
         self.s.layers.iter().map(LlamaLayer)
     }
+
+    fn lora(&self, adapter: usize, layer: usize) -> Option<LoraLayer<&SliceOn<Self::Handle>>> {
+        fn map(w: &LoraWeight<Weight>) -> LoraWeight<&[u8]> {
+            w.as_ref().map_physical(|u| &**u)
+        }
+        let LoraLayer { att_qkv, att_o } = &self.adapters.get(adapter)?[layer];
+        Some(LoraLayer {
+            att_qkv: att_qkv.as_ref().map(map),
+            att_o: att_o.as_ref().map(map),
+        })
+    }
+
+    #[inline]
+    fn rotary_embedding<T, U>(&self, t: &mut Tensor<T>, pos: &Tensor<U>, theta: f32)
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        debug_assert_eq!(theta, self.s.config.theta);
+        self.rope.rotary_embedding(t, pos);
+    }
+
+    #[inline]
+    fn softmax_with_mask<T>(&self, att: &mut Tensor<T>, mask: &[f32])
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+    {
+        self.kernels.softmax_with_mask(att, mask);
+    }
+
+    #[inline]
+    fn layer_norm<T, U, V>(
+        &self,
+        y: &mut Tensor<T>,
+        x: &Tensor<U>,
+        w: &Tensor<V>,
+        b: Option<&Tensor<V>>,
+        epsilon: f32,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.kernels.layer_norm(y, x, w, b, epsilon);
+    }
+
+    fn mat_mul_weight<T, U, V>(
+        &self,
+        c: &mut Tensor<T>,
+        beta: f32,
+        a: &Tensor<U>,
+        w: &Tensor<V>,
+        alpha: f32,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        if w.data_layout() == U8 {
+            // 4 位权重是 `[n, k]` 逐行量化后的字节转置得到的 `[row, n]`
+            let &[_, k] = a.shape() else { panic!() };
+            let &[_, n] = w.shape() else { panic!() };
+            let bytes = unsafe { from_raw_parts(w.base(), w.bytes_size()) };
+            let w = MatrixQ4::from_bytes(k, n, bytes);
+            self.kernels.mat_mul_q4(c, beta, a, &w, alpha);
+        } else {
+            self.kernels.mat_mul(c, beta, a, w, alpha, &ThisThread);
+        }
+    }
+
+    fn mlp<T, U, V, W>(
+        &self,
+        x: &mut Tensor<T>,
+        x1: &Tensor<U>,
+        gate_up: &mut Tensor<V>,
+        w_gate_up: &Tensor<W>,
+        w_down: &Tensor<W>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: DerefMut<Target = SliceOn<Self::Handle>>,
+        W: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        if w_gate_up.data_layout() != U8 && w_down.data_layout() != U8 {
+            self.kernels
+                .mlp(x, x1, gate_up, w_gate_up, w_down, 1., true, &ThisThread);
+            return;
+        }
+        // 融合的算子不支持量化权重，拆成两次矩阵乘
+        let di = self.s.config.di;
+        self.mat_mul_weight(gate_up, 0., x1, w_gate_up, 1.);
+        swiglu(gate_up);
+        let gate = gate_up
+            .as_ref()
+            .slice(&[slice![=>], slice![=> di]])
+            .map_physical(|u| &**u);
+        self.mat_mul_weight(x, 1., &gate, w_down, 1.);
+    }
+}
+
+/// 就地计算 `gate = silu(gate) * up`，`gate_up` 是 `[n, 2di]` 的 f16 张量。
+fn swiglu<T: DerefMut<Target = [u8]>>(gate_up: &mut Tensor<T>) {
+    let &[n, di] = gate_up.shape() else { panic!() };
+    assert_eq!(gate_up.data_layout(), F16);
+    let &[sn, sd] = gate_up.strides() else {
+        unreachable!()
+    };
+    let (sn, sd, di) = (sn as isize, sd as isize, di as isize / 2);
+    let base = gate_up.base_mut().cast::<f16>();
+    for i in 0..n as isize {
+        for j in 0..di {
+            unsafe {
+                let gate = base.offset(i * sn + j * sd);
+                let up = gate.offset(di * sd);
+                let g = gate.read_unaligned().to_f32();
+                let u = up.read_unaligned().to_f32();
+                gate.write_unaligned(f16::from_f32(g / (1. + (-g).exp()) * u));
+            }
+        }
+    }
 }
 
 struct LlamaLayer<'a>(&'a LayerStorage<Weight>);
@@ -110,6 +474,22 @@ impl<'a> llama::LLamaLayer for LlamaLayer<'a> {
     fn mlp_down(&self) -> Tensor<Self::Storage<'_>> {
         self.0.mlp_down.clone()
     }
+    #[inline]
+    fn att_layernorm_bias(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        self.0.att_layernorm_bias.clone()
+    }
+    #[inline]
+    fn mlp_layernorm_bias(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        self.0.mlp_layernorm_bias.clone()
+    }
+    #[inline]
+    fn att_q_norm(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        self.0.att_q_norm.clone()
+    }
+    #[inline]
+    fn att_k_norm(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        self.0.att_k_norm.clone()
+    }
 }
 
 impl CausalLM for Transformer {
@@ -141,16 +521,51 @@ impl CausalLM for Transformer {
             })
     }
 
-    fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage> {
-        let dt = self.s.config.dt;
-        let d = self.s.config.d;
+    fn evict_cache(
+        &self,
+        cache: &Tensor<Self::Storage>,
+        pos: upos,
+        sink: upos,
+        n: upos,
+    ) -> Option<Tensor<Self::Storage>> {
+        let mut ans = self
+            .s
+            .config
+            .evict_cache(cache, pos, sink, n, Blob::new, |dst, src| {
+                src.map_physical(|u| &**u)
+                    .reform_to(&mut dst.map_physical(|u| &mut **u))
+            });
+        // 前移的键重新编码到新的位置上
+        let len = pos - sink - n;
+        if n > 0 && len > 0 {
+            let &[nlayers, 2, nkvh, _, dh] = ans.shape() else {
+                panic!()
+            };
+            for layer in 0..nlayers {
+                let mut k = ans
+                    .as_mut()
+                    .slice(&[
+                        slice![=layer],
+                        slice![=0],
+                        slice![=>],
+                        slice![sink =>=> len],
+                        slice![=>],
+                    ])
+                    .reshape(&[nkvh, len, dh])
+                    .transpose(&[1, 0, 2])
+                    .map_physical(|u| &mut **u);
+                self.rope.rotate_back(&mut k, n);
+            }
+        }
+        Some(ans)
+    }
 
+    fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage> {
         let tokens = queries.into_iter().collect::<Vec<_>>();
         let nt = tokens.len() as udim;
 
-        let mut x = Tensor::alloc(dt, &[nt, d], Blob::new);
-        self.kernels
-            .gather(&mut x, &self.s.embed_tokens, tokens, &ThisThread);
+        let mut x = Tensor::alloc(self.s.config.dt, &[nt, self.s.config.d], Blob::new);
+        self.token_embed_into(tokens, &mut x);
         x
     }
 
@@ -158,8 +573,10 @@ impl CausalLM for Transformer {
         &self,
         queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage> {
-        <Self as ComputeStream>::forward(self, queries, token_embedded)
+    ) -> Result<Tensor<Self::Storage>, ForwardError> {
+        let queries = queries.into_iter().collect::<Vec<_>>();
+        self.kernels
+            .install(|| <Self as ComputeStream>::forward(self, queries, token_embedded))
     }
 
     fn decode(
@@ -168,18 +585,18 @@ impl CausalLM for Transformer {
         hidden_state: Tensor<Self::Storage>,
     ) -> Tensor<Self::Storage> {
         let dt = self.s.config.dt;
-        let d = self.s.config.d;
         let epsilon = self.s.config.epsilon;
 
         let mut x = hidden_state;
         let range = DecodingMeta::select(&mut x, decoding, |dst, src| dst.copy_from_slice(src));
 
+        let lm_layernorm = &self.s.lm_layernorm;
+        let lm_layernorm_bias = self.s.lm_layernorm_bias.as_ref();
+        let lm_head = &self.s.lm_head;
         if range.is_empty() {
-            return Tensor::alloc(dt, &[0, d as _], Blob::new);
+            return Tensor::alloc(dt, &[0, lm_head.shape()[1]], Blob::new);
         }
 
-        let lm_layernorm = &self.s.lm_layernorm;
-        let lm_head = &self.s.lm_head;
         let mut x = x.slice(&[slice![range.start => range.end], slice![=>]]);
         let mut logits = Tensor::alloc(dt, &[x.shape()[0], lm_head.shape()[1]], Blob::new);
 
@@ -187,10 +604,18 @@ impl CausalLM for Transformer {
         let x_ = x
             .as_ref()
             .map_physical(|u| unsafe { from_raw_parts(u.as_ptr(), u.len()) });
-        self.kernels()
-            .rms_norm(&mut x, &x_, lm_layernorm, epsilon, self.queue());
-        self.kernels()
-            .mat_mul(&mut logits, 0., &x, lm_head, 1., self.queue());
+        self.normalize(
+            &mut x,
+            &x_,
+            lm_layernorm,
+            lm_layernorm_bias,
+            self.s.config.norm,
+            epsilon,
+        );
+        self.kernels.install(|| {
+            self.kernels()
+                .mat_mul(&mut logits, 0., &x, lm_head, 1., self.queue())
+        });
 
         logits
     }
@@ -198,25 +623,54 @@ impl CausalLM for Transformer {
     fn sample(
         &self,
         args: impl IntoIterator<Item = SampleMeta>,
-        logits: Tensor<Self::Storage>,
+        mut logits: Tensor<Self::Storage>,
     ) -> Vec<utok> {
         let &[_, voc] = logits.shape() else { panic!() };
-        let logits: &[f16] = reslice(logits.as_slice());
-        args.into_iter()
-            .flat_map(|meta| repeat(meta.args).take(meta.num_decode))
-            .enumerate()
-            .map(|(i, args)| {
-                self.kernels.sample(
-                    args.temperature,
-                    args.top_p,
-                    args.top_k,
-                    &common_cpu::slice!(logits; voc; [i]),
-                )
-            })
-            .collect()
+        let args = args.into_iter().collect::<Vec<_>>();
+        let logits = reslice_mut(logits.as_mut_slice());
+        self.kernels
+            .install(|| sample(&self.kernels, args, logits, voc))
     }
 }
 
+/// 逐行处理并采样 logits，每个请求使用各自的采样参数。
+///
+/// 不同请求的行并行处理，结果保持请求的顺序；
+/// 同一请求的多行按顺序处理，保证 Mirostat 等跨步状态的更新顺序确定。
+fn sample(
+    kernels: &CpuKernels,
+    args: impl IntoIterator<Item = SampleMeta>,
+    logits: &mut [f16],
+    voc: udim,
+) -> Vec<utok> {
+    let voc = voc as usize;
+    let mut rest = logits;
+    let tasks = args
+        .into_iter()
+        .map(|meta| {
+            let len = (meta.num_decode * voc).min(rest.len());
+            let (rows, tail) = std::mem::take(&mut rest).split_at_mut(len);
+            rest = tail;
+            (meta, rows)
+        })
+        .collect::<Vec<_>>();
+    tasks
+        .into_par_iter()
+        .map(|(meta, rows)| {
+            rows.chunks_exact_mut(voc)
+                .map(|row| {
+                    meta.process(row);
+                    let args = meta.sample_args(voc);
+                    let token = kernels.sample(args.temperature, args.top_p, args.top_k, row);
+                    meta.observe(row, token);
+                    token
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>()
+        .concat()
+}
+
 #[test]
 fn test_infer() {
     causal_lm::test_impl::<Transformer>(
@@ -227,3 +681,1066 @@ fn test_infer() {
         ],
     );
 }
+
+#[test]
+fn test_blob_pool() {
+    let model = random_model(4, 2);
+    let mut cache = model.new_cache();
+    let mut token = model.bos_token();
+    let mut allocated = 0;
+    for pos in 0..model.max_seq_len() {
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: pos..pos + 1,
+            adapter: None,
+            mask: None,
+        }];
+        let hidden_state = model.forward(queries, model.token_embed([token])).unwrap();
+        let decoding = [DecodingMeta {
+            num_query: 1,
+            num_decode: 1,
+        }];
+        let logits = model.decode(decoding, hidden_state);
+        let args = [SampleMeta {
+            num_decode: 1,
+            args: causal_lm::SampleArgs::ARG_MAX,
+            ..Default::default()
+        }];
+        token = model.sample(args, logits)[0];
+        if pos == 0 {
+            allocated = model.blob_pool().allocated();
+        }
+    }
+    assert!(allocated > 0);
+    // 第一步之后只有随注意力长度增长的缓冲区在跨越 2 的幂时重新分配
+    let grown = model.blob_pool().allocated() - allocated;
+    assert!(grown <= 7, "{grown} blobs allocated after the first step");
+}
+
+#[test]
+fn test_config() {
+    let model = random_model(8, 2);
+    let config = model.config();
+    assert_eq!((config.nh, config.nkvh, config.dh), (8, 2, 2));
+    assert_eq!((config.d, config.voc, config.nlayers), (16, 32, 2));
+    assert_eq!(config.max_seq_len, model.max_seq_len());
+    assert_eq!(config.eos_token, model.eos_token());
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    let model = <Transformer as Model>::load(model_dir, ()).unwrap();
+    let config = model.config();
+    assert_eq!(config.nh % config.nkvh, 0);
+    assert_eq!(config.nlayers as usize, model.s.layers.len());
+    assert_eq!(config.max_seq_len, model.max_seq_len());
+    assert_eq!(config.eos_token, model.eos_token());
+}
+
+#[test]
+fn test_token_embed_into() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let model = random_model(4, 2);
+    let (dt, d) = (model.s.config.dt, model.s.config.d);
+
+    let mut out = Tensor::alloc(dt, &[3, d], Blob::new);
+    for tokens in [[1, 2, 3], [7, 7, 0], [1, 2, 3]] {
+        model.token_embed_into(tokens, &mut out);
+        assert_eq!(out.as_slice(), model.token_embed(tokens).as_slice());
+    }
+
+    // 词数与缓冲区不一致时报错而不越界写入
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        model.token_embed_into([1, 2], &mut out)
+    }));
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_logit_bias() {
+    use causal_lm::SampleArgs;
+    use std::collections::HashMap;
+
+    const VOC: udim = 16;
+    let kernels = CpuKernels::default();
+    let args = SampleArgs {
+        temperature: 1.,
+        top_p: 1.,
+        top_k: VOC as _,
+    };
+    let row = (0..VOC)
+        .map(|i| f16::from_f32((i % 4) as f32 / 4.))
+        .collect::<Vec<_>>();
+
+    // 两个请求分别禁止词 3 和强制词 5
+    for _ in 0..64 {
+        let mut logits = [row.clone(), row.clone()].concat();
+        let metas = [
+            SampleMeta {
+                num_decode: 1,
+                args,
+                logit_bias: HashMap::from([(3, f32::NEG_INFINITY)]),
+                ..Default::default()
+            },
+            SampleMeta {
+                num_decode: 1,
+                args,
+                logit_bias: HashMap::from([(5, 100.)]),
+                ..Default::default()
+            },
+        ];
+        let tokens = sample(&kernels, metas, &mut logits, VOC);
+        assert_ne!(tokens[0], 3);
+        assert_eq!(tokens[1], 5);
+    }
+}
+
+#[test]
+fn test_penalties() {
+    use causal_lm::SampleArgs;
+    use std::collections::HashMap;
+
+    const VOC: udim = 4;
+    let kernels = CpuKernels::default();
+    let row = [1., 2., 2.5, 0.].map(f16::from_f32);
+    let mut logits = [row, row].concat();
+    // 只有第一个序列生成过两次词 2，惩罚不影响第二个序列
+    let metas = [
+        SampleMeta {
+            num_decode: 1,
+            args: SampleArgs::ARG_MAX,
+            frequency_penalty: 0.5,
+            token_counts: HashMap::from([(2, 2)]),
+            ..Default::default()
+        },
+        SampleMeta {
+            num_decode: 1,
+            args: SampleArgs::ARG_MAX,
+            frequency_penalty: 0.5,
+            ..Default::default()
+        },
+    ];
+    let tokens = sample(&kernels, metas, &mut logits, VOC);
+    assert_eq!(tokens, [1, 2]);
+    assert_eq!(logits[2], f16::from_f32(2.5 - 2. * 0.5));
+    assert_eq!(logits[VOC as usize + 2], row[2]);
+}
+
+#[test]
+fn test_sample_parallel() {
+    use causal_lm::SampleArgs;
+    use std::{collections::HashMap, iter::repeat};
+
+    const VOC: udim = 64;
+    const N: usize = 16;
+    let kernels = CpuKernels::with_threads(4);
+    // 随机采样：先按顺序执行 top-k 和 top-p，再由算子按温度采样
+    let args = SampleArgs {
+        temperature: 0.8,
+        top_p: 0.9,
+        top_k: 8,
+    };
+    // 采样算子没有可设置的随机种子，偏置使每行的峰值概率超过 top-p，
+    // 截断后只剩一个候选词，随机采样的结果因而确定
+    let peak = |i: usize| (i * 5 % VOC as usize) as utok;
+    let metas = (0..N)
+        .map(|i| SampleMeta {
+            num_decode: i % 3,
+            args,
+            logit_bias: HashMap::from([(peak(i), 16.)]),
+            frequency_penalty: 0.5,
+            token_counts: HashMap::from([((i * 7 % VOC as usize) as utok, i)]),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    let nrows = metas.iter().map(|m| m.num_decode).sum::<usize>();
+    let logits = (0..nrows * VOC as usize)
+        .map(|i| f16::from_f32(((i * 13) % 29) as f32 / 8.))
+        .collect::<Vec<_>>();
+
+    let mut batched = logits.clone();
+    let parallel = kernels.install(|| sample(&kernels, metas.clone(), &mut batched, VOC));
+
+    let mut rows = logits.chunks_exact(VOC as usize);
+    let mut sequential = Vec::new();
+    for meta in metas {
+        for row in rows.by_ref().take(meta.num_decode) {
+            let mut row = row.to_vec();
+            sequential.extend(sample(&kernels, [meta.clone()], &mut row, VOC));
+        }
+    }
+    assert_eq!(parallel.len(), nrows);
+    assert_eq!(parallel, sequential);
+    let expected = (0..N)
+        .flat_map(|i| repeat(peak(i)).take(i % 3))
+        .collect::<Vec<_>>();
+    assert_eq!(parallel, expected);
+}
+
+#[test]
+fn test_lora_segmented() {
+    use common_cpu::tensor::reslice_mut;
+
+    const ROWS: [udim; 2] = [2, 3];
+    const D_IN: udim = 8;
+    const D_OUT: udim = 6;
+    const RANK: udim = 4;
+
+    fn fill(shape: &[udim], f: impl Fn(usize) -> f32) -> Tensor<Blob> {
+        let mut t = Tensor::alloc(F16, shape, Blob::new);
+        for (i, x) in reslice_mut::<u8, f16>(t.physical_mut())
+            .iter_mut()
+            .enumerate()
+        {
+            *x = f16::from_f32(f(i));
+        }
+        t
+    }
+    fn adapter(seed: usize) -> LoraWeight<Blob> {
+        LoraWeight {
+            a: fill(&[D_IN, RANK], |i| ((i + seed) % 5) as f32 / 8.),
+            b: fill(&[RANK, D_OUT], |i| ((i * 3 + seed) % 7) as f32 / 8.),
+            scale: 0.5,
+        }
+    }
+
+    let nt = ROWS.iter().sum::<udim>();
+    let x = fill(&[nt, D_IN], |i| (i % 3) as f32 / 4.);
+    let mut y = fill(&[nt, D_OUT], |_| 0.);
+    let mut buf = fill(&[nt, RANK], |_| 0.);
+    let adapters = [adapter(1), adapter(4)];
+
+    let kernels = CpuKernels::default();
+    llama::lora_segmented(
+        &kernels,
+        &mut y,
+        &x,
+        ROWS.into_iter().zip(adapters.iter().map(Some)),
+        &mut buf,
+        &ThisThread,
+    );
+
+    let get = |t: &Tensor<Blob>, r: usize, c: usize| {
+        let cols = t.shape()[1] as usize;
+        reslice::<u8, f16>(t.physical())[r * cols + c].to_f32()
+    };
+    let mut row = 0;
+    for (&len, w) in ROWS.iter().zip(&adapters) {
+        for r in row..row + len as usize {
+            for c in 0..D_OUT as usize {
+                let expected = (0..RANK as usize)
+                    .map(|k| {
+                        let xa = (0..D_IN as usize)
+                            .map(|j| get(&x, r, j) * get(&w.a, j, k))
+                            .sum::<f32>();
+                        xa * get(&w.b, k, c)
+                    })
+                    .sum::<f32>()
+                    * w.scale;
+                assert!((get(&y, r, c) - expected).abs() < 1e-2);
+            }
+        }
+        row += len as usize;
+    }
+}
+
+#[test]
+fn test_sliding_window() {
+    use common_cpu::tensor::reslice_mut;
+    use std::iter::zip;
+
+    const NH: udim = 2;
+    const SEQ: udim = 3;
+    const ATT: udim = 7;
+    const POS: udim = ATT - SEQ;
+    const WINDOW: udim = 3;
+
+    let scores = (0..NH * SEQ * ATT)
+        .map(|i| ((i * 5) % 9) as f32 / 4. - 1.)
+        .collect::<Vec<_>>();
+    let mut att = Tensor::alloc(F16, &[NH, SEQ, ATT], Blob::new);
+    for (x, y) in reslice_mut::<u8, f16>(att.physical_mut())
+        .iter_mut()
+        .zip(&scores)
+    {
+        *x = f16::from_f32(*y);
+    }
+
+    let mask = causal_lm::sliding_window_mask(POS, SEQ, ATT, WINDOW).unwrap();
+    CpuKernels::default().softmax_with_mask(&mut att, &mask);
+    let att = reslice::<u8, f16>(att.as_slice());
+
+    // 手动屏蔽窗口外位置的完整注意力
+    for (row, (x, y)) in zip(scores.chunks_exact(ATT as _), att.chunks_exact(ATT as _)).enumerate()
+    {
+        let p = POS + row as udim % SEQ;
+        let visible = |j: udim| j <= p && p - j < WINDOW;
+        let sum = (0..ATT)
+            .filter(|&j| visible(j))
+            .map(|j| x[j as usize].exp())
+            .sum::<f32>();
+        for j in 0..ATT {
+            let expected = if visible(j) {
+                x[j as usize].exp() / sum
+            } else {
+                0.
+            };
+            assert!((y[j as usize].to_f32() - expected).abs() < 1e-3);
+        }
+    }
+
+    // 窗口覆盖全部位置时退化为完整的因果注意力
+    assert!(causal_lm::sliding_window_mask(0, SEQ, SEQ, SEQ).is_none());
+}
+
+#[test]
+fn test_embed_pooling() {
+    const D: udim = 4;
+    let hidden = [[0.5f32, -1., 2., 0.], [1.5, 1., -2., 0.25]];
+    let mut x = Tensor::alloc(F16, &[2, D], Blob::new);
+    for (y, x) in reslice_mut::<u8, f16>(x.physical_mut())
+        .iter_mut()
+        .zip(hidden.iter().flatten())
+    {
+        *y = f16::from_f32(*x);
+    }
+    let pooled = |pooling| {
+        reslice::<u8, f16>(pool(&x, pooling).as_slice())
+            .iter()
+            .map(|x| x.to_f32())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(pooled(Pooling::Cls), hidden[0]);
+    assert_eq!(pooled(Pooling::LastToken), hidden[1]);
+    assert_eq!(pooled(Pooling::Mean), [1., 0., 0., 0.125]);
+
+    // 其他数据类型按原类型池化
+    let mut x = Tensor::alloc(F32, &[2, D], Blob::new);
+    reslice_mut::<u8, f32>(x.physical_mut()).copy_from_slice(&hidden.concat());
+    let mean = pool(&x, Pooling::Mean);
+    assert_eq!(mean.data_layout(), F32);
+    assert_eq!(reslice::<u8, f32>(mean.as_slice()), [1., 0., 0., 0.125]);
+    let cls = pool(&x, Pooling::Cls);
+    assert_eq!(reslice::<u8, f32>(cls.as_slice()), hidden[0]);
+
+    let model = random_model(4, 2);
+    let tokens = [model.bos_token(), 1];
+    let hidden = model.embed(&tokens, None).unwrap();
+    let d = model.s.config.d as usize;
+    assert_eq!(hidden.shape(), &[2, d as udim]);
+    let hidden = reslice::<u8, f16>(hidden.as_slice());
+    let mean = model.embed(&tokens, Some(Pooling::Mean)).unwrap();
+    assert_eq!(mean.shape(), &[d as udim]);
+    for (i, y) in reslice::<u8, f16>(mean.as_slice()).iter().enumerate() {
+        let expected = (hidden[i].to_f32() + hidden[d + i].to_f32()) / 2.;
+        assert!((y.to_f32() - expected).abs() < 1e-2);
+    }
+}
+
+#[test]
+fn test_load_gguf() {
+    use std::io::Write;
+
+    const VOC: u64 = 16;
+    const D: u64 = 8;
+    const DKV: u64 = 4;
+    const DI: u64 = 12;
+
+    /// 按 GGUF v3 格式写出一个单层的小模型，归一化权重为 f32，其余为 f16。
+    fn gguf() -> Vec<u8> {
+        let string = |buf: &mut Vec<u8>, s: &str| {
+            buf.extend((s.len() as u64).to_le_bytes());
+            buf.extend(s.as_bytes());
+        };
+        let meta = [
+            ("llama.embedding_length", D),
+            ("llama.block_count", 1),
+            ("llama.feed_forward_length", DI),
+            ("llama.attention.head_count", 2),
+            ("llama.attention.head_count_kv", 1),
+            ("llama.context_length", 32),
+        ];
+        let tensors = [
+            ("token_embd.weight", vec![D, VOC]),
+            ("blk.0.attn_norm.weight", vec![D]),
+            ("blk.0.attn_q.weight", vec![D, D]),
+            ("blk.0.attn_k.weight", vec![D, DKV]),
+            ("blk.0.attn_v.weight", vec![D, DKV]),
+            ("blk.0.attn_output.weight", vec![D, D]),
+            ("blk.0.ffn_norm.weight", vec![D]),
+            ("blk.0.ffn_gate.weight", vec![D, DI]),
+            ("blk.0.ffn_up.weight", vec![D, DI]),
+            ("blk.0.ffn_down.weight", vec![DI, D]),
+            ("output_norm.weight", vec![D]),
+        ];
+
+        let mut buf = b"GGUF".to_vec();
+        buf.extend(3u32.to_le_bytes());
+        buf.extend((tensors.len() as u64).to_le_bytes());
+        buf.extend((meta.len() as u64 + 1).to_le_bytes());
+        string(&mut buf, "general.architecture");
+        buf.extend(8u32.to_le_bytes());
+        string(&mut buf, "llama");
+        for (key, value) in meta {
+            string(&mut buf, key);
+            buf.extend(4u32.to_le_bytes());
+            buf.extend((value as u32).to_le_bytes());
+        }
+
+        let mut data = Vec::new();
+        for (name, dims) in &tensors {
+            string(&mut buf, name);
+            buf.extend((dims.len() as u32).to_le_bytes());
+            for d in dims {
+                buf.extend(d.to_le_bytes());
+            }
+            let norm = dims.len() == 1;
+            buf.extend((if norm { 0u32 } else { 1 }).to_le_bytes());
+            buf.extend((data.len() as u64).to_le_bytes());
+
+            let n = dims.iter().product::<u64>();
+            for i in 0..n {
+                if norm {
+                    data.extend(1f32.to_le_bytes());
+                } else {
+                    let x = ((i * 7 + name.len() as u64) % 11) as f32 / 20. - 0.25;
+                    data.extend(f16::from_f32(x).to_le_bytes());
+                }
+            }
+            data.resize(data.len().next_multiple_of(32), 0);
+        }
+        buf.resize(buf.len().next_multiple_of(32), 0);
+        buf.extend(data);
+        buf
+    }
+
+    let path = std::env::temp_dir().join("test_load_gguf.gguf");
+    std::fs::File::create(&path)
+        .unwrap()
+        .write_all(&gguf())
+        .unwrap();
+    let model = <Transformer as Model>::load(&path, ()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let config = &model.s.config;
+    assert_eq!(config.dt, digit_layout::types::F16);
+    assert_eq!(config.voc, VOC as udim);
+    assert_eq!((config.nh, config.nkvh, config.dh), (2, 1, 4));
+    // 没有 output.weight 时输出层与词嵌入共享
+    assert_eq!(model.s.lm_head.shape(), &[D as udim, VOC as udim]);
+
+    let mut cache = model.new_cache();
+    let tokens = [1, 5, 3];
+    let token_embedded = model.token_embed(tokens);
+    let queries = [QueryContext {
+        cache: Some(&mut cache),
+        range: 0..tokens.len() as upos,
+        adapter: None,
+        mask: None,
+    }];
+    let hidden_state = model.forward(queries, token_embedded).unwrap();
+    let decoding = [DecodingMeta {
+        num_query: tokens.len(),
+        num_decode: 1,
+    }];
+    let logits = model.decode(decoding, hidden_state);
+    assert_eq!(logits.shape(), &[1, VOC as udim]);
+    assert!(reslice::<u8, f16>(logits.as_slice())
+        .iter()
+        .all(|x| x.is_finite()));
+}
+
+/// 构造一个随机权重的两层小模型，`d = 2 * nh`，`voc = 32`。
+#[cfg(test)]
+fn random_model(nh: udim, nkvh: udim) -> Transformer {
+    use llama::Norm;
+
+    const VOC: udim = 32;
+    const DH: udim = 2;
+    const DI: udim = 24;
+    let d = nh * DH;
+    let dkv = nkvh * DH;
+
+    let mut seed = 0u32;
+    let mut weight = |shape: &[udim]| {
+        let mut t = Tensor::alloc(F16, shape, Blob::new);
+        for x in reslice_mut::<u8, f16>(t.physical_mut()) {
+            seed += 1;
+            *x = f16::from_f32(((seed * 37) % 101) as f32 / 200. - 0.25);
+        }
+        t.map_physical(Weight::from)
+    };
+    let s = Storage {
+        config: InferenceConfig {
+            dt: F16,
+            voc: VOC,
+            nlayers: 2,
+            nh,
+            nkvh,
+            d,
+            dh: DH,
+            dkv,
+            di: DI,
+            max_seq_len: 16,
+            bos_token: 1,
+            eos_token: 2,
+            epsilon: 1e-5,
+            theta: 1e4,
+            sliding_window: None,
+            norm: Norm::RmsNorm,
+            attention_scale: None,
+        },
+        embed_tokens: weight(&[VOC, d]),
+        layers: (0..2)
+            .map(|_| LayerStorage {
+                att_layernorm: weight(&[d]),
+                att_qkv: weight(&[d + dkv + dkv, d]).transpose(&[1, 0]),
+                att_o: weight(&[d, d]).transpose(&[1, 0]),
+                mlp_layernorm: weight(&[d]),
+                mlp_gate_up: weight(&[DI + DI, d]).transpose(&[1, 0]),
+                mlp_down: weight(&[d, DI]).transpose(&[1, 0]),
+                att_layernorm_bias: None,
+                mlp_layernorm_bias: None,
+                att_q_norm: None,
+                att_k_norm: None,
+            })
+            .collect(),
+        lm_layernorm: weight(&[d]),
+        lm_layernorm_bias: None,
+        lm_head: weight(&[VOC, d]).transpose(&[1, 0]),
+    };
+    Transformer {
+        rope: RopeCache::new(s.config.theta, s.config.dh, s.config.max_seq_len),
+        s,
+        adapters: Vec::new(),
+        kernels: Default::default(),
+        pool: Default::default(),
+    }
+}
+
+/// 在 `pos` 处把 `tokens` 预填充进 `cache`，返回最后一个词的 logits。
+#[cfg(test)]
+fn prefill(
+    model: &Transformer,
+    cache: &mut Tensor<Blob>,
+    tokens: &[utok],
+    pos: upos,
+) -> Tensor<Blob> {
+    let token_embedded = model.token_embed(tokens.iter().copied());
+    let queries = [QueryContext {
+        cache: Some(cache),
+        range: pos..pos + tokens.len() as upos,
+        adapter: None,
+        mask: None,
+    }];
+    let hidden_state = model.forward(queries, token_embedded).unwrap();
+    let decoding = [DecodingMeta {
+        num_query: tokens.len(),
+        num_decode: 1,
+    }];
+    model.decode(decoding, hidden_state)
+}
+
+#[test]
+fn test_grouped_query() {
+    const NH: udim = 8;
+    const DH: udim = 2;
+    const VOC: udim = 32;
+
+    // 多头、分组和多查询注意力
+    for nkvh in [NH, NH / 4, 1] {
+        let model = random_model(NH, nkvh);
+        let mut caches = [model.new_cache(), model.new_cache()];
+        for cache in &caches {
+            assert_eq!(cache.shape(), &[2, 2, nkvh, 16, DH]);
+        }
+        let step = |caches: &mut [Tensor<Blob>], seqs: &[&[utok]], pos: &[upos]| {
+            let token_embedded = model.token_embed(seqs.iter().flat_map(|s| s.iter().copied()));
+            let queries = caches
+                .iter_mut()
+                .zip(seqs)
+                .zip(pos)
+                .map(|((cache, seq), &pos)| QueryContext {
+                    cache: Some(cache),
+                    range: pos..pos + seq.len() as upos,
+                    adapter: None,
+                    mask: None,
+                });
+            let hidden_state = model.forward(queries, token_embedded).unwrap();
+            let decoding = seqs.iter().map(|seq| DecodingMeta {
+                num_query: seq.len(),
+                num_decode: 1,
+            });
+            let logits = model.decode(decoding, hidden_state);
+            assert_eq!(logits.shape(), &[seqs.len() as udim, VOC]);
+            assert!(reslice::<u8, f16>(logits.as_slice())
+                .iter()
+                .all(|x| x.is_finite()));
+        };
+        // 一批长度不同的提示词，再用复制的缓存继续解码一步
+        step(&mut caches, &[&[1, 5, 9], &[3]], &[0, 0]);
+        let mut caches = [
+            model.duplicate_cache(&caches[0], 3),
+            model.duplicate_cache(&caches[1], 1),
+        ];
+        step(&mut caches, &[&[7], &[4]], &[3, 1]);
+    }
+}
+
+#[test]
+fn test_empty_batch() {
+    let model = random_model(4, 2);
+    let d = model.s.config.d;
+
+    // 没有活跃序列的一步推理
+    let token_embedded = model.token_embed([]);
+    assert_eq!(token_embedded.shape(), &[0, d]);
+    let hidden_state = model.forward([], token_embedded).unwrap();
+    assert_eq!(hidden_state.shape(), &[0, d]);
+    let logits = model.decode([], hidden_state);
+    assert_eq!(logits.shape(), &[0, 32]);
+    assert!(model.sample([], logits).is_empty());
+
+    // 所有请求都不解码
+    let mut cache = model.new_cache();
+    let queries = [QueryContext {
+        cache: Some(&mut cache),
+        range: 0..2,
+        adapter: None,
+        mask: None,
+    }];
+    let hidden_state = model.forward(queries, model.token_embed([1, 3])).unwrap();
+    let decoding = [DecodingMeta {
+        num_query: 2,
+        num_decode: 0,
+    }];
+    assert_eq!(model.decode(decoding, hidden_state).shape(), &[0, 32]);
+}
+
+#[test]
+fn test_forward_seq_len() {
+    use causal_lm::SeqLenError;
+
+    let model = random_model(4, 2);
+    let max_seq_len = model.max_seq_len();
+
+    // 查询越过缓存的边界时返回错误而不是崩溃
+    let mut cache = model.new_cache();
+    let queries = [QueryContext {
+        cache: Some(&mut cache),
+        range: max_seq_len - 1..max_seq_len + 1,
+        adapter: None,
+        mask: None,
+    }];
+    let e = model.forward(queries, model.token_embed([1, 3])).err();
+    assert_eq!(
+        e,
+        Some(ForwardError::SeqLen(SeqLenError {
+            att_len: max_seq_len + 1,
+            max_seq_len,
+        }))
+    );
+    let tokens = vec![1; max_seq_len as usize + 1];
+    assert_eq!(model.embed(&tokens, None).err(), e);
+}
+
+#[test]
+fn test_tie_word_embeddings() {
+    let mut s = random_model(4, 2).s;
+    s.lm_head = s.embed_tokens.clone().transpose(&[1, 0]);
+    let dir = std::env::temp_dir().join("llama_cpu_test_tie_word_embeddings");
+    s.save(&dir).unwrap();
+    let model = <Transformer as Model>::load(&dir, ()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(model.s.is_tied());
+
+    let tokens = [1, 3, 5];
+    let mut cache = model.new_cache();
+    let queries = [QueryContext {
+        cache: Some(&mut cache),
+        range: 0..tokens.len() as upos,
+        adapter: None,
+        mask: None,
+    }];
+    let hidden_state = model.forward(queries, model.token_embed(tokens)).unwrap();
+    let decoding = [DecodingMeta {
+        num_query: tokens.len(),
+        num_decode: tokens.len(),
+    }];
+    let logits = model.decode(decoding, hidden_state);
+    assert_eq!(logits.shape(), &[tokens.len() as udim, s.config.voc]);
+}
+
+#[test]
+fn test_hidden_states() {
+    let model = random_model(4, 2);
+    let nlayers = model.s.layers.len();
+    let tokens = [1, 3, 5, 7];
+
+    let mut cache = model.new_cache();
+    let queries = [QueryContext {
+        cache: Some(&mut cache),
+        range: 0..tokens.len() as upos,
+        adapter: None,
+        mask: None,
+    }];
+    let expected = model.forward(queries, model.token_embed(tokens)).unwrap();
+
+    let mut cache = model.new_cache();
+    let queries = [QueryContext {
+        cache: Some(&mut cache),
+        range: 0..tokens.len() as upos,
+        adapter: None,
+        mask: None,
+    }];
+    let (x, states) = model
+        .forward_with_hidden_states(queries, model.token_embed(tokens))
+        .unwrap();
+    // 收集隐藏状态不改变前向传播的结果
+    assert_eq!(x.as_slice(), expected.as_slice());
+    assert_eq!(states.len(), nlayers);
+    assert!(states.iter().all(|s| s.shape() == x.shape()));
+    assert_eq!(states[nlayers - 1].as_slice(), expected.as_slice());
+    assert_ne!(states[0].as_slice(), expected.as_slice());
+}
+
+#[test]
+fn test_mirostat() {
+    use causal_lm::{Mirostat, MirostatState, SampleArgs};
+    use std::sync::{Arc, Mutex};
+
+    const VOC: udim = 16;
+    const STEPS: usize = 32;
+    let kernels = CpuKernels::default();
+    let args = SampleArgs {
+        temperature: 1.,
+        top_p: 1.,
+        top_k: VOC as _,
+    };
+    let row = (0..VOC)
+        .map(|i| f16::from_f32(i as f32 / 8.))
+        .collect::<Vec<_>>();
+
+    // 目标惊异度极低时只保留概率最大的词，惊异度为 0，mu 每步增加 eta * tau
+    let low = Arc::new(Mutex::new(MirostatState::new(Mirostat::V2, 0.01, 0.5)));
+    let high = Arc::new(Mutex::new(MirostatState::new(Mirostat::V1, 3., 0.1)));
+    let metas = [low.clone(), high.clone()].map(|state| SampleMeta {
+        num_decode: 1,
+        args,
+        mirostat: Some(state),
+        ..Default::default()
+    });
+    let mut mu = high.lock().unwrap().mu;
+    let mut changed = 0;
+    for _ in 0..STEPS {
+        let mut logits = [row.clone(), row.clone()].concat();
+        let tokens = sample(&kernels, metas.clone(), &mut logits, VOC);
+        assert_eq!(tokens[0], VOC - 1);
+
+        let mu_ = high.lock().unwrap().mu;
+        if mu_ != mu {
+            changed += 1;
+        }
+        mu = mu_;
+    }
+    let low = low.lock().unwrap();
+    assert!((low.mu - (0.02 + STEPS as f32 * 0.5 * 0.01)).abs() < 1e-4);
+    // 状态在解码步之间延续
+    assert!(changed > 0);
+}
+
+#[test]
+fn test_attention_scale() {
+    const DH: udim = 2;
+    let forward = |attention_scale| {
+        let mut model = random_model(4, 2);
+        model.s.config.attention_scale = attention_scale;
+        let tokens = [1, 3, 5, 7];
+        let mut cache = model.new_cache();
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: 0..tokens.len() as upos,
+            adapter: None,
+            mask: None,
+        }];
+        let x = model.forward(queries, model.token_embed(tokens)).unwrap();
+        (model.constant().attention_scale, x)
+    };
+    let (default, expected) = forward(None);
+    assert_eq!(default, (DH as f32).sqrt().recip());
+    // 显式指定默认值与不指定完全一致
+    let (scale, x) = forward(Some(default));
+    assert_eq!(scale, default);
+    assert_eq!(x.as_slice(), expected.as_slice());
+    let (scale, x) = forward(Some(default * 2.));
+    assert_eq!(scale, default * 2.);
+    assert_ne!(x.as_slice(), expected.as_slice());
+
+    // 注意力分数按缩放因子的比例变化
+    let mut q = Tensor::alloc(F16, &[3, DH], Blob::new);
+    let mut k = Tensor::alloc(F16, &[5, DH], Blob::new);
+    for (i, x) in reslice_mut::<u8, f16>(q.physical_mut())
+        .iter_mut()
+        .chain(reslice_mut::<u8, f16>(k.physical_mut()))
+        .enumerate()
+    {
+        *x = f16::from_f32(((i * 7) % 11) as f32 / 4. - 1.);
+    }
+    let k = k.transpose(&[1, 0]);
+    let kernels = CpuKernels::default();
+    let scores = |scale| {
+        let mut att = Tensor::alloc(F16, &[3, 5], Blob::new);
+        kernels.mat_mul(&mut att, 0., &q, &k, scale, &ThisThread);
+        reslice::<u8, f16>(att.as_slice())
+            .iter()
+            .map(|x| x.to_f32())
+            .collect::<Vec<_>>()
+    };
+    let base = scores(default);
+    let scaled = scores(scale);
+    assert!(base.iter().any(|&x| x != 0.));
+    for (x, y) in base.iter().zip(&scaled) {
+        assert_eq!(*y, x * 2.);
+    }
+}
+
+#[test]
+fn test_qk_norm() {
+    const DH: udim = 2;
+    let nh = 4;
+    let mut model = random_model(nh, 2);
+
+    // 每个头单独归一化，权重为 1 时每个头的均方根为 1
+    let mut t = Tensor::alloc(F16, &[3, nh * DH], Blob::new);
+    for (i, x) in reslice_mut::<u8, f16>(t.physical_mut())
+        .iter_mut()
+        .enumerate()
+    {
+        *x = f16::from_f32((i * (i % 3 + 1)) as f32 / 8. - 0.5);
+    }
+    let mut ones = Tensor::alloc(F16, &[DH], Blob::new);
+    reslice_mut::<u8, f16>(ones.physical_mut()).fill(f16::ONE);
+    model.head_rms_norm(&mut t, &ones, 1e-5);
+    for head in reslice::<u8, f16>(t.as_slice()).chunks_exact(DH as _) {
+        let rms = head.iter().map(|x| x.to_f32().powi(2)).sum::<f32>() / DH as f32;
+        assert!((rms.sqrt() - 1.).abs() < 1e-2, "{head:?}");
+    }
+
+    let forward = |model: &Transformer| {
+        let tokens = [1, 3, 5, 7];
+        let mut cache = model.new_cache();
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: 0..tokens.len() as upos,
+            adapter: None,
+            mask: None,
+        }];
+        model.forward(queries, model.token_embed(tokens)).unwrap()
+    };
+    let expected = forward(&model);
+    for layer in &mut model.s.layers {
+        let mut w = Tensor::alloc(F16, &[DH], Blob::new);
+        for (i, x) in reslice_mut::<u8, f16>(w.physical_mut())
+            .iter_mut()
+            .enumerate()
+        {
+            *x = f16::from_f32(0.5 + i as f32);
+        }
+        let w = w.map_physical(Weight::from);
+        layer.att_q_norm = Some(w.clone());
+        layer.att_k_norm = Some(w);
+    }
+    let x = forward(&model);
+    assert!(reslice::<u8, f16>(x.as_slice())
+        .iter()
+        .all(|x| x.is_finite()));
+    assert_ne!(x.as_slice(), expected.as_slice());
+}
+
+#[test]
+fn test_sample_per_sequence() {
+    use causal_lm::SampleArgs;
+
+    const VOC: udim = 16;
+    let kernels = CpuKernels::default();
+    const TOP_K: usize = 4;
+    let hot = SampleArgs {
+        temperature: 4.,
+        top_p: 1.,
+        top_k: TOP_K,
+    };
+    // 贪心和高温的序列交替出现在同一批次中
+    let layout = [(SampleArgs::ARG_MAX, 2), (hot, 3), (SampleArgs::ARG_MAX, 1)];
+    let metas = layout
+        .iter()
+        .map(|&(args, num_decode)| SampleMeta {
+            num_decode,
+            args,
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    let nrows = layout.iter().map(|(_, n)| n).sum::<usize>();
+    // 第 i 行的最大值在 `i * 5 % VOC`
+    let logits = (0..nrows)
+        .flat_map(|i| {
+            let peak = i * 5 % VOC as usize;
+            (0..VOC as usize)
+                .map(move |j| f16::from_f32(if j == peak { 2. } else { j as f32 / 64. }))
+        })
+        .collect::<Vec<_>>();
+
+    let greedy = [0, 1, 5];
+    // 高温的行只能采样到各自 logits 最大的 `TOP_K` 个词
+    let top_k = |i: usize| {
+        let row = &logits[i * VOC as usize..][..VOC as usize];
+        let mut tokens = (0..VOC).collect::<Vec<_>>();
+        tokens.sort_by(|&a, &b| row[b as usize].total_cmp(&row[a as usize]));
+        tokens.truncate(TOP_K);
+        tokens
+    };
+    for _ in 0..8 {
+        let mut batched = logits.clone();
+        let tokens = sample(&kernels, metas.clone(), &mut batched, VOC);
+        assert_eq!(tokens.len(), nrows);
+        assert!(tokens.iter().all(|&t| t < VOC));
+        for i in greedy {
+            assert_eq!(tokens[i], (i * 5 % VOC as usize) as utok);
+        }
+        for i in 2..5 {
+            assert!(top_k(i).contains(&tokens[i]), "row {i}: {}", tokens[i]);
+        }
+    }
+}
+
+#[test]
+fn test_evict_cache() {
+    const SINK: upos = 4;
+    const EVICT: upos = 10;
+    const LEN: upos = 20;
+
+    // 单层模型的缓存只取决于词和位置，淘汰后与直接预填充保留的词结果相同
+    let mut model = random_model(4, 2);
+    model.s.layers.truncate(1);
+    model.s.config.nlayers = 1;
+    model.s.config.max_seq_len = 32;
+    model.rope = RopeCache::new(model.s.config.theta, model.s.config.dh, 32);
+
+    let tokens = (0..LEN).map(|i| i * 7 % 32).collect::<Vec<_>>();
+    let mut cache = model.new_cache();
+    prefill(&model, &mut cache, &tokens, 0);
+    let mut evicted = model.evict_cache(&cache, LEN, SINK, EVICT).unwrap();
+
+    fn view(t: &Tensor<Blob>, kv: udim, start: upos, len: upos) -> Tensor<&[u8]> {
+        let seq = slice![start =>=> len];
+        t.as_ref()
+            .slice(&[slice![=>], slice![=kv], slice![=>], seq, slice![=>]])
+            .map_physical(|u| &**u)
+    }
+    // 汇聚点原样保留，被淘汰位置之后的值前移
+    for kv in 0..2 {
+        assert!(view(&evicted, kv, 0, SINK).logical_eq(&view(&cache, kv, 0, SINK)));
+    }
+    let rest = LEN - SINK - EVICT;
+    assert!(view(&evicted, 1, SINK, rest).logical_eq(&view(&cache, 1, SINK + EVICT, rest)));
+
+    // 从缩短后的位置继续解码
+    let pos = LEN - EVICT;
+    let logits = prefill(&model, &mut evicted, &[3], pos);
+
+    let mut kept = tokens[..SINK as usize].to_vec();
+    kept.extend(&tokens[(SINK + EVICT) as usize..]);
+    kept.push(3);
+    let mut fresh = model.new_cache();
+    let expected = prefill(&model, &mut fresh, &kept, 0);
+    assert!(logits.approx_eq(&expected, 1e-2, 1e-2));
+}
+
+#[test]
+fn test_attention_sink() {
+    const SINK: upos = 2;
+    const WINDOW: upos = 6;
+    const STEPS: usize = 40;
+
+    let model = random_model(4, 2);
+    let max_seq_len = model.s.config.max_seq_len;
+    let step = |cache: &mut Tensor<Blob>, tokens: &[utok], pos: upos| {
+        let logits = prefill(&model, cache, tokens, pos);
+        reslice::<u8, f16>(logits.as_slice()).to_vec()
+    };
+
+    // 远超 `SINK + WINDOW` 的贪心生成，缓存中的词与 `history` 同步淘汰
+    let mut history = vec![1, 5, 9, 3];
+    let mut cache = model.new_cache();
+    let mut logits = step(&mut cache, &history, 0);
+    let mut pos = history.len() as upos;
+    for _ in 0..STEPS {
+        assert!(logits.iter().all(|x| x.is_finite()));
+        let token = (0..logits.len())
+            .max_by(|&i, &j| logits[i].partial_cmp(&logits[j]).unwrap())
+            .unwrap() as utok;
+
+        let new_pos = model.slide_cache(&mut cache, pos, SINK, WINDOW);
+        assert!(new_pos <= SINK + WINDOW);
+        history.drain(SINK as usize..(SINK + pos - new_pos) as usize);
+        pos = new_pos;
+        assert!(pos < max_seq_len);
+
+        logits = step(&mut cache, &[token], pos);
+        history.push(token);
+        pos += 1;
+    }
+    assert!(logits.iter().all(|x| x.is_finite()));
+    assert_eq!(history.len() as upos, pos);
+
+    // 第一层的键只取决于词和位置，重新编码后与在新位置上直接预填充的结果相同
+    let mut expected = model.new_cache();
+    step(&mut expected, &history, 0);
+    let layer0 = [
+        slice![=0],
+        slice![=0],
+        slice![=>],
+        slice![=>pos],
+        slice![=>],
+    ];
+    let keys = |t: &Tensor<Blob>| t.as_ref().slice(&layer0).map_physical(|u| u.to_vec());
+    assert!(keys(&cache).approx_eq(&keys(&expected), 1e-2, 0.));
+}
+
+#[test]
+fn test_forward_q4() {
+    use std::iter::zip;
+
+    let model = random_model(16, 4);
+    let tokens = [1, 5, 9, 3, 7];
+    let expected = prefill(&model, &mut model.new_cache(), &tokens, 0);
+
+    let dir = std::env::temp_dir().join("llama_cpu_test_forward_q4");
+    quantize_q4(random_model(16, 4).s).save(&dir).unwrap();
+    let q4 = <Transformer as Model>::load(&dir, ()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(q4.s.is_q4());
+    // `di = 24` 不是 32 的整数倍，`mlp_down` 保持 f16
+    let layer = &q4.s.layers[0];
+    for w in &layer.projections()[..3] {
+        assert_eq!(w.data_layout(), U8);
+    }
+    assert_eq!(layer.mlp_down.data_layout(), F16);
+    // 每 32 个 f16 权重量化为 18 字节
+    let att_o = &model.s.layers[0].att_o;
+    assert_eq!(layer.att_o.bytes_size() * 64, att_o.bytes_size() * 18);
+
+    let logits = prefill(&q4, &mut q4.new_cache(), &tokens, 0);
+    assert_eq!(logits.shape(), expected.shape());
+    let (err, norm) = zip(
+        reslice::<u8, f16>(logits.as_slice()),
+        reslice::<u8, f16>(expected.as_slice()),
+    )
+    .fold((0., 0.), |(err, norm), (x, y)| {
+        let (x, y) = (x.to_f32(), y.to_f32());
+        (err + (x - y).powi(2), norm + y.powi(2))
+    });
+    assert!(norm > 0.);
+    let rel = (err / norm).sqrt();
+    assert!(rel < 0.15, "relative error: {rel}");
+}
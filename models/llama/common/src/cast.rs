@@ -1,50 +1,170 @@
 ﻿use crate::{InferenceConfig, LayerStorage, Storage, Weight};
 use common::{bf16, f16, Blob};
 use digit_layout::{
-    types::{BF16, F16, F32},
+    types::{BF16, F16, F32, F64, U8},
     AsDigit, DigitLayout,
 };
-use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use log::warn;
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
+};
+use std::sync::Mutex;
 use tensor::Tensor;
 
 impl Storage {
+    #[inline]
     pub fn cast(self, dt: DigitLayout) -> Self {
-        if self.config.dt == dt {
-            return self;
+        self.cast_with(dt, |_| false, |_, _| {})
+    }
+
+    /// 转换数据类型，`keep` 返回 `true` 的张量保持原有类型。
+    ///
+    /// 张量名与保存的文件中一致，如 `lm_head.weight`、`model.layers.0.input_layernorm.weight`。
+    /// 输出层与词嵌入共享存储时只转换一次，转换后仍然共享，是否保持类型由词嵌入的名字决定。
+    /// 每处理完一个张量调用一次 `progress(已处理张量数, 已处理字节数)`。
+    ///
+    /// 各张量在 rayon 线程池中并行转换，同时进行的转换数不超过线程数。
+    pub fn cast_with(
+        self,
+        dt: DigitLayout,
+        keep: impl Fn(&str) -> bool + Sync,
+        progress: impl FnMut(usize, usize) + Send,
+    ) -> Self {
+        let tied = self.is_tied();
+        let Self {
+            config,
+            embed_tokens,
+            layers,
+            lm_layernorm,
+            lm_layernorm_bias,
+            lm_head,
+        } = self;
+        // 偏置和按头归一化的权重是可选的，记录各层有哪些以便按顺序取回
+        let optional = layers
+            .iter()
+            .map(|l| {
+                [
+                    l.att_layernorm_bias.is_some(),
+                    l.mlp_layernorm_bias.is_some(),
+                    l.att_q_norm.is_some(),
+                    l.att_k_norm.is_some(),
+                ]
+            })
+            .collect::<Vec<_>>();
+        let lm_bias = lm_layernorm_bias.is_some();
+
+        let mut tensors = vec![("model.embed_tokens.weight".to_string(), embed_tokens)];
+        for (i, l) in layers.into_iter().enumerate() {
+            let name = |name: &str| format!("model.layers.{i}.{name}.weight");
+            tensors.extend([
+                (name("input_layernorm"), l.att_layernorm),
+                (name("self_attn.qkv_proj"), l.att_qkv),
+                (name("self_attn.o_proj"), l.att_o),
+                (name("post_attention_layernorm"), l.mlp_layernorm),
+                (name("mlp.gate_up_proj"), l.mlp_gate_up),
+                (name("mlp.down_proj"), l.mlp_down),
+            ]);
+            let bias = |name: &str| format!("model.layers.{i}.{name}.bias");
+            tensors.extend(
+                [
+                    (bias("input_layernorm"), l.att_layernorm_bias),
+                    (bias("post_attention_layernorm"), l.mlp_layernorm_bias),
+                    (name("self_attn.q_norm"), l.att_q_norm),
+                    (name("self_attn.k_norm"), l.att_k_norm),
+                ]
+                .into_iter()
+                .filter_map(|(name, t)| Some((name, t?))),
+            );
+        }
+        tensors.push(("model.norm.weight".into(), lm_layernorm));
+        tensors.extend(lm_layernorm_bias.map(|t| ("model.norm.bias".into(), t)));
+        if !tied {
+            tensors.push(("lm_head.weight".into(), lm_head));
         }
+
+        let state = Mutex::new((0, 0, progress));
+        let mut tensors = tensors
+            .into_par_iter()
+            .map(|(name, t)| {
+                let size = t.bytes_size();
+                let ans = if keep(&name) { t } else { cast(t, dt) };
+                let (count, bytes, progress) = &mut *state.lock().unwrap();
+                *count += 1;
+                *bytes += size;
+                progress(*count, *bytes);
+                ans
+            })
+            .collect::<Vec<_>>()
+            .into_iter();
+        let mut next = || tensors.next().unwrap();
+
+        let embed_tokens = next();
         Self {
-            config: InferenceConfig { dt, ..self.config },
-            embed_tokens: cast(self.embed_tokens, dt),
-            layers: self
-                .layers
+            config: InferenceConfig { dt, ..config },
+            layers: optional
                 .into_iter()
-                .map(|l| LayerStorage {
-                    att_layernorm: cast(l.att_layernorm, dt),
-                    att_qkv: cast(l.att_qkv, dt),
-                    att_o: cast(l.att_o, dt),
-                    mlp_layernorm: cast(l.mlp_layernorm, dt),
-                    mlp_gate_up: cast(l.mlp_gate_up, dt),
-                    mlp_down: cast(l.mlp_down, dt),
+                .map(|[att, mlp, q, k]| LayerStorage {
+                    att_layernorm: next(),
+                    att_qkv: next(),
+                    att_o: next(),
+                    mlp_layernorm: next(),
+                    mlp_gate_up: next(),
+                    mlp_down: next(),
+                    att_layernorm_bias: att.then(&mut next),
+                    mlp_layernorm_bias: mlp.then(&mut next),
+                    att_q_norm: q.then(&mut next),
+                    att_k_norm: k.then(&mut next),
                 })
                 .collect(),
-            lm_layernorm: cast(self.lm_layernorm, dt),
-            lm_head: cast(self.lm_head, dt),
+            lm_layernorm: next(),
+            lm_layernorm_bias: lm_bias.then(&mut next),
+            lm_head: if tied {
+                embed_tokens.clone().transpose(&[1, 0])
+            } else {
+                next()
+            },
+            embed_tokens,
         }
     }
 }
 
 fn cast(src: Tensor<Weight>, dt: DigitLayout) -> Tensor<Weight> {
     match (src.data_layout(), dt) {
+        (a, b) if a == b => src,
+        // 量化为 4 位的投影权重保持不变，只能用于 f16 的模型
+        (U8, F16) => src,
         (F16, BF16) => typed(src, |x: &f16| bf16::from_f32(x.to_f32())),
         (F16, F32) => typed(src, |x: &f16| x.to_f32()),
         (BF16, F16) => typed(src, |x: &bf16| f16::from_f32(x.to_f32())),
         (BF16, F32) => typed(src, |x: &bf16| x.to_f32()),
         (F32, F16) => typed(src, |x: &f32| f16::from_f32(*x)),
         (F32, BF16) => typed(src, |x: &f32| bf16::from_f32(*x)),
+        (F64, F16) => saturating(src, f16::from_f64, |x| x.is_finite()),
+        (F64, BF16) => saturating(src, bf16::from_f64, |x| x.is_finite()),
+        (F64, F32) => saturating(src, |x| x as f32, |x| x.is_finite()),
         _ => todo!(),
     }
 }
 
+/// 从 f64 向下转换，超出目标类型范围的有限值饱和为无穷，并记录这样的值的数量。
+fn saturating<U: AsDigit + Send>(
+    src: Tensor<Weight>,
+    cast: impl Fn(f64) -> U + Sync,
+    is_finite: impl Fn(U) -> bool + Sync,
+) -> Tensor<Weight> {
+    let overflow = tensor::reslice::<u8, f64>(src.physical())
+        .par_iter()
+        .filter(|&&x| x.is_finite() && !is_finite(cast(x)))
+        .count();
+    if overflow > 0 {
+        warn!(
+            "{overflow} values out of {:?} range saturate to infinity",
+            U::LAYOUT
+        );
+    }
+    typed(src, |x: &f64| cast(*x))
+}
+
 fn typed<T: AsDigit + Sync, U: AsDigit + Send>(
     src: Tensor<Weight>,
     cast: impl Fn(&T) -> U + Sync,
@@ -61,3 +181,149 @@ fn typed<T: AsDigit + Sync, U: AsDigit + Send>(
 
     ans.map_physical(|b| b.into())
 }
+
+#[test]
+fn test_cast_with() {
+    use crate::{test_storage, test_weight, TestDir};
+
+    let (voc, d, nh, nkvh, di) = (8, 8, 2, 1, 4);
+    let dh = d / nh;
+    let storage = test_storage(F32, [voc, 1, nh, nkvh, d, dh, di], test_weight(F32));
+
+    let mut calls = Vec::new();
+    let casted = storage.cast_with(
+        F16,
+        |name| name == "lm_head.weight",
+        |count, bytes| calls.push((count, bytes)),
+    );
+    assert_eq!(calls.len(), casted.num_tensors());
+    assert!(calls.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+    assert_eq!(calls.last().unwrap().0, calls.len());
+    assert_eq!(casted.config.dt, F16);
+    assert_eq!(casted.lm_head.data_layout(), F32);
+    assert_eq!(casted.embed_tokens.data_layout(), F16);
+
+    // 混合精度的模型可以保存并重新加载
+    let dir = TestDir::new("llama_test_cast_with");
+    let mut calls = Vec::new();
+    casted
+        .save_with_progress(&dir, |count, bytes| calls.push((count, bytes)))
+        .unwrap();
+    assert_eq!(calls.len(), casted.num_tensors());
+    assert!(calls.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+    let loaded = Storage::load_safetensors(&dir).unwrap();
+
+    assert_eq!(loaded.config.dt, F16);
+    assert_eq!(loaded.lm_head.data_layout(), F32);
+    assert_eq!(loaded.lm_layernorm.data_layout(), F16);
+    assert_eq!(loaded.layers[0].att_qkv.data_layout(), F16);
+}
+
+#[test]
+fn test_cast_tied() {
+    use crate::{test_storage, test_weight, TestDir};
+
+    let (voc, d, nh, di) = (12, 8, 2, 4);
+    let mut storage = test_storage(F32, [voc, 1, nh, nh, d, d / nh, di], test_weight(F32));
+    storage.lm_head = storage.embed_tokens.clone().transpose(&[1, 0]);
+    let num_tensors = storage.num_tensors();
+
+    // 共享的权重只转换一次，转换后仍然共享
+    let mut count = 0;
+    let casted = storage.cast_with(F16, |_| false, |n, _| count = n);
+    assert_eq!(count, num_tensors);
+    assert!(casted.is_tied());
+    assert_eq!(casted.lm_head.data_layout(), F16);
+    assert_eq!(casted.lm_head.shape(), &[d, voc]);
+
+    // 保存后重新加载仍然共享
+    let dir = TestDir::new("llama_test_cast_tied");
+    casted.save(&dir).unwrap();
+    let loaded = Storage::load_safetensors(&dir).unwrap();
+    assert!(loaded.is_tied());
+    assert_eq!(loaded.config.dt, F16);
+    assert_eq!(
+        **loaded.embed_tokens.physical(),
+        **casted.embed_tokens.physical()
+    );
+}
+
+#[test]
+fn test_cast_parallel() {
+    use crate::test_storage;
+    use tensor::{reslice_mut, udim};
+
+    let (voc, d, nh, nkvh, di, nlayers) = (32, 16, 4, 2, 24, 6);
+    let dh = d / nh;
+    let mut seed = 0;
+    let weight = |shape: &[udim]| {
+        let mut t = Tensor::alloc(F32, shape, Blob::new);
+        for x in reslice_mut::<u8, f32>(t.physical_mut()) {
+            seed += 1;
+            *x = ((seed * 37) % 101) as f32 / 13. - 3.;
+        }
+        t.map_physical(Weight::from)
+    };
+    let storage = test_storage(F32, [voc, nlayers, nh, nkvh, d, dh, di], weight);
+
+    // 逐个张量顺序转换的参照结果
+    let tensors = |s: &Storage| {
+        let mut ans = vec![s.embed_tokens.clone()];
+        for l in &s.layers {
+            ans.extend([
+                l.att_layernorm.clone(),
+                l.att_qkv.clone(),
+                l.att_o.clone(),
+                l.mlp_layernorm.clone(),
+                l.mlp_gate_up.clone(),
+                l.mlp_down.clone(),
+            ]);
+        }
+        ans.extend([s.lm_layernorm.clone(), s.lm_head.clone()]);
+        ans
+    };
+    let expected = tensors(&storage)
+        .into_iter()
+        .map(|t| cast(t, BF16))
+        .collect::<Vec<_>>();
+
+    let casted = storage.cast(BF16);
+    let actual = tensors(&casted);
+    assert_eq!(actual.len(), expected.len());
+    for (a, b) in actual.iter().zip(&expected) {
+        assert_eq!(a.data_layout(), BF16);
+        assert_eq!(a.shape(), b.shape());
+        assert_eq!(&**a.physical(), &**b.physical());
+    }
+}
+
+#[test]
+fn test_cast_f64() {
+    use tensor::{reslice, reslice_mut};
+
+    let data = [0.1f64, -2.5, 0.3, 1e-3, 65504., 1e6, -1e6, f64::INFINITY];
+    let mut t = Tensor::alloc(F64, &[data.len() as _], Blob::new);
+    reslice_mut::<u8, f64>(t.physical_mut()).copy_from_slice(&data);
+    let t = t.map_physical(Weight::from);
+
+    // 转换到 f32 后数值在误差范围内
+    let single = cast(t.clone(), F32);
+    assert_eq!(single.data_layout(), F32);
+    assert_eq!(single.shape(), t.shape());
+    for (x, y) in data.iter().zip(reslice::<u8, f32>(single.physical())) {
+        if x.is_finite() {
+            assert!((*y as f64 - x).abs() <= x.abs() * 1e-7, "{x} -> {y}");
+        } else {
+            assert_eq!(*y as f64, *x);
+        }
+    }
+
+    // 超出 f16 范围的值饱和为无穷
+    let half = cast(t, F16);
+    let half = reslice::<u8, f16>(half.physical());
+    assert_eq!(half[1], f16::from_f32(-2.5));
+    assert_eq!(half[4], f16::MAX);
+    assert_eq!(half[5], f16::INFINITY);
+    assert_eq!(half[6], f16::NEG_INFINITY);
+    assert_eq!(half[7], f16::INFINITY);
+}
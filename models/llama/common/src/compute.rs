@@ -1,9 +1,17 @@
-﻿use causal_lm::QueryContext;
+﻿use crate::{
+    lora::{lora_segmented, LoraLayer, LoraWeight},
+    Norm,
+};
+use causal_lm::{ForwardError, QueryContext};
 use common_devices::{Kernels, KernelsA, SliceOn};
 use itertools::izip;
 use operators::{Handle, QueueOf};
-use std::ops::{Deref, DerefMut};
-use tensor::{slice, split, udim, LocalSplitable, Tensor};
+use std::{
+    borrow::Cow,
+    iter::zip,
+    ops::{Deref, DerefMut},
+};
+use tensor::{slice, split, udim, LocalSplitable, Splitable, Tensor};
 
 pub trait ComputeStream {
     type Handle: Handle;
@@ -31,15 +39,162 @@ pub trait ComputeStream {
         &self,
     ) -> impl Iterator<Item = impl LLamaLayer<Byte = <Self::Handle as Handle>::Byte>>;
 
+    /// 序号为 `adapter` 的 LoRA 适配器在第 `layer` 层的权重，默认不支持适配器。
+    #[inline]
+    fn lora(&self, _adapter: usize, _layer: usize) -> Option<LoraLayer<&SliceOn<Self::Handle>>> {
+        None
+    }
+
+    /// 是否实现了 [`softmax_with_mask`](Self::softmax_with_mask)，不支持时带掩码的查询在前向传播之前被拒绝。
+    const ATTENTION_MASK: bool = false;
+
+    /// 在 `[nh, seq_len, att_len]` 的注意力分数上加 `mask` 后做 softmax，默认不支持自定义掩码。
+    fn softmax_with_mask<T>(&self, _att: &mut Tensor<T>, _mask: &[f32])
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+    {
+        unreachable!("queries with attention mask must be rejected before forward")
+    }
+
+    /// 按 `norm` 归一化 `x`，结果写入 `y`，`b` 只在层归一化时使用。
+    #[inline]
+    fn normalize<T, U, V>(
+        &self,
+        y: &mut Tensor<T>,
+        x: &Tensor<U>,
+        w: &Tensor<V>,
+        b: Option<&Tensor<V>>,
+        norm: Norm,
+        epsilon: f32,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        match norm {
+            Norm::RmsNorm => self.kernels().rms_norm(y, x, w, epsilon, self.queue()),
+            Norm::LayerNorm => self.layer_norm(y, x, w, b, epsilon),
+        }
+    }
+
+    /// 对 `[n, nh * dh]` 的 `t` 逐头原地做均方根归一化，每个头的 `dh` 个元素共享权重 `w`。
+    fn head_rms_norm<T, V>(&self, t: &mut Tensor<T>, w: &Tensor<V>, epsilon: f32)
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        let &[_, d] = t.shape() else { panic!() };
+        let &[dh] = w.shape() else { panic!() };
+        assert_eq!(d % dh, 0, "width {d} is not a multiple of head dim {dh}");
+        let t = t.as_mut().map_physical(|u| LocalSplitable::from(&mut **u));
+        for mut head in t.split(1, &vec![dh; (d / dh) as usize]) {
+            let x = cols(&head, dh);
+            self.kernels()
+                .rms_norm(&mut head, &x, w, epsilon, self.queue());
+        }
+    }
+
+    /// 减去均值的层归一化，默认不支持。
+    ///
+    /// 不支持的设备必须在加载时拒绝 [`Norm::LayerNorm`] 的模型，因此默认实现不会被调用。
+    fn layer_norm<T, U, V>(
+        &self,
+        _y: &mut Tensor<T>,
+        _x: &Tensor<U>,
+        _w: &Tensor<V>,
+        _b: Option<&Tensor<V>>,
+        _epsilon: f32,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        unreachable!("layer norm models must be rejected when loading")
+    }
+
+    /// 施加旋转位置编码，默认每次调用算子现场计算旋转因子。
+    #[inline]
+    fn rotary_embedding<T, U>(&self, t: &mut Tensor<T>, pos: &Tensor<U>, theta: f32)
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.kernels().rope(t, pos, theta, self.queue());
+    }
+
+    /// `c = beta * c + alpha * a x w`，`w` 是层的投影权重，默认直接做矩阵乘。
+    ///
+    /// 支持量化权重的设备在此按权重的数据类型分派。
+    #[inline]
+    fn mat_mul_weight<T, U, V>(
+        &self,
+        c: &mut Tensor<T>,
+        beta: f32,
+        a: &Tensor<U>,
+        w: &Tensor<V>,
+        alpha: f32,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.kernels().mat_mul(c, beta, a, w, alpha, self.queue());
+    }
+
+    /// 门控 MLP，结果累加到 `x` 上，`gate_up` 存放中间结果，默认调用融合的算子。
+    #[inline]
+    fn mlp<T, U, V, W>(
+        &self,
+        x: &mut Tensor<T>,
+        x1: &Tensor<U>,
+        gate_up: &mut Tensor<V>,
+        w_gate_up: &Tensor<W>,
+        w_down: &Tensor<W>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: DerefMut<Target = SliceOn<Self::Handle>>,
+        W: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.kernels()
+            .mlp(x, x1, gate_up, w_gate_up, w_down, 1., true, self.queue());
+    }
+
+    #[inline]
     fn forward<'q>(
+        &self,
+        queries: impl IntoIterator<Item = QueryContext<'q, Self::Storage>>,
+        token_embedded: Tensor<Self::Storage>,
+    ) -> Result<Tensor<Self::Storage>, ForwardError>
+    where
+        Self::Storage: 'q,
+    {
+        self.forward_inspect(queries, token_embedded, |_, _| {})
+    }
+
+    /// 前向传播，每层计算完成后以层序号和该层输出的隐藏状态调用 `inspect`。
+    ///
+    /// `inspect` 只读取隐藏状态，不影响计算结果。查询越过 K-V 缓存的边界，
+    /// 或设备不支持查询的注意力掩码时不做任何计算，直接返回错误。
+    fn forward_inspect<'q>(
         &self,
         queries: impl IntoIterator<Item = QueryContext<'q, Self::Storage>>,
         mut token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+        mut inspect: impl FnMut(usize, &Tensor<&SliceOn<Self::Handle>>),
+    ) -> Result<Tensor<Self::Storage>, ForwardError>
     where
         Self::Storage: 'q,
     {
         let mut queries = queries.into_iter().collect::<Vec<_>>();
+        // 注意力长度不能越过 K-V 缓存的边界
+        for q in &queries {
+            q.check_cache()?;
+            if q.mask.is_some() && !Self::ATTENTION_MASK {
+                return Err(ForwardError::Unsupported(
+                    "custom attention mask is not supported on this device",
+                ));
+            }
+        }
         let mut nt = 0;
         let mut max_seq_len = 0;
         let mut max_att_len = 0;
@@ -54,27 +209,36 @@ pub trait ComputeStream {
                 seq
             })
             .collect::<Vec<_>>();
+        // 没有需要推理的词，直接返回零行的隐藏状态
+        if nt == 0 {
+            return Ok(token_embedded);
+        }
 
         let ComputeConst {
             nh,
             nkvh,
+            dh,
             di,
             epsilon,
             theta,
+            sliding_window,
+            norm,
+            attention_scale,
         } = self.constant();
         let dt = token_embedded.data_layout();
         let d = token_embedded.shape()[1];
-        let dh = d / nh;
+        let dq = nh * dh;
         let dkv = nkvh * dh;
+        // 注意力输出与归一化结果复用同一块缓存
+        let dx = d.max(dq);
         let head_group = nh / nkvh;
-        let head_div = (dh as f32).sqrt().recip();
         let queue = self.queue();
 
         let mut x = token_embedded
             .as_mut()
             .map_physical(|u| self.map_storage(u));
-        let reusing = (d + dkv + dkv).max(di + di);
-        let mut state_buf = Tensor::alloc(dt, &[nt, d + reusing], |len| self.malloc(len));
+        let reusing = (dq + dkv + dkv).max(di + di);
+        let mut state_buf = Tensor::alloc(dt, &[nt, dx + reusing], |len| self.malloc(len));
 
         let mut q_buf = self.malloc((nh * max_seq_len * dh) as usize * dt.nbytes());
         let mut att_buf = self.malloc((nh * max_seq_len * max_att_len) as usize * dt.nbytes());
@@ -82,22 +246,55 @@ pub trait ComputeStream {
         let pos = pos.as_ref().map_physical(|u| self.map_pos(u));
 
         for (layer, params) in self.layers().enumerate() {
-            let (mut x1, qkv) = split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: d, reusing);
-            let mut qkv = qkv.slice(&[slice![=>], slice![=> d + dkv + dkv]]);
+            // 每个请求在这一层使用的适配器
+            let loras = queries
+                .iter()
+                .map(|q| q.adapter.and_then(|i| self.lora(i, layer)))
+                .collect::<Vec<_>>();
+            let mut lora_buf = loras
+                .iter()
+                .flatten()
+                .flat_map(|l| [&l.att_qkv, &l.att_o])
+                .flatten()
+                .map(LoraWeight::rank)
+                .max()
+                .map(|r| Tensor::alloc(dt, &[nt, r], |len| self.malloc(len)));
 
-            self.kernels()
-                .rms_norm(&mut x1, &x, &params.att_layernorm(), epsilon, queue);
-            self.kernels()
-                .mat_mul(&mut qkv, 0., &x1, &params.att_qkv(), 1., queue);
+            let (x1, qkv) = split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: dx, reusing);
+            let mut qkv = qkv.slice(&[slice![=>], slice![=> dq + dkv + dkv]]);
+            let o = cols(&x1, dq);
+            let mut x1 = cols(&x1, d);
 
-            let (q, k, v) = split!(qkv; [1]: d, dkv, dkv);
+            self.normalize(
+                &mut x1,
+                &x,
+                &params.att_layernorm(),
+                params.att_layernorm_bias().as_ref(),
+                norm,
+                epsilon,
+            );
+            self.mat_mul_weight(&mut qkv, 0., &x1, &params.att_qkv(), 1.);
+            if let Some(buf) = lora_buf.as_mut() {
+                let segments = zip(&seq_len, &loras)
+                    .map(|(&len, l)| (len, l.as_ref().and_then(|l| l.att_qkv.as_ref())));
+                lora_segmented(self.kernels(), &mut qkv, &x1, segments, buf, queue);
+            }
+
+            let (mut q, mut k, v) = split!(qkv; [1]: dq, dkv, dkv);
+            // 旋转位置编码之前对每个头分别归一化
+            if let Some(w) = params.att_q_norm() {
+                self.head_rms_norm(&mut q, &w, epsilon);
+            }
+            if let Some(w) = params.att_k_norm() {
+                self.head_rms_norm(&mut k, &w, epsilon);
+            }
             let mut q = q.reshape(&[nt, nh, dh]);
             let mut k = k.reshape(&[nt, nkvh, dh]);
             let v = v.reshape(&[nt, nkvh, dh]);
-            let o = x1.reshape(&[nt, nh, dh]);
+            let o = o.reshape(&[nt, nh, dh]);
 
-            self.kernels().rope(&mut q, &pos, theta, queue);
-            self.kernels().rope(&mut k, &pos, theta, queue);
+            self.rotary_embedding(&mut q, &pos, theta);
+            self.rotary_embedding(&mut k, &pos, theta);
 
             let q = q.transpose(&[1, 0, 2]).split(1, &seq_len);
             let k = k.transpose(&[1, 0, 2]).split(1, &seq_len);
@@ -108,6 +305,16 @@ pub trait ComputeStream {
                 let pos = query.pos();
                 let seq_len = query.seq_len();
                 let att_len = query.att_len();
+                let window = sliding_window
+                    .and_then(|w| causal_lm::sliding_window_mask(pos, seq_len, att_len, w));
+                let mask = match (query.mask, window) {
+                    (Some(mask), Some(mut window)) => {
+                        zip(&mut window, mask).for_each(|(w, m)| *w += m);
+                        Some(Cow::Owned(window))
+                    }
+                    (Some(mask), None) => Some(Cow::Borrowed(mask)),
+                    (None, window) => window.map(Cow::Owned),
+                };
                 let mut cache = query
                     .cache
                     .as_mut()
@@ -115,6 +322,8 @@ pub trait ComputeStream {
                 let mut query = QueryContext {
                     cache: cache.as_mut(),
                     range: query.range.clone(),
+                    adapter: query.adapter,
+                    mask: mask.as_deref(),
                 };
                 let Some((mut k_cache, mut v_cache)) = query.cache(layer as _) else {
                     continue;
@@ -140,9 +349,12 @@ pub trait ComputeStream {
 
                 let mut att = Tensor::new(dt, shape_att0, &mut att_buf[..]);
                 self.kernels()
-                    .mat_mul(&mut att, 0., &q_att, &k_att, head_div, queue);
+                    .mat_mul(&mut att, 0., &q_att, &k_att, attention_scale, queue);
                 let mut att = att.reshape(shape_att1);
-                self.kernels().softmax(&mut att, queue);
+                match mask.as_deref() {
+                    Some(mask) => self.softmax_with_mask(&mut att, mask),
+                    None => self.kernels().softmax(&mut att, queue),
+                }
                 let mut x2 = q_att;
                 self.kernels()
                     .mat_mul(&mut x2, 0., &att.reshape(shape_att0), &v_att, 1., queue);
@@ -150,39 +362,61 @@ pub trait ComputeStream {
                 self.kernels().reform(&mut o, &x2.reshape(shape_q0), queue);
             }
 
-            let (mut x1, gate_up) = split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: d, reusing);
+            let (x1, gate_up) = split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: dx, reusing);
             let mut gate_up = gate_up.slice(&[slice![=>], slice![=> di + di]]);
+            let o = cols(&x1, dq);
+            let mut x1 = cols(&x1, d);
 
-            self.kernels()
-                .mat_mul(&mut x, 1., &x1, &params.att_o(), 1., queue);
-            self.kernels()
-                .rms_norm(&mut x1, &x, &params.mlp_layernorm(), epsilon, queue);
-            self.kernels().mlp(
+            self.mat_mul_weight(&mut x, 1., &o, &params.att_o(), 1.);
+            if let Some(mut buf) = lora_buf {
+                let segments = zip(&seq_len, &loras)
+                    .map(|(&len, l)| (len, l.as_ref().and_then(|l| l.att_o.as_ref())));
+                lora_segmented(self.kernels(), &mut x, &o, segments, &mut buf, queue);
+                self.free(buf.take_physical());
+            }
+            self.normalize(
+                &mut x1,
+                &x,
+                &params.mlp_layernorm(),
+                params.mlp_layernorm_bias().as_ref(),
+                norm,
+                epsilon,
+            );
+            self.mlp(
                 &mut x,
                 &x1,
                 &mut gate_up,
                 &params.mlp_gate_up(),
                 &params.mlp_down(),
-                1.,
-                true,
-                queue,
             );
+            inspect(layer, &x.as_ref().map_physical(|u| &**u));
         }
         self.free_pos(pos.take_physical());
         self.free(state_buf.take_physical());
         self.free(q_buf);
         self.free(att_buf);
         drop(x);
-        token_embedded
+        Ok(token_embedded)
     }
 }
 
+/// 取张量第 1 维的前 `len` 个元素，与原张量共享存储。
+#[inline]
+fn cols<T: Splitable>(t: &Tensor<T>, len: udim) -> Tensor<T> {
+    t.split(1, &[len]).pop_front().unwrap()
+}
+
 pub struct ComputeConst {
     pub nh: udim,
     pub nkvh: udim,
+    pub dh: udim,
     pub di: udim,
     pub epsilon: f32,
     pub theta: f32,
+    pub sliding_window: Option<udim>,
+    pub norm: Norm,
+    /// 注意力分数的缩放因子。
+    pub attention_scale: f32,
 }
 
 pub trait LLamaLayer {
@@ -197,4 +431,25 @@ pub trait LLamaLayer {
     fn mlp_layernorm(&self) -> Tensor<Self::Storage<'_>>;
     fn mlp_gate_up(&self) -> Tensor<Self::Storage<'_>>;
     fn mlp_down(&self) -> Tensor<Self::Storage<'_>>;
+
+    /// 注意力前层归一化的偏置，默认没有。
+    #[inline]
+    fn att_layernorm_bias(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        None
+    }
+    /// MLP 前层归一化的偏置，默认没有。
+    #[inline]
+    fn mlp_layernorm_bias(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        None
+    }
+    /// 每个头的 q 在旋转位置编码前归一化的权重，默认没有。
+    #[inline]
+    fn att_q_norm(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        None
+    }
+    /// 每个头的 k 在旋转位置编码前归一化的权重，默认没有。
+    #[inline]
+    fn att_k_norm(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        None
+    }
 }
@@ -0,0 +1,336 @@
+use crate::{load::concat0, InferenceConfig, LayerStorage, Norm, Storage, Weight};
+use common::{
+    Blob,
+    FileLoadError::{self, Io, Mismatch, UnsupportedDtype},
+};
+use digit_layout::types::{BF16, F16, F32};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, ErrorKind::InvalidData, Read, Seek, SeekFrom},
+    path::Path,
+};
+use tensor::{udim, Tensor};
+
+impl Storage {
+    /// 加载 llama.cpp 导出的 GGUF 文件，目前只支持 F32、F16 和 BF16 张量。
+    ///
+    /// GGUF 中的 Q、K 权重已经是交错的 RoPE 布局，不需要像 safetensors 那样重排。
+    /// 所有张量转换为词嵌入的数据类型。
+    pub fn load_gguf(path: impl AsRef<Path>) -> Result<Self, FileLoadError> {
+        let mut file = Reader::new(BufReader::new(File::open(path).map_err(Io)?));
+        let gguf = Gguf::read(&mut file).map_err(Io)?;
+
+        let arch = match gguf.meta.get("general.architecture") {
+            Some(Meta::Str(arch)) => arch.clone(),
+            _ => "llama".into(),
+        };
+        let mut missing = Vec::new();
+        let mut uint = |key: &str| {
+            let key = format!("{arch}.{key}");
+            gguf.uint(&key).unwrap_or_else(|| {
+                missing.push(format!("missing metadata {key}"));
+                0
+            }) as udim
+        };
+        let d = uint("embedding_length");
+        let nlayers = uint("block_count");
+        let di = uint("feed_forward_length");
+        let nh = uint("attention.head_count");
+        let max_seq_len = uint("context_length");
+        if !missing.is_empty() {
+            return Err(Mismatch(missing));
+        }
+        let nkvh = gguf
+            .uint(&format!("{arch}.attention.head_count_kv"))
+            .map_or(nh, |n| n as _);
+        let dh = gguf
+            .uint(&format!("{arch}.attention.key_length"))
+            .map_or(d / nh, |n| n as _);
+        // 只有层归一化的 epsilon 时使用层归一化
+        let (norm, epsilon) = match (
+            gguf.float(&format!("{arch}.attention.layer_norm_rms_epsilon")),
+            gguf.float(&format!("{arch}.attention.layer_norm_epsilon")),
+        ) {
+            (None, Some(eps)) => (Norm::LayerNorm, eps as _),
+            (eps, _) => (Norm::RmsNorm, eps.unwrap_or(1e-5) as _),
+        };
+        let theta = gguf.float(&format!("{arch}.rope.freq_base")).unwrap_or(1e4) as _;
+        let attention_scale = gguf
+            .float(&format!("{arch}.attention.scale"))
+            .map(|s| s as _);
+
+        let mut tensor = |name: &str, shape: &[udim]| -> Result<Tensor<Weight>, FileLoadError> {
+            let info = gguf
+                .tensors
+                .get(name)
+                .ok_or_else(|| Mismatch(vec![format!("missing tensor: {name}")]))?;
+            if info.shape != shape {
+                return Err(Mismatch(vec![format!(
+                    "tensor {name} has shape {:?}, expected {shape:?}",
+                    info.shape
+                )]));
+            }
+            let dt = match info.ty {
+                0 => F32,
+                1 => F16,
+                30 => BF16,
+                ty => {
+                    return Err(UnsupportedDtype(format!(
+                        "tensor {name} has quantized or unknown ggml type {ty}"
+                    )))
+                }
+            };
+            let mut t = Tensor::alloc(dt, shape, Blob::new);
+            file.0
+                .seek(SeekFrom::Start(gguf.data_offset + info.offset))
+                .and_then(|_| file.0.read_exact(t.physical_mut()))
+                .map_err(Io)?;
+            if cfg!(target_endian = "big") {
+                t.byteswap_inplace();
+            }
+            Ok(t.map_physical(Weight::from))
+        };
+
+        let embed_tokens = {
+            let voc = gguf
+                .tensors
+                .get("token_embd.weight")
+                .and_then(|info| info.shape.first().copied())
+                .unwrap_or(0);
+            tensor("token_embd.weight", &[voc, d])?
+        };
+        // 可以缺省的偏置和按头归一化的权重
+        let optional = |name: String| Some(name).filter(|name| gguf.tensors.contains_key(name));
+        let voc = embed_tokens.shape()[0];
+        let dt = embed_tokens.data_layout();
+        let (dq, dkv) = (nh * dh, nkvh * dh);
+
+        let layers = (0..nlayers)
+            .map(|l| {
+                let name = |name: &str| format!("blk.{l}.{name}.weight");
+                let bias = |name: &str| optional(format!("blk.{l}.{name}.bias"));
+                Ok(LayerStorage {
+                    att_layernorm: tensor(&name("attn_norm"), &[d])?,
+                    att_qkv: concat0(&[
+                        tensor(&name("attn_q"), &[dq, d])?,
+                        tensor(&name("attn_k"), &[dkv, d])?,
+                        tensor(&name("attn_v"), &[dkv, d])?,
+                    ])
+                    .transpose(&[1, 0]),
+                    att_o: tensor(&name("attn_output"), &[d, dq])?.transpose(&[1, 0]),
+                    mlp_layernorm: tensor(&name("ffn_norm"), &[d])?,
+                    mlp_gate_up: concat0(&[
+                        tensor(&name("ffn_gate"), &[di, d])?,
+                        tensor(&name("ffn_up"), &[di, d])?,
+                    ])
+                    .transpose(&[1, 0]),
+                    mlp_down: tensor(&name("ffn_down"), &[d, di])?.transpose(&[1, 0]),
+                    att_layernorm_bias: bias("attn_norm")
+                        .map(|name| tensor(&name, &[d]))
+                        .transpose()?,
+                    mlp_layernorm_bias: bias("ffn_norm")
+                        .map(|name| tensor(&name, &[d]))
+                        .transpose()?,
+                    att_q_norm: optional(name("attn_q_norm"))
+                        .map(|name| tensor(&name, &[dh]))
+                        .transpose()?,
+                    att_k_norm: optional(name("attn_k_norm"))
+                        .map(|name| tensor(&name, &[dh]))
+                        .transpose()?,
+                })
+            })
+            .collect::<Result<Vec<_>, FileLoadError>>()?;
+        let lm_layernorm = tensor("output_norm.weight", &[d])?;
+        let lm_layernorm_bias = optional("output_norm.bias".into())
+            .map(|name| tensor(&name, &[d]))
+            .transpose()?;
+        // 没有输出层时与词嵌入共享权重
+        let lm_head = if gguf.tensors.contains_key("output.weight") {
+            tensor("output.weight", &[voc, d])?
+        } else {
+            embed_tokens.clone()
+        }
+        .transpose(&[1, 0]);
+
+        let storage = Self {
+            config: InferenceConfig {
+                dt,
+                voc,
+                nlayers,
+                nh,
+                nkvh,
+                d,
+                dh,
+                dkv,
+                di,
+                max_seq_len,
+                bos_token: gguf.uint("tokenizer.ggml.bos_token_id").unwrap_or(1) as _,
+                eos_token: gguf.uint("tokenizer.ggml.eos_token_id").unwrap_or(2) as _,
+                epsilon,
+                theta,
+                sliding_window: None,
+                norm,
+                attention_scale,
+            },
+            embed_tokens,
+            layers,
+            lm_layernorm,
+            lm_layernorm_bias,
+            lm_head,
+        };
+        // llama.cpp 通常以 f32 保存归一化权重
+        Ok(storage.cast(dt))
+    }
+}
+
+/// GGUF 文件头，不包含张量数据。
+struct Gguf {
+    meta: HashMap<String, Meta>,
+    tensors: HashMap<String, TensorInfo>,
+    /// 数据区在文件中的起始位置。
+    data_offset: u64,
+}
+
+/// 元数据的值，数组的内容用不到，不保留。
+enum Meta {
+    UInt(u64),
+    Int(i64),
+    Float(f64),
+    Bool,
+    Str(String),
+    Array,
+}
+
+struct TensorInfo {
+    /// 按行优先排列的形状，与 GGUF 中的维度顺序相反。
+    shape: Vec<udim>,
+    ty: u32,
+    offset: u64,
+}
+
+impl Gguf {
+    fn read<R: Read>(r: &mut Reader<R>) -> io::Result<Self> {
+        if &r.bytes::<4>()? != b"GGUF" {
+            return Err(invalid("not a gguf file"));
+        }
+        let version = r.u32()?;
+        if !(2..=3).contains(&version) {
+            return Err(invalid(format!("unsupported gguf version {version}")));
+        }
+        let num_tensors = r.u64()?;
+        let num_meta = r.u64()?;
+
+        let mut meta = HashMap::new();
+        for _ in 0..num_meta {
+            let key = r.string()?;
+            let ty = r.u32()?;
+            meta.insert(key, r.meta(ty)?);
+        }
+        let mut tensors = HashMap::new();
+        for _ in 0..num_tensors {
+            let name = r.string()?;
+            let ndim = r.u32()?;
+            let mut shape = (0..ndim)
+                .map(|_| r.u64().map(|d| d as udim))
+                .collect::<io::Result<Vec<_>>>()?;
+            shape.reverse();
+            let ty = r.u32()?;
+            let offset = r.u64()?;
+            tensors.insert(name, TensorInfo { shape, ty, offset });
+        }
+
+        let align = match meta.get("general.alignment") {
+            Some(Meta::UInt(align)) => *align,
+            _ => 32,
+        };
+        Ok(Self {
+            meta,
+            tensors,
+            data_offset: r.1.next_multiple_of(align),
+        })
+    }
+
+    fn uint(&self, key: &str) -> Option<u64> {
+        match self.meta.get(key)? {
+            Meta::UInt(x) => Some(*x),
+            Meta::Int(x) => (*x).try_into().ok(),
+            _ => None,
+        }
+    }
+
+    fn float(&self, key: &str) -> Option<f64> {
+        match self.meta.get(key)? {
+            Meta::Float(x) => Some(*x),
+            _ => None,
+        }
+    }
+}
+
+/// 记录已读字节数的小端读取器。
+struct Reader<R>(R, u64);
+
+impl<R: Read> Reader<R> {
+    #[inline]
+    fn new(r: R) -> Self {
+        Self(r, 0)
+    }
+
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.0.read_exact(&mut buf)?;
+        self.1 += N as u64;
+        Ok(buf)
+    }
+
+    #[inline]
+    fn u32(&mut self) -> io::Result<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    #[inline]
+    fn u64(&mut self) -> io::Result<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u64()? as usize;
+        let mut buf = vec![0; len];
+        self.0.read_exact(&mut buf)?;
+        self.1 += len as u64;
+        String::from_utf8(buf).map_err(invalid)
+    }
+
+    fn meta(&mut self, ty: u32) -> io::Result<Meta> {
+        Ok(match ty {
+            0 => Meta::UInt(u8::from_le_bytes(self.bytes()?) as _),
+            1 => Meta::Int(i8::from_le_bytes(self.bytes()?) as _),
+            2 => Meta::UInt(u16::from_le_bytes(self.bytes()?) as _),
+            3 => Meta::Int(i16::from_le_bytes(self.bytes()?) as _),
+            4 => Meta::UInt(self.u32()? as _),
+            5 => Meta::Int(i32::from_le_bytes(self.bytes()?) as _),
+            6 => Meta::Float(f32::from_le_bytes(self.bytes()?) as _),
+            7 => {
+                self.bytes::<1>()?;
+                Meta::Bool
+            }
+            8 => Meta::Str(self.string()?),
+            9 => {
+                let ty = self.u32()?;
+                for _ in 0..self.u64()? {
+                    self.meta(ty)?;
+                }
+                Meta::Array
+            }
+            10 => Meta::UInt(self.u64()?),
+            11 => Meta::Int(i64::from_le_bytes(self.bytes()?)),
+            12 => Meta::Float(f64::from_le_bytes(self.bytes()?)),
+            ty => return Err(invalid(format!("unknown gguf value type {ty}"))),
+        })
+    }
+}
+
+#[inline]
+fn invalid(msg: impl ToString) -> io::Error {
+    io::Error::new(InvalidData, msg.to_string())
+}
@@ -1,6 +1,6 @@
-﻿use common::utok;
+﻿use common::{utok, FileLoadError};
 use digit_layout::{
-    types::{BF16, F16, F32},
+    types::{BF16, F16, F32, F64},
     DigitLayout,
 };
 
@@ -12,33 +12,50 @@ pub(crate) struct ConfigJson {
     pub intermediate_size: usize,
     pub max_position_embeddings: usize,
     pub num_attention_heads: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub head_dim: Option<usize>,
     pub num_hidden_layers: usize,
     pub num_key_value_heads: usize,
     pub vocab_size: usize,
     #[serde(default = "default_rms_norm_eps")]
     pub rms_norm_eps: f32,
+    /// 配置时使用层归一化，取代 `rms_norm_eps`。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer_norm_eps: Option<f32>,
     #[serde(default = "default_rope_theta")]
     pub rope_theta: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sliding_window: Option<usize>,
+    /// 注意力分数的缩放因子，不存在时为 `1/sqrt(head_dim)`。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attention_scale: Option<f32>,
+    /// 输出层是否与词嵌入共享权重，不存在时按是否有 `lm_head.weight` 判断。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tie_word_embeddings: Option<bool>,
     pub torch_dtype: String,
 }
 
 impl ConfigJson {
-    pub fn data_layout(&self) -> DigitLayout {
+    pub fn data_layout(&self) -> Result<DigitLayout, FileLoadError> {
         match self.torch_dtype.as_str() {
-            "float16" => F16,
-            "float32" => F32,
-            "bfloat16" => BF16,
-            _ => todo!(),
+            "float16" => Ok(F16),
+            "float32" => Ok(F32),
+            "bfloat16" => Ok(BF16),
+            "float64" => Ok(F64),
+            dtype => Err(FileLoadError::UnsupportedDtype(format!(
+                "torch_dtype \"{dtype}\" is not supported, expected one of: float16, float32, bfloat16, float64"
+            ))),
         }
     }
 }
 
-pub(crate) fn data_layout_name(layout: DigitLayout) -> &'static str {
+pub(crate) fn data_layout_name(layout: DigitLayout) -> Option<&'static str> {
     match layout {
-        F16 => "float16",
-        F32 => "float32",
-        BF16 => "bfloat16",
-        _ => todo!(),
+        F16 => Some("float16"),
+        F32 => Some("float32"),
+        BF16 => Some("bfloat16"),
+        F64 => Some("float64"),
+        _ => None,
     }
 }
 
@@ -51,3 +68,36 @@ const fn default_rms_norm_eps() -> f32 {
 const fn default_rope_theta() -> f32 {
     1e4
 }
+
+#[test]
+fn test_data_layout() {
+    use digit_layout::types::U8;
+
+    let config = |dtype: &str| -> ConfigJson {
+        serde_json::from_str(&format!(
+            r#"{{
+                "bos_token_id": 1,
+                "eos_token_id": 2,
+                "hidden_size": 64,
+                "intermediate_size": 128,
+                "max_position_embeddings": 256,
+                "num_attention_heads": 4,
+                "num_hidden_layers": 2,
+                "num_key_value_heads": 2,
+                "vocab_size": 32,
+                "torch_dtype": "{dtype}"
+            }}"#
+        ))
+        .unwrap()
+    };
+
+    for dt in [F16, F32, BF16, F64] {
+        let name = data_layout_name(dt).unwrap();
+        assert_eq!(config(name).data_layout().unwrap(), dt);
+    }
+    match config("int8").data_layout() {
+        Err(FileLoadError::UnsupportedDtype(msg)) => assert!(msg.contains("int8")),
+        _ => panic!(),
+    }
+    assert_eq!(data_layout_name(U8), None);
+}
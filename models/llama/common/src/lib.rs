@@ -1,17 +1,21 @@
 mod cast;
 mod compute;
+mod gguf;
 mod json;
 mod load;
+mod lora;
 mod save;
 
 use common::{safe_tensors::SharedTensor, upos, utok, Blob};
-use digit_layout::DigitLayout;
+use digit_layout::{types::U8, DigitLayout};
 use std::{ops::Deref, sync::Arc};
 use tensor::{slice, udim, Tensor};
 
 pub use common_devices::SliceOn;
 pub use compute::{ComputeConst, ComputeStream, LLamaLayer};
+pub use lora::{lora_segmented, LoraLayer, LoraWeight};
 pub use operators::{Handle, QueueOf};
+pub use save::Sharding;
 
 pub struct Storage {
     pub config: InferenceConfig,
@@ -19,6 +23,8 @@ pub struct Storage {
     pub embed_tokens: Tensor<Weight>,
     pub layers: Vec<LayerStorage<Weight>>,
     pub lm_layernorm: Tensor<Weight>,
+    /// 输出前层归一化的偏置，只有 [`Norm::LayerNorm`] 使用。
+    pub lm_layernorm_bias: Option<Tensor<Weight>>,
     pub lm_head: Tensor<Weight>,
 }
 
@@ -29,14 +35,24 @@ pub struct LayerStorage<T> {
     pub mlp_layernorm: Tensor<T>,
     pub mlp_gate_up: Tensor<T>,
     pub mlp_down: Tensor<T>,
+    /// 注意力前层归一化的偏置，只有 [`Norm::LayerNorm`] 使用。
+    pub att_layernorm_bias: Option<Tensor<T>>,
+    /// MLP 前层归一化的偏置，只有 [`Norm::LayerNorm`] 使用。
+    pub mlp_layernorm_bias: Option<Tensor<T>>,
+    /// 每个头的 q 在旋转位置编码前做均方根归一化的权重，形状为 `[dh]`。
+    pub att_q_norm: Option<Tensor<T>>,
+    /// 每个头的 k 在旋转位置编码前做均方根归一化的权重，形状为 `[dh]`。
+    pub att_k_norm: Option<Tensor<T>>,
 }
 
 impl<T> LayerStorage<T> {
     pub fn map<U>(&self, mut f: impl FnMut(&T) -> U) -> LayerStorage<U> {
         macro_rules! map {
-            ($($ident:ident)+) => {
+            ($($ident:ident)+; $($optional:ident)+) => {
                 LayerStorage {$(
                     $ident: self.$ident.as_ref().map_physical(&mut f),
+                )+$(
+                    $optional: self.$optional.as_ref().map(|t| t.as_ref().map_physical(&mut f)),
                 )+}
             };
         }
@@ -46,9 +62,45 @@ impl<T> LayerStorage<T> {
             att_o
             mlp_layernorm
             mlp_gate_up
-            mlp_down
+            mlp_down;
+            att_layernorm_bias
+            mlp_layernorm_bias
+            att_q_norm
+            att_k_norm
         }
     }
+
+    /// 可以量化为 4 位的投影权重，见 [`q4_row_bytes`]。
+    #[inline]
+    pub fn projections(&self) -> [&Tensor<T>; 4] {
+        [
+            &self.att_qkv,
+            &self.att_o,
+            &self.mlp_gate_up,
+            &self.mlp_down,
+        ]
+    }
+}
+
+impl Storage {
+    /// 是否有量化为 4 位的投影权重，不支持的设备应在加载时拒绝。
+    pub fn is_q4(&self) -> bool {
+        self.layers
+            .iter()
+            .flat_map(LayerStorage::projections)
+            .any(|t| t.data_layout() == U8)
+    }
+}
+
+/// 投影权重 `[n, k]` 量化为 4 位后以 `[n, q4_row_bytes(k)]` 的 u8 张量存储，
+/// 每 32 个权重一块，每块是 f16 的缩放和 16 字节的量化值，`k` 不是 32 的整数倍时不能量化。
+#[inline]
+pub const fn q4_row_bytes(k: udim) -> Option<udim> {
+    if k % 32 == 0 {
+        Some(k / 32 * 18)
+    } else {
+        None
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -59,6 +111,7 @@ pub struct InferenceConfig {
     pub nh: udim,
     pub nkvh: udim,
     pub d: udim,
+    pub dh: udim,
     pub dkv: udim,
     pub di: udim,
     pub max_seq_len: udim,
@@ -66,19 +119,38 @@ pub struct InferenceConfig {
     pub eos_token: utok,
     pub epsilon: f32,
     pub theta: f32,
+    /// 滑动窗口注意力的窗口长度，`None` 表示完整注意力。
+    pub sliding_window: Option<udim>,
+    /// 各层和输出前使用的归一化。
+    pub norm: Norm,
+    /// 注意力分数的缩放因子，`None` 表示 `1/sqrt(dh)`。
+    pub attention_scale: Option<f32>,
+}
+
+/// 归一化的方式。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Norm {
+    /// 均方根归一化，不减均值，没有偏置。
+    #[default]
+    RmsNorm,
+    /// 减去均值的层归一化，可以带偏置。
+    LayerNorm,
 }
 
 impl InferenceConfig {
+    /// 计算注意力分数时使用的缩放因子。
+    #[inline]
+    pub fn attention_scale(&self) -> f32 {
+        self.attention_scale
+            .unwrap_or_else(|| (self.dh as f32).sqrt().recip())
+    }
+
+    /// KV 缓存的形状为 `[nlayers, 2, nkvh, max_seq_len, dh]`，按 KV 头数而不是注意力头数分配。
     pub fn new_cache<S>(&self, f: impl FnOnce(usize) -> S) -> Tensor<S> {
+        self.check_kv_heads();
         Tensor::alloc(
             self.dt,
-            &[
-                self.nlayers,
-                2,
-                self.nkvh,
-                self.max_seq_len,
-                self.d / self.nh,
-            ],
+            &[self.nlayers, 2, self.nkvh, self.max_seq_len, self.dh],
             f,
         )
     }
@@ -90,11 +162,16 @@ impl InferenceConfig {
         malloc: impl FnOnce(usize) -> S,
         reform: impl FnOnce(Tensor<&mut S>, Tensor<&S>),
     ) -> Tensor<S> {
+        let &[_nlayers, 2, nkvh, max_seq_len, _dh] = cache.shape() else {
+            panic!()
+        };
+        assert_eq!(
+            nkvh, self.nkvh,
+            "cache has {nkvh} kv heads, expected {}",
+            self.nkvh
+        );
         let mut ans = Tensor::alloc(cache.data_layout(), cache.shape(), malloc);
         if pos > 0 {
-            let &[_nlayers, 2, _nkvh, max_seq_len, _dh] = cache.shape() else {
-                panic!()
-            };
             assert!(pos <= max_seq_len);
             let slice = [
                 slice![=>],
@@ -107,6 +184,63 @@ impl InferenceConfig {
         }
         ans
     }
+
+    /// 从有效长度为 `pos` 的缓存中丢弃 `sink..sink + n` 的位置，返回有效长度为 `pos - n` 的新缓存。
+    ///
+    /// 前 `sink` 个位置作为注意力汇聚点保留，之后的位置依次前移。
+    /// 这里只搬移数据，前移的键仍带有原位置的旋转位置编码，需要调用者重新编码。
+    pub fn evict_cache<S>(
+        &self,
+        cache: &Tensor<S>,
+        pos: upos,
+        sink: upos,
+        n: upos,
+        malloc: impl FnOnce(usize) -> S,
+        mut reform: impl FnMut(Tensor<&mut S>, Tensor<&S>),
+    ) -> Tensor<S> {
+        let &[_nlayers, 2, nkvh, max_seq_len, _dh] = cache.shape() else {
+            panic!()
+        };
+        assert_eq!(
+            nkvh, self.nkvh,
+            "cache has {nkvh} kv heads, expected {}",
+            self.nkvh
+        );
+        assert!(pos <= max_seq_len);
+        assert!(
+            sink + n <= pos,
+            "cannot evict {n} positions after {sink} sinks from cache of length {pos}"
+        );
+        let mut ans = Tensor::alloc(cache.data_layout(), cache.shape(), malloc);
+        let range = |seq| [slice![=>], slice![=>], slice![=>], seq, slice![=>]];
+        if sink > 0 {
+            let slice = range(slice![=>sink]);
+            reform(ans.as_mut().slice(&slice), cache.as_ref().slice(&slice));
+        }
+        let len = pos - sink - n;
+        if len > 0 {
+            reform(
+                ans.as_mut().slice(&range(slice![sink =>=> len])),
+                cache.as_ref().slice(&range(slice![sink + n =>=> len])),
+            );
+        }
+        ans
+    }
+
+    fn check_kv_heads(&self) {
+        let Self {
+            nh, nkvh, dh, dkv, ..
+        } = *self;
+        assert!(
+            nkvh > 0 && nh % nkvh == 0,
+            "{nh} attention heads cannot be grouped into {nkvh} kv heads"
+        );
+        assert_eq!(
+            dkv,
+            nkvh * dh,
+            "dkv {dkv} mismatch with {nkvh} kv heads of {dh}"
+        );
+    }
 }
 
 #[derive(Clone)]
@@ -139,3 +273,101 @@ impl Deref for Weight {
         }
     }
 }
+
+/// 测试用的均方根归一化模型，`weight` 按形状依次生成各张量。
+#[cfg(test)]
+pub(crate) fn test_storage(
+    dt: DigitLayout,
+    [voc, nlayers, nh, nkvh, d, dh, di]: [udim; 7],
+    mut weight: impl FnMut(&[udim]) -> Tensor<Weight>,
+) -> Storage {
+    let (dq, dkv) = (nh * dh, nkvh * dh);
+    Storage {
+        config: InferenceConfig {
+            dt,
+            voc,
+            nlayers,
+            nh,
+            nkvh,
+            d,
+            dh,
+            dkv,
+            di,
+            max_seq_len: 16,
+            bos_token: 1,
+            eos_token: 2,
+            epsilon: 1e-5,
+            theta: 1e4,
+            sliding_window: None,
+            norm: Norm::RmsNorm,
+            attention_scale: None,
+        },
+        embed_tokens: weight(&[voc, d]),
+        layers: (0..nlayers)
+            .map(|_| LayerStorage {
+                att_layernorm: weight(&[d]),
+                att_qkv: weight(&[dq + dkv + dkv, d]).transpose(&[1, 0]),
+                att_o: weight(&[d, dq]).transpose(&[1, 0]),
+                mlp_layernorm: weight(&[d]),
+                mlp_gate_up: weight(&[di + di, d]).transpose(&[1, 0]),
+                mlp_down: weight(&[d, di]).transpose(&[1, 0]),
+                att_layernorm_bias: None,
+                mlp_layernorm_bias: None,
+                att_q_norm: None,
+                att_k_norm: None,
+            })
+            .collect(),
+        lm_layernorm: weight(&[d]),
+        lm_layernorm_bias: None,
+        lm_head: weight(&[voc, d]).transpose(&[1, 0]),
+    }
+}
+
+/// 测试用的权重，按字节依次填充序号。
+#[cfg(test)]
+pub(crate) fn test_weight(dt: DigitLayout) -> impl FnMut(&[udim]) -> Tensor<Weight> {
+    move |shape| {
+        let mut t = Tensor::alloc(dt, shape, Blob::new);
+        for (i, b) in t.physical_mut().iter_mut().enumerate() {
+            *b = i as _;
+        }
+        t.map_physical(Weight::from)
+    }
+}
+
+/// 测试用的临时目录，离开作用域时删除。
+#[cfg(test)]
+pub(crate) struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        Self(dir)
+    }
+}
+
+#[cfg(test)]
+impl Deref for TestDir {
+    type Target = std::path::Path;
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<std::path::Path> for TestDir {
+    #[inline]
+    fn as_ref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
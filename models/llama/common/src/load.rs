@@ -1,28 +1,87 @@
-﻿use crate::{json::ConfigJson, InferenceConfig, LayerStorage, Storage, Weight};
+use crate::{json::ConfigJson, q4_row_bytes, InferenceConfig, LayerStorage, Norm, Storage, Weight};
 use common::{
     safe_tensors::{Dtype, SafeTensors},
     Blob,
     FileLoadError::{self, Io, Json},
 };
-use digit_layout::DigitLayout;
+use digit_layout::{
+    types::{F16, F32, U8},
+    DigitLayout,
+};
 use std::{fs::File, path::Path, pin::Pin, sync::Arc};
 use tensor::{udim, Shape, Tensor};
 
 impl Storage {
+    #[inline]
     pub fn load_safetensors(model_dir: impl AsRef<Path>) -> Result<Self, FileLoadError> {
+        Self::load(model_dir, false)
+    }
+
+    /// 加载模型并校验张量数据，见 [SafeTensors::verify]。
+    #[inline]
+    pub fn load_safetensors_verified(model_dir: impl AsRef<Path>) -> Result<Self, FileLoadError> {
+        Self::load(model_dir, true)
+    }
+
+    fn load(model_dir: impl AsRef<Path>, verify: bool) -> Result<Self, FileLoadError> {
         let config = File::open(model_dir.as_ref().join("config.json")).map_err(Io)?;
         let config: ConfigJson = serde_json::from_reader(&config).map_err(Json)?;
-        let model = SafeTensors::load_from_dir(model_dir)?.share();
+        let model = SafeTensors::load_from_dir(model_dir)?;
+        if verify {
+            model.verify()?;
+        }
+        let model = model.share();
 
-        let dt = config.data_layout();
+        let dt = config.data_layout()?;
         let voc = config.vocab_size as udim;
         let d = config.hidden_size as udim;
         let nh = config.num_attention_heads as udim;
         let nkvh = config.num_key_value_heads as udim;
-        let dh = d / nh;
+        let dh = config.head_dim.map_or(d / nh, |dh| dh as udim);
+        let dq = dh * nh;
         let dkv = dh * nkvh;
         let di = config.intermediate_size as udim;
 
+        let embed_tokens = tensor(&model, "model.embed_tokens.weight", dt, [voc, d])?;
+        // 共享词嵌入时输出层与词嵌入都是 `[voc, d]`，转置后得到 `[d, voc]`；
+        // 配置明确不共享时必须有输出层
+        let tied = config
+            .tie_word_embeddings
+            .unwrap_or_else(|| !model.contains("lm_head.weight"));
+        let lm_head = if tied {
+            embed_tokens.clone()
+        } else {
+            tensor(&model, "lm_head.weight", dt, [voc, d])?
+        }
+        .transpose(&[1, 0]);
+        // 配置 `layer_norm_eps` 的模型使用层归一化，偏置可以缺省
+        let (norm, epsilon) = match config.layer_norm_eps {
+            Some(eps) => (Norm::LayerNorm, eps),
+            None => (Norm::RmsNorm, config.rms_norm_eps),
+        };
+        let bias = |weight: &str| {
+            let name = weight.replace(".weight", ".bias");
+            model
+                .contains(&name)
+                .then(|| tensor(&model, &name, dt, [d]))
+                .transpose()
+        };
+        // 分开存储的 q、k 每个头内按旋转位置编码重排，按头归一化的权重也要同样重排
+        let head_norm = |name: &str, permute: bool| {
+            model
+                .contains(name)
+                .then(|| {
+                    tensor(&model, name, dt, [dh]).map(|w| {
+                        if permute {
+                            concat0(&[w.reshape(&[2, dh / 2]).transpose(&[1, 0])]).reshape(&[dh])
+                        } else {
+                            w
+                        }
+                    })
+                })
+                .transpose()
+        };
+
         Ok(Self {
             config: InferenceConfig {
                 dt,
@@ -31,64 +90,74 @@ impl Storage {
                 nh,
                 nkvh,
                 d,
+                dh,
                 dkv,
                 di,
                 max_seq_len: config.max_position_embeddings as _,
                 bos_token: config.bos_token_id,
                 eos_token: config.eos_token_id,
-                epsilon: config.rms_norm_eps,
+                epsilon,
                 theta: config.rope_theta,
+                sliding_window: config.sliding_window.map(|w| w as _),
+                norm,
+                attention_scale: config.attention_scale,
             },
 
-            embed_tokens: tensor(&model, "model.embed_tokens.weight", dt, [voc, d]),
+            embed_tokens,
             layers: (0..config.num_hidden_layers)
-                .map(|l| {
+                .map(|l| -> Result<_, FileLoadError> {
                     let name = |name: &str| format!("model.layers.{l}.{name}.weight");
-                    LayerStorage {
-                        att_layernorm: tensor(&model, &name("input_layernorm"), dt, [d]),
+                    let permute = !model.contains(&name("self_attn.qkv_proj"));
+                    Ok(LayerStorage {
+                        att_layernorm: tensor(&model, &name("input_layernorm"), dt, [d])?,
                         att_qkv: {
                             let qkv = name("self_attn.qkv_proj");
                             if model.contains(&qkv) {
-                                tensor(&model, &qkv, dt, [d + dkv + dkv, d])
+                                projection(&model, &qkv, dt, [dq + dkv + dkv, d])?
                             } else {
                                 let sq = &[nh, 2, dh / 2, d];
                                 let skv = &[nkvh, 2, dh / 2, d];
                                 let perm = &[0, 2, 1, 3];
 
-                                let q = tensor(&model, &name("self_attn.q_proj"), dt, [d, d])
+                                let q = tensor(&model, &name("self_attn.q_proj"), dt, [dq, d])?
                                     .reshape(sq)
                                     .transpose(perm);
-                                let k = tensor(&model, &name("self_attn.k_proj"), dt, [dkv, d])
+                                let k = tensor(&model, &name("self_attn.k_proj"), dt, [dkv, d])?
                                     .reshape(skv)
                                     .transpose(perm);
-                                let v = tensor(&model, &name("self_attn.v_proj"), dt, [dkv, d])
+                                let v = tensor(&model, &name("self_attn.v_proj"), dt, [dkv, d])?
                                     .reshape(skv);
-                                concat0(&[q, k, v]).reshape(&[d + dkv + dkv, d])
+                                concat0(&[q, k, v]).reshape(&[dq + dkv + dkv, d])
                             }
                         }
                         .transpose(&[1, 0]),
-                        att_o: tensor(&model, &name("self_attn.o_proj"), dt, [d, d])
+                        att_o: projection(&model, &name("self_attn.o_proj"), dt, [d, dq])?
                             .transpose(&[1, 0]),
-                        mlp_layernorm: tensor(&model, &name("post_attention_layernorm"), dt, [d]),
+                        mlp_layernorm: tensor(&model, &name("post_attention_layernorm"), dt, [d])?,
                         mlp_gate_up: {
                             let gate_up = name("mlp.gate_up_proj");
                             if model.contains(&gate_up) {
-                                tensor(&model, &gate_up, dt, [di + di, d])
+                                projection(&model, &gate_up, dt, [di + di, d])?
                             } else {
                                 concat0(&[
-                                    tensor(&model, &name("mlp.gate_proj"), dt, [di, d]),
-                                    tensor(&model, &name("mlp.up_proj"), dt, [di, d]),
+                                    tensor(&model, &name("mlp.gate_proj"), dt, [di, d])?,
+                                    tensor(&model, &name("mlp.up_proj"), dt, [di, d])?,
                                 ])
                             }
                         }
                         .transpose(&[1, 0]),
-                        mlp_down: tensor(&model, &name("mlp.down_proj"), dt, [d, di])
+                        mlp_down: projection(&model, &name("mlp.down_proj"), dt, [d, di])?
                             .transpose(&[1, 0]),
-                    }
+                        att_layernorm_bias: bias(&name("input_layernorm"))?,
+                        mlp_layernorm_bias: bias(&name("post_attention_layernorm"))?,
+                        att_q_norm: head_norm(&name("self_attn.q_norm"), permute)?,
+                        att_k_norm: head_norm(&name("self_attn.k_norm"), permute)?,
+                    })
                 })
-                .collect(),
-            lm_layernorm: tensor(&model, "model.norm.weight", dt, [d]),
-            lm_head: tensor(&model, "lm_head.weight", dt, [voc, d]).transpose(&[1, 0]),
+                .collect::<Result<_, _>>()?,
+            lm_layernorm: tensor(&model, "model.norm.weight", dt, [d])?,
+            lm_layernorm_bias: bias("model.norm.weight")?,
+            lm_head,
         })
     }
 }
@@ -98,19 +167,59 @@ fn tensor<const N: usize>(
     name: &str,
     dt: DigitLayout,
     shape: [udim; N],
-) -> Tensor<Weight> {
-    let shared = model
-        .share_tensor(name)
-        .unwrap_or_else(|| panic!("missing tensor: {name}"));
-    assert_eq!(convert(shared.dtype()), dt);
+) -> Result<Tensor<Weight>, FileLoadError> {
+    let shared = model.require_tensor(name)?;
+    // 除模型的数据类型外，张量还可以保留 f32，如转换时保留精度的输出层
+    let found = convert(shared.dtype());
+    if found != dt && found != F32 {
+        return Err(FileLoadError::Mismatch(vec![format!(
+            "{name}: expected {dt:?} or {F32:?}, found {found:?}"
+        )]));
+    }
+    let dt = found;
     assert_eq!(
         &*shared.shape().iter().map(|&d| d as udim).collect::<Shape>(),
         shape
     );
-    Tensor::new(dt, &shape, Weight::SafeTensor(shared))
+    let t = Tensor::new(dt, &shape, Weight::SafeTensor(shared));
+    // safetensors 以小端存储，大端主机上需要复制并翻转字节序
+    Ok(if cfg!(target_endian = "big") {
+        from_little_endian(t)
+    } else {
+        t
+    })
 }
 
-fn concat0(tensors: &[Tensor<Weight>]) -> Tensor<Weight> {
+/// 合并存储的投影权重 `[n, k]`，也可以是量化为 4 位的 u8 张量，见 [`q4_row_bytes`]。
+fn projection(
+    model: &Pin<Arc<SafeTensors>>,
+    name: &str,
+    dt: DigitLayout,
+    [n, k]: [udim; 2],
+) -> Result<Tensor<Weight>, FileLoadError> {
+    if model.require_tensor(name)?.dtype() != Dtype::U8 {
+        return tensor(model, name, dt, [n, k]);
+    }
+    // 量化权重只与 f16 的激活相乘
+    match q4_row_bytes(k) {
+        Some(row) if dt == F16 => tensor(model, name, U8, [n, row]),
+        _ => Err(FileLoadError::Mismatch(vec![format!(
+            "{name}: 4-bit weights of width {k} in a {dt:?} model"
+        )])),
+    }
+}
+
+fn from_little_endian(t: Tensor<Weight>) -> Tensor<Weight> {
+    if t.data_layout().nbytes() <= 1 {
+        return t;
+    }
+    let mut ans = Tensor::alloc(t.data_layout(), t.shape(), Blob::new);
+    t.reform_to(&mut ans);
+    ans.byteswap_inplace();
+    ans.map_physical(Weight::from)
+}
+
+pub(crate) fn concat0(tensors: &[Tensor<Weight>]) -> Tensor<Weight> {
     assert!(tensors
         .windows(2)
         .all(|t| t[0].data_layout() == t[1].data_layout()));
@@ -162,3 +271,176 @@ fn test_load() {
         println!("load: {:?}", time.elapsed());
     };
 }
+
+#[test]
+fn test_head_dim() {
+    use crate::{test_storage, test_weight, TestDir};
+
+    let (voc, d, nh, nkvh, dh, di) = (8, 8, 2, 1, 6, 4);
+    let (dq, dkv) = (nh * dh, nkvh * dh);
+    let storage = test_storage(F16, [voc, 1, nh, nkvh, d, dh, di], test_weight(F16));
+
+    let dir = TestDir::new("llama_test_head_dim");
+    storage.save(&dir).unwrap();
+    let loaded = Storage::load_safetensors(&dir).unwrap();
+
+    assert_eq!(loaded.config.dh, dh);
+    assert_eq!(loaded.layers[0].att_qkv.shape(), &[d, dq + dkv + dkv]);
+    assert_eq!(loaded.layers[0].att_o.shape(), &[dq, d]);
+    assert_eq!(
+        loaded.config.new_cache(Blob::new).shape(),
+        &[1, 2, nkvh, 16, dh]
+    );
+}
+
+#[test]
+fn test_tie_word_embeddings() {
+    use crate::{test_storage, test_weight, TestDir};
+    use common::safe_tensors::SafeTensors;
+    use std::fs;
+
+    let (voc, d, nh, di) = (12, 8, 2, 4);
+    let mut storage = test_storage(F16, [voc, 1, nh, nh, d, d / nh, di], test_weight(F16));
+    storage.lm_head = storage.embed_tokens.clone().transpose(&[1, 0]);
+    assert!(storage.is_tied());
+
+    // 共享权重时不保存输出层
+    let dir = TestDir::new("llama_test_tie_word_embeddings");
+    storage.save(&dir).unwrap();
+    assert!(!SafeTensors::load_from_dir(&dir)
+        .unwrap()
+        .contains("lm_head.weight"));
+    let config = fs::read_to_string(dir.join("config.json")).unwrap();
+    assert!(config.contains(r#""tie_word_embeddings": true"#));
+
+    // 配置中没有标记时，缺少输出层同样视为共享权重
+    let mut json = serde_json::from_str::<serde_json::Value>(&config).unwrap();
+    json.as_object_mut().unwrap().remove("tie_word_embeddings");
+    fs::write(dir.join("config.json"), json.to_string()).unwrap();
+    let loaded = Storage::load_safetensors(&dir).unwrap();
+
+    assert!(loaded.is_tied());
+    assert_eq!(loaded.lm_head.shape(), &[d, voc]);
+    assert_eq!(
+        **loaded.embed_tokens.physical(),
+        **storage.embed_tokens.physical()
+    );
+}
+
+#[test]
+fn test_load_verified() {
+    use crate::{test_storage, test_weight, TestDir};
+    use std::fs::{self, OpenOptions};
+
+    let (voc, d, nh, nkvh, di) = (8, 8, 2, 1, 4);
+    let dh = d / nh;
+    let storage = test_storage(F16, [voc, 1, nh, nkvh, d, dh, di], test_weight(F16));
+
+    let dir = TestDir::new("llama_test_load_verified");
+    storage.save(&dir).unwrap();
+    let file = dir.join("model.safetensors");
+    assert!(Storage::load_safetensors_verified(&dir).is_ok());
+
+    // 哈希不符的伴随文件
+    let sidecar = dir.join("model.safetensors.sha256");
+    fs::write(&sidecar, format!("{}  model.safetensors\n", "0".repeat(64))).unwrap();
+    match Storage::load_safetensors_verified(&dir) {
+        Err(FileLoadError::Mismatch(errors)) => {
+            assert_eq!(errors.len(), 1);
+            assert!(errors[0].contains("sha256 mismatch"));
+        }
+        _ => panic!("sha256 mismatch not detected"),
+    }
+    fs::remove_file(&sidecar).unwrap();
+
+    // 截断最后一个张量 `lm_head.weight` 的数据
+    let len = fs::metadata(&file).unwrap().len();
+    OpenOptions::new()
+        .write(true)
+        .open(&file)
+        .unwrap()
+        .set_len(len - 4)
+        .unwrap();
+    match Storage::load_safetensors_verified(&dir) {
+        Err(FileLoadError::Mismatch(errors)) => {
+            assert_eq!(errors.len(), 1);
+            assert!(errors[0].starts_with("lm_head.weight"), "{}", errors[0]);
+        }
+        _ => panic!("truncation not detected"),
+    }
+}
+
+#[test]
+fn test_missing_tensor() {
+    use crate::{test_storage, test_weight, TestDir};
+    use std::fs;
+
+    let (voc, d, nh, di) = (8, 8, 2, 4);
+    let storage = test_storage(F16, [voc, 1, nh, nh, d, d / nh, di], test_weight(F16));
+
+    let dir = TestDir::new("llama_test_missing_tensor");
+    storage.save(&dir).unwrap();
+    let file = dir.join("model.safetensors");
+    let bytes = fs::read(&file).unwrap();
+    // 改名不改长度，文件头中其他张量的偏移保持不变
+    let check = |name: &str| {
+        let pos = bytes
+            .windows(name.len())
+            .position(|w| w == name.as_bytes())
+            .unwrap();
+        let mut renamed = bytes.clone();
+        renamed[pos + name.len() - 6] = b'W';
+        fs::write(&file, renamed).unwrap();
+        match Storage::load_safetensors(&dir) {
+            Err(FileLoadError::MissingTensor {
+                name: missing,
+                files,
+            }) => {
+                assert_eq!(missing, name);
+                assert_eq!(files, [file.clone()]);
+            }
+            Err(e) => panic!("unexpected error: {e:?}"),
+            Ok(_) => panic!("missing {name} not detected"),
+        }
+    };
+    check("model.layers.0.mlp.down_proj.weight");
+    // 配置明确不共享权重时，缺少输出层是错误而不是回退到词嵌入
+    let config = fs::read_to_string(dir.join("config.json")).unwrap();
+    assert!(config.contains(r#""tie_word_embeddings": false"#));
+    check("lm_head.weight");
+}
+
+#[test]
+fn test_dtype_mismatch() {
+    use crate::{test_storage, test_weight, TestDir};
+    use digit_layout::types::BF16;
+
+    let (voc, d, nh, di) = (8, 8, 2, 4);
+    let shape = [voc, 1, nh, nh, d, d / nh, di];
+    let dir = TestDir::new("llama_test_dtype_mismatch");
+
+    // 保留 f32 的张量可以加载
+    let mut storage = test_storage(F16, shape, test_weight(F16));
+    storage.lm_layernorm = Tensor::alloc(F32, &[d], Blob::new).map_physical(Weight::from);
+    storage.save(&dir).unwrap();
+    let loaded = Storage::load_safetensors(&dir).unwrap();
+    assert_eq!(loaded.lm_layernorm.data_layout(), F32);
+    assert_eq!(loaded.layers[0].att_o.data_layout(), F16);
+
+    // 其他数据类型不符合模型的数据类型
+    let mut storage = test_storage(F16, shape, test_weight(F16));
+    storage.lm_layernorm = Tensor::alloc(BF16, &[d], Blob::new).map_physical(Weight::from);
+    storage.save(&dir).unwrap();
+    match Storage::load_safetensors(&dir) {
+        Err(FileLoadError::Mismatch(errors)) => {
+            assert_eq!(
+                errors,
+                [format!(
+                    "model.norm.weight: expected {F16:?} or {F32:?}, found {BF16:?}"
+                )]
+            );
+        }
+        Err(e) => panic!("unexpected error: {e:?}"),
+        Ok(_) => panic!("dtype mismatch not detected"),
+    }
+}
@@ -0,0 +1,88 @@
+use common_devices::{KernelsA, SliceOn};
+use operators::QueueOf;
+use std::ops::{Deref, DerefMut};
+use tensor::{slice, udim, Tensor};
+
+/// 一组 LoRA 低秩权重，增量为 `scale · x · a · b`。
+pub struct LoraWeight<T> {
+    /// 降维矩阵（`d_in x rank`）。
+    pub a: Tensor<T>,
+    /// 升维矩阵（`rank x d_out`）。
+    pub b: Tensor<T>,
+    /// 增量的缩放系数，通常为 `alpha / rank`。
+    pub scale: f32,
+}
+
+impl<T> LoraWeight<T> {
+    #[inline]
+    pub fn rank(&self) -> udim {
+        self.a.shape()[1]
+    }
+
+    #[inline]
+    pub fn as_ref(&self) -> LoraWeight<&T> {
+        LoraWeight {
+            a: self.a.as_ref(),
+            b: self.b.as_ref(),
+            scale: self.scale,
+        }
+    }
+
+    #[inline]
+    pub fn map_physical<U>(self, mut f: impl FnMut(T) -> U) -> LoraWeight<U> {
+        LoraWeight {
+            a: self.a.map_physical(&mut f),
+            b: self.b.map_physical(&mut f),
+            scale: self.scale,
+        }
+    }
+}
+
+/// 一个适配器在一层中的 LoRA 权重。
+///
+/// `att_qkv` 的输出通道须与加载后的 `att_qkv` 权重布局一致。
+pub struct LoraLayer<T> {
+    pub att_qkv: Option<LoraWeight<T>>,
+    pub att_o: Option<LoraWeight<T>>,
+}
+
+/// 分段计算 LoRA 增量：`y[seg] += scale · x[seg] · a · b`。
+///
+/// `segments` 按顺序给出每段的行数和该段使用的权重，没有权重的段跳过。
+/// `buf` 是至少 `rows x max_rank` 的暂存空间。
+pub fn lora_segmented<'w, K, T, U, V, W>(
+    kernels: &K,
+    y: &mut Tensor<T>,
+    x: &Tensor<U>,
+    segments: impl IntoIterator<Item = (udim, Option<&'w LoraWeight<V>>)>,
+    buf: &mut Tensor<W>,
+    queue: &QueueOf<<K as KernelsA>::Handle>,
+) where
+    K: KernelsA,
+    T: DerefMut<Target = SliceOn<<K as KernelsA>::Handle>>,
+    U: Deref<Target = SliceOn<<K as KernelsA>::Handle>>,
+    V: Deref<Target = SliceOn<<K as KernelsA>::Handle>> + 'w,
+    W: DerefMut<Target = SliceOn<<K as KernelsA>::Handle>>,
+{
+    let mut start = 0;
+    for (len, lora) in segments {
+        if let Some(lora) = lora.filter(|_| len > 0) {
+            let rows = slice![start =>=> len];
+            let x = x
+                .as_ref()
+                .slice(&[rows.clone(), slice![=>]])
+                .map_physical(|u| &**u);
+            let mut y = y
+                .as_mut()
+                .slice(&[rows.clone(), slice![=>]])
+                .map_physical(|u| &mut **u);
+            let mut tmp = buf
+                .as_mut()
+                .slice(&[rows, slice![=> lora.rank()]])
+                .map_physical(|u| &mut **u);
+            kernels.mat_mul(&mut tmp, 0., &x, &lora.a, 1., queue);
+            kernels.mat_mul(&mut y, 1., &tmp, &lora.b, lora.scale, queue);
+        }
+        start += len;
+    }
+}
@@ -1,20 +1,125 @@
 ﻿use crate::{
     json::{data_layout_name, ConfigJson},
-    Storage, Weight,
+    Norm, Storage, Weight,
+};
+use common::safe_tensors::{
+    Dtype, SafeTensorsHeader, SafeTensorsHeaderMetadata, SafeTensorsIndex,
+    SafeTensorsIndexMetadata, TensorInfo,
 };
-use common::safe_tensors::{Dtype, SafeTensorsHeader, SafeTensorsHeaderMetadata, TensorInfo};
 use digit_layout::DigitLayout;
 use std::{
+    cmp::Reverse,
     collections::HashMap,
     fs,
-    io::{self, BufWriter, Write},
+    io::{self, BufWriter, ErrorKind::InvalidInput, Write},
     path::Path,
 };
 use tensor::Tensor;
 
 impl Storage {
+    #[inline]
     pub fn save(&self, dir: impl AsRef<Path>) -> io::Result<()> {
+        self.save_with_progress(dir, |_, _| {})
+    }
+
+    /// 保存模型，每写完一个张量调用一次 `progress(已写张量数, 已写字节数)`。
+    pub fn save_with_progress(
+        &self,
+        dir: impl AsRef<Path>,
+        mut progress: impl FnMut(usize, usize),
+    ) -> io::Result<()> {
+        let dir = dir.as_ref();
+        self.save_config(dir)?;
+        write_safetensors(
+            dir.join("model.safetensors"),
+            &self.named_tensors(),
+            &mut progress,
+        )
+    }
+
+    /// 模型中的张量数量。
+    #[inline]
+    pub fn num_tensors(&self) -> usize {
+        3 + 6 * self.layers.len() - self.is_tied() as usize + self.optional().count()
+    }
+
+    /// 输出层是否与词嵌入共享存储，共享时不单独保存输出层。
+    pub fn is_tied(&self) -> bool {
+        self.lm_head.physical().as_ptr() == self.embed_tokens.physical().as_ptr()
+            && self.lm_head.shape() == [self.embed_tokens.shape()[1], self.embed_tokens.shape()[0]]
+    }
+
+    /// 层归一化的偏置和按头归一化的权重等可选的张量。
+    fn optional(&self) -> impl Iterator<Item = &Tensor<Weight>> {
+        self.layers
+            .iter()
+            .flat_map(|l| {
+                [
+                    &l.att_layernorm_bias,
+                    &l.mlp_layernorm_bias,
+                    &l.att_q_norm,
+                    &l.att_k_norm,
+                ]
+            })
+            .chain([&self.lm_layernorm_bias])
+            .flatten()
+    }
+
+    /// 把模型保存为多个分片文件和对应的 `model.safetensors.index.json`，单个张量不会跨越分片。
+    pub fn save_sharded(&self, dir: impl AsRef<Path>, sharding: Sharding) -> io::Result<()> {
+        let tensors = self.named_tensors();
+        let sizes = tensors
+            .iter()
+            .map(|(_, t)| t.bytes_size())
+            .collect::<Vec<_>>();
+        let plan = match sharding {
+            Sharding::Count(0) => {
+                return Err(io::Error::new(InvalidInput, "shards must be positive"));
+            }
+            Sharding::Count(n) => assign_shards(&sizes, n.min(sizes.len())),
+            Sharding::MaxBytes(max) => {
+                let largest = sizes.iter().copied().max().unwrap_or(0);
+                if largest > max {
+                    return Err(io::Error::new(
+                        InvalidInput,
+                        format!("tensor of {largest} bytes exceeds max shard size {max} bytes"),
+                    ));
+                }
+                let total = sizes.iter().sum::<usize>();
+                (total.div_ceil(max).max(1)..=sizes.len())
+                    .map(|n| assign_shards(&sizes, n))
+                    .find(|plan| plan.iter().all(|shard| shard_bytes(shard, &sizes) <= max))
+                    .unwrap()
+            }
+        };
+
         let dir = dir.as_ref();
+        self.save_config(dir)?;
+
+        let n = plan.len();
+        let mut weight_map = HashMap::new();
+        for (i, shard) in plan.iter().enumerate() {
+            let name = format!("model-{:05}-of-{n:05}.safetensors", i + 1);
+            let shard = shard
+                .iter()
+                .map(|&j| tensors[j].clone())
+                .collect::<Vec<_>>();
+            weight_map.extend(shard.iter().map(|(t, _)| (t.clone(), name.clone())));
+            write_safetensors(dir.join(&name), &shard, &mut |_, _| {})?;
+        }
+        let index = SafeTensorsIndex {
+            metadata: SafeTensorsIndexMetadata {
+                total_size: sizes.iter().sum(),
+            },
+            weight_map,
+        };
+        fs::write(
+            dir.join("model.safetensors.index.json"),
+            serde_json::to_string_pretty(&index)?,
+        )
+    }
+
+    fn save_config(&self, dir: &Path) -> io::Result<()> {
         fs::create_dir_all(dir)?;
         let config = serde_json::to_string_pretty(&ConfigJson {
             bos_token_id: self.config.bos_token,
@@ -23,24 +128,122 @@ impl Storage {
             intermediate_size: self.config.di as _,
             max_position_embeddings: self.config.max_seq_len as _,
             num_attention_heads: self.config.nh as _,
+            head_dim: (self.config.dh * self.config.nh != self.config.d)
+                .then_some(self.config.dh as _),
             num_hidden_layers: self.config.nlayers as _,
             num_key_value_heads: self.config.nkvh as _,
             vocab_size: self.config.voc as _,
             rms_norm_eps: self.config.epsilon,
+            layer_norm_eps: (self.config.norm == Norm::LayerNorm).then_some(self.config.epsilon),
             rope_theta: self.config.theta,
-            torch_dtype: data_layout_name(self.config.dt).to_string(),
+            sliding_window: self.config.sliding_window.map(|w| w as _),
+            attention_scale: self.config.attention_scale,
+            tie_word_embeddings: Some(self.is_tied()),
+            torch_dtype: data_layout_name(self.config.dt)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        InvalidInput,
+                        format!("unsupported data type {:?}", self.config.dt),
+                    )
+                })?
+                .to_string(),
         })?;
-        fs::write(dir.join("config.json"), config)?;
+        fs::write(dir.join("config.json"), config)
+    }
 
-        let mut offset = 0usize;
-        let mut header = SafeTensorsHeader {
-            tensors: HashMap::new(),
-            metadata: SafeTensorsHeaderMetadata {
-                format: "rs".into(),
-            },
-        };
+    /// 按保存顺序列出所有张量，形状与文件中的一致。
+    fn named_tensors(&self) -> Vec<(String, Tensor<Weight>)> {
+        let mut ans = vec![(
+            "model.embed_tokens.weight".into(),
+            self.embed_tokens.clone(),
+        )];
+        for (i, l) in self.layers.iter().enumerate() {
+            #[rustfmt::skip]
+            let iter = [
+                ("input_layernorm"         , l.att_layernorm.clone()),
+                ("self_attn.qkv_proj"      , l.att_qkv      .clone().transpose(&[1, 0])),
+                ("self_attn.o_proj"        , l.att_o        .clone().transpose(&[1, 0])),
+                ("post_attention_layernorm", l.mlp_layernorm.clone()),
+                ("mlp.gate_up_proj"        , l.mlp_gate_up  .clone().transpose(&[1, 0])),
+                ("mlp.down_proj"           , l.mlp_down     .clone().transpose(&[1, 0])),
+            ];
+            ans.extend(iter.map(|(name, t)| (format!("model.layers.{i}.{name}.weight"), t)));
+            let biases = [
+                ("input_layernorm", &l.att_layernorm_bias),
+                ("post_attention_layernorm", &l.mlp_layernorm_bias),
+            ];
+            ans.extend(biases.into_iter().filter_map(|(name, t)| {
+                Some((format!("model.layers.{i}.{name}.bias"), t.clone()?))
+            }));
+            let head_norms = [
+                ("self_attn.q_norm", &l.att_q_norm),
+                ("self_attn.k_norm", &l.att_k_norm),
+            ];
+            ans.extend(head_norms.into_iter().filter_map(|(name, t)| {
+                Some((format!("model.layers.{i}.{name}.weight"), t.clone()?))
+            }));
+        }
+        ans.push(("model.norm.weight".into(), self.lm_layernorm.clone()));
+        if let Some(bias) = &self.lm_layernorm_bias {
+            ans.push(("model.norm.bias".into(), bias.clone()));
+        }
+        if !self.is_tied() {
+            ans.push((
+                "lm_head.weight".into(),
+                self.lm_head.clone().transpose(&[1, 0]),
+            ));
+        }
+        ans
+    }
+}
+
+/// 模型分片的方式。
+#[derive(Clone, Copy, Debug)]
+pub enum Sharding {
+    /// 分成指定数量的分片。
+    Count(usize),
+    /// 每个分片不超过指定字节数。
+    MaxBytes(usize),
+}
+
+/// 按字节数均衡地把张量分配到 `n` 个分片，分片内保持原有顺序。
+fn assign_shards(sizes: &[usize], n: usize) -> Vec<Vec<usize>> {
+    let mut order = (0..sizes.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| Reverse(sizes[i]));
 
-        let mut t = |tensor: &Tensor<Weight>| TensorInfo {
+    let mut shards = vec![Vec::new(); n];
+    let mut bytes = vec![0; n];
+    for i in order {
+        let (j, _) = bytes.iter().enumerate().min_by_key(|&(_, &b)| b).unwrap();
+        shards[j].push(i);
+        bytes[j] += sizes[i];
+    }
+    for shard in &mut shards {
+        shard.sort_unstable();
+    }
+    shards
+}
+
+#[inline]
+fn shard_bytes(shard: &[usize], sizes: &[usize]) -> usize {
+    shard.iter().map(|&i| sizes[i]).sum()
+}
+
+fn write_safetensors(
+    path: impl AsRef<Path>,
+    tensors: &[(String, Tensor<Weight>)],
+    progress: &mut impl FnMut(usize, usize),
+) -> io::Result<()> {
+    let mut offset = 0usize;
+    let mut header = SafeTensorsHeader {
+        tensors: HashMap::new(),
+        metadata: SafeTensorsHeaderMetadata {
+            format: "rs".into(),
+            extra: HashMap::new(),
+        },
+    };
+    for (name, tensor) in tensors {
+        let info = TensorInfo {
             dtype: convert(tensor.data_layout()),
             shape: tensor.shape().iter().map(|&d| d as _).collect(),
             data_offsets: {
@@ -49,64 +252,35 @@ impl Storage {
                 (start, offset)
             },
         };
+        header.tensors.insert(name.clone(), info);
+    }
 
-        header
-            .tensors
-            .insert("model.embed_tokens.weight".into(), t(&self.embed_tokens));
-        for (i, l) in self.layers.iter().enumerate() {
-            #[rustfmt::skip]
-            let iter = [
-                ("input_layernorm"         , &l.att_layernorm),
-                ("self_attn.qkv_proj"      , &l.att_qkv    .clone().transpose(&[1, 0])),
-                ("self_attn.o_proj"        , &l.att_o      .clone().transpose(&[1, 0])),
-                ("post_attention_layernorm", &l.mlp_layernorm),
-                ("mlp.gate_up_proj"        , &l.mlp_gate_up.clone().transpose(&[1, 0])),
-                ("mlp.down_proj"           , &l.mlp_down   .clone().transpose(&[1, 0])),
-            ];
-            header.tensors.extend(
-                iter.map(|(name, tensor)| (format!("model.layers.{i}.{name}.weight"), t(tensor))),
-            );
-        }
-        header.tensors.extend([
-            ("model.norm.weight".into(), t(&self.lm_layernorm)),
-            (
-                "lm_head.weight".into(),
-                t(&self.lm_head.clone().transpose(&[1, 0])),
-            ),
-        ]);
-
-        let header = {
-            let str = serde_json::to_string(&header)?;
-            let len = str.len();
-            const ALIGN: usize = std::mem::size_of::<usize>();
-            let aligned = (len + ALIGN - 1) & !(ALIGN - 1);
-
-            let mut buffer = Vec::with_capacity(aligned);
-            let mut write = BufWriter::new(&mut buffer);
-            write.write_all(&(aligned as u64).to_le_bytes())?;
-            write.write_all(str.as_bytes())?;
-            for _ in len..aligned {
-                write.write_all(&[32])?;
-            }
-            drop(write);
-            buffer
-        };
+    let header = {
+        let str = serde_json::to_string(&header)?;
+        let len = str.len();
+        const ALIGN: usize = std::mem::size_of::<usize>();
+        let aligned = (len + ALIGN - 1) & !(ALIGN - 1);
 
-        let mut file = fs::File::create(dir.join("model.safetensors"))?;
-        file.write_all(&header)?;
-        file.write_all(self.embed_tokens.physical())?;
-        for l in self.layers.iter() {
-            file.write_all(l.att_layernorm.physical())?;
-            file.write_all(l.att_qkv.physical())?;
-            file.write_all(l.att_o.physical())?;
-            file.write_all(l.mlp_layernorm.physical())?;
-            file.write_all(l.mlp_gate_up.physical())?;
-            file.write_all(l.mlp_down.physical())?;
+        let mut buffer = Vec::with_capacity(aligned);
+        let mut write = BufWriter::new(&mut buffer);
+        write.write_all(&(aligned as u64).to_le_bytes())?;
+        write.write_all(str.as_bytes())?;
+        for _ in len..aligned {
+            write.write_all(&[32])?;
         }
-        file.write_all(self.lm_layernorm.physical())?;
-        file.write_all(self.lm_head.physical())?;
-        Ok(())
+        drop(write);
+        buffer
+    };
+
+    let mut file = fs::File::create(path)?;
+    file.write_all(&header)?;
+    let mut bytes = 0;
+    for (i, (_, tensor)) in tensors.iter().enumerate() {
+        file.write_all(tensor.physical())?;
+        bytes += tensor.bytes_size();
+        progress(i + 1, bytes);
     }
+    Ok(())
 }
 
 fn convert(dtype: DigitLayout) -> Dtype {
@@ -128,3 +302,40 @@ fn convert(dtype: DigitLayout) -> Dtype {
         _ => todo!(),
     }
 }
+
+#[test]
+fn test_save_sharded() {
+    use crate::{test_storage, TestDir};
+    use common::Blob;
+    use digit_layout::types::F16;
+    use tensor::udim;
+
+    let (voc, d, nh, nkvh, dh, di) = (16, 8, 2, 1, 4, 12);
+    let mut seed = 0u8;
+    let weight = |shape: &[udim]| {
+        let mut t = Tensor::alloc(F16, shape, Blob::new);
+        for b in t.physical_mut().iter_mut() {
+            seed = seed.wrapping_add(1);
+            *b = seed;
+        }
+        t.map_physical(Weight::from)
+    };
+    let storage = test_storage(F16, [voc, 2, nh, nkvh, d, dh, di], weight);
+
+    let dir = TestDir::new("llama_test_save_sharded");
+    storage.save_sharded(&dir, Sharding::Count(3)).unwrap();
+    assert!(dir.join("model.safetensors.index.json").is_file());
+    assert!(dir.join("model-00003-of-00003.safetensors").is_file());
+
+    let loaded = Storage::load_safetensors(&dir).unwrap();
+    for ((name, a), (_, b)) in storage.named_tensors().iter().zip(loaded.named_tensors()) {
+        assert_eq!(a.shape(), b.shape(), "{name}");
+        assert_eq!(**a.physical(), **b.physical(), "{name}");
+    }
+
+    // 单个张量不能跨越分片
+    let largest = (voc * d) as usize * 2;
+    assert!(storage
+        .save_sharded(&dir, Sharding::MaxBytes(largest - 1))
+        .is_err());
+}
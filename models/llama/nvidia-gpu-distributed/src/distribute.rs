@@ -113,7 +113,8 @@ impl<'a> Distributer<'a> {
             );
         }
         let shape_gate_up = &[d, (di + di) / n];
-        let (gate, up) = split!(layer.mlp_gate_up; [1]: di, di);
+        let mut gate_up = layer.mlp_gate_up.chunk(1, 2);
+        let (gate, up) = (gate_up.pop_front().unwrap(), gate_up.pop_front().unwrap());
         // mlp gate
         {
             let w = di / n;
@@ -6,22 +6,22 @@ mod parameters;
 #[macro_use]
 extern crate log;
 
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
-use common::{upos, utok, FileLoadError};
+use causal_lm::{CausalLM, DecodingMeta, ForwardError, Model, QueryContext, SampleMeta};
+use common::{f16, upos, utok, FileLoadError};
 use common_nv::{
     cuda::{
-        AsRaw, Context, ContextResource, ContextSpore, DevByte, DevMem, DevMemSpore, Device,
-        HostMemSpore, Stream, StreamSpore,
+        memcpy_d2h, AsRaw, Context, ContextResource, ContextSpore, DevByte, DevMem, DevMemSpore,
+        Device, HostMemSpore, Stream, StreamSpore,
     },
     nccl::{CommunicatorGroup, ReduceType},
     slice, split, udim, KernelsA, KernelsB, LocalSplitable, NvidiaKernels, Tensor,
 };
 use itertools::izip;
-use llama::InferenceConfig;
+use llama::{InferenceConfig, Norm};
 use parameters::{Layer, ParameterMatrix};
 use std::{
-    iter::{repeat, zip},
-    mem::{take, ManuallyDrop},
+    iter::zip,
+    mem::{size_of, take, ManuallyDrop},
     path::Path,
     slice::{from_raw_parts, from_raw_parts_mut},
     sync::Arc,
@@ -53,6 +53,35 @@ impl Model for Transformer {
         let time = Instant::now();
         let host = llama::Storage::load_safetensors(model_dir)?;
         info!("load host: {:?}", time.elapsed());
+        if host.config.dh * host.config.nh != host.config.d {
+            return Err(FileLoadError::Unsupported(
+                "explicit head_dim is not supported by distributed inference".into(),
+            ));
+        }
+        if host
+            .layers
+            .iter()
+            .any(|l| l.att_q_norm.is_some() || l.att_k_norm.is_some())
+        {
+            return Err(FileLoadError::Unsupported(
+                "QK norm is not supported by distributed inference".into(),
+            ));
+        }
+        if host.config.norm == Norm::LayerNorm {
+            return Err(FileLoadError::Unsupported(
+                "layer norm is not supported by distributed inference".into(),
+            ));
+        }
+        if host.is_q4() {
+            return Err(FileLoadError::Unsupported(
+                "4-bit quantized weights are not supported by distributed inference".into(),
+            ));
+        }
+        if host.config.sliding_window.is_some() {
+            return Err(FileLoadError::Unsupported(
+                "sliding window attention is not supported by distributed inference".into(),
+            ));
+        }
 
         let kernels = NvidiaKernels::new(&meta, host.config.d as _, host.config.voc as _);
 
@@ -201,11 +230,20 @@ impl CausalLM for Transformer {
         &self,
         queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         mut token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+    ) -> Result<Tensor<Self::Storage>, ForwardError>
     where
         Self: 'a,
     {
         let queries = queries.into_iter().collect::<Vec<_>>();
+        // 注意力长度不能越过 K-V 缓存的边界
+        for q in &queries {
+            q.check_cache()?;
+            if q.mask.is_some() {
+                return Err(ForwardError::Unsupported(
+                    "custom attention mask is not supported by distributed inference",
+                ));
+            }
+        }
         let mut nt = 0;
         let mut max_seq_len = 0;
         let mut max_att_len = 0;
@@ -286,6 +324,8 @@ impl CausalLM for Transformer {
                                 .map(|(cache, range)| QueryContext {
                                     cache: cache.as_mut(),
                                     range: range.clone(),
+                                    adapter: None,
+                                    mask: None,
                                 })
                                 .collect::<Vec<_>>();
 
@@ -348,7 +388,7 @@ impl CausalLM for Transformer {
                 .map(|t| t.join().unwrap())
                 .collect::<Vec<_>>();
         });
-        token_embedded
+        Ok(token_embedded)
     }
 
     fn decode(
@@ -419,25 +459,50 @@ impl CausalLM for Transformer {
         args: impl IntoIterator<Item = SampleMeta>,
         logits: Tensor<Self::Storage>,
     ) -> Vec<utok> {
+        let voc = self.config.voc as usize;
+        let args = args.into_iter().collect::<Vec<_>>();
         let workspace_ptr = unsafe { self.sample_workspace.as_raw() };
         let workspace_len = self.sample_workspace.len();
-        let Cache { contexts, mem } = logits.physical();
+        let mut logits = logits;
+        let Cache { contexts, mem } = logits.physical_mut();
         contexts[0].apply(|ctx| {
             let workspace =
                 unsafe { from_raw_parts_mut(workspace_ptr as *mut DevByte, workspace_len) };
-            self.kernels.sample(
-                self.config.voc as _,
-                args.into_iter()
-                    .flat_map(|meta| repeat(meta.args).take(meta.num_decode)),
-                mem[0].sprout_ref(ctx),
+            let stream = self.streams[0].sprout_ref(ctx);
+            let mem = &mut **mem[0].sprout_mut(ctx);
+            // 需要处理的行拷贝到主机上处理后写回，再与其他行一起交给采样算子
+            let host = if args.iter().any(SampleMeta::needs_process) {
+                let mut host = vec![f16::ZERO; mem.len() / size_of::<f16>()];
+                stream.synchronize();
+                memcpy_d2h(&mut host, mem);
+                causal_lm::process_rows(&args, &mut host, voc);
+                stream.memcpy_h2d(mem, &host);
+                Some(host)
+            } else {
+                None
+            };
+            let tokens = self.kernels.sample(
+                voc,
+                causal_lm::sample_args(&args, voc),
+                mem,
                 workspace,
-                self.streams[0].sprout_ref(ctx),
-            )
+                stream,
+            );
+            if let Some(host) = host {
+                causal_lm::observe_rows(&args, &host, voc, &tokens);
+            }
+            tokens
         })
     }
 }
 
 impl Transformer {
+    /// 模型的结构参数，与切分前的完整模型一致。
+    #[inline]
+    pub fn config(&self) -> &InferenceConfig {
+        &self.config
+    }
+
     fn self_att(
         &self,
         kernels: &NvidiaKernels,
@@ -462,7 +527,10 @@ impl Transformer {
         let dkv = nkvh * dh;
         let di = self.config.di;
         let head_group = nh / nkvh;
-        let head_div = (dh as f32).sqrt().recip();
+        let head_div = self
+            .config
+            .attention_scale
+            .unwrap_or_else(|| (dh as f32).sqrt().recip());
         let theta = self.config.theta;
         let epsilon = self.config.epsilon;
 
@@ -5,8 +5,8 @@ mod resource;
 #[macro_use]
 extern crate log;
 
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
-use common::{upos, utok, Blob, FileLoadError};
+use causal_lm::{CausalLM, DecodingMeta, ForwardError, Model, QueryContext, SampleMeta};
+use common::{f16, upos, utok, Blob, FileLoadError};
 use common_nv::{
     cuda::{memcpy_d2h, AsRaw},
     slice, udim, Gpu, Kernels, KernelsA, KernelsB, NvidiaKernels, Tensor,
@@ -15,13 +15,12 @@ use cuda::{
     ContextResource, ContextSpore, DevByte, DevMem, DevMemSpore, Device, EventSpore, HostMemSpore,
     Stream, StreamSpore,
 };
-use llama::{ComputeConst, InferenceConfig, LayerStorage, SliceOn, Weight};
+use llama::{ComputeConst, InferenceConfig, LayerStorage, Norm, SliceOn, Weight};
 use resource::Resource;
 use std::{
     cell::RefCell,
     collections::VecDeque,
-    iter::repeat,
-    mem::{take, ManuallyDrop},
+    mem::{size_of, take, ManuallyDrop},
     ops::Deref,
     path::Path,
     rc::Rc,
@@ -81,6 +80,30 @@ impl Model for Transformer {
         let time = Instant::now();
         let host = llama::Storage::load_safetensors(model_dir)?;
         info!("load host: {:?}", time.elapsed());
+        if host
+            .layers
+            .iter()
+            .any(|l| l.att_q_norm.is_some() || l.att_k_norm.is_some())
+        {
+            return Err(FileLoadError::Unsupported(
+                "QK norm is not supported on this device".into(),
+            ));
+        }
+        if host.config.norm == Norm::LayerNorm {
+            return Err(FileLoadError::Unsupported(
+                "layer norm is not supported on this device".into(),
+            ));
+        }
+        if host.is_q4() {
+            return Err(FileLoadError::Unsupported(
+                "4-bit quantized weights are not supported on this device".into(),
+            ));
+        }
+        if host.config.sliding_window.is_some() {
+            return Err(FileLoadError::Unsupported(
+                "sliding window attention is not supported on this device".into(),
+            ));
+        }
         let load_layers = (load_layers as udim).min(host.config.nlayers);
 
         let resource = Arc::new(Resource::new(&device));
@@ -143,6 +166,12 @@ impl Model for Transformer {
 }
 
 impl Transformer {
+    /// 模型的结构参数。
+    #[inline]
+    pub fn config(&self) -> &InferenceConfig {
+        &self.0.config
+    }
+
     #[inline]
     fn cache(&self, len: usize) -> Cache {
         Cache::new(&self.0.resource, len)
@@ -215,7 +244,7 @@ impl CausalLM for Transformer {
         &self,
         queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+    ) -> Result<Tensor<Self::Storage>, ForwardError>
     where
         Self: 'a,
     {
@@ -225,9 +254,13 @@ impl CausalLM for Transformer {
             let stream = ComputeStream {
                 nh: self.0.config.nh,
                 nkvh: self.0.config.nkvh,
+                dh: self.0.config.dh,
                 di: self.0.config.di,
                 epsilon: self.0.config.epsilon,
                 theta: self.0.config.theta,
+                sliding_window: self.0.config.sliding_window,
+                norm: self.0.config.norm,
+                attention_scale: self.0.config.attention_scale(),
                 kernels: &self.0.kernels,
                 compute,
                 transfer,
@@ -295,19 +328,37 @@ impl CausalLM for Transformer {
         args: impl IntoIterator<Item = SampleMeta>,
         logits: Tensor<Self::Storage>,
     ) -> Vec<utok> {
+        let voc = self.0.config.voc as usize;
+        let args = args.into_iter().collect::<Vec<_>>();
         let workspace_ptr = unsafe { self.0.sample_workspace.as_raw() };
         let workspace_len = self.0.sample_workspace.len();
         self.0.resource.apply(|compute| {
             let workspace =
                 unsafe { from_raw_parts_mut(workspace_ptr as *mut DevByte, workspace_len) };
-            self.0.kernels.sample(
-                self.0.config.voc as _,
-                args.into_iter()
-                    .flat_map(|meta| repeat(meta.args).take(meta.num_decode)),
-                logits.take_physical().mem.sprout_ref(compute.ctx()),
+            let mut logits = logits.take_physical();
+            let mem = &mut **logits.mem.sprout_mut(compute.ctx());
+            // 需要处理的行拷贝到主机上处理后写回，再与其他行一起交给采样算子
+            let host = if args.iter().any(SampleMeta::needs_process) {
+                let mut host = vec![f16::ZERO; mem.len() / size_of::<f16>()];
+                compute.synchronize();
+                memcpy_d2h(&mut host, mem);
+                causal_lm::process_rows(&args, &mut host, voc);
+                compute.memcpy_h2d(mem, &host);
+                Some(host)
+            } else {
+                None
+            };
+            let tokens = self.0.kernels.sample(
+                voc,
+                causal_lm::sample_args(&args, voc),
+                mem,
                 workspace,
                 compute,
-            )
+            );
+            if let Some(host) = host {
+                causal_lm::observe_rows(&args, &host, voc, &tokens);
+            }
+            tokens
         })
     }
 }
@@ -358,9 +409,13 @@ impl Drop for Transformer {
 struct ComputeStream<'a> {
     nh: udim,
     nkvh: udim,
+    dh: udim,
     di: udim,
     epsilon: f32,
     theta: f32,
+    sliding_window: Option<udim>,
+    norm: Norm,
+    attention_scale: f32,
     kernels: &'a NvidiaKernels,
     compute: &'a Stream<'a>,
     transfer: &'a Stream<'a>,
@@ -413,9 +468,13 @@ impl<'a> llama::ComputeStream for ComputeStream<'a> {
         ComputeConst {
             nh: self.nh,
             nkvh: self.nkvh,
+            dh: self.dh,
             di: self.di,
             epsilon: self.epsilon,
             theta: self.theta,
+            sliding_window: self.sliding_window,
+            norm: self.norm,
+            attention_scale: self.attention_scale,
         }
     }
 
@@ -13,6 +13,8 @@ pub struct ConfigJson {
     pub intermediate_size: usize,
     pub max_position_embeddings: usize,
     pub num_attention_heads: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub head_dim: Option<usize>,
     pub num_hidden_layers: usize,
     pub num_key_value_heads: usize,
     pub vocab_size: usize,
@@ -20,6 +22,8 @@ pub struct ConfigJson {
     pub rms_norm_eps: f32,
     #[serde(default = "default_rope_theta")]
     pub rope_theta: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sliding_window: Option<usize>,
     pub torch_dtype: String,
     pub num_local_experts: usize,
     pub num_experts_per_tok: usize,
@@ -32,12 +36,21 @@ impl ConfigJson {
         serde_json::from_str(&content).map_err(FileLoadError::Json)
     }
 
-    pub fn data_layout(&self) -> DigitLayout {
+    /// 注意力头维度，未显式指定时为 `hidden_size / num_attention_heads`。
+    #[inline]
+    pub fn head_dim(&self) -> usize {
+        self.head_dim
+            .unwrap_or(self.hidden_size / self.num_attention_heads)
+    }
+
+    pub fn data_layout(&self) -> Result<DigitLayout, FileLoadError> {
         match self.torch_dtype.as_str() {
-            "float16" => F16,
-            "float32" => F32,
-            "bfloat16" => BF16,
-            _ => todo!(),
+            "float16" => Ok(F16),
+            "float32" => Ok(F32),
+            "bfloat16" => Ok(BF16),
+            dtype => Err(FileLoadError::UnsupportedDtype(format!(
+                "torch_dtype \"{dtype}\" is not supported, expected one of: float16, float32, bfloat16"
+            ))),
         }
     }
 }
@@ -51,3 +64,32 @@ const fn default_rms_norm_eps() -> f32 {
 const fn default_rope_theta() -> f32 {
     1e4
 }
+
+#[test]
+fn test_data_layout() {
+    let config = |dtype: &str| -> ConfigJson {
+        serde_json::from_str(&format!(
+            r#"{{
+                "bos_token_id": 1,
+                "eos_token_id": 2,
+                "hidden_size": 64,
+                "intermediate_size": 128,
+                "max_position_embeddings": 256,
+                "num_attention_heads": 4,
+                "num_hidden_layers": 2,
+                "num_key_value_heads": 2,
+                "vocab_size": 32,
+                "torch_dtype": "{dtype}",
+                "num_local_experts": 4,
+                "num_experts_per_tok": 2
+            }}"#
+        ))
+        .unwrap()
+    };
+
+    assert_eq!(config("bfloat16").data_layout().unwrap(), BF16);
+    match config("float64").data_layout() {
+        Err(FileLoadError::UnsupportedDtype(msg)) => assert!(msg.contains("float64")),
+        _ => panic!(),
+    }
+}
@@ -22,9 +22,9 @@ impl MixtralParams {
         let nh = config.num_attention_heads as udim;
         let nkvh = config.num_key_value_heads as udim;
         let d = config.hidden_size as udim;
-        let dkv = d * nkvh / nh;
-        let sq = &[nh, 2, d / nh / 2, d];
-        let skv = &[nkvh, 2, dkv / nkvh / 2, d];
+        let dh = config.head_dim() as udim;
+        let sq = &[nh, 2, dh / 2, d];
+        let skv = &[nkvh, 2, dh / 2, d];
         let perm = &[0, 2, 1, 3];
         for name in tensor_names {
             if name.contains("q_proj") {
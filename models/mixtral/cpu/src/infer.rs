@@ -1,11 +1,11 @@
 use super::MixtralCPU;
-use causal_lm::{CausalLM, DecodingMeta, QueryContext, SampleMeta};
+use causal_lm::{CausalLM, DecodingMeta, ForwardError, QueryContext, SampleMeta};
 use common::{f16, upos, utok, Blob};
 use common_cpu::{KernelsA, KernelsB, ThisThread};
 use digit_layout::{types::U32, DigitLayout};
 use itertools::izip;
-use std::{iter::repeat, slice::from_raw_parts};
-use tensor::{reslice, reslice_mut, slice, split, udim, LocalSplitable, Tensor};
+use std::slice::from_raw_parts;
+use tensor::{reslice, reslice_mut, slice, split, udim, LocalSplitable, Splitable, Tensor};
 
 impl CausalLM for MixtralCPU {
     type Storage = Blob;
@@ -28,9 +28,8 @@ impl CausalLM for MixtralCPU {
         let nlayers = self.nlayers;
         let nkvh = self.nkvh;
         let max_seq_len = self.max_seq_len;
-        let d = self.d;
-        let nh = self.nh;
-        Tensor::alloc(dt, &[nlayers, 2, nkvh, max_seq_len, d / nh], Blob::new)
+        let dh = self.dh;
+        Tensor::alloc(dt, &[nlayers, 2, nkvh, max_seq_len, dh], Blob::new)
     }
 
     fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage> {
@@ -72,11 +71,20 @@ impl CausalLM for MixtralCPU {
         &self,
         queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+    ) -> Result<Tensor<Self::Storage>, ForwardError>
     where
         Self: 'a,
     {
         let mut queries = queries.into_iter().collect::<Vec<_>>();
+        // 注意力长度不能越过 K-V 缓存的边界
+        for q in &queries {
+            q.check_cache()?;
+            if q.mask.is_some() {
+                return Err(ForwardError::Unsupported(
+                    "custom attention mask is not supported by mixtral",
+                ));
+            }
+        }
         let mut nt = 0;
         let mut max_seq_len = 0;
         let mut max_att_len = 0;
@@ -96,18 +104,22 @@ impl CausalLM for MixtralCPU {
         let d = self.d;
         let nh = self.nh;
         let nkvh = self.nkvh;
-        let dh = d / nh;
+        let dh = self.dh;
+        let dq = nh * dh;
         let dkv = nkvh * dh;
         let di = self.di;
         let head_group = nh / nkvh;
         let head_div = (dh as f32).sqrt().recip();
+        // 注意力输出与归一化结果复用同一块缓存
+        let dx = d.max(dq);
 
-        let reusing = (d + dkv + dkv).max(di + di);
-        let mut state_buf = Tensor::alloc(dt, &[nt, d + reusing], Blob::new);
+        let reusing = (dq + dkv + dkv).max(di + di);
+        let mut state_buf = Tensor::alloc(dt, &[nt, dx + reusing], Blob::new);
         macro_rules! state {
-            () => {
-                split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: d, reusing)
-            };
+            () => {{
+                let (x1, reusing) = split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: dx, reusing);
+                (cols(&x1, d), cols(&x1, dq), reusing)
+            }};
         }
 
         let mut q_buf = Blob::new((nh * max_seq_len * dh) as usize * dt.nbytes());
@@ -120,8 +132,8 @@ impl CausalLM for MixtralCPU {
 
         let mut x = token_embedded;
         for layer in 0..self.nlayers {
-            let (mut x1, qkv) = state!();
-            let mut qkv = qkv.slice(&[slice![=>], slice![=> d + dkv + dkv]]);
+            let (mut x1, o, qkv) = state!();
+            let mut qkv = qkv.slice(&[slice![=>], slice![=> dq + dkv + dkv]]);
 
             let input_layernorm = self.params.input_layernorm(layer);
             self.kernels
@@ -131,11 +143,11 @@ impl CausalLM for MixtralCPU {
             self.kernels
                 .mat_mul(&mut qkv, 0., &x1, &w_qkv, 1., &ThisThread);
 
-            let (q, k, v) = split!(qkv; [1]: d, dkv, dkv);
+            let (q, k, v) = split!(qkv; [1]: dq, dkv, dkv);
             let mut q = q.reshape(&[nt, nh, dh]);
             let mut k = k.reshape(&[nt, nkvh, dh]);
             let v = v.reshape(&[nt, nkvh, dh]);
-            let o = x1.reshape(&[nt, nh, dh]);
+            let o = o.reshape(&[nt, nh, dh]);
 
             self.kernels.rope(&mut q, &pos, self.theta, &ThisThread);
             self.kernels.rope(&mut k, &pos, self.theta, &ThisThread);
@@ -175,7 +187,13 @@ impl CausalLM for MixtralCPU {
                 self.kernels
                     .mat_mul(&mut att, 0., &q_att, &k_att, head_div, &ThisThread);
                 let mut att = att.reshape(shape_att1);
-                self.kernels.softmax(&mut att, &ThisThread);
+                match self
+                    .sliding_window
+                    .and_then(|w| causal_lm::sliding_window_mask(pos, seq_len, att_len, w))
+                {
+                    Some(mask) => self.kernels.softmax_with_mask(&mut att, &mask),
+                    None => self.kernels.softmax(&mut att, &ThisThread),
+                }
                 let mut x2 = q_att;
                 self.kernels.mat_mul(
                     &mut x2,
@@ -189,11 +207,11 @@ impl CausalLM for MixtralCPU {
                 x2.reshape(shape_q0).reform_to(&mut o);
             }
 
-            let (mut x1, gate_up) = state!();
+            let (mut x1, o, gate_up) = state!();
             let gate_up = gate_up.slice(&[slice![=>], slice![=> di + di]]);
 
             let wo = self.params.w_o(layer).transpose(&[1, 0]);
-            self.kernels.mat_mul(&mut x, 1., &x1, &wo, 1., &ThisThread);
+            self.kernels.mat_mul(&mut x, 1., &o, &wo, 1., &ThisThread);
 
             let post_layernorm = self.params.post_attention_layernorm(layer);
             self.kernels
@@ -240,7 +258,7 @@ impl CausalLM for MixtralCPU {
             }
         }
 
-        x
+        Ok(x)
     }
 
     fn decode(
@@ -278,22 +296,24 @@ impl CausalLM for MixtralCPU {
     fn sample(
         &self,
         args: impl IntoIterator<Item = SampleMeta>,
-        logits: Tensor<Self::Storage>,
+        mut logits: Tensor<Self::Storage>,
     ) -> Vec<utok> {
         let &[_, voc] = logits.shape() else { panic!() };
-        let logits: &[f16] = reslice(logits.as_slice());
-        args.into_iter()
-            .flat_map(|meta| repeat(meta.args).take(meta.num_decode))
-            .enumerate()
-            .map(|(i, args)| {
-                self.kernels.sample(
-                    args.temperature,
-                    args.top_p,
-                    args.top_k,
-                    &common_cpu::slice!(logits; voc; [i]),
-                )
-            })
-            .collect()
+        let logits: &mut [f16] = reslice_mut(logits.as_mut_slice());
+        let mut rows = logits.chunks_exact_mut(voc as usize);
+        let mut ans = Vec::new();
+        for meta in args {
+            for row in rows.by_ref().take(meta.num_decode) {
+                meta.process(row);
+                let args = meta.sample_args(voc as _);
+                let token = self
+                    .kernels
+                    .sample(args.temperature, args.top_p, args.top_k, row);
+                meta.observe(row, token);
+                ans.push(token);
+            }
+        }
+        ans
     }
 }
 
@@ -302,6 +322,12 @@ fn tensor(dt: DigitLayout, shape: &[udim]) -> Tensor<Blob> {
     Tensor::alloc(dt, shape, Blob::new)
 }
 
+/// 取张量第 1 维的前 `len` 个元素，与原张量共享存储。
+#[inline]
+fn cols<T: Splitable>(t: &Tensor<T>, len: udim) -> Tensor<T> {
+    t.split(1, &[len]).pop_front().unwrap()
+}
+
 fn topk(logits: &Tensor<Blob>, k: usize, weight: &mut Tensor<Blob>, indices: &mut Tensor<Blob>) {
     let n = logits.shape()[0];
     let dim = logits.shape()[1];
@@ -15,6 +15,7 @@ pub struct MixtralCPU {
     nlayers: udim,
     nh: udim,
     nkvh: udim,
+    dh: udim,
     max_seq_len: udim,
     d: udim,
     di: udim,
@@ -22,6 +23,7 @@ pub struct MixtralCPU {
     k: udim,
     epsilon: f32,
     theta: f32,
+    sliding_window: Option<udim>,
     params: MixtralParams,
 
     kernels: CpuKernels,
@@ -36,15 +38,17 @@ impl Model for MixtralCPU {
         Ok(Self {
             bos_token: config.bos_token_id,
             eos_token: config.eos_token_id,
-            data_type: config.data_layout(),
+            data_type: config.data_layout()?,
             nlayers: config.num_hidden_layers as _,
             nh: config.num_attention_heads as _,
             nkvh: config.num_key_value_heads as _,
+            dh: config.head_dim() as _,
             max_seq_len: config.max_position_embeddings as _,
             d: config.hidden_size as _,
             di: config.intermediate_size as _,
             epsilon: config.rms_norm_eps,
             theta: config.rope_theta,
+            sliding_window: config.sliding_window.map(|w| w as _),
             params: MixtralParams::new(&config, SafeTensors::load_from_dir(model_dir)?),
             ne: config.num_local_experts as _,
             k: config.num_experts_per_tok as _,
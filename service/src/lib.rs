@@ -1,11 +1,15 @@
 #![deny(warnings)]
 
+mod metrics;
+#[cfg(test)]
+mod mock;
 mod session;
 mod session_manager;
 mod tokenizer;
 
 use causal_lm::{CausalLM, SampleArgs};
 use chat_template::ChatTemplate;
+use common::utok;
 use session::{Dispatcher, Generator};
 use std::{
     fmt::{self, Debug},
@@ -13,13 +17,17 @@ use std::{
     path::Path,
     sync::Arc,
 };
-use tokeneer::{Bpe, Lpe, Tokeneer};
-use tokenizer::{BPECommonNormalizer, Normalizer, Tokenize};
 use tokio::task::JoinHandle;
 
 pub use chat_template::Message;
-pub use session::{BusySession, ChatError, Session};
+pub use metrics::Metrics;
+pub use session::{AttentionSink, BusySession, ChatError, FinishReason, Session};
 pub use session_manager::{SessionError, SessionManager};
+pub use tokenizer::{
+    EncodeOptions, FlushPolicy, HfTokenizer, Normalizer, SpecialTokenError, StreamDecoder,
+    Tokenize, TokenizeBatch, Tokenizer, TokenizerLoadError, Truncation, UnicodeForm,
+    UnicodeNormalizer, VocabTxt, VocabTxtError,
+};
 
 /// 对话服务。
 pub struct Service<M: CausalLM> {
@@ -32,8 +40,7 @@ pub struct Service<M: CausalLM> {
 /// 推理线程的生命周期与这个组件绑定。
 struct ServiceComponent<M: CausalLM> {
     handle: Arc<Dispatcher<M>>,
-    tokenizer: Box<dyn Tokenize + Send + Sync>,
-    normalizer: Box<dyn Normalizer + Send + Sync>,
+    tokenizer: Tokenizer,
     template: ChatTemplate,
     bos: String,
     #[allow(unused)]
@@ -55,10 +62,22 @@ where
     M::Error: Debug,
 {
     pub fn load(model_dir: impl AsRef<Path>, meta: M::Meta) -> (Self, JoinHandle<()>) {
-        let handle = Arc::new(Dispatcher::from(M::load(&model_dir, meta).unwrap()));
-        let tokenizer = tokenizer(&model_dir);
-        let normalizer = normalizer(&model_dir);
+        let model = M::load(&model_dir, meta).unwrap();
+        let tokenizer = Tokenizer::load(&model_dir).unwrap();
         let template = template(model_dir);
+        Self::new(model, tokenizer, template)
+    }
+
+    /// 用已经加载的模型、分词器和对话模板启动服务。
+    pub(crate) fn new(
+        model: M,
+        mut tokenizer: Tokenizer,
+        template: ChatTemplate,
+    ) -> (Self, JoinHandle<()>) {
+        let handle = Arc::new(Dispatcher::from(model));
+        // 以模型实际使用的起始符和结束符为准
+        tokenizer.set_bos(handle.model.bos_token());
+        tokenizer.set_eos(handle.model.eos_token());
         (
             Self {
                 component: Arc::new(ServiceComponent {
@@ -66,7 +85,6 @@ where
                     bos: tokenizer.decode(handle.model.bos_token()).into(),
                     eos: tokenizer.decode(handle.model.eos_token()).into(),
                     tokenizer,
-                    normalizer,
                     template,
                 }),
                 default_sample: Default::default(),
@@ -85,12 +103,50 @@ impl<M: CausalLM> Service<M> {
         session
     }
 
-    /// 从对话服务启动一个文本生成器。
+    /// 设置每步推理同时处理的最大序列数，默认不限制。
+    ///
+    /// 超出的序列排队等待，与批次中的序列按到达顺序轮流推理。
+    #[inline]
+    pub fn set_max_batch(&self, max: usize) {
+        self.component.handle.set_max_batch(max);
+    }
+
+    /// 注册一个特殊词，会话和生成器编码时将 `text` 整体映射到 `token`。
+    ///
+    /// 必须在启动会话和生成器之前调用。
+    pub fn add_special_token(&mut self, text: &str, token: utok) -> Result<(), SpecialTokenError> {
+        Arc::get_mut(&mut self.component)
+            .expect("special tokens must be added before launching sessions")
+            .tokenizer
+            .add_special_token(text, token)
+    }
+
+    /// 服务的监控指标。
+    #[inline]
+    pub fn metrics(&self) -> &Metrics {
+        &self.component.handle.metrics
+    }
+
+    /// 从对话服务启动一个文本生成器，提示词超出模型的最大序列长度时返回错误。
     #[inline]
-    pub fn generate(&self, prompt: impl fmt::Display, sample: Option<SampleArgs>) -> Generator<M> {
+    pub fn generate(
+        &self,
+        prompt: impl fmt::Display,
+        sample: Option<SampleArgs>,
+    ) -> Result<Generator<M>, ChatError> {
         let sample = sample.unwrap_or(self.default_sample);
         Generator::new(self.component.clone(), prompt, sample)
     }
+
+    /// 从起始符生成一个词以预热推理，完成至少一步预填充时返回 `true`。
+    pub async fn warmup(&self) -> bool {
+        let steps = self.metrics().prefill_steps();
+        let Ok(mut generator) = self.generate("", None) else {
+            return false;
+        };
+        generator.decode().await;
+        self.metrics().prefill_steps() > steps
+    }
 }
 
 #[test]
@@ -124,7 +180,7 @@ fn test() {
                 role: "user",
                 content: prompt,
             }]);
-            let mut busy = session.chat();
+            let mut busy = session.chat().unwrap();
             while let Some(s) = busy.decode().await {
                 print!("{}", s.color(color));
                 std::io::stdout().flush().unwrap();
@@ -136,7 +192,144 @@ fn test() {
     runtime.shutdown_background();
 }
 
+#[test]
+fn test_continuous_batching() {
+    use std::iter::zip;
+    use tokio::runtime::Builder;
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    println!("model_dir: {}", model_dir.display());
+
+    let runtime = Builder::new_current_thread().build().unwrap();
+    let _rt = runtime.enter();
+
+    let (service, _handle) = Service::<llama_cpu::Transformer>::load(model_dir, ());
+    service.set_max_batch(2);
+
+    const STEPS: usize = 8;
+    let prompts = [
+        "Once upon a time,",
+        "The capital of France is",
+        "1, 2, 3, 4,",
+    ];
+    let sample = Some(SampleArgs::ARG_MAX);
+    let take = |n: usize, generator: &mut Generator<llama_cpu::Transformer>| {
+        let mut text = String::new();
+        for _ in 0..n {
+            match runtime.block_on(generator.decode()) {
+                Some(s) => text.push_str(&s),
+                None => break,
+            }
+        }
+        text
+    };
+
+    // 逐个请求单独推理的结果
+    let expected = prompts
+        .iter()
+        .map(|p| take(STEPS, &mut service.generate(p, sample).unwrap()))
+        .collect::<Vec<_>>();
+
+    // 请求在其他序列推理的过程中陆续加入
+    let mut outputs = vec![String::new(); prompts.len()];
+    let mut generators = Vec::new();
+    for (i, prompt) in prompts.iter().enumerate() {
+        generators.push(service.generate(prompt, sample).unwrap());
+        for (j, generator) in generators.iter_mut().enumerate().take(i) {
+            outputs[j].push_str(&take(2, generator));
+        }
+    }
+    for (i, generator) in generators.iter_mut().enumerate() {
+        let rest = STEPS - (prompts.len() - 1 - i) * 2;
+        outputs[i].push_str(&take(rest, generator));
+    }
+    drop(generators);
+    runtime.shutdown_background();
+
+    for (output, expected) in zip(outputs, expected) {
+        println!("{output:?}");
+        assert_eq!(output, expected);
+    }
+}
+
+#[test]
+fn test_metrics() {
+    use tokio::runtime::Builder;
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    println!("model_dir: {}", model_dir.display());
+
+    let runtime = Builder::new_current_thread().build().unwrap();
+    let _rt = runtime.enter();
+
+    let (service, _handle) = Service::<llama_cpu::Transformer>::load(model_dir, ());
+    let mut generator = service
+        .generate("Once upon a time,", Some(SampleArgs::ARG_MAX))
+        .unwrap();
+    // 每次解码至少收到一个词
+    let mut decoded = 0;
+    while decoded < 8 && runtime.block_on(generator.decode()).is_some() {
+        decoded += 1;
+    }
+
+    let metrics = service.metrics();
+    println!("{metrics}");
+    assert_eq!(metrics.requests(), 1);
+    assert!(metrics.active_sequences() <= 1);
+    assert!(metrics.generated_tokens() >= decoded);
+    assert!(metrics.prefill_steps() >= 1);
+    assert!(metrics.decode_steps() >= decoded.saturating_sub(1));
+    drop(generator);
+    runtime.shutdown_background();
+}
+
+#[test]
+fn test_load_bos_eos() {
+    use tokio::runtime::Builder;
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    println!("model_dir: {}", model_dir.display());
+
+    let runtime = Builder::new_current_thread().build().unwrap();
+    let _rt = runtime.enter();
+
+    let (service, _handle) = Service::<llama_cpu::Transformer>::load(model_dir, ());
+    let ServiceComponent {
+        handle, tokenizer, ..
+    } = &*service.component;
+    let bos = handle.model.bos_token();
+    let eos = handle.model.eos_token();
+    // 加载时设置了模型的起始符和结束符
+    let opts = EncodeOptions {
+        add_bos: true,
+        add_eos: true,
+        ..Default::default()
+    };
+    let tokens = tokenizer.encode_with("Hi", opts);
+    assert_eq!(tokens.first(), Some(&bos));
+    assert_eq!(tokens.last(), Some(&eos));
+    assert!(tokenizer.is_special(bos) && tokenizer.is_special(eos));
+
+    drop(service);
+    runtime.shutdown_background();
+}
+
 fn template(model_dir: impl AsRef<Path>) -> ChatTemplate {
+    // 优先使用模型目录中 tokenizer_config.json 提供的模板
+    if let Some(template) = File::open(model_dir.as_ref().join("tokenizer_config.json"))
+        .ok()
+        .and_then(|f| serde_json::from_reader::<_, serde_json::Value>(f).ok())
+        .and_then(|config| config.get("chat_template")?.as_str().map(String::from))
+    {
+        return ChatTemplate::new(template);
+    }
+
     let template = if model_dir
         .as_ref()
         .display()
@@ -152,33 +345,3 @@ fn template(model_dir: impl AsRef<Path>) -> ChatTemplate {
     };
     ChatTemplate::new(template.into())
 }
-
-fn normalizer(model_dir: impl AsRef<Path>) -> Box<dyn Normalizer + Send + Sync> {
-    if model_dir.as_ref().join("tokenizer.model").is_file() {
-        return Box::new(BPECommonNormalizer {});
-    }
-    if model_dir.as_ref().join("vocabs.txt").is_file() {
-        return Box::new(());
-    }
-    panic!("Tokenizer file not found");
-}
-
-fn tokenizer(model_dir: impl AsRef<Path>) -> Box<dyn Tokenize + Send + Sync> {
-    use std::io::ErrorKind::NotFound;
-
-    let mmap = |name: &str| {
-        File::open(model_dir.as_ref().join(name)).and_then(|f| unsafe { memmap2::Mmap::map(&f) })
-    };
-
-    match mmap("tokenizer.model") {
-        Ok(f) => return Box::new(Tokeneer::new(Bpe::from_tokenizer_model(&f))),
-        Err(e) if e.kind() == NotFound => {}
-        Err(e) => panic!("{e:?}"),
-    }
-    match mmap("vocabs.txt") {
-        Ok(f) => return Box::new(Tokeneer::new(Lpe::from_vocabs_txt(&f))),
-        Err(e) if e.kind() == NotFound => {}
-        Err(e) => panic!("{e:?}"),
-    }
-    panic!("Tokenizer file not found");
-}
@@ -0,0 +1,187 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+    time::Duration,
+};
+
+/// 推理服务的监控指标，以 Prometheus 文本格式输出。
+///
+/// 所有指标都是原子量，推理线程更新时不加锁。
+#[derive(Default, Debug)]
+pub struct Metrics {
+    requests: AtomicU64,
+    generated_tokens: AtomicU64,
+    active_sequences: AtomicU64,
+    prefill: Histogram,
+    decode: Histogram,
+}
+
+impl Metrics {
+    /// 收到的推理请求数。
+    #[inline]
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Relaxed)
+    }
+
+    /// 生成并发送给会话的词数。
+    #[inline]
+    pub fn generated_tokens(&self) -> u64 {
+        self.generated_tokens.load(Relaxed)
+    }
+
+    /// 正在推理的序列数。
+    #[inline]
+    pub fn active_sequences(&self) -> u64 {
+        self.active_sequences.load(Relaxed)
+    }
+
+    /// 包含预填充的推理步数。
+    #[inline]
+    pub fn prefill_steps(&self) -> u64 {
+        self.prefill.count.load(Relaxed)
+    }
+
+    /// 只包含解码的推理步数。
+    #[inline]
+    pub fn decode_steps(&self) -> u64 {
+        self.decode.count.load(Relaxed)
+    }
+
+    #[inline]
+    pub(crate) fn start_sequence(&self) {
+        self.requests.fetch_add(1, Relaxed);
+        self.active_sequences.fetch_add(1, Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn end_sequence(&self) {
+        self.active_sequences.fetch_sub(1, Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn add_tokens(&self, n: u64) {
+        self.generated_tokens.fetch_add(n, Relaxed);
+    }
+
+    /// 记录一步推理的耗时，批次中有任何序列处理多于一个词时计入预填充。
+    #[inline]
+    pub(crate) fn observe_step(&self, prefill: bool, time: Duration) {
+        let histogram = if prefill { &self.prefill } else { &self.decode };
+        histogram.observe(time);
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        counter(
+            f,
+            "infinilm_requests_total",
+            "Number of inference requests.",
+            self.requests(),
+        )?;
+        counter(
+            f,
+            "infinilm_generated_tokens_total",
+            "Number of tokens generated.",
+            self.generated_tokens(),
+        )?;
+        writeln!(
+            f,
+            "# HELP infinilm_active_sequences Number of sequences being inferred."
+        )?;
+        writeln!(f, "# TYPE infinilm_active_sequences gauge")?;
+        writeln!(f, "infinilm_active_sequences {}", self.active_sequences())?;
+        self.prefill.fmt(
+            f,
+            "infinilm_prefill_step_seconds",
+            "Latency of inference steps containing prefill.",
+        )?;
+        self.decode.fmt(
+            f,
+            "infinilm_decode_step_seconds",
+            "Latency of decode-only inference steps.",
+        )
+    }
+}
+
+fn counter(f: &mut fmt::Formatter, name: &str, help: &str, value: u64) -> fmt::Result {
+    writeln!(f, "# HELP {name} {help}")?;
+    writeln!(f, "# TYPE {name} counter")?;
+    writeln!(f, "{name} {value}")
+}
+
+/// 直方图的桶上界，单位为秒。
+const BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5.,
+];
+
+#[derive(Default, Debug)]
+struct Histogram {
+    /// 各桶的计数，不累计，最后一个是超出所有上界的计数。
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, time: Duration) {
+        let secs = time.as_secs_f64();
+        let i = BUCKETS
+            .iter()
+            .position(|&b| secs <= b)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[i].fetch_add(1, Relaxed);
+        self.sum_nanos.fetch_add(time.as_nanos() as _, Relaxed);
+        self.count.fetch_add(1, Relaxed);
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter, name: &str, help: &str) -> fmt::Result {
+        writeln!(f, "# HELP {name} {help}")?;
+        writeln!(f, "# TYPE {name} histogram")?;
+        let mut acc = 0;
+        for (le, n) in BUCKETS.iter().zip(&self.buckets) {
+            acc += n.load(Relaxed);
+            writeln!(f, "{name}_bucket{{le=\"{le}\"}} {acc}")?;
+        }
+        acc += self.buckets[BUCKETS.len()].load(Relaxed);
+        writeln!(f, "{name}_bucket{{le=\"+Inf\"}} {acc}")?;
+        let sum = self.sum_nanos.load(Relaxed) as f64 / 1e9;
+        writeln!(f, "{name}_sum {sum}")?;
+        writeln!(f, "{name}_count {}", self.count.load(Relaxed))
+    }
+}
+
+#[test]
+fn test_metrics() {
+    let metrics = Metrics::default();
+    metrics.start_sequence();
+    metrics.observe_step(true, Duration::from_millis(30));
+    metrics.add_tokens(1);
+    for _ in 0..3 {
+        metrics.observe_step(false, Duration::from_micros(1500));
+        metrics.add_tokens(1);
+    }
+    metrics.end_sequence();
+
+    assert_eq!(metrics.requests(), 1);
+    assert_eq!(metrics.generated_tokens(), 4);
+    assert_eq!(metrics.active_sequences(), 0);
+    assert_eq!((metrics.prefill_steps(), metrics.decode_steps()), (1, 3));
+
+    let text = metrics.to_string();
+    println!("{text}");
+    for line in [
+        "infinilm_requests_total 1",
+        "infinilm_generated_tokens_total 4",
+        "infinilm_active_sequences 0",
+        "infinilm_prefill_step_seconds_bucket{le=\"0.025\"} 0",
+        "infinilm_prefill_step_seconds_bucket{le=\"0.05\"} 1",
+        "infinilm_prefill_step_seconds_count 1",
+        "infinilm_decode_step_seconds_bucket{le=\"0.001\"} 0",
+        "infinilm_decode_step_seconds_bucket{le=\"0.0025\"} 3",
+        "infinilm_decode_step_seconds_bucket{le=\"+Inf\"} 3",
+        "infinilm_decode_step_seconds_sum 0.0045",
+    ] {
+        assert!(text.lines().any(|l| l == line), "missing line: {line}");
+    }
+}
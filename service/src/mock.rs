@@ -0,0 +1,108 @@
+use crate::{tokenizer::mock::Ascii, Service};
+use causal_lm::{CausalLM, DecodingMeta, ForwardError, Model, QueryContext, SampleMeta};
+use chat_template::ChatTemplate;
+use common::{upos, utok, Blob};
+use digit_layout::types::U32;
+use std::path::Path;
+use tensor::{reslice, reslice_mut, Tensor};
+use tokio::runtime::{Builder, Runtime};
+
+/// 词表大小，与 [`Ascii`] 分词器的词一一对应。
+const VOC: utok = 128;
+pub(crate) const BOS: utok = 1;
+pub(crate) const EOS: utok = 2;
+pub(crate) const MAX_SEQ_LEN: upos = 256;
+
+/// 总是生成序号加一的词的模型，到达词表末尾时生成结束符。
+///
+/// 隐藏状态和 logits 都直接保存词的序号，缓存只用于检查序列长度。
+pub(crate) struct Successor;
+
+impl Model for Successor {
+    type Meta = ();
+    type Error = ();
+    fn load(_: impl AsRef<Path>, _: Self::Meta) -> Result<Self, Self::Error> {
+        Ok(Self)
+    }
+}
+
+impl CausalLM for Successor {
+    type Storage = Blob;
+
+    fn max_seq_len(&self) -> upos {
+        MAX_SEQ_LEN
+    }
+    fn bos_token(&self) -> utok {
+        BOS
+    }
+    fn eos_token(&self) -> utok {
+        EOS
+    }
+    fn new_cache(&self) -> Tensor<Self::Storage> {
+        Tensor::alloc(U32, &[1, 2, 1, MAX_SEQ_LEN, 1], Blob::new)
+    }
+    fn duplicate_cache(&self, _: &Tensor<Self::Storage>, _: upos) -> Tensor<Self::Storage> {
+        self.new_cache()
+    }
+    fn evict_cache(
+        &self,
+        _: &Tensor<Self::Storage>,
+        _: upos,
+        _: upos,
+        _: upos,
+    ) -> Option<Tensor<Self::Storage>> {
+        Some(self.new_cache())
+    }
+    fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage> {
+        let tokens = queries.into_iter().collect::<Vec<_>>();
+        let mut x = Tensor::alloc(U32, &[tokens.len() as _, 1], Blob::new);
+        reslice_mut::<u8, utok>(x.physical_mut()).copy_from_slice(&tokens);
+        x
+    }
+    fn forward<'a>(
+        &self,
+        queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
+        token_embedded: Tensor<Self::Storage>,
+    ) -> Result<Tensor<Self::Storage>, ForwardError> {
+        for q in queries {
+            q.check_cache()?;
+        }
+        Ok(token_embedded)
+    }
+    fn decode(
+        &self,
+        decoding: impl IntoIterator<Item = DecodingMeta>,
+        mut hidden_state: Tensor<Self::Storage>,
+    ) -> Tensor<Self::Storage> {
+        let range = DecodingMeta::select(&mut hidden_state, decoding, |dst, src| {
+            dst.copy_from_slice(src)
+        });
+        let x = &reslice::<u8, utok>(hidden_state.physical())[range];
+        let mut logits = Tensor::alloc(U32, &[x.len() as _, 1], Blob::new);
+        reslice_mut::<u8, utok>(logits.physical_mut()).copy_from_slice(x);
+        logits
+    }
+    fn sample(
+        &self,
+        args: impl IntoIterator<Item = SampleMeta>,
+        logits: Tensor<Self::Storage>,
+    ) -> Vec<utok> {
+        let n = args.into_iter().map(|meta| meta.num_decode).sum::<usize>();
+        reslice::<u8, utok>(logits.physical())[..n]
+            .iter()
+            .map(|&t| if t + 1 < VOC { t + 1 } else { EOS })
+            .collect()
+    }
+}
+
+/// 在新建的单线程运行时中启动 [`Successor`] 的服务，对话模板直接连接各消息的内容。
+pub(crate) fn service() -> (Runtime, Service<Successor>) {
+    const TEMPLATE: &str = "{% for message in messages %}{{ message['content'] }}{% endfor %}";
+
+    let runtime = Builder::new_current_thread().build().unwrap();
+    let (service, _handle) = {
+        let _rt = runtime.enter();
+        Service::new(Successor, Ascii.into(), ChatTemplate::new(TEMPLATE.into()))
+    };
+    (runtime, service)
+}
@@ -24,15 +24,19 @@ impl<T> Batcher<T> {
         self.condvar.notify_one();
     }
 
+    /// 按入队顺序取出至多 `max` 个元素，队列为空时阻塞。
     #[inline]
-    pub fn deq(&self) -> Vec<T> {
-        std::mem::take(
-            &mut self
-                .condvar
-                .wait_while(self.queue.lock().unwrap(), |(q, a)| q.is_empty() && *a)
-                .unwrap()
-                .0,
-        )
+    pub fn deq(&self, max: usize) -> Vec<T> {
+        let mut lock = self
+            .condvar
+            .wait_while(self.queue.lock().unwrap(), |(q, a)| q.is_empty() && *a)
+            .unwrap();
+        let queue = &mut lock.0;
+        if queue.len() <= max {
+            std::mem::take(queue)
+        } else {
+            queue.drain(..max).collect()
+        }
     }
 
     #[inline]
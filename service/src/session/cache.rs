@@ -159,6 +159,8 @@ impl<Storage> Cache<Storage> {
         QueryContext {
             range: self.cached_len() as upos..(self.cached_len() + self.to_be_cached_len()) as upos,
             cache: Some(&mut (self.cache)),
+            adapter: None,
+            mask: None,
         }
     }
 
@@ -238,6 +240,39 @@ impl<Storage> Cache<Storage> {
             );
         }
     }
+    /// StreamingLLM 式的注意力汇聚：已缓存的部分超过 `sink + window` 时，
+    /// 由模型淘汰开头 `sink` 个之后、最近 `window` 个之前的缓存位置。
+    ///
+    /// 模型不支持淘汰缓存时返回 `false`，缓存保持不变。
+    pub fn slide(
+        &mut self,
+        t: &impl CausalLM<Storage = Storage>,
+        sink: usize,
+        window: usize,
+    ) -> bool {
+        let len = self.cached_len();
+        let n = len.saturating_sub(sink + window);
+        if n == 0 {
+            return true;
+        }
+        let Some(cache) = t.evict_cache(&self.cache, len as _, sink as _, n as _) else {
+            return false;
+        };
+        self.cache = cache;
+        // 缓存中的第 `sink..sink + n` 个位置对应的词不再缓存
+        let evicted = self
+            .cached
+            .iter()
+            .flat_map(Clone::clone)
+            .skip(sink)
+            .take(n)
+            .collect::<Vec<_>>();
+        for i in evicted {
+            self.cached.remove(i..i + 1);
+        }
+        info!("cache slid, {n} positions evicted");
+        true
+    }
     /// 重置并清空缓存窗口。
     pub fn reset_with(&mut self, tokens: Vec<utok>, pos: usize) {
         self.tokens = tokens;
@@ -291,7 +326,7 @@ impl<Storage> Cache<Storage> {
 
     /// 获取cached 总长度
     #[inline]
-    fn cached_len(&self) -> usize {
+    pub fn cached_len(&self) -> usize {
         self.cached.iter().map(|range| range.len()).sum()
     }
 
@@ -1,18 +1,29 @@
-﻿use super::{batcher::Batcher, cache::Cache, task::Task};
-use crate::ServiceComponent;
-use causal_lm::{CausalLM, DecodingMeta, SampleArgs, SampleMeta};
+﻿use super::{
+    batcher::Batcher,
+    cache::Cache,
+    task::{Task, TaskOptions},
+    AttentionSink, FinishReason,
+};
+use crate::{Metrics, ServiceComponent};
+use causal_lm::{CausalLM, DecodingMeta, SampleMeta, SeqLenError};
 use common::utok;
+use log::warn;
 use std::{
     iter::zip,
     mem::{replace, size_of},
     str,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Arc, Mutex, OnceLock,
+    },
+    time::Instant,
 };
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 
 pub(super) struct TaskHandle<M: CausalLM> {
     receiver: Option<UnboundedReceiver<utok>>,
     cache: Arc<Mutex<Option<Cache<M::Storage>>>>,
+    finish: Arc<OnceLock<FinishReason>>,
     buffer: Utf8Buffer,
 }
 
@@ -20,41 +31,84 @@ impl<M: CausalLM> TaskHandle<M> {
     #[inline]
     pub fn take(&mut self) -> Cache<M::Storage> {
         // 停止响应接收
-        let _ = self.receiver.take();
+        self.cancel();
         // 取走 cache
         self.cache.lock().unwrap().take().unwrap()
     }
+
+    /// 推理任务结束的原因，任务仍在进行时返回 `None`。
+    #[inline]
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.finish.get().copied()
+    }
+
+    /// 不经解码地接收推理任务生成的下一个词。
+    #[cfg(test)]
+    pub async fn recv(&mut self) -> Option<utok> {
+        self.receiver.as_mut()?.recv().await
+    }
+
+    /// 在下一个词的边界停止推理，已生成但尚未接收的词从缓存中移除。
+    pub fn cancel(&mut self) {
+        let Some(mut receiver) = self.receiver.take() else {
+            return;
+        };
+        // 持有缓存锁关闭管道，之后推理任务不能再发送或缓存新词
+        let mut cache = self.cache.lock().unwrap();
+        receiver.close();
+        let _ = self.finish.set(FinishReason::Cancel);
+        let mut unread = 0;
+        while receiver.try_recv().is_ok() {
+            unread += 1;
+        }
+        if let Some(cache) = cache.as_mut().filter(|_| unread > 0) {
+            let end = cache.end();
+            cache.revert(end - unread);
+        }
+    }
 }
 
 impl<M: CausalLM> ServiceComponent<M> {
-    pub(super) fn infer(&self, sample: SampleArgs, mut cache: Cache<M::Storage>) -> TaskHandle<M> {
-        let max = self.handle.model.max_seq_len() as usize;
+    /// 启动推理任务；上下文超出最大序列长度时不入队，交还缓存。
+    pub(super) fn infer(
+        &self,
+        options: TaskOptions,
+        mut cache: Cache<M::Storage>,
+    ) -> Result<TaskHandle<M>, (Cache<M::Storage>, SeqLenError)> {
+        let max_seq_len = self.handle.model.max_seq_len();
+        let max = max_seq_len as usize;
+        if let Some(AttentionSink { sink, window }) = options.attention_sink {
+            cache.slide(&self.handle.model, sink, window);
+        }
         cache.reset_within_start_and_end_range(max / 4, max / 4, max / 4 * 3);
+        if let Err(e) = cache.as_ctx().check_seq_len(max_seq_len) {
+            return Err((cache, e));
+        }
         // 生成推理任务与会话的交互管道
         let cache = Arc::new(Mutex::new(Some(cache)));
         let (sender, receiver) = unbounded_channel();
-        self.handle
-            .batcher
-            .enq(Task::new(cache.clone(), sample, sender));
-        TaskHandle {
+        let finish = Arc::new(OnceLock::new());
+        self.handle.batcher.enq(Task::new(
+            cache.clone(),
+            options,
+            sender,
+            finish.clone(),
+            self.handle.metrics.clone(),
+        ));
+        Ok(TaskHandle {
             receiver: Some(receiver),
             cache,
+            finish,
             buffer: Default::default(),
-        }
+        })
     }
 
     pub(super) async fn decode(&self, x: &mut TaskHandle<M>) -> Option<String> {
         loop {
-            let s = x.receiver.as_mut().unwrap().recv().await.map(|token| {
-                // detokenize and denormalize the token
-                let ServiceComponent {
-                    normalizer,
-                    tokenizer,
-                    ..
-                } = self;
-                normalizer.decode(tokenizer.decode(token))
-            })?;
-            let s = x.buffer.push(s.as_bytes());
+            // 按字节解码，凑成完整的字符后再反规范化
+            let token = x.receiver.as_mut()?.recv().await?;
+            let s = x.buffer.push(self.tokenizer.decode_bytes(token));
+            let s = self.tokenizer.normalizer.decode(&s).into_owned();
             if !s.is_empty() {
                 return Some(s);
             }
@@ -65,6 +119,8 @@ impl<M: CausalLM> ServiceComponent<M> {
 pub(crate) struct Dispatcher<M: CausalLM> {
     pub model: M,
     pub(super) batcher: Batcher<Task<M::Storage>>,
+    pub metrics: Arc<Metrics>,
+    max_batch: AtomicUsize,
 }
 
 impl<M: CausalLM> From<M> for Dispatcher<M> {
@@ -73,11 +129,20 @@ impl<M: CausalLM> From<M> for Dispatcher<M> {
         Self {
             model,
             batcher: Batcher::new(),
+            metrics: Default::default(),
+            max_batch: AtomicUsize::new(usize::MAX),
         }
     }
 }
 
 impl<M: CausalLM> Dispatcher<M> {
+    /// 设置每步推理的最大序列数。
+    #[inline]
+    pub fn set_max_batch(&self, max: usize) {
+        assert!(max > 0);
+        self.max_batch.store(max, Relaxed);
+    }
+
     /// 通过关闭任务队列通知推理线程退出。
     #[inline]
     pub fn stop(&self) {
@@ -91,7 +156,31 @@ where
     M::Storage: Send,
 {
     pub fn run(self: Arc<Self>) {
-        while let Some(tasks) = Some(self.batcher.deq()).filter(|t| !t.is_empty()) {
+        // 完成一步的任务重新排到队尾，等待中的任务因此按到达顺序轮流加入批次
+        while let Some(tasks) =
+            Some(self.batcher.deq(self.max_batch.load(Relaxed))).filter(|t| !t.is_empty())
+        {
+            // 超出最大序列长度或不允许再生成的任务直接丢弃，会话随即结束解码
+            let max_seq_len = self.model.max_seq_len();
+            let tasks = tasks
+                .into_iter()
+                .filter(|t| {
+                    if t.is_full() {
+                        t.finish(FinishReason::Length);
+                        return false;
+                    }
+                    let mut cache = t.lock_cache();
+                    let result = cache
+                        .as_mut()
+                        .map(|c| c.as_ctx().check_seq_len(max_seq_len));
+                    if let Some(Err(e)) = result {
+                        warn!("Task dropped: {e}");
+                        t.finish(FinishReason::Length);
+                        return false;
+                    }
+                    true
+                })
+                .collect::<Vec<_>>();
             // 锁定所有请求的缓存
             let mut caches = tasks.iter().map(Task::lock_cache).collect::<Vec<_>>();
             // 统计每个任务的查询长度
@@ -102,6 +191,8 @@ where
             if num_query.iter().all(|&n| n == 0) {
                 continue;
             }
+            let time = Instant::now();
+            let prefill = num_query.iter().any(|&n| n > 1);
             // 词嵌入
             let queries = caches
                 .iter()
@@ -113,7 +204,18 @@ where
             let queries = caches
                 .iter_mut()
                 .filter_map(|c| c.as_mut().map(Cache::as_ctx).filter(|q| q.seq_len() > 0));
-            let hidden_state = self.model.forward(queries, token_embedded);
+            let hidden_state = match self.model.forward(queries, token_embedded) {
+                Ok(hidden_state) => hidden_state,
+                // 模型拒绝了这一批次，批次中的任务全部结束
+                Err(e) => {
+                    warn!("Batch dropped: {e}");
+                    drop(caches);
+                    for t in tasks {
+                        t.finish(FinishReason::Length);
+                    }
+                    continue;
+                }
+            };
             drop(caches);
             // 采样
             let num_decode = tasks
@@ -130,8 +232,12 @@ where
             let args = zip(&tasks, &num_decode).map(|(t, &num_decode)| SampleMeta {
                 num_decode,
                 args: *t.sample(),
+                mirostat: t.mirostat().cloned(),
+                no_repeat_ngram: t.no_repeat_ngram().cloned(),
+                ..Default::default()
             });
             let tokens = self.model.sample(args, logits);
+            self.metrics.observe_step(prefill, time.elapsed());
             // 为每次推理启动一个任务执行发射
             let self_ = self.clone();
             tokio::task::spawn_blocking(move || {
@@ -143,10 +249,20 @@ where
                     .filter(|(_, n)| *n > 0)
                     .map(|(t, _)| t)
                     .zip(tokens)
-                    .filter(|(_, token)| *token != eos)
                     .for_each(|(mut task, token)| {
-                        if task.push(token, start_size, end_size, max) {
-                            self_.batcher.enq(task);
+                        let stop = task.is_stop(token, eos);
+                        if stop.is_none() || task.include_stop() {
+                            // 先计数再发送，会话收到词时计数已经更新
+                            self_.metrics.add_tokens(1);
+                            // 会话已取消
+                            if !task.push(&self_.model, token, start_size, end_size, max) {
+                                return;
+                            }
+                        }
+                        // 结束符发送并缓存后不再继续推理，结束原因在任务释放前记录
+                        match stop.or(task.is_full().then_some(FinishReason::Length)) {
+                            Some(reason) => task.finish(reason),
+                            None => self_.batcher.enq(task),
                         }
                     });
             });
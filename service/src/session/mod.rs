@@ -6,8 +6,9 @@ mod task;
 
 use crate::ServiceComponent;
 use cache::Cache;
-use causal_lm::{CausalLM, SampleArgs};
+use causal_lm::{CausalLM, MirostatState, SampleArgs, SeqLenError};
 use chat_template::Message;
+use common::{upos, utok};
 use dialog::Dialog;
 use dispatch::TaskHandle;
 use log::info;
@@ -17,6 +18,7 @@ use std::{
     sync::Arc,
     vec,
 };
+use task::TaskOptions;
 
 pub(crate) use dispatch::Dispatcher;
 
@@ -24,31 +26,85 @@ pub(crate) use dispatch::Dispatcher;
 pub struct Session<M: CausalLM> {
     component: Arc<ServiceComponent<M>>,
     pub sample: SampleArgs,
+    /// Mirostat 采样的初始状态，每次对话从这个状态开始，`None` 表示不启用。
+    pub mirostat: Option<MirostatState>,
+    /// 禁止在生成的词中重复的 n 元组长度，0 或 1 表示不启用。
+    pub no_repeat_ngram_size: usize,
+    /// 是否在输出中保留结束符，默认丢弃。
+    pub include_stop: bool,
+    /// 是否在对话的第一个句子前添加起始符。
+    ///
+    /// 对话模板已经渲染了起始符的模型不需要开启。
+    pub add_bos: bool,
+    /// 除模型结束符外，同样结束生成的词，用于对话结束符与文本结束符不同的模型。
+    pub stop_tokens: Vec<utok>,
+    /// 每次对话最多生成的词数，`None` 表示不限制。
+    pub max_tokens: Option<usize>,
+    /// 注意力汇聚的设置，`None` 表示上下文超长时重新预填充窗口。
+    pub attention_sink: Option<AttentionSink>,
 
     dialog: Dialog,
     cache: Option<Cache<M::Storage>>,
 }
 
 /// 对话错误类型。
-///
-/// 目前唯一可能的对话错误是增量对话中句子位置异常。
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-pub struct ChatError;
+pub enum ChatError {
+    /// 增量对话中句子位置异常。
+    DialogPos,
+    /// 推理的上下文超出模型的最大序列长度。
+    SeqLen(SeqLenError),
+}
 
 impl error::Error for ChatError {}
 impl fmt::Display for ChatError {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "chat error")
+        match self {
+            Self::DialogPos => write!(f, "chat error: invalid dialog position"),
+            Self::SeqLen(e) => write!(f, "chat error: {e}"),
+        }
     }
 }
 
+/// StreamingLLM 式的注意力汇聚：缓存超过 `sink + window` 个位置时淘汰中间的部分，
+/// 保留开头的 `sink` 个和最近的 `window` 个位置，避免重新预填充。
+///
+/// 模型不支持淘汰缓存时（见 [`CausalLM::evict_cache`]）退回到重新预填充窗口。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct AttentionSink {
+    /// 始终保留的开头的位置数。
+    pub sink: usize,
+    /// 保留的最近的位置数。
+    pub window: usize,
+}
+
+/// 推理任务结束的原因。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum FinishReason {
+    /// 生成了模型结束符。
+    Eos,
+    /// 生成了会话指定的停止词。
+    Stop,
+    /// 生成的词数达到上限，或上下文超出模型的最大序列长度。
+    Length,
+    /// 会话取消了推理。
+    Cancel,
+}
+
 impl<M: CausalLM> From<Arc<ServiceComponent<M>>> for Session<M> {
     #[inline]
     fn from(component: Arc<ServiceComponent<M>>) -> Self {
         Self {
             component,
             sample: Default::default(),
+            mirostat: None,
+            no_repeat_ngram_size: 0,
+            include_stop: false,
+            add_bos: false,
+            stop_tokens: Vec::new(),
+            max_tokens: None,
+            attention_sink: None,
 
             dialog: Default::default(),
             cache: Default::default(),
@@ -67,6 +123,13 @@ impl<M: CausalLM> Session<M> {
         Self {
             component: self.component.clone(),
             sample: self.sample,
+            mirostat: self.mirostat.clone(),
+            no_repeat_ngram_size: self.no_repeat_ngram_size,
+            include_stop: self.include_stop,
+            add_bos: self.add_bos,
+            stop_tokens: self.stop_tokens.clone(),
+            max_tokens: self.max_tokens,
+            attention_sink: self.attention_sink,
             dialog: self.dialog.clone(),
             cache: self
                 .cache
@@ -93,7 +156,7 @@ impl<M: CausalLM> Session<M> {
                 Ok(())
             }
             Equal => Ok(()),
-            Greater => Err(ChatError),
+            Greater => Err(ChatError::DialogPos),
         }
     }
 
@@ -114,8 +177,10 @@ impl<M: CausalLM> Session<M> {
                     true,
                 )
                 .unwrap();
-            let s = self.component.normalizer.encode(&s);
-            let s = self.component.tokenizer.encode(&s);
+            let mut s = self.component.tokenizer.encode(&s);
+            if self.add_bos && self.dialog.num_sentences() == 0 {
+                s.insert(0, self.component.handle.model.bos_token());
+            }
 
             cache.extend(&s);
             self.dialog.push(s);
@@ -124,13 +189,47 @@ impl<M: CausalLM> Session<M> {
         assert_eq!(cache.end(), self.dialog.num_tokens());
     }
 
+    /// 检查对话的总长度不超过模型的最大序列长度。
+    pub fn check_seq_len(&self) -> Result<(), SeqLenError> {
+        let att_len = self.dialog.num_tokens() as upos;
+        let max_seq_len = self.component.handle.model.max_seq_len();
+        if att_len <= max_seq_len {
+            Ok(())
+        } else {
+            Err(SeqLenError {
+                att_len,
+                max_seq_len,
+            })
+        }
+    }
+
     /// 启动推理任务，返回忙会话。
-    pub fn chat(&mut self) -> BusySession<M> {
+    ///
+    /// 推理的上下文超出模型的最大序列长度时不启动任务，缓存留在会话中。
+    pub fn chat(&mut self) -> Result<BusySession<M>, ChatError> {
         let cache = self.cache.take().unwrap();
-        let handle = self.component.infer(self.sample, cache);
-        BusySession {
-            session: self,
-            handle,
+        match self.component.infer(self.task_options(), cache) {
+            Ok(handle) => Ok(BusySession {
+                session: self,
+                handle,
+            }),
+            Err((cache, e)) => {
+                self.cache = Some(cache);
+                Err(ChatError::SeqLen(e))
+            }
+        }
+    }
+
+    /// 收集会话中属于推理任务的生成选项。
+    fn task_options(&self) -> TaskOptions {
+        TaskOptions {
+            sample: self.sample,
+            mirostat: self.mirostat.clone(),
+            no_repeat_ngram_size: self.no_repeat_ngram_size,
+            include_stop: self.include_stop,
+            stop_tokens: self.stop_tokens.clone(),
+            max_tokens: self.max_tokens,
+            attention_sink: self.attention_sink,
         }
     }
 
@@ -138,7 +237,15 @@ impl<M: CausalLM> Session<M> {
         let end = self.dialog.num_tokens();
         if cache.end() > end {
             // 无论忙会话为何丢弃，只要生成了新句子，就补充一个结束符
-            cache.push(self.component.handle.model.eos_token());
+            let eos = self.component.handle.model.eos_token();
+            if cache
+                .slice_tail(end)
+                .last()
+                .filter(|&&t| t == eos || self.stop_tokens.contains(&t))
+                .is_none()
+            {
+                cache.push(eos);
+            }
             // 只要忙会话收集到任何 token，就生成一个新的句子
             self.dialog.push(cache.slice_tail(end).to_vec());
         }
@@ -160,6 +267,20 @@ impl<M: CausalLM> BusySession<'_, M> {
     pub async fn decode(&mut self) -> Option<String> {
         self.session.component.decode(&mut self.handle).await
     }
+
+    /// 推理任务结束的原因，任务仍在进行时返回 `None`。
+    #[inline]
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.handle.finish_reason()
+    }
+
+    /// 在下一个词的边界停止推理，之后 [decode](Self::decode) 返回 `None`。
+    ///
+    /// 会话中只保留已接收的部分输出。
+    #[inline]
+    pub fn cancel(&mut self) {
+        self.handle.cancel();
+    }
 }
 
 impl<M: CausalLM> Drop for BusySession<'_, M> {
@@ -176,16 +297,28 @@ pub struct Generator<M: CausalLM> {
 }
 
 impl<M: CausalLM> Generator<M> {
+    /// 在下一个词的边界停止生成，之后 [decode](Self::decode) 返回 `None`。
+    #[inline]
+    pub fn cancel(&mut self) {
+        self.handle.cancel();
+    }
+
     pub(crate) fn new(
         component: Arc<ServiceComponent<M>>,
         prompt: impl fmt::Display,
         sample: SampleArgs,
-    ) -> Self {
+    ) -> Result<Self, ChatError> {
         let prompt = format!("{}{}", component.bos, prompt);
-        let prompt = component.normalizer.encode(&prompt);
         let tokens = component.tokenizer.encode(&prompt);
-        let handle = component.infer(sample, Cache::new(&component.handle.model, tokens));
-        Self { handle, component }
+        let cache = Cache::new(&component.handle.model, tokens);
+        let options = TaskOptions {
+            sample,
+            ..Default::default()
+        };
+        let handle = component
+            .infer(options, cache)
+            .map_err(|(_, e)| ChatError::SeqLen(e))?;
+        Ok(Self { handle, component })
     }
 
     /// 接收模型解码产生的文本。
@@ -193,6 +326,12 @@ impl<M: CausalLM> Generator<M> {
     pub async fn decode(&mut self) -> Option<String> {
         self.component.decode(&mut self.handle).await
     }
+
+    /// 生成结束的原因，生成仍在进行时返回 `None`。
+    #[inline]
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.handle.finish_reason()
+    }
 }
 
 impl<M: CausalLM> Drop for Generator<M> {
@@ -201,3 +340,293 @@ impl<M: CausalLM> Drop for Generator<M> {
         let _ = self.handle.take();
     }
 }
+
+/// 从忙会话接收至多 `max` 个词，推理任务提前结束时返回已收到的词。
+#[cfg(test)]
+fn receive<M: CausalLM>(
+    runtime: &tokio::runtime::Runtime,
+    busy: &mut BusySession<M>,
+    max: usize,
+) -> Vec<utok> {
+    runtime.block_on(async {
+        let mut ans = Vec::new();
+        while ans.len() < max {
+            match busy.handle.recv().await {
+                Some(token) => ans.push(token),
+                None => break,
+            }
+        }
+        ans
+    })
+}
+
+#[test]
+fn test_cancel() {
+    use crate::mock::EOS;
+    use std::{thread::sleep, time::Duration};
+
+    let (runtime, service) = crate::mock::service();
+    let mut session = service.launch();
+    session.sample = SampleArgs::ARG_MAX;
+    session.extend(&[Message {
+        role: "user",
+        content: "Tell me a long story.",
+    }]);
+    let before = session.dialog.num_tokens();
+
+    const K: usize = 4;
+    let mut busy = session.chat().unwrap();
+    let received = receive(&runtime, &mut busy, K);
+    assert_eq!(received.len(), K);
+    // 推理任务在此期间继续生成，取消时这些词应被丢弃
+    sleep(Duration::from_millis(200));
+    busy.cancel();
+    assert!(runtime.block_on(busy.decode()).is_none());
+    drop(busy);
+
+    // 会话只保留已接收的词和补充的结束符，缓存归还给会话
+    assert_eq!(session.dialog.num_tokens(), before + K + 1);
+    let cache = session.cache.as_ref().unwrap();
+    assert_eq!(cache.end(), session.dialog.num_tokens());
+    assert_eq!(cache.slice_tail(before), [&received[..], &[EOS]].concat());
+
+    drop(session);
+    runtime.shutdown_background();
+}
+
+#[test]
+fn test_include_stop() {
+    use crate::mock::EOS;
+
+    let (runtime, service) = crate::mock::service();
+
+    const MAX: usize = 256;
+    let mut outputs = Vec::new();
+    for include_stop in [false, true] {
+        let mut session = service.launch();
+        session.sample = SampleArgs::ARG_MAX;
+        session.include_stop = include_stop;
+        session.extend(&[Message {
+            role: "user",
+            content: "Hi",
+        }]);
+        let before = session.dialog.num_tokens();
+
+        let mut busy = session.chat().unwrap();
+        let tokens = receive(&runtime, &mut busy, MAX);
+        assert!(tokens.len() < MAX, "no eos within {MAX} tokens");
+        drop(busy);
+
+        // 无论是否保留，会话中的句子都只以一个结束符结尾
+        let tail = session.cache.as_ref().unwrap().slice_tail(before);
+        assert_eq!(tail.last(), Some(&EOS));
+        assert_ne!(tail.iter().nth_back(1), Some(&EOS));
+        outputs.push(tokens);
+    }
+
+    let [dropped, included] = &outputs[..] else {
+        unreachable!()
+    };
+    assert_ne!(dropped.last(), Some(&EOS));
+    assert_eq!(included.last(), Some(&EOS));
+    assert_eq!(&included[..included.len() - 1], &dropped[..]);
+    runtime.shutdown_background();
+}
+
+#[test]
+fn test_check_seq_len() {
+    use crate::mock::MAX_SEQ_LEN;
+
+    let (runtime, service) = crate::mock::service();
+
+    let mut session = service.launch();
+    session.extend(&[Message {
+        role: "user",
+        content: "Hi",
+    }]);
+    assert_eq!(session.check_seq_len(), Ok(()));
+    let mut busy = session.chat().unwrap();
+    assert!(runtime.block_on(busy.decode()).is_some());
+    drop(busy);
+    assert!(service.generate("Hi", None).is_ok());
+
+    // 每个词至少编码为一个 token，提示词一定超出最大序列长度
+    let prompt = "hello ".repeat(MAX_SEQ_LEN as usize + 1);
+    let mut session = service.launch();
+    session.extend(&[Message {
+        role: "user",
+        content: &prompt,
+    }]);
+    let e = session.check_seq_len().unwrap_err();
+    assert_eq!(e.max_seq_len, MAX_SEQ_LEN);
+    assert!(e.att_len > MAX_SEQ_LEN);
+    // 超出最大序列长度的会话和生成器都不启动推理
+    assert!(matches!(session.chat(), Err(ChatError::SeqLen(x)) if x == e));
+    assert!(session.cache.is_some());
+    assert!(matches!(
+        service.generate(&prompt, None),
+        Err(ChatError::SeqLen(x)) if x.att_len > MAX_SEQ_LEN
+    ));
+
+    drop(session);
+    runtime.shutdown_background();
+}
+
+#[test]
+fn test_stop_tokens() {
+    use crate::mock::{BOS, EOS};
+
+    let (runtime, service) = crate::mock::service();
+
+    const MAX: usize = 64;
+    // 贪心生成，返回收到的词和会话中新句子的词
+    let generate = |stop_tokens: Vec<utok>, include_stop: bool| {
+        let mut session = service.launch();
+        session.sample = SampleArgs::ARG_MAX;
+        session.add_bos = true;
+        session.stop_tokens = stop_tokens;
+        session.include_stop = include_stop;
+        session.extend(&[Message {
+            role: "user",
+            content: "Tell me a story.",
+        }]);
+        let before = session.dialog.num_tokens();
+        assert_eq!(session.cache.as_ref().unwrap().slice_tail(0)[0], BOS);
+
+        let mut busy = session.chat().unwrap();
+        let tokens = receive(&runtime, &mut busy, MAX);
+        drop(busy);
+        let tail = session.cache.as_ref().unwrap().slice_tail(before).to_vec();
+        (tokens, tail)
+    };
+
+    let (reference, _) = generate(vec![], false);
+    // 选一个不是结束符、且之前没有出现过的词作为额外的停止词
+    let i = (1..reference.len())
+        .find(|&i| reference[i] != EOS && !reference[..i].contains(&reference[i]))
+        .expect("reply too short to pick a stop token");
+    let stop = reference[i];
+
+    let (tokens, tail) = generate(vec![stop], false);
+    assert_eq!(tokens, &reference[..i]);
+    // 丢弃停止词时，会话中的句子补充模型结束符
+    assert_eq!(tail, [&reference[..i], &[EOS]].concat());
+
+    let (tokens, tail) = generate(vec![stop], true);
+    assert_eq!(tokens, &reference[..=i]);
+    assert_eq!(tail, &reference[..=i]);
+
+    runtime.shutdown_background();
+}
+
+#[test]
+fn test_finish_reason() {
+    let (runtime, service) = crate::mock::service();
+
+    const MAX: usize = 64;
+    // 贪心生成直到推理任务结束，返回收到的词和结束原因
+    let generate = |stop_tokens: Vec<utok>, max_tokens: usize| {
+        let mut session = service.launch();
+        session.sample = SampleArgs::ARG_MAX;
+        session.stop_tokens = stop_tokens;
+        session.max_tokens = Some(max_tokens);
+        session.extend(&[Message {
+            role: "user",
+            content: "Hi",
+        }]);
+        let mut busy = session.chat().unwrap();
+        assert_eq!(busy.finish_reason(), None);
+        let tokens = receive(&runtime, &mut busy, usize::MAX);
+        (tokens, busy.finish_reason())
+    };
+
+    // 结束符不发送给会话，未达到上限就结束的只能是结束符
+    let (reference, reason) = generate(vec![], MAX);
+    assert!(reference.len() < MAX, "no eos within {MAX} tokens");
+    assert_eq!(reason, Some(FinishReason::Eos));
+
+    // 在结束符之前达到生成词数的上限
+    const LIMIT: usize = 4;
+    assert!(
+        reference.len() > LIMIT,
+        "greedy reply is only {} tokens",
+        reference.len(),
+    );
+    let (tokens, reason) = generate(vec![], LIMIT);
+    assert_eq!(tokens, &reference[..LIMIT]);
+    assert_eq!(reason, Some(FinishReason::Length));
+
+    // 上限为 0 时不生成任何词
+    let (tokens, reason) = generate(vec![], 0);
+    assert_eq!(tokens, vec![]);
+    assert_eq!(reason, Some(FinishReason::Length));
+
+    // 在结束符之前生成停止词
+    let (tokens, reason) = generate(vec![reference[0]], MAX);
+    assert_eq!(tokens, vec![]);
+    assert_eq!(reason, Some(FinishReason::Stop));
+
+    // 会话取消推理
+    let mut session = service.launch();
+    session.extend(&[Message {
+        role: "user",
+        content: "Tell me a long story.",
+    }]);
+    let mut busy = session.chat().unwrap();
+    busy.cancel();
+    assert!(runtime.block_on(busy.decode()).is_none());
+    assert_eq!(busy.finish_reason(), Some(FinishReason::Cancel));
+    drop(busy);
+
+    drop(session);
+    runtime.shutdown_background();
+}
+
+#[test]
+fn test_attention_sink() {
+    let (runtime, service) = crate::mock::service();
+
+    const SINK: usize = 4;
+    const WINDOW: usize = 8;
+    const MAX: usize = 32;
+    let mut session = service.launch();
+    session.sample = SampleArgs::ARG_MAX;
+    session.max_tokens = Some(MAX);
+    session.attention_sink = Some(AttentionSink {
+        sink: SINK,
+        window: WINDOW,
+    });
+    session.extend(&[Message {
+        role: "user",
+        content: "Tell me a long story.",
+    }]);
+    let mut busy = session.chat().unwrap();
+    let tokens = receive(&runtime, &mut busy, usize::MAX);
+    assert_eq!(tokens.len(), MAX);
+    drop(busy);
+
+    // 每步解码后淘汰中间的缓存，缓存归还给会话时不超过汇聚和窗口的大小
+    let cache = session.cache.as_ref().unwrap();
+    assert!(cache.cached_len() <= SINK + WINDOW + 1);
+
+    drop(session);
+    runtime.shutdown_background();
+}
+
+#[test]
+fn test_special_token() {
+    let (runtime, mut service) = crate::mock::service();
+    const TOOL_CALL: utok = 100;
+    service.add_special_token("<tool_call>", TOOL_CALL).unwrap();
+
+    // 会话编码时特殊词整体映射为一个词
+    let mut session = service.launch();
+    session.extend(&[Message {
+        role: "user",
+        content: "<tool_call>",
+    }]);
+    assert_eq!(session.dialog.last_prompt().unwrap(), [TOOL_CALL]);
+
+    drop(session);
+    runtime.shutdown_background();
+}
@@ -1,27 +1,83 @@
-﻿use super::cache::Cache;
-use causal_lm::SampleArgs;
+﻿use super::{cache::Cache, AttentionSink, FinishReason};
+use crate::Metrics;
+use causal_lm::{CausalLM, MirostatState, NgramBlocker, SampleArgs};
 use common::utok;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
 use tokio::sync::mpsc::UnboundedSender;
 
+/// 每个推理任务的生成选项，由会话在启动推理时给出。
+#[derive(Clone, Default)]
+pub(super) struct TaskOptions {
+    pub sample: SampleArgs,
+    /// Mirostat 采样的初始状态，`None` 表示不启用。
+    pub mirostat: Option<MirostatState>,
+    /// 禁止重复的 n 元组长度，0 或 1 表示不启用。
+    pub no_repeat_ngram_size: usize,
+    /// 生成结束符时是否将其发送给会话。
+    pub include_stop: bool,
+    /// 除模型结束符外，同样结束生成的词。
+    pub stop_tokens: Vec<utok>,
+    /// 最多生成的词数。
+    pub max_tokens: Option<usize>,
+    /// 注意力汇聚的设置，`None` 表示缓存超长时重新预填充窗口。
+    pub attention_sink: Option<AttentionSink>,
+}
+
 pub(super) struct Task<Storage> {
     sample: SampleArgs,
+    /// 在任务的各解码步之间延续的 Mirostat 状态。
+    mirostat: Option<Arc<Mutex<MirostatState>>>,
+    /// 任务生成的词的 n 元组历史。
+    no_repeat_ngram: Option<Arc<Mutex<NgramBlocker>>>,
+    /// 生成结束符时是否将其发送给会话。
+    include_stop: bool,
+    /// 除模型结束符外，同样结束生成的词。
+    stop_tokens: Vec<utok>,
+    /// 最多生成的词数和已经生成的词数。
+    max_tokens: Option<usize>,
+    generated: usize,
+    attention_sink: Option<AttentionSink>,
     sender: UnboundedSender<utok>,
+    finish: Arc<OnceLock<FinishReason>>,
 
     cache: Arc<Mutex<Option<Cache<Storage>>>>,
+    metrics: Arc<Metrics>,
 }
 
 impl<Storage> Task<Storage> {
     #[inline]
     pub fn new(
         cache: Arc<Mutex<Option<Cache<Storage>>>>,
-        sample: SampleArgs,
+        options: TaskOptions,
         sender: UnboundedSender<utok>,
+        finish: Arc<OnceLock<FinishReason>>,
+        metrics: Arc<Metrics>,
     ) -> Self {
+        let TaskOptions {
+            sample,
+            mirostat,
+            no_repeat_ngram_size,
+            include_stop,
+            stop_tokens,
+            max_tokens,
+            attention_sink,
+        } = options;
+        metrics.start_sequence();
         Self {
             sample,
+            mirostat: mirostat.map(|state| Arc::new(Mutex::new(state))),
+            no_repeat_ngram: Some(NgramBlocker::new(no_repeat_ngram_size))
+                .filter(NgramBlocker::is_enabled)
+                .map(|blocker| Arc::new(Mutex::new(blocker))),
+            include_stop,
+            stop_tokens,
+            max_tokens,
+            generated: 0,
+            attention_sink,
             sender,
+            finish,
             cache,
+            metrics,
         }
     }
 
@@ -30,6 +86,39 @@ impl<Storage> Task<Storage> {
         &self.sample
     }
     #[inline]
+    pub fn mirostat(&self) -> Option<&Arc<Mutex<MirostatState>>> {
+        self.mirostat.as_ref()
+    }
+    #[inline]
+    pub fn no_repeat_ngram(&self) -> Option<&Arc<Mutex<NgramBlocker>>> {
+        self.no_repeat_ngram.as_ref()
+    }
+    #[inline]
+    pub fn include_stop(&self) -> bool {
+        self.include_stop
+    }
+    /// 判断 `token` 是否结束这个任务的生成。
+    #[inline]
+    pub fn is_stop(&self, token: utok, eos: utok) -> Option<FinishReason> {
+        if token == eos {
+            Some(FinishReason::Eos)
+        } else if self.stop_tokens.contains(&token) {
+            Some(FinishReason::Stop)
+        } else {
+            None
+        }
+    }
+    /// 生成的词数是否已经达到上限。
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.max_tokens.is_some_and(|n| self.generated >= n)
+    }
+    /// 记录任务结束的原因，只有第一次记录有效。
+    #[inline]
+    pub fn finish(&self, reason: FinishReason) {
+        let _ = self.finish.set(reason);
+    }
+    #[inline]
     pub fn is_alive(&self) -> bool {
         !self.sender.is_closed()
     }
@@ -38,11 +127,25 @@ impl<Storage> Task<Storage> {
         self.cache.lock().unwrap()
     }
 
+    /// 发送并缓存新词，会话取消时发送失败，返回 `false`。
     #[inline]
-    pub fn push(&mut self, token: utok, start_size: usize, end_size: usize, max: usize) -> bool {
-        if self.sender.send(token).is_ok() {
-            if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+    pub fn push(
+        &mut self,
+        model: &impl CausalLM<Storage = Storage>,
+        token: utok,
+        start_size: usize,
+        end_size: usize,
+        max: usize,
+    ) -> bool {
+        // 与取消操作互斥，保证发送出去的词都已缓存
+        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+            if self.sender.send(token).is_ok() {
                 cache.push(token);
+                self.generated += 1;
+                // 模型不支持淘汰缓存时退回到重新预填充窗口
+                if let Some(AttentionSink { sink, window }) = self.attention_sink {
+                    cache.slide(model, sink, window);
+                }
                 cache.reset_within_start_and_end_range(start_size, end_size, max);
                 return true;
             }
@@ -50,3 +153,10 @@ impl<Storage> Task<Storage> {
         false
     }
 }
+
+impl<Storage> Drop for Task<Storage> {
+    #[inline]
+    fn drop(&mut self) {
+        self.metrics.end_sequence();
+    }
+}
@@ -0,0 +1,711 @@
+use super::{
+    has_inner_space, piece_str, split_words, BPECommonNormalizer, Normalizer, Tokenize,
+    TokenizerLoadError,
+};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::{de::Error as _, Deserialize};
+use serde_json::Value;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs,
+    ops::Range,
+    path::Path,
+};
+use tokeneer::utok;
+
+/// 从 HuggingFace `tokenizer.json` 加载的 BPE 分词器。
+///
+/// 支持字节级（GPT-2 风格）和 SentencePiece 风格（`▁` 替换空格并带字节回退）两种 BPE。
+pub struct HfTokenizer {
+    vocab: HashMap<String, utok>,
+    /// 每个词解码得到的字节串。
+    pieces: Vec<Box<[u8]>>,
+    /// 解码字节串 -> 词，是 `pieces` 的反向索引。
+    ids: HashMap<Box<[u8]>, utok>,
+    /// 相邻词对 -> (合并优先级, 合并结果)。
+    merges: HashMap<(utok, utok), (u32, utok)>,
+    /// 特殊词，按长度降序排列以优先匹配最长的词。
+    added: Vec<(String, utok)>,
+    /// 标记为 `special` 的特殊词。
+    special: HashSet<utok>,
+    unk: Option<utok>,
+    byte_level: bool,
+    add_prefix_space: bool,
+    byte_fallback: bool,
+    sentence_piece: bool,
+    /// 没有词在其他字符之后包含 `▁`，非字节级的文本可以在每段 `▁` 之前切分后并行合并。
+    splittable: bool,
+}
+
+#[derive(Deserialize)]
+struct TokenizerJson {
+    model: ModelJson,
+    #[serde(default)]
+    added_tokens: Vec<AddedTokenJson>,
+    #[serde(default)]
+    normalizer: Option<Value>,
+    #[serde(default)]
+    pre_tokenizer: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct ModelJson {
+    #[serde(rename = "type", default)]
+    ty: Option<String>,
+    vocab: HashMap<String, utok>,
+    #[serde(default)]
+    merges: Vec<MergeJson>,
+    #[serde(default)]
+    unk_token: Option<String>,
+    #[serde(default)]
+    byte_fallback: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MergeJson {
+    Joined(String),
+    Pair(String, String),
+}
+
+#[derive(Deserialize)]
+struct AddedTokenJson {
+    id: utok,
+    content: String,
+    #[serde(default)]
+    special: bool,
+}
+
+impl HfTokenizer {
+    pub fn from_json(json: &[u8]) -> Result<Self, serde_json::Error> {
+        let TokenizerJson {
+            model,
+            added_tokens,
+            normalizer,
+            pre_tokenizer,
+        } = serde_json::from_slice(json)?;
+        if model.ty.as_deref().is_some_and(|ty| ty != "BPE") {
+            return Err(serde_json::Error::custom(format!(
+                "unsupported tokenizer model: {}",
+                model.ty.unwrap(),
+            )));
+        }
+
+        let byte_level = find_step(pre_tokenizer.as_ref(), "ByteLevel");
+        let add_prefix_space = byte_level
+            .and_then(|v| v.get("add_prefix_space")?.as_bool())
+            .unwrap_or(true);
+        let byte_level = byte_level.is_some();
+        let sentence_piece = find_step(pre_tokenizer.as_ref(), "Metaspace").is_some()
+            || find_step(normalizer.as_ref(), "Replace")
+                .is_some_and(|v| v.get("content").and_then(Value::as_str) == Some("▁"));
+        Self::new(
+            model,
+            added_tokens,
+            byte_level,
+            add_prefix_space,
+            sentence_piece,
+        )
+    }
+
+    /// 从 `vocab.json` 和 `merges.txt` 加载分词器。
+    ///
+    /// 词表中出现 `Ġ` 时按字节级 BPE 处理，否则出现 `▁` 时按 SentencePiece 风格处理。
+    pub fn from_files(
+        vocab_json: impl AsRef<Path>,
+        merges_txt: impl AsRef<Path>,
+    ) -> Result<Self, TokenizerLoadError> {
+        let vocab = fs::read(vocab_json).map_err(TokenizerLoadError::Io)?;
+        let vocab = serde_json::from_slice(&vocab).map_err(TokenizerLoadError::Json)?;
+        let merges = fs::read_to_string(merges_txt).map_err(TokenizerLoadError::Io)?;
+        Self::from_vocab_merges(vocab, &merges).map_err(TokenizerLoadError::Json)
+    }
+
+    fn from_vocab_merges(
+        vocab: HashMap<String, utok>,
+        merges: &str,
+    ) -> Result<Self, serde_json::Error> {
+        // 第一行可能是 `#version: 0.2` 形式的版本说明
+        let merges = merges
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with("#version"))
+            .map(|line| MergeJson::Joined(line.into()))
+            .collect();
+        let byte_level = vocab.keys().any(|piece| piece.contains('Ġ'));
+        let sentence_piece = !byte_level && vocab.keys().any(|piece| piece.contains('▁'));
+        let model = ModelJson {
+            ty: None,
+            unk_token: vocab.contains_key("<unk>").then(|| "<unk>".into()),
+            byte_fallback: sentence_piece && vocab.contains_key("<0x00>"),
+            vocab,
+            merges,
+        };
+        Self::new(model, Vec::new(), byte_level, false, sentence_piece)
+    }
+
+    fn new(
+        model: ModelJson,
+        added_tokens: Vec<AddedTokenJson>,
+        byte_level: bool,
+        add_prefix_space: bool,
+        sentence_piece: bool,
+    ) -> Result<Self, serde_json::Error> {
+        let unicode_byte = if byte_level {
+            bytes_char()
+                .into_iter()
+                .enumerate()
+                .map(|(b, c)| (c, b as u8))
+                .collect::<HashMap<_, _>>()
+        } else {
+            HashMap::new()
+        };
+        let len = model
+            .vocab
+            .values()
+            .chain(added_tokens.iter().map(|t| &t.id))
+            .max()
+            .map_or(0, |&id| id as usize + 1);
+        let mut pieces = vec![Box::<[u8]>::default(); len];
+        for (piece, &id) in &model.vocab {
+            pieces[id as usize] = if byte_level {
+                let mut bytes = Vec::with_capacity(piece.len());
+                for c in piece.chars() {
+                    match unicode_byte.get(&c) {
+                        Some(&b) => bytes.push(b),
+                        None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                    }
+                }
+                bytes.into()
+            } else if let Some(b) = parse_byte(piece) {
+                Box::from([b].as_slice())
+            } else {
+                piece.as_bytes().into()
+            };
+        }
+        for token in &added_tokens {
+            pieces[token.id as usize] = token.content.as_bytes().into();
+        }
+        let mut ids = HashMap::with_capacity(pieces.len());
+        for (id, piece) in pieces.iter().enumerate() {
+            if !piece.is_empty() {
+                ids.entry(piece.clone()).or_insert(id as utok);
+            }
+        }
+
+        let mut merges = HashMap::with_capacity(model.merges.len());
+        for (rank, merge) in model.merges.iter().enumerate() {
+            let (a, b) = match merge {
+                MergeJson::Joined(s) => s
+                    .split_once(' ')
+                    .ok_or_else(|| serde_json::Error::custom(format!("invalid merge: {s}")))?,
+                MergeJson::Pair(a, b) => (a.as_str(), b.as_str()),
+            };
+            let (Some(&a_), Some(&b_), Some(&merged)) = (
+                model.vocab.get(a),
+                model.vocab.get(b),
+                model.vocab.get(&format!("{a}{b}")),
+            ) else {
+                return Err(serde_json::Error::custom(format!(
+                    "merge not in vocab: {a} {b}"
+                )));
+            };
+            merges.entry((a_, b_)).or_insert((rank as u32, merged));
+        }
+
+        let special = added_tokens
+            .iter()
+            .filter(|t| t.special)
+            .map(|t| t.id)
+            .collect();
+        let mut added = added_tokens
+            .into_iter()
+            .map(|t| (t.content, t.id))
+            .collect::<Vec<_>>();
+        added.sort_by_key(|(content, _)| std::cmp::Reverse(content.len()));
+
+        let splittable = !model
+            .vocab
+            .keys()
+            .any(|piece| has_inner_space(piece.as_bytes()));
+        Ok(Self {
+            unk: model.unk_token.and_then(|t| model.vocab.get(&t).copied()),
+            vocab: model.vocab,
+            pieces,
+            ids,
+            merges,
+            added,
+            special,
+            byte_level,
+            add_prefix_space,
+            byte_fallback: model.byte_fallback,
+            sentence_piece,
+            splittable,
+        })
+    }
+
+    /// 与 `tokenizer.json` 中 normalizer/pre_tokenizer 对应的规范化器。
+    ///
+    /// SentencePiece 风格的 `▁` 替换映射到 [`BPECommonNormalizer`]，字节级 BPE 无需规范化。
+    pub fn normalizer(&self) -> Box<dyn Normalizer + Send + Sync> {
+        if self.sentence_piece {
+            Box::new(BPECommonNormalizer)
+        } else {
+            Box::new(())
+        }
+    }
+
+    /// 标记为 `special` 的特殊词序号。
+    #[inline]
+    pub fn special_tokens(&self) -> impl Iterator<Item = utok> + '_ {
+        self.special.iter().copied()
+    }
+
+    /// 查找 `text` 中最先出现的特殊词，返回其位置、长度和序号。
+    fn find_added(&self, text: &str) -> Option<(usize, usize, utok)> {
+        if self.added.is_empty() {
+            return None;
+        }
+        text.char_indices().find_map(|(i, _)| {
+            self.added
+                .iter()
+                .find(|(content, _)| text[i..].starts_with(content.as_str()))
+                .map(|(content, id)| (i, content.len(), *id))
+        })
+    }
+
+    /// 对不含特殊词的一段文本分词，`offset` 是这段文本在整个输入中的字节位置。
+    ///
+    /// 字节级 BPE 不会跨预分词的边界合并，SentencePiece 风格的 BPE 在 `splittable` 时
+    /// 不会跨 `▁` 之前的边界合并；词数足够多时各词并行合并，结果与顺序合并相同。
+    fn encode_segment(&self, text: &str, offset: usize, ans: &mut Vec<(utok, Range<usize>)>) {
+        if text.is_empty() {
+            return;
+        }
+        let bytes_char = bytes_char();
+        let words = if self.byte_level {
+            pre_tokenize(text)
+        } else if self.splittable {
+            split_words(text)
+        } else {
+            vec![text]
+        };
+        let encode = |word: &&str| {
+            let start = offset + (word.as_ptr() as usize - text.as_ptr() as usize);
+            if self.byte_level {
+                self.encode_word(word, start, &bytes_char)
+            } else {
+                self.encode_chars(word, start)
+            }
+        };
+        if words.len() >= PARALLEL_WORDS {
+            let words = words.par_iter().map(encode).collect::<Vec<_>>();
+            ans.extend(words.into_iter().flatten());
+        } else {
+            ans.extend(words.iter().flat_map(encode));
+        }
+    }
+
+    /// 对 SentencePiece 风格的一段文本分词，`offset` 是这段文本在整个输入中的字节位置。
+    fn encode_chars(&self, text: &str, offset: usize) -> Vec<(utok, Range<usize>)> {
+        let mut symbols = Vec::with_capacity(text.len());
+        for (i, c) in text.char_indices() {
+            let start = offset + i;
+            let mut buf = [0; 4];
+            let c = c.encode_utf8(&mut buf);
+            if let Some(&id) = self.vocab.get(&*c) {
+                symbols.push((id, start..start + c.len()));
+            } else if self.byte_fallback {
+                symbols.extend(c.bytes().enumerate().filter_map(|(j, b)| {
+                    self.vocab
+                        .get(&format!("<0x{b:02X}>"))
+                        .map(|&id| (id, start + j..start + j + 1))
+                }));
+            } else {
+                symbols.extend(self.unk.map(|id| (id, start..start + c.len())));
+            }
+        }
+        self.merge(&mut symbols);
+        symbols
+    }
+
+    /// 对字节级 BPE 预分词得到的一个词分词，`start` 是这个词在整个输入中的字节位置。
+    fn encode_word(
+        &self,
+        word: &str,
+        start: usize,
+        bytes_char: &[char; 256],
+    ) -> Vec<(utok, Range<usize>)> {
+        let mut symbols = word
+            .bytes()
+            .enumerate()
+            .filter_map(|(i, b)| {
+                self.vocab
+                    .get(bytes_char[b as usize].to_string().as_str())
+                    .map(|&id| (id, start + i..start + i + 1))
+            })
+            .collect::<Vec<_>>();
+        self.merge(&mut symbols);
+        symbols
+    }
+
+    /// 反复合并优先级最高的相邻词对，合并结果覆盖两个词的原文范围。
+    fn merge(&self, symbols: &mut Vec<(utok, Range<usize>)>) {
+        while let Some((_, i, merged)) = symbols
+            .windows(2)
+            .enumerate()
+            .filter_map(|(i, pair)| {
+                self.merges
+                    .get(&(pair[0].0, pair[1].0))
+                    .map(|&(rank, merged)| (rank, i, merged))
+            })
+            .min()
+        {
+            let (_, next) = symbols.remove(i + 1);
+            let (token, range) = &mut symbols[i];
+            *token = merged;
+            range.end = next.end;
+        }
+    }
+}
+
+impl Tokenize for HfTokenizer {
+    #[inline]
+    fn encode(&self, text: &str) -> Vec<utok> {
+        self.encode_with_offsets(text)
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect()
+    }
+
+    fn encode_with_offsets(&self, text: &str) -> Vec<(utok, Range<usize>)> {
+        let prefix = self.byte_level && self.add_prefix_space && !text.starts_with(' ');
+        let text = if prefix {
+            Cow::Owned(format!(" {text}"))
+        } else {
+            Cow::Borrowed(text)
+        };
+
+        let mut ans = Vec::new();
+        let mut offset = 0;
+        let mut rest = &*text;
+        while let Some((pos, len, id)) = self.find_added(rest) {
+            self.encode_segment(&rest[..pos], offset, &mut ans);
+            ans.push((id, offset + pos..offset + pos + len));
+            rest = &rest[pos + len..];
+            offset += pos + len;
+        }
+        self.encode_segment(rest, offset, &mut ans);
+        // 补充的前导空格不属于原文
+        if prefix {
+            for (_, range) in &mut ans {
+                *range = range.start.saturating_sub(1)..range.end - 1;
+            }
+        }
+        ans
+    }
+
+    #[inline]
+    fn decode(&self, token: utok) -> &str {
+        piece_str(self.decode_bytes(token))
+    }
+
+    #[inline]
+    fn decode_bytes(&self, token: utok) -> &[u8] {
+        &self.pieces[token as usize]
+    }
+
+    /// 先按解码后的字节串查找，再按词表中的原始形式（如 `<0xNN>`、`Ġword`）查找。
+    #[inline]
+    fn token_to_id(&self, piece: &str) -> Option<utok> {
+        self.ids
+            .get(piece.as_bytes())
+            .or_else(|| self.vocab.get(piece))
+            .copied()
+    }
+}
+
+/// 预分词得到的词数达到这个值时并行合并。
+pub(super) const PARALLEL_WORDS: usize = 256;
+
+/// 在 normalizer/pre_tokenizer 中查找指定类型的步骤，支持 `Sequence` 嵌套。
+fn find_step<'a>(value: Option<&'a Value>, ty: &str) -> Option<&'a Value> {
+    let value = value?;
+    if value.get("type").and_then(Value::as_str) == Some(ty) {
+        return Some(value);
+    }
+    ["normalizers", "pretokenizers"]
+        .into_iter()
+        .filter_map(|key| value.get(key)?.as_array())
+        .flatten()
+        .find_map(|v| find_step(Some(v), ty))
+}
+
+/// 解析 `<0xXX>` 形式的字节词。
+pub(super) fn parse_byte(piece: &str) -> Option<u8> {
+    piece
+        .strip_prefix("<0x")?
+        .strip_suffix('>')
+        .filter(|hex| hex.len() == 2)
+        .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+}
+
+/// GPT-2 字节级 BPE 的字节到可见字符映射。
+fn bytes_char() -> [char; 256] {
+    let mut ans = ['\0'; 256];
+    let mut n = 0;
+    for b in 0..=255u8 {
+        ans[b as usize] = if matches!(b, b'!'..=b'~' | 0xa1..=0xac | 0xae..=0xff) {
+            b as char
+        } else {
+            n += 1;
+            char::from_u32(255 + n).unwrap()
+        };
+    }
+    ans
+}
+
+/// 近似 GPT-2 的预分词正则：缩写、可带一个前导空格的字母/数字/符号串、空白串。
+fn pre_tokenize(text: &str) -> Vec<&str> {
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum Class {
+        Letter,
+        Number,
+        Space,
+        Other,
+    }
+    fn class(c: char) -> Class {
+        if c.is_alphabetic() {
+            Class::Letter
+        } else if c.is_numeric() {
+            Class::Number
+        } else if c.is_whitespace() {
+            Class::Space
+        } else {
+            Class::Other
+        }
+    }
+
+    let chars = text.char_indices().collect::<Vec<_>>();
+    let end = |j: usize| chars.get(j).map_or(text.len(), |&(p, _)| p);
+    let mut ans = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        if c == '\'' {
+            if let Some(s) = ["s", "t", "m", "d", "re", "ve", "ll"]
+                .into_iter()
+                .find(|s| text[start + 1..].starts_with(s))
+            {
+                i += 1 + s.len();
+                ans.push(&text[start..end(i)]);
+                continue;
+            }
+        }
+
+        let mut j = i;
+        if c == ' '
+            && chars
+                .get(i + 1)
+                .is_some_and(|&(_, c)| class(c) != Class::Space)
+        {
+            j += 1;
+        }
+        let cls = class(chars[j].1);
+        let run = chars[j..]
+            .iter()
+            .take_while(|&&(_, c)| class(c) == cls)
+            .count();
+        j += run;
+        // 空白串后接非空白时，留下最后一个空白与后面的词合并
+        if cls == Class::Space && run > 1 && j < chars.len() {
+            j -= 1;
+        }
+        ans.push(&text[start..end(j)]);
+        i = j;
+    }
+    ans
+}
+
+#[test]
+fn test() {
+    const JSON: &str = r#"{
+        "added_tokens": [{ "id": 17, "content": "<|endoftext|>", "special": true }],
+        "normalizer": null,
+        "pre_tokenizer": { "type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true },
+        "model": {
+            "type": "BPE",
+            "vocab": {
+                "h": 0, "e": 1, "l": 2, "o": 3, "Ġ": 4, "w": 5, "r": 6, "d": 7,
+                "he": 8, "ll": 9, "hell": 10, "hello": 11,
+                "Ġw": 12, "or": 13, "Ġwor": 14, "Ġworl": 15, "Ġworld": 16
+            },
+            "merges": ["h e", "l l", "he ll", "hell o", "Ġ w", "o r", "Ġw or", "Ġwor l", "Ġworl d"]
+        }
+    }"#;
+
+    let tokenizer = HfTokenizer::from_json(JSON.as_bytes()).unwrap();
+    assert_eq!(tokenizer.encode("hello world"), [11, 16]);
+    assert_eq!(tokenizer.encode("hold"), [0, 3, 2, 7]);
+    assert_eq!(tokenizer.encode("hello<|endoftext|>"), [11, 17]);
+    assert_eq!(tokenizer.decode(16), " world");
+    assert_eq!(tokenizer.decode(17), "<|endoftext|>");
+    assert_eq!(tokenizer.normalizer().encode("hello world"), "hello world");
+}
+
+#[test]
+fn test_token_to_id() {
+    const JSON: &str = r#"{
+        "normalizer": {
+            "type": "Sequence",
+            "normalizers": [
+                { "type": "Prepend", "prepend": "▁" },
+                { "type": "Replace", "pattern": { "String": " " }, "content": "▁" }
+            ]
+        },
+        "model": {
+            "type": "BPE",
+            "vocab": { "<unk>": 0, "▁": 1, "H": 2, "i": 3, "▁H": 4, "▁Hi": 5, "<0x0A>": 6, "<0xE4>": 7 },
+            "merges": ["▁ H", "▁H i"],
+            "unk_token": "<unk>",
+            "byte_fallback": true
+        }
+    }"#;
+
+    let tokenizer = HfTokenizer::from_json(JSON.as_bytes()).unwrap();
+    for token in 0..7 {
+        assert_eq!(tokenizer.token_to_id(tokenizer.decode(token)), Some(token));
+    }
+    assert_eq!(tokenizer.token_to_id("<0x0A>"), Some(6));
+    assert_eq!(tokenizer.token_to_id("\n"), Some(6));
+    assert_eq!(tokenizer.token_to_id("<0xE4>"), Some(7));
+    assert_eq!(tokenizer.token_to_id("▁Hello"), None);
+    // 多字节字符的一部分不是合法的文本，只能按字节解码
+    assert_eq!(tokenizer.decode_bytes(7), [0xE4]);
+    assert_eq!(tokenizer.decode(7), "\u{FFFD}");
+
+    assert_eq!(tokenizer.encode("▁Hi\n"), [5, 6]);
+    assert_eq!(tokenizer.normalizer().decode("▁Hi"), " Hi");
+}
+
+#[test]
+fn test_from_files() {
+    let dir = std::env::temp_dir().join("infinilm-tokenizer-from-files");
+    fs::create_dir_all(&dir).unwrap();
+    let load = |vocab: &str, merges: &str| {
+        let vocab_json = dir.join("vocab.json");
+        let merges_txt = dir.join("merges.txt");
+        fs::write(&vocab_json, vocab).unwrap();
+        fs::write(&merges_txt, merges).unwrap();
+        HfTokenizer::from_files(vocab_json, merges_txt).unwrap()
+    };
+
+    // 字节级 BPE，空格映射为 `Ġ`
+    let tokenizer = load(
+        r#"{"h": 0, "e": 1, "l": 2, "o": 3, "Ġ": 4, "w": 5, "r": 6, "d": 7,
+            "he": 8, "ll": 9, "hell": 10, "hello": 11,
+            "Ġw": 12, "or": 13, "Ġwor": 14, "Ġworl": 15, "Ġworld": 16}"#,
+        "#version: 0.2\nh e\nl l\nhe ll\nhell o\nĠ w\no r\nĠw or\nĠwor l\nĠworl d\n",
+    );
+    assert_eq!(tokenizer.encode("hello world"), [11, 16]);
+    assert_eq!(tokenizer.encode("hello  hold"), [11, 4, 4, 0, 3, 2, 7]);
+    assert_eq!(tokenizer.decode(16), " world");
+    assert_eq!(tokenizer.token_to_id("Ġworld"), Some(16));
+
+    // SentencePiece 风格，空格由规范化器替换为 `▁`
+    let tokenizer = load(
+        r#"{"<unk>": 0, "▁": 1, "H": 2, "i": 3, "▁H": 4, "▁Hi": 5, "<0x0A>": 6, "<0x00>": 7}"#,
+        "▁ H\n▁H i\n",
+    );
+    let text = tokenizer.normalizer().encode("Hi Hi\n");
+    assert_eq!(text, "▁Hi▁Hi\n");
+    assert_eq!(tokenizer.encode(&text), [5, 5, 6]);
+    assert_eq!(tokenizer.decode(6), "\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_encode_with_offsets() {
+    const JSON: &str = r#"{
+        "pre_tokenizer": { "type": "ByteLevel", "add_prefix_space": true },
+        "model": {
+            "type": "BPE",
+            "vocab": {
+                "h": 0, "i": 1, "Ġ": 2, "ä": 3, "½": 4, "ł": 5, "Ġh": 6, "Ġhi": 7
+            },
+            "merges": ["Ġ h", "Ġh i"]
+        }
+    }"#;
+
+    let tokenizer = HfTokenizer::from_json(JSON.as_bytes()).unwrap();
+    // 自动补充的前导空格不计入第一个词的范围
+    assert_eq!(tokenizer.encode_with_offsets("hi"), [(7, 0..2)]);
+    assert_eq!(
+        tokenizer.encode_with_offsets(" hi hi"),
+        [(7, 0..3), (7, 3..6)]
+    );
+    // 多字节字符的每个字节各占一个位置
+    assert_eq!(
+        tokenizer.encode_with_offsets(" 你"),
+        [(2, 0..1), (3, 1..2), (4, 2..3), (5, 3..4)]
+    );
+}
+
+#[test]
+fn test_encode_parallel() {
+    const JSON: &str = r#"{
+        "pre_tokenizer": { "type": "ByteLevel", "add_prefix_space": false },
+        "model": {
+            "type": "BPE",
+            "vocab": {
+                "h": 0, "e": 1, "l": 2, "o": 3, "Ġ": 4, "w": 5, "r": 6, "d": 7, ",": 8, "Ċ": 9,
+                "he": 10, "ll": 11, "hell": 12, "hello": 13,
+                "Ġw": 14, "or": 15, "Ġwor": 16, "Ġworl": 17, "Ġworld": 18, "Ġh": 19
+            },
+            "merges": [
+                "h e", "l l", "he ll", "hell o", "Ġ w", "o r", "Ġw or", "Ġwor l", "Ġworl d", "Ġ h"
+            ]
+        }
+    }"#;
+
+    let tokenizer = HfTokenizer::from_json(JSON.as_bytes()).unwrap();
+    let text = "hello world, hello  world\nworld hello,\n".repeat(64);
+    let words = pre_tokenize(&text);
+    assert!(words.len() >= PARALLEL_WORDS);
+
+    // 逐词顺序合并的参照结果
+    let bytes_char = bytes_char();
+    let expected = words
+        .iter()
+        .flat_map(|word| {
+            let start = word.as_ptr() as usize - text.as_ptr() as usize;
+            tokenizer.encode_word(word, start, &bytes_char)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(tokenizer.encode_with_offsets(&text), expected);
+
+    // 长文本的结果与逐段分词拼接相同
+    let paragraph = tokenizer.encode("hello world, hello  world\nworld hello,\n");
+    assert!(paragraph.len() < PARALLEL_WORDS);
+    assert_eq!(tokenizer.encode(&text), paragraph.repeat(64));
+
+    // SentencePiece 风格的文本在 `▁` 之前切分，结果与整体合并相同
+    const SENTENCE_PIECE: &str = r#"{
+        "model": {
+            "type": "BPE",
+            "vocab": { "<unk>": 0, "▁": 1, "H": 2, "i": 3, "▁H": 4, "▁Hi": 5, "<0x0A>": 6 },
+            "merges": ["▁ H", "▁H i"],
+            "unk_token": "<unk>",
+            "byte_fallback": true
+        }
+    }"#;
+    let tokenizer = HfTokenizer::from_json(SENTENCE_PIECE.as_bytes()).unwrap();
+    assert!(tokenizer.splittable);
+    let text = "▁Hi▁H▁▁i\n".repeat(100);
+    assert!(split_words(&text).len() >= PARALLEL_WORDS);
+    assert_eq!(
+        tokenizer.encode_with_offsets(&text),
+        tokenizer.encode_chars(&text, 0),
+    );
+}
@@ -0,0 +1,52 @@
+use super::{special::SpecialTrie, Tokenize, Tokenizer};
+use std::collections::{HashMap, HashSet};
+use tokeneer::utok;
+
+/// 按序号直接给出词的词表，只用于解码。
+pub(super) struct Pieces(pub &'static [&'static str]);
+
+impl Tokenize for Pieces {
+    fn encode(&self, _: &str) -> Vec<utok> {
+        unimplemented!()
+    }
+    fn decode(&self, token: utok) -> &str {
+        self.0[token as usize]
+    }
+}
+
+/// 每个 ASCII 字符编码为与码位同序号的词，其他字节编码为 `0`。
+pub(crate) struct Ascii;
+
+impl Tokenize for Ascii {
+    fn encode(&self, text: &str) -> Vec<utok> {
+        text.bytes()
+            .map(|b| if b.is_ascii() { b as _ } else { 0 })
+            .collect()
+    }
+    fn decode(&self, token: utok) -> &str {
+        static ASCII: [u8; 128] = {
+            let mut ans = [0; 128];
+            let mut i = 0;
+            while i < ans.len() {
+                ans[i] = i as _;
+                i += 1;
+            }
+            ans
+        };
+        std::str::from_utf8(&ASCII[token as usize..][..1]).unwrap()
+    }
+}
+
+impl From<Ascii> for Tokenizer {
+    fn from(tokenize: Ascii) -> Self {
+        Self {
+            tokenize: Box::new(tokenize),
+            normalizer: Box::new(()),
+            special: HashSet::new(),
+            added: SpecialTrie::default(),
+            added_text: HashMap::new(),
+            bos: None,
+            eos: None,
+        }
+    }
+}
@@ -0,0 +1,1066 @@
+mod hf;
+#[cfg(test)]
+pub(crate) mod mock;
+mod special;
+mod stream;
+mod unicode;
+mod vocab_txt;
+
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use special::SpecialTrie;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{self, File},
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+use tokeneer::{utok, Bpe, Lpe, Tokeneer};
+use unicode_normalization::char::is_combining_mark;
+
+pub use hf::HfTokenizer;
+pub use stream::{FlushPolicy, StreamDecoder};
+pub use unicode::{UnicodeForm, UnicodeNormalizer};
+pub use vocab_txt::{VocabTxt, VocabTxtError};
+
+/// 分词器及与之配套的规范化器。
+pub struct Tokenizer {
+    pub tokenize: Box<dyn Tokenize + Send + Sync>,
+    pub normalizer: Box<dyn Normalizer + Send + Sync>,
+    /// 已注册的特殊词序号。
+    special: HashSet<utok>,
+    /// 运行时注册的特殊词，在分词前整体匹配。
+    added: SpecialTrie,
+    /// 运行时注册的特殊词序号 -> 文本。
+    added_text: HashMap<utok, String>,
+    bos: Option<utok>,
+    eos: Option<utok>,
+}
+
+/// 超出最大长度时截断的一端。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub enum Truncation {
+    /// 丢弃末尾的词。
+    #[default]
+    Right,
+    /// 丢弃开头的词。
+    Left,
+}
+
+/// [`Tokenizer::encode_with`] 的选项。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub struct EncodeOptions {
+    /// 最大长度，包含起始符和结束符。
+    pub max_len: Option<usize>,
+    /// 截断的一端。
+    pub truncation: Truncation,
+    /// 不足 `max_len` 时在末尾填充的词。
+    pub padding: Option<utok>,
+    /// 是否在开头添加起始符。
+    pub add_bos: bool,
+    /// 是否在末尾添加结束符。
+    pub add_eos: bool,
+}
+
+/// 加载分词器可能产生的错误。
+#[derive(Debug)]
+pub enum TokenizerLoadError {
+    /// IO 错误。
+    Io(io::Error),
+    /// Json 解析错误。
+    Json(serde_json::Error),
+    /// `vocabs.txt` 解析错误。
+    VocabTxt(VocabTxtError),
+    /// 目录中没有任何可识别的分词器文件。
+    NotFound(PathBuf),
+}
+
+impl fmt::Display for TokenizerLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "failed to read tokenizer: {e}"),
+            Self::Json(e) => write!(f, "failed to parse tokenizer.json: {e}"),
+            Self::VocabTxt(e) => write!(f, "{e}"),
+            Self::NotFound(dir) => write!(
+                f,
+                "no tokenizer found in {}, looked for: {}",
+                dir.display(),
+                Tokenizer::CANDIDATES.join(", "),
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TokenizerLoadError {}
+
+/// 注册特殊词时的冲突。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SpecialTokenError {
+    /// 文本已经对应另一个词。
+    TextInUse { text: String, token: utok },
+    /// 序号已经对应另一段文本。
+    IdInUse { token: utok, text: String },
+}
+
+impl fmt::Display for SpecialTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::TextInUse { text, token } => write!(f, "{text:?} is already token {token}"),
+            Self::IdInUse { token, text } => write!(f, "token {token} is already {text:?}"),
+        }
+    }
+}
+
+impl std::error::Error for SpecialTokenError {}
+
+impl Tokenizer {
+    /// 按顺序尝试的分词器文件。
+    pub const CANDIDATES: [&'static str; 3] = ["tokenizer.model", "vocabs.txt", "tokenizer.json"];
+
+    /// 检查模型目录中存在的文件，自动选择分词器格式。
+    ///
+    /// 起始符和结束符取自 `config.json`，同时注册为特殊词。
+    pub fn load(model_dir: impl AsRef<Path>) -> Result<Self, TokenizerLoadError> {
+        let model_dir = model_dir.as_ref();
+        let mut ans = Self::load_format(model_dir)?;
+        let [bos, eos] = config_special(model_dir);
+        if let Some(bos) = bos {
+            ans.set_bos(bos);
+        }
+        if let Some(eos) = eos {
+            ans.set_eos(eos);
+        }
+        Ok(ans)
+    }
+
+    fn load_format(model_dir: &Path) -> Result<Self, TokenizerLoadError> {
+        let mmap = |name: &str| match File::open(model_dir.join(name)) {
+            Ok(f) => unsafe { memmap2::Mmap::map(&f) }
+                .map(Some)
+                .map_err(TokenizerLoadError::Io),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(TokenizerLoadError::Io(e)),
+        };
+
+        if let Some(f) = mmap("tokenizer.model")? {
+            return Ok(Self {
+                tokenize: Box::new(Indexed::new(Tokeneer::new(Bpe::from_tokenizer_model(&f)))),
+                normalizer: Box::new(BPECommonNormalizer),
+                special: HashSet::new(),
+                added: SpecialTrie::default(),
+                added_text: HashMap::new(),
+                bos: None,
+                eos: None,
+            });
+        }
+        match VocabTxt::load_streaming(model_dir.join("vocabs.txt")) {
+            Ok(vocab) => {
+                return Ok(Self {
+                    tokenize: Box::new(Indexed::new(Tokeneer::new(Lpe::new(vocab.pieces(), 0)))),
+                    normalizer: Box::new(()),
+                    special: HashSet::new(),
+                    added: SpecialTrie::default(),
+                    added_text: HashMap::new(),
+                    bos: None,
+                    eos: None,
+                })
+            }
+            Err(VocabTxtError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(TokenizerLoadError::VocabTxt(e)),
+        }
+        if let Some(f) = mmap("tokenizer.json")? {
+            return HfTokenizer::from_json(&f)
+                .map(Self::from)
+                .map_err(TokenizerLoadError::Json);
+        }
+        Err(TokenizerLoadError::NotFound(model_dir.to_path_buf()))
+    }
+
+    /// 在现有的规范化器之前执行 Unicode 规范化。
+    pub fn with_unicode(self, unicode: UnicodeNormalizer) -> Self {
+        Self {
+            normalizer: Box::new((unicode, self.normalizer)),
+            ..self
+        }
+    }
+
+    /// 规范化并分词，给出每个词覆盖的原文字节范围。
+    ///
+    /// 范围总是落在原文的字符边界上；一个字符被拆成多个词时，这些词的范围相同。
+    pub fn encode_with_offsets(&self, text: &str) -> Vec<(utok, Range<usize>)> {
+        let mut ans = Vec::new();
+        for (range, token) in self.added.split(text) {
+            if let Some(token) = token {
+                ans.push((token, range));
+                continue;
+            }
+            let (normalized, map) = self.normalizer.encode_with_offsets(&text[range.clone()]);
+            ans.extend(
+                self.tokenize
+                    .encode_with_offsets(&normalized)
+                    .into_iter()
+                    .map(|(token, r)| {
+                        let r = map_range(&map, r);
+                        (token, range.start + r.start..range.start + r.end)
+                    }),
+            );
+        }
+        ans
+    }
+
+    /// 规范化并分词，运行时注册的特殊词整体编码为一个词。
+    pub fn encode(&self, text: &str) -> Vec<utok> {
+        let mut ans = Vec::new();
+        for (range, token) in self.added.split(text) {
+            match token {
+                Some(token) => ans.push(token),
+                None => ans.extend(self.tokenize.encode(&self.normalizer.encode(&text[range]))),
+            }
+        }
+        ans
+    }
+
+    /// 解码一个词，运行时注册的特殊词解码为注册时的文本。
+    #[inline]
+    pub fn decode(&self, token: utok) -> &str {
+        self.added_text
+            .get(&token)
+            .map_or_else(|| self.tokenize.decode(token), String::as_str)
+    }
+
+    /// 按字节解码一个词，运行时注册的特殊词解码为注册时的文本。
+    #[inline]
+    pub fn decode_bytes(&self, token: utok) -> &[u8] {
+        self.added_text
+            .get(&token)
+            .map_or_else(|| self.tokenize.decode_bytes(token), |s| s.as_bytes())
+    }
+
+    /// 注册一个特殊词，编码时 `text` 不经规范化和 BPE，整体映射到 `token`。
+    ///
+    /// `text` 已经是另一个词，或 `token` 已经注册为另一段文本时返回错误；重复注册相同的词不做任何事。
+    pub fn add_special_token(&mut self, text: &str, token: utok) -> Result<(), SpecialTokenError> {
+        assert!(!text.is_empty(), "special token must not be empty");
+        if let Some(existing) = self.added_text.get(&token) {
+            if existing != text {
+                return Err(SpecialTokenError::IdInUse {
+                    token,
+                    text: existing.clone(),
+                });
+            }
+        }
+        let existing = self
+            .added
+            .split(text)
+            .into_iter()
+            .find_map(|(range, t)| t.filter(|_| range == (0..text.len())))
+            .or_else(|| self.tokenize.token_to_id(text));
+        match existing {
+            Some(existing) if existing != token => Err(SpecialTokenError::TextInUse {
+                text: text.into(),
+                token: existing,
+            }),
+            _ => {
+                self.added.insert(text, token);
+                self.added_text.insert(token, text.into());
+                self.register_special(token);
+                Ok(())
+            }
+        }
+    }
+
+    /// 设置起始符，同时将其注册为特殊词。
+    #[inline]
+    pub fn set_bos(&mut self, token: utok) {
+        self.bos = Some(token);
+        self.register_special(token);
+    }
+
+    /// 设置结束符，同时将其注册为特殊词。
+    #[inline]
+    pub fn set_eos(&mut self, token: utok) {
+        self.eos = Some(token);
+        self.register_special(token);
+    }
+
+    /// 按 `opts` 规范化并分词。
+    ///
+    /// 截断只作用于文本部分，添加的起始符和结束符总能保留。
+    pub fn encode_with(&self, text: &str, opts: EncodeOptions) -> Vec<utok> {
+        let special = |add: bool, token: Option<utok>, name: &str| {
+            add.then(|| token.unwrap_or_else(|| panic!("{name} token not set")))
+        };
+        let bos = special(opts.add_bos, self.bos, "bos");
+        let eos = special(opts.add_eos, self.eos, "eos");
+
+        let mut body = self.encode(text);
+        if let Some(max_len) = opts.max_len {
+            let len = max_len.saturating_sub(bos.iter().chain(&eos).count());
+            if body.len() > len {
+                match opts.truncation {
+                    Truncation::Right => body.truncate(len),
+                    Truncation::Left => {
+                        body.drain(..body.len() - len);
+                    }
+                }
+            }
+        }
+
+        let mut ans = Vec::with_capacity(opts.max_len.unwrap_or(0).max(body.len() + 2));
+        ans.extend(bos);
+        ans.extend(body);
+        ans.extend(eos);
+        if let (Some(max_len), Some(pad)) = (opts.max_len, opts.padding) {
+            if ans.len() < max_len {
+                ans.resize(max_len, pad);
+            }
+        }
+        ans
+    }
+
+    /// 将 `token` 注册为特殊词。
+    #[inline]
+    pub fn register_special(&mut self, token: utok) {
+        self.special.insert(token);
+    }
+
+    /// 判断 `token` 是否是已注册的特殊词。
+    #[inline]
+    pub fn is_special(&self, token: utok) -> bool {
+        self.special.contains(&token)
+    }
+
+    /// 解码一个词序列，跳过所有已注册的特殊词。
+    ///
+    /// 只按序号判断，与特殊词字面相同的普通词不受影响。
+    pub fn decode_skipping_special(&self, tokens: &[utok]) -> String {
+        let mut decoder = StreamDecoder::new(&*self.tokenize);
+        let mut ans = tokens
+            .iter()
+            .filter(|&&t| !self.is_special(t))
+            .map(|&t| decoder.push(t))
+            .collect::<String>();
+        ans.push_str(&decoder.flush());
+        ans
+    }
+}
+
+/// 读取模型目录中 `config.json` 给出的起始符和结束符，无法读取时都为 `None`。
+fn config_special(model_dir: &Path) -> [Option<utok>; 2] {
+    let Some(config) = fs::read(model_dir.join("config.json"))
+        .ok()
+        .and_then(|json| serde_json::from_slice::<serde_json::Value>(&json).ok())
+    else {
+        return [None; 2];
+    };
+    let id = |key| config.get(key).and_then(serde_json::Value::as_u64);
+    [id("bos_token_id"), id("eos_token_id")].map(|id| id.map(|id| id as utok))
+}
+
+impl From<HfTokenizer> for Tokenizer {
+    fn from(tokenize: HfTokenizer) -> Self {
+        Self {
+            normalizer: tokenize.normalizer(),
+            special: tokenize.special_tokens().collect(),
+            added: SpecialTrie::default(),
+            added_text: HashMap::new(),
+            bos: None,
+            eos: None,
+            tokenize: Box::new(tokenize),
+        }
+    }
+}
+
+pub trait Tokenize {
+    fn encode(&self, text: &str) -> Vec<utok>;
+    /// 解码一个词，不是完整 UTF-8 序列的词（如字节词）解码为替换字符。
+    fn decode(&self, token: utok) -> &str;
+    /// 解码一个词的原始字节串，可能是多字节字符的一部分。
+    ///
+    /// 默认实现返回 [`decode`](Self::decode) 的字节。
+    #[inline]
+    fn decode_bytes(&self, token: utok) -> &[u8] {
+        self.decode(token).as_bytes()
+    }
+    /// 分词并给出每个词在 `text` 中覆盖的字节范围。
+    ///
+    /// 默认实现按各词解码结果的长度依次累加，要求解码结果连接起来恰好是 `text`。
+    fn encode_with_offsets(&self, text: &str) -> Vec<(utok, Range<usize>)> {
+        let mut start = 0;
+        self.encode(text)
+            .into_iter()
+            .map(|token| {
+                let end = (start + self.decode_bytes(token).len()).min(text.len());
+                let range = start..end;
+                start = end;
+                (token, range)
+            })
+            .collect()
+    }
+    /// 查询解码结果为 `piece` 的词。
+    ///
+    /// 默认实现要求 `piece` 恰好编码为一个词，分词器应尽量提供基于索引的实现。
+    fn token_to_id(&self, piece: &str) -> Option<utok> {
+        match *self.encode(piece) {
+            [token] if self.decode(token) == piece => Some(token),
+            _ => None,
+        }
+    }
+    /// 解码完整的词序列，按 SentencePiece 的规则整理空白。
+    ///
+    /// 字节回退词合并为多字节字符，`▁` 转换为空格；去掉开头的一个空格，标点之前不保留空格。
+    fn decode_sequence(&self, tokens: &[utok]) -> String {
+        let mut decoder = StreamDecoder::new(self);
+        let mut text = tokens.iter().map(|&t| decoder.push(t)).collect::<String>();
+        text.push_str(&decoder.flush());
+        clean_up_spaces(&text)
+    }
+}
+
+/// 去掉开头的一个空格和标点之前的空格。
+fn clean_up_spaces(text: &str) -> String {
+    let text = text.strip_prefix(' ').unwrap_or(text);
+    let mut ans = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let before_punct = chars
+            .peek()
+            .is_some_and(|c| matches!(c, '.' | ',' | '!' | '?' | ';' | ':'));
+        if c != ' ' || !before_punct {
+            ans.push(c);
+        }
+    }
+    ans
+}
+
+/// 批量分词，对所有 [`Tokenize`] 实现可用。
+pub trait TokenizeBatch {
+    /// 并行地对多个字符串分词，结果与输入顺序一致。
+    fn encode_batch(&self, texts: &[&str]) -> Vec<Vec<utok>>;
+}
+
+impl<T: Tokenize + Sync + ?Sized> TokenizeBatch for T {
+    #[inline]
+    fn encode_batch(&self, texts: &[&str]) -> Vec<Vec<utok>> {
+        texts.par_iter().map(|text| self.encode(text)).collect()
+    }
+}
+
+impl<M: tokeneer::Method> Tokenize for Tokeneer<M> {
+    #[inline]
+    fn encode(&self, text: &str) -> Vec<utok> {
+        self.encode(text)
+    }
+    #[inline]
+    fn decode(&self, token: utok) -> &str {
+        piece_str(self.decode_bytes(token))
+    }
+    #[inline]
+    fn decode_bytes(&self, token: utok) -> &[u8] {
+        self.internal().decode(token)
+    }
+}
+
+/// 在每段连续的 `▁` 之前切分 SentencePiece 风格的文本。
+///
+/// 词表中没有在其他字符之后包含 `▁` 的词时（见 [`has_inner_space`]），合并不会跨越这些位置。
+fn split_words(text: &str) -> Vec<&str> {
+    let mut ans = Vec::new();
+    let mut start = 0;
+    let mut prev = '▁';
+    for (i, c) in text.char_indices() {
+        if c == '▁' && prev != '▁' {
+            ans.push(&text[start..i]);
+            start = i;
+        }
+        prev = c;
+    }
+    ans.push(&text[start..]);
+    ans
+}
+
+/// 词中是否有 `▁` 紧跟在其他字符之后。
+fn has_inner_space(piece: &[u8]) -> bool {
+    const SPACE: &[u8] = "▁".as_bytes();
+    let mut rest = piece;
+    let mut after_other = false;
+    while let Some((_, tail)) = rest.split_first() {
+        if let Some(tail) = rest.strip_prefix(SPACE) {
+            if after_other {
+                return true;
+            }
+            rest = tail;
+        } else {
+            after_other = true;
+            rest = tail;
+        }
+    }
+    false
+}
+
+/// 完整的 UTF-8 字节串转换为文本，否则为替换字符。
+#[inline]
+fn piece_str(piece: &[u8]) -> &str {
+    std::str::from_utf8(piece).unwrap_or("\u{FFFD}")
+}
+
+/// 带有反向索引的 [`Tokeneer`]，构造时为所有词的解码结果建立 `HashMap`。
+struct Indexed<M> {
+    tokeneer: Tokeneer<M>,
+    /// 解码字节串 -> 词，重复的字节串取最后一个。
+    ids: HashMap<Box<[u8]>, utok>,
+    /// 单字节 -> 解码为该字节的第一个词。
+    ///
+    /// SentencePiece 词表中字节词（`<0xNN>`）排在普通词之前，因此这里是字节词。
+    bytes: [Option<utok>; 256],
+    /// 没有词在其他字符之后包含 `▁`，长文本可以在每段 `▁` 之前切分后并行分词。
+    splittable: bool,
+}
+
+impl<M: tokeneer::Method> Indexed<M> {
+    fn new(tokeneer: Tokeneer<M>) -> Self {
+        let method = tokeneer.internal();
+        let mut ids = HashMap::with_capacity(method.vocab_size());
+        let mut bytes = [None; 256];
+        let mut splittable = true;
+        for token in 0..method.vocab_size() as utok {
+            let piece = method.decode(token);
+            if let &[b] = piece {
+                bytes[b as usize].get_or_insert(token);
+            }
+            splittable &= !has_inner_space(piece);
+            ids.insert(piece.into(), token);
+        }
+        Self {
+            tokeneer,
+            ids,
+            bytes,
+            splittable,
+        }
+    }
+}
+
+impl<M: tokeneer::Method> Tokenize for Indexed<M> {
+    /// 长文本在每段 `▁` 之前切分后并行分词，结果与整体分词相同。
+    fn encode(&self, text: &str) -> Vec<utok> {
+        if self.splittable {
+            let words = split_words(text);
+            if words.len() >= hf::PARALLEL_WORDS {
+                let words = words
+                    .par_iter()
+                    .map(|word| self.tokeneer.encode(word))
+                    .collect::<Vec<_>>();
+                return words.concat();
+            }
+        }
+        self.tokeneer.encode(text)
+    }
+    #[inline]
+    fn decode(&self, token: utok) -> &str {
+        Tokenize::decode(&self.tokeneer, token)
+    }
+    #[inline]
+    fn decode_bytes(&self, token: utok) -> &[u8] {
+        Tokenize::decode_bytes(&self.tokeneer, token)
+    }
+    /// 先按解码后的字节串查找，`<0xNN>` 形式的字节词按字节查找。
+    #[inline]
+    fn token_to_id(&self, piece: &str) -> Option<utok> {
+        self.ids
+            .get(piece.as_bytes())
+            .copied()
+            .or_else(|| hf::parse_byte(piece).and_then(|b| self.bytes[b as usize]))
+    }
+}
+
+pub trait Normalizer {
+    fn encode<'a>(&self, text: &'a str) -> Cow<'a, str>;
+    fn decode<'a>(&self, text: &'a str) -> Cow<'a, str>;
+    /// 规范化并给出结果中每个字节来自原文的范围，范围总是落在字符边界上。
+    ///
+    /// 默认实现把原文按组合符号切分为字符簇，对各簇分别规范化；
+    /// 拼接结果与整体规范化不一致时，所有字节都对应整个原文。
+    fn encode_with_offsets<'a>(&self, text: &'a str) -> (Cow<'a, str>, Vec<Range<usize>>) {
+        let ans = self.encode(text);
+        if *ans == *text {
+            return (ans, char_ranges(text));
+        }
+        let mut normalized = String::with_capacity(ans.len());
+        let mut map = Vec::with_capacity(ans.len());
+        let mut start = 0;
+        for (i, c) in text.char_indices().skip(1).chain([(text.len(), ' ')]) {
+            if i < text.len() && is_combining_mark(c) {
+                continue;
+            }
+            let cluster = self.encode(&text[start..i]);
+            normalized.push_str(&cluster);
+            map.extend(std::iter::repeat(start..i).take(cluster.len()));
+            start = i;
+        }
+        if normalized != *ans {
+            map = vec![0..text.len(); ans.len()];
+        }
+        (ans, map)
+    }
+}
+
+/// 原文中每个字节所在字符的范围。
+fn char_ranges(text: &str) -> Vec<Range<usize>> {
+    text.char_indices()
+        .flat_map(|(i, c)| std::iter::repeat(i..i + c.len_utf8()).take(c.len_utf8()))
+        .collect()
+}
+
+/// 把规范化结果中的字节范围映射回原文，`map` 来自 [`Normalizer::encode_with_offsets`]。
+fn map_range(map: &[Range<usize>], range: Range<usize>) -> Range<usize> {
+    match map.get(range.clone()) {
+        Some([first, rest @ ..]) => {
+            let end = rest.iter().map(|r| r.end).fold(first.end, usize::max);
+            first.start..end
+        }
+        _ => {
+            let pos = map
+                .get(range.start)
+                .map_or_else(|| map.last().map_or(0, |r| r.end), |r| r.start);
+            pos..pos
+        }
+    }
+}
+
+impl Normalizer for () {
+    #[inline]
+    fn encode<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(text)
+    }
+
+    #[inline]
+    fn decode<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(text)
+    }
+}
+
+impl<N: Normalizer + ?Sized> Normalizer for Box<N> {
+    #[inline]
+    fn encode<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        (**self).encode(text)
+    }
+
+    #[inline]
+    fn decode<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        (**self).decode(text)
+    }
+
+    #[inline]
+    fn encode_with_offsets<'a>(&self, text: &'a str) -> (Cow<'a, str>, Vec<Range<usize>>) {
+        (**self).encode_with_offsets(text)
+    }
+}
+
+/// 依次执行两个规范化器，解码时顺序相反。
+impl<A: Normalizer, B: Normalizer> Normalizer for (A, B) {
+    fn encode<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self.0.encode(text) {
+            Cow::Borrowed(text) => self.1.encode(text),
+            Cow::Owned(text) => Cow::Owned(self.1.encode(&text).into_owned()),
+        }
+    }
+
+    fn decode<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self.1.decode(text) {
+            Cow::Borrowed(text) => self.0.decode(text),
+            Cow::Owned(text) => Cow::Owned(self.0.decode(&text).into_owned()),
+        }
+    }
+
+    fn encode_with_offsets<'a>(&self, text: &'a str) -> (Cow<'a, str>, Vec<Range<usize>>) {
+        let (mid, map0) = self.0.encode_with_offsets(text);
+        let (ans, map1) = self.1.encode_with_offsets(&mid);
+        let map = map1.into_iter().map(|r| map_range(&map0, r)).collect();
+        (Cow::Owned(ans.into_owned()), map)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct BPECommonNormalizer;
+
+impl Normalizer for BPECommonNormalizer {
+    fn encode<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut ans = String::new();
+        if text
+            .chars()
+            .next()
+            .filter(char::is_ascii_alphabetic)
+            .is_some()
+        {
+            ans.push('▁');
+        }
+        for c in text.chars() {
+            ans.push(match c {
+                ' ' => '▁',
+                c => c,
+            });
+        }
+        Cow::Owned(ans)
+    }
+
+    fn encode_with_offsets<'a>(&self, text: &'a str) -> (Cow<'a, str>, Vec<Range<usize>>) {
+        let ans = self.encode(text);
+        // 补充的前导 `▁` 不对应原文中的任何字符
+        let prefix = ans.len() - text.len() - text.matches(' ').count() * ('▁'.len_utf8() - 1);
+        let mut map = vec![0..0; prefix];
+        for (i, c) in text.char_indices() {
+            let len = if c == ' ' {
+                '▁'.len_utf8()
+            } else {
+                c.len_utf8()
+            };
+            map.extend(std::iter::repeat(i..i + c.len_utf8()).take(len));
+        }
+        (ans, map)
+    }
+
+    #[inline]
+    fn decode<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if text.contains('▁') {
+            Cow::Owned(text.replace('▁', " "))
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+}
+
+#[test]
+fn test_load() {
+    let root = std::env::temp_dir().join("infinilm-tokenizer-load");
+    let json_dir = root.join("json");
+    let empty_dir = root.join("empty");
+    fs::create_dir_all(&json_dir).unwrap();
+    fs::create_dir_all(&empty_dir).unwrap();
+    fs::write(
+        json_dir.join("tokenizer.json"),
+        r#"{"model":{"type":"BPE","vocab":{"a":0,"b":1,"ab":2},"merges":["a b"]}}"#,
+    )
+    .unwrap();
+
+    let tokenizer = Tokenizer::load(&json_dir).unwrap();
+    assert_eq!(tokenizer.tokenize.encode("abb"), [2, 1]);
+
+    let err = Tokenizer::load(&empty_dir).err().unwrap();
+    assert!(matches!(err, TokenizerLoadError::NotFound(_)));
+    let msg = err.to_string();
+    for name in Tokenizer::CANDIDATES {
+        assert!(msg.contains(name), "{msg}");
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_load_detect_format() {
+    const PIECES: [&str; 6] = ["<unk>", "<s>", "</s>", "a", "b", "ab"];
+    // SentencePiece 的 `ModelProto`，每个词是一个 `pieces` 字段，包含文本、分数和类型
+    let model = PIECES
+        .iter()
+        .enumerate()
+        .flat_map(|(i, piece)| {
+            let ty = match i {
+                0 => 2,
+                1 | 2 => 3,
+                _ => 1,
+            };
+            let mut entry = vec![10, piece.len() as u8];
+            entry.extend(piece.as_bytes());
+            entry.push(21);
+            entry.extend((-(i as f32)).to_le_bytes());
+            entry.extend([24, ty]);
+            let mut field = vec![10, entry.len() as u8];
+            field.extend(entry);
+            field
+        })
+        .collect::<Vec<_>>();
+    let vocabs = PIECES
+        .iter()
+        .map(|piece| format!("\"{piece}\"\n"))
+        .collect::<String>();
+
+    let root = std::env::temp_dir().join("infinilm-tokenizer-detect");
+    let model_dir = root.join("model");
+    let vocabs_dir = root.join("vocabs");
+    let both_dir = root.join("both");
+    for dir in [&model_dir, &vocabs_dir, &both_dir] {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(
+            dir.join("config.json"),
+            r#"{"bos_token_id":1,"eos_token_id":2}"#,
+        )
+        .unwrap();
+    }
+    fs::write(model_dir.join("tokenizer.model"), &model).unwrap();
+    fs::write(vocabs_dir.join("vocabs.txt"), &vocabs).unwrap();
+    fs::write(both_dir.join("tokenizer.model"), &model).unwrap();
+    fs::write(both_dir.join("vocabs.txt"), "\"x\"\n").unwrap();
+
+    let check = |dir: &Path| {
+        let tokenizer = Tokenizer::load(dir).unwrap();
+        for (i, piece) in PIECES.iter().enumerate().skip(3) {
+            assert_eq!(tokenizer.tokenize.decode(i as _), *piece);
+            // 反向索引是解码的逆
+            assert_eq!(tokenizer.tokenize.token_to_id(piece), Some(i as _));
+        }
+        assert_eq!(tokenizer.tokenize.token_to_id("ba"), None);
+        // 模型的起始符和结束符是特殊词
+        assert_eq!(tokenizer.decode_skipping_special(&[1, 3, 4, 2]), "ab");
+        let opts = EncodeOptions {
+            add_bos: true,
+            add_eos: true,
+            ..Default::default()
+        };
+        assert_eq!(tokenizer.encode_with("", opts), [1, 2]);
+    };
+    check(&model_dir);
+    check(&vocabs_dir);
+    // 同时存在时按 `CANDIDATES` 的顺序优先使用 `tokenizer.model`
+    check(&both_dir);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_encode_parallel() {
+    const PIECES: [&str; 7] = ["<unk>", "▁", "a", "b", "▁a", "ab", "▁ab"];
+    let tokenizer = Indexed::new(Tokeneer::new(Lpe::new(PIECES.map(str::as_bytes), 0)));
+    assert!(tokenizer.splittable);
+    assert!(!has_inner_space("▁▁a".as_bytes()));
+    assert!(has_inner_space("a▁".as_bytes()));
+
+    // 长文本在 `▁` 之前切分后并行分词，结果与整体分词相同
+    let text = "▁ab▁▁a▁b▁aab".repeat(64);
+    assert!(split_words(&text).len() >= hf::PARALLEL_WORDS);
+    assert_eq!(
+        Tokenize::encode(&tokenizer, &text),
+        tokenizer.tokeneer.encode(&text),
+    );
+}
+
+#[test]
+fn test_encode_batch() {
+    let tokenizer: Box<dyn Tokenize + Send + Sync> = Box::new(
+        HfTokenizer::from_json(
+            br#"{"model":{"type":"BPE","vocab":{"a":0,"b":1,"c":2,"ab":3,"abc":4},"merges":["a b","ab c"]}}"#,
+        )
+        .unwrap(),
+    );
+
+    let texts = (0..1000)
+        .map(|i| ["a", "b", "c", "ab", "abc"][..=i % 5].concat())
+        .collect::<Vec<_>>();
+    let texts = texts.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let batch = tokenizer.encode_batch(&texts);
+    assert_eq!(batch.len(), texts.len());
+    for (text, tokens) in texts.iter().zip(batch) {
+        assert_eq!(tokens, tokenizer.encode(text));
+    }
+}
+
+#[test]
+fn test_decode_skipping_special() {
+    const JSON: &str = r#"{
+        "added_tokens": [
+            { "id": 2, "content": "<|user|>", "special": false },
+            { "id": 3, "content": "</s>", "special": true },
+            { "id": 4, "content": "<|user|>", "special": true }
+        ],
+        "model": {
+            "type": "BPE",
+            "vocab": { "Hi": 0, "!": 1 },
+            "merges": []
+        }
+    }"#;
+
+    let mut tokenizer = Tokenizer::from(HfTokenizer::from_json(JSON.as_bytes()).unwrap());
+    assert!(tokenizer.is_special(3));
+    assert!(tokenizer.is_special(4));
+    // 2 与特殊词 4 字面相同，但不是特殊词
+    assert_eq!(
+        tokenizer.decode_skipping_special(&[4, 0, 1, 2, 3]),
+        "Hi!<|user|>"
+    );
+
+    tokenizer.register_special(1);
+    assert_eq!(tokenizer.decode_skipping_special(&[4, 0, 1, 3]), "Hi");
+}
+
+#[test]
+fn test_decode_sequence() {
+    use mock::Pieces;
+
+    let pieces = Pieces(&[
+        "▁Hello", "▁,", "▁world", "▁!", "▁", "<0xE4>", "<0xBD>", "<0xA0>", "好", ".", "a",
+    ]);
+    assert_eq!(
+        pieces.decode_sequence(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+        "Hello, world! 你好."
+    );
+    // 开头的词没有 `▁` 时不去掉字符
+    assert_eq!(pieces.decode_sequence(&[10, 2, 9]), "a world.");
+    assert_eq!(pieces.decode_sequence(&[]), "");
+}
+
+#[test]
+fn test_with_unicode() {
+    let tokenizer = Tokenizer::from(
+        HfTokenizer::from_json(
+            r#"{"model":{"type":"BPE","vocab":{"é":0,"e":1,"́":2},"merges":[]}}"#.as_bytes(),
+        )
+        .unwrap(),
+    );
+    let encode = |tokenizer: &Tokenizer, text: &str| {
+        tokenizer
+            .tokenize
+            .encode(&tokenizer.normalizer.encode(text))
+    };
+    assert_eq!(encode(&tokenizer, "e\u{301}"), [1, 2]);
+
+    let tokenizer = tokenizer.with_unicode(UnicodeNormalizer::new().with_form(UnicodeForm::Nfc));
+    assert_eq!(encode(&tokenizer, "e\u{301}"), [0]);
+    assert_eq!(encode(&tokenizer, "\u{e9}"), [0]);
+}
+
+#[test]
+fn test_tokenizer_encode_with_offsets() {
+    const JSON: &str = r#"{
+        "normalizer": { "type": "Replace", "pattern": { "String": " " }, "content": "▁" },
+        "model": {
+            "type": "BPE",
+            "vocab": {
+                "▁": 0, "h": 1, "i": 2, "▁h": 3, "▁hi": 4, "c": 5, "a": 6, "f": 7, "e": 8,
+                "́": 9, "ca": 10, "caf": 11, "<0xE4>": 12, "<0xBD>": 13, "<0xA0>": 14
+            },
+            "merges": ["▁ h", "▁h i", "c a", "ca f"],
+            "byte_fallback": true
+        }
+    }"#;
+    let tokenizer = Tokenizer::from(HfTokenizer::from_json(JSON.as_bytes()).unwrap());
+    let pieces = |text: &str, tokenizer: &Tokenizer| {
+        tokenizer
+            .encode_with_offsets(text)
+            .into_iter()
+            .map(|(_, range)| &text[range])
+            .collect::<Vec<_>>()
+    };
+
+    // 第一个 `▁` 是补充的，第一个词只覆盖 `hi`
+    let text = "hi hi";
+    let tokens = tokenizer.encode_with_offsets(text);
+    assert_eq!(tokens.iter().map(|(t, _)| *t).collect::<Vec<_>>(), [4, 4]);
+    assert_eq!(pieces(text, &tokenizer), ["hi", " hi"]);
+
+    // 字节回退的三个词都对应同一个字符
+    assert_eq!(pieces("hi你", &tokenizer), ["hi", "你", "你", "你"]);
+
+    // NFD 改变了长度，分解出的重音仍对应原文的 `é`
+    let tokenizer = tokenizer.with_unicode(UnicodeNormalizer::new().with_form(UnicodeForm::Nfd));
+    let text = "caf\u{e9} hi";
+    let tokens = tokenizer.encode_with_offsets(text);
+    assert_eq!(
+        tokens.iter().map(|(t, _)| *t).collect::<Vec<_>>(),
+        [0, 11, 8, 9, 4]
+    );
+    assert_eq!(
+        pieces(text, &tokenizer),
+        ["", "caf", "\u{e9}", "\u{e9}", " hi"]
+    );
+}
+
+#[test]
+fn test_encode_with() {
+    let mut tokenizer = Tokenizer::from(
+        HfTokenizer::from_json(
+            br#"{"model":{"type":"BPE","vocab":{"<s>":0,"</s>":1,"<pad>":2,"a":3,"b":4,"c":5},"merges":[]}}"#,
+        )
+        .unwrap(),
+    );
+    tokenizer.set_bos(0);
+    tokenizer.set_eos(1);
+    assert!(tokenizer.is_special(0) && tokenizer.is_special(1));
+
+    let opts = EncodeOptions {
+        add_bos: true,
+        add_eos: true,
+        ..Default::default()
+    };
+    assert_eq!(tokenizer.encode_with("abc", opts), [0, 3, 4, 5, 1]);
+
+    // 右截断保留起始符和结束符
+    let truncated = EncodeOptions {
+        max_len: Some(4),
+        ..opts
+    };
+    assert_eq!(tokenizer.encode_with("abcabc", truncated), [0, 3, 4, 1]);
+    let left = EncodeOptions {
+        truncation: Truncation::Left,
+        ..truncated
+    };
+    assert_eq!(tokenizer.encode_with("abcabc", left), [0, 4, 5, 1]);
+    let bos_only = EncodeOptions {
+        add_eos: false,
+        ..truncated
+    };
+    assert_eq!(tokenizer.encode_with("abcabc", bos_only), [0, 3, 4, 5]);
+
+    // 填充到目标长度
+    let padded = EncodeOptions {
+        max_len: Some(6),
+        padding: Some(2),
+        ..opts
+    };
+    assert_eq!(tokenizer.encode_with("ab", padded), [0, 3, 4, 1, 2, 2]);
+    assert_eq!(tokenizer.encode_with("abcabc", padded), [0, 3, 4, 5, 3, 1]);
+}
+
+#[test]
+fn test_add_special_token() {
+    let mut tokenizer = Tokenizer::from(
+        HfTokenizer::from_json(
+            br#"{"model":{"type":"BPE","vocab":{"<":0,">":1,"_":2,"t":3,"o":4,"l":5,"c":6,"a":7,"to":8},"merges":["t o"]}}"#,
+        )
+        .unwrap(),
+    );
+    let text = "a<tool_call>to";
+    assert_eq!(tokenizer.encode(text), [7, 0, 8, 4, 5, 2, 6, 7, 5, 5, 1, 8]);
+
+    tokenizer.add_special_token("<tool_call>", 100).unwrap();
+    assert_eq!(tokenizer.encode(text), [7, 100, 8]);
+    assert_eq!(tokenizer.decode(100), "<tool_call>");
+    assert!(tokenizer.is_special(100));
+    let offsets = tokenizer.encode_with_offsets(text);
+    assert_eq!(offsets, [(7, 0..1), (100, 1..12), (8, 12..14)]);
+
+    // 重复注册相同的词不报错
+    tokenizer.add_special_token("<tool_call>", 100).unwrap();
+    assert_eq!(
+        tokenizer.add_special_token("<tool_call>", 101),
+        Err(SpecialTokenError::TextInUse {
+            text: "<tool_call>".into(),
+            token: 100
+        })
+    );
+    assert_eq!(
+        tokenizer.add_special_token("</tool_call>", 100),
+        Err(SpecialTokenError::IdInUse {
+            token: 100,
+            text: "<tool_call>".into()
+        })
+    );
+    // 与词表中已有的词冲突
+    assert_eq!(
+        tokenizer.add_special_token("to", 102),
+        Err(SpecialTokenError::TextInUse {
+            text: "to".into(),
+            token: 8
+        })
+    );
+}
@@ -0,0 +1,104 @@
+use std::{collections::HashMap, ops::Range};
+use tokeneer::utok;
+
+/// 运行时注册的特殊词，用字典树做最长匹配。
+#[derive(Default)]
+pub(super) struct SpecialTrie {
+    nodes: Vec<Node>,
+}
+
+#[derive(Default)]
+struct Node {
+    next: HashMap<u8, usize>,
+    token: Option<utok>,
+}
+
+impl SpecialTrie {
+    /// 插入 `text`，已存在时返回原有的词并保持不变。
+    pub fn insert(&mut self, text: &str, token: utok) -> Option<utok> {
+        assert!(!text.is_empty());
+        if self.nodes.is_empty() {
+            self.nodes.push(Node::default());
+        }
+        let mut node = 0;
+        for b in text.bytes() {
+            node = match self.nodes[node].next.get(&b) {
+                Some(&next) => next,
+                None => {
+                    self.nodes.push(Node::default());
+                    let next = self.nodes.len() - 1;
+                    self.nodes[node].next.insert(b, next);
+                    next
+                }
+            };
+        }
+        let existing = self.nodes[node].token;
+        self.nodes[node].token.get_or_insert(token);
+        existing
+    }
+
+    /// 从 `text` 开头匹配最长的特殊词，返回长度和序号。
+    fn longest(&self, text: &[u8]) -> Option<(usize, utok)> {
+        let mut node = 0;
+        let mut ans = None;
+        for (i, b) in text.iter().enumerate() {
+            match self.nodes[node].next.get(b) {
+                Some(&next) => node = next,
+                None => break,
+            }
+            if let Some(token) = self.nodes[node].token {
+                ans = Some((i + 1, token));
+            }
+        }
+        ans
+    }
+
+    /// 把 `text` 切分为普通片段和特殊词，普通片段的序号为 `None`。
+    pub fn split(&self, text: &str) -> Vec<(Range<usize>, Option<utok>)> {
+        let mut ans = Vec::new();
+        if self.nodes.is_empty() {
+            if !text.is_empty() {
+                ans.push((0..text.len(), None));
+            }
+            return ans;
+        }
+
+        let bytes = text.as_bytes();
+        let mut start = 0;
+        let mut i = 0;
+        while i < text.len() {
+            if let Some((len, token)) = self.longest(&bytes[i..]) {
+                if start < i {
+                    ans.push((start..i, None));
+                }
+                ans.push((i..i + len, Some(token)));
+                i += len;
+                start = i;
+            } else {
+                i += text[i..].chars().next().unwrap().len_utf8();
+            }
+        }
+        if start < text.len() {
+            ans.push((start..text.len(), None));
+        }
+        ans
+    }
+}
+
+#[test]
+fn test_split() {
+    let mut trie = SpecialTrie::default();
+    assert_eq!(trie.split("ab"), [(0..2, None)]);
+    assert_eq!(trie.insert("<a>", 10), None);
+    assert_eq!(trie.insert("<a><b>", 11), None);
+    assert_eq!(trie.insert("<a>", 12), Some(10));
+    assert_eq!(
+        trie.split("x<a>你<a><b>"),
+        [
+            (0..1, None),
+            (1..4, Some(10)),
+            (4..7, None),
+            (7..13, Some(11)),
+        ]
+    );
+}
@@ -0,0 +1,159 @@
+use super::{hf::parse_byte, Tokenize};
+use std::{
+    mem::take,
+    str::{from_utf8, from_utf8_unchecked},
+};
+use tokeneer::utok;
+
+/// 流式解码器，将逐个到达的词拼接为完整的文本。
+///
+/// 字节回退词（`<0xNN>`）和跨词的多字节字符会被缓存，直到构成完整的 UTF-8 序列；
+/// SentencePiece 的 `▁` 会被转换为空格。
+pub struct StreamDecoder<'a, T: ?Sized> {
+    tokenizer: &'a T,
+    buf: Vec<u8>,
+    policy: FlushPolicy,
+}
+
+/// 不完整字节的刷新策略。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub enum FlushPolicy {
+    /// 一直缓存，直到字节构成完整的字符。
+    #[default]
+    Wait,
+    /// 缓存超过指定字节数时，以替换字符（`U+FFFD`）输出并清空缓存。
+    MaxPending(usize),
+}
+
+impl<'a, T: Tokenize + ?Sized> StreamDecoder<'a, T> {
+    #[inline]
+    pub fn new(tokenizer: &'a T) -> Self {
+        Self {
+            tokenizer,
+            buf: Vec::new(),
+            policy: FlushPolicy::Wait,
+        }
+    }
+
+    /// 设置不完整字节的刷新策略。
+    #[inline]
+    pub fn with_policy(self, policy: FlushPolicy) -> Self {
+        Self { policy, ..self }
+    }
+
+    /// 解码一个词，返回可以输出的文本；仍有未完成的字节时可能返回空字符串。
+    pub fn push(&mut self, token: utok) -> String {
+        const SPACE: &[u8] = "▁".as_bytes();
+
+        // 按字节解码，不完整的多字节字符留在缓存中
+        let piece = self.tokenizer.decode_bytes(token);
+        if let Some(b) = from_utf8(piece).ok().and_then(parse_byte) {
+            self.buf.push(b);
+        } else {
+            let mut bytes = piece;
+            while let Some((&b, tail)) = bytes.split_first() {
+                if let Some(tail) = bytes.strip_prefix(SPACE) {
+                    self.buf.push(b' ');
+                    bytes = tail;
+                } else {
+                    self.buf.push(b);
+                    bytes = tail;
+                }
+            }
+        }
+        self.take_valid()
+    }
+
+    /// 以替换字符输出所有缓存的字节。
+    #[inline]
+    pub fn flush(&mut self) -> String {
+        String::from_utf8_lossy(&take(&mut self.buf)).into_owned()
+    }
+
+    /// 是否有未输出的字节。
+    #[inline]
+    pub fn is_pending(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    /// 取出缓存中所有完整的字符，非法字节替换为 `U+FFFD`，末尾不完整的字节继续保留。
+    fn take_valid(&mut self) -> String {
+        let mut ans = String::new();
+        loop {
+            match from_utf8(&self.buf) {
+                Ok(s) => {
+                    ans.push_str(s);
+                    self.buf.clear();
+                    break;
+                }
+                Err(e) => {
+                    let len = e.valid_up_to();
+                    ans.push_str(unsafe { from_utf8_unchecked(&self.buf[..len]) });
+                    match e.error_len() {
+                        Some(n) => {
+                            ans.push(char::REPLACEMENT_CHARACTER);
+                            self.buf.drain(..len + n);
+                        }
+                        None => {
+                            self.buf.drain(..len);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        if let FlushPolicy::MaxPending(max) = self.policy {
+            if self.buf.len() > max {
+                ans.push_str(&String::from_utf8_lossy(&take(&mut self.buf)));
+            }
+        }
+        ans
+    }
+}
+
+#[test]
+fn test() {
+    use super::mock::Pieces;
+
+    let tokenizer = Pieces(&[
+        "▁Hello", "▁world", "!", "<0xF0>", "<0x9F>", "<0x98>", "<0x80>",
+    ]);
+
+    // 词首空格
+    let mut decoder = StreamDecoder::new(&tokenizer);
+    assert_eq!(decoder.push(0), " Hello");
+    assert_eq!(decoder.push(1), " world");
+    assert_eq!(decoder.push(2), "!");
+    assert!(!decoder.is_pending());
+
+    // 😀 = F0 9F 98 80，由多个字节词组成
+    let mut decoder = StreamDecoder::new(&tokenizer);
+    assert_eq!(decoder.push(3), "");
+    assert_eq!(decoder.push(4), "");
+    assert!(decoder.is_pending());
+    assert_eq!(decoder.push(5), "");
+    assert_eq!(decoder.push(6), "😀");
+    assert!(!decoder.is_pending());
+}
+
+#[test]
+fn test_flush_policy() {
+    use super::mock::Pieces;
+
+    let bytes = Pieces(&["<0xF0>", "<0x9F>", "<0x98>", "a"]);
+
+    // 不完整的 4 字节字符超过 2 字节上限时被替换输出
+    let mut decoder = StreamDecoder::new(&bytes).with_policy(FlushPolicy::MaxPending(2));
+    assert_eq!(decoder.push(0), "");
+    assert_eq!(decoder.push(1), "");
+    assert_eq!(decoder.push(2), "\u{FFFD}");
+    assert!(!decoder.is_pending());
+    assert_eq!(decoder.push(3), "a");
+
+    // 默认策略一直等待
+    let mut decoder = StreamDecoder::new(&bytes);
+    for token in 0..3 {
+        assert_eq!(decoder.push(token), "");
+    }
+    assert!(decoder.is_pending());
+}
@@ -0,0 +1,121 @@
+use super::Normalizer;
+use std::borrow::Cow;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Unicode 规范化形式。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum UnicodeForm {
+    /// 标准组合形式。
+    Nfc,
+    /// 标准分解形式。
+    Nfd,
+}
+
+/// 在分词前执行 Unicode 规范化、大小写折叠和去除重音。
+///
+/// 默认不做任何处理，通过 [`with_form`](Self::with_form) 等方法逐项开启。
+/// 只改变文本本身，词表内没有的字符仍然交给分词器做字节回退。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub struct UnicodeNormalizer {
+    form: Option<UnicodeForm>,
+    lowercase: bool,
+    strip_accents: bool,
+}
+
+impl UnicodeNormalizer {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 设置规范化形式。
+    #[inline]
+    pub fn with_form(self, form: UnicodeForm) -> Self {
+        Self {
+            form: Some(form),
+            ..self
+        }
+    }
+
+    /// 设置是否转换为小写。
+    #[inline]
+    pub fn with_lowercase(self, lowercase: bool) -> Self {
+        Self { lowercase, ..self }
+    }
+
+    /// 设置是否去除重音，即分解后丢弃所有组合符号。
+    #[inline]
+    pub fn with_strip_accents(self, strip_accents: bool) -> Self {
+        Self {
+            strip_accents,
+            ..self
+        }
+    }
+}
+
+impl Normalizer for UnicodeNormalizer {
+    fn encode<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        if self.strip_accents && text.nfd().any(is_combining_mark) {
+            text = Cow::Owned(text.nfd().filter(|&c| !is_combining_mark(c)).collect());
+        }
+        match self.form {
+            Some(UnicodeForm::Nfc) => text = Cow::Owned(text.nfc().collect()),
+            Some(UnicodeForm::Nfd) => text = Cow::Owned(text.nfd().collect()),
+            None => {}
+        }
+        if self.lowercase && text.chars().any(char::is_uppercase) {
+            text = Cow::Owned(text.to_lowercase());
+        }
+        text
+    }
+
+    #[inline]
+    fn decode<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(text)
+    }
+}
+
+#[test]
+fn test_unicode_normalizer() {
+    use super::{BPECommonNormalizer, HfTokenizer, Tokenize};
+
+    const JSON: &str = r#"{
+        "normalizer": { "type": "Replace", "pattern": { "String": " " }, "content": "▁" },
+        "model": {
+            "type": "BPE",
+            "vocab": {
+                "<unk>": 0, "▁": 1, "c": 2, "a": 3, "f": 4, "é": 5, "e": 6,
+                "<0xC3>": 7, "<0xB1>": 8, "<0xCC>": 9, "<0x81>": 10,
+                "ca": 11, "caf": 12, "café": 13
+            },
+            "merges": ["c a", "ca f", "caf é"],
+            "unk_token": "<unk>",
+            "byte_fallback": true
+        }
+    }"#;
+    let tokenizer = HfTokenizer::from_json(JSON.as_bytes()).unwrap();
+    let encode = |normalizer: &dyn Normalizer, text: &str| {
+        tokenizer.encode(&BPECommonNormalizer.encode(&normalizer.encode(text)))
+    };
+
+    let composed = "caf\u{e9}";
+    let decomposed = "cafe\u{301}";
+    let nfc = UnicodeNormalizer::new().with_form(UnicodeForm::Nfc);
+    assert_eq!(encode(&nfc, composed), [1, 13]);
+    assert_eq!(encode(&nfc, decomposed), [1, 13]);
+    // 不规范化时分解形式只能按字节回退
+    assert_eq!(encode(&(), decomposed), [1, 12, 6, 9, 10]);
+
+    // 词表中没有的 `ñ` 组合后仍走字节回退
+    assert_eq!(encode(&nfc, "n\u{303}"), [7, 8]);
+
+    let nfd = UnicodeNormalizer::new().with_form(UnicodeForm::Nfd);
+    assert_eq!(nfd.encode(composed), decomposed);
+
+    let plain = UnicodeNormalizer::new()
+        .with_lowercase(true)
+        .with_strip_accents(true);
+    assert_eq!(plain.encode("CAF\u{c9} Déjà"), "cafe deja");
+    assert!(matches!(plain.encode("cafe"), Cow::Borrowed(_)));
+}
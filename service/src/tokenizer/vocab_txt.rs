@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+use tokeneer::utok;
+
+/// `vocabs.txt` 中的词表，每行一个以双引号包围的词，词的序号即其所在的有效行序号。
+///
+/// 空行和以 `#` 开头的注释行不占用序号。
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct VocabTxt {
+    /// 词 -> 字节串。
+    pieces: Vec<Box<[u8]>>,
+    /// 字节串 -> 词，重复的字节串取第一个。
+    ids: HashMap<Box<[u8]>, utok>,
+}
+
+/// 解析 `vocabs.txt` 时的错误。
+#[derive(Debug)]
+pub enum VocabTxtError {
+    /// IO 错误。
+    Io(io::Error),
+    /// 某一行不是以双引号包围的词，`line` 从 1 开始计数。
+    Malformed { line: usize },
+}
+
+impl fmt::Display for VocabTxtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "failed to read vocabs.txt: {e}"),
+            Self::Malformed { line } => write!(f, "malformed piece at vocabs.txt line {line}"),
+        }
+    }
+}
+
+impl std::error::Error for VocabTxtError {}
+
+impl VocabTxt {
+    /// 解析已经完整读入内存的 `vocabs.txt`。
+    pub fn from_bytes(txt: &[u8]) -> Result<Self, VocabTxtError> {
+        let mut ans = Self::default();
+        for (i, line) in txt.split(|&b| b == b'\n').enumerate() {
+            ans.push_line(i + 1, line)?;
+        }
+        Ok(ans)
+    }
+
+    /// 逐行读取 `vocabs.txt`，不在内存中保留文件原文。
+    pub fn load_streaming(path: impl AsRef<Path>) -> Result<Self, VocabTxtError> {
+        let mut reader = BufReader::new(File::open(path).map_err(VocabTxtError::Io)?);
+        let mut ans = Self::default();
+        let mut line = Vec::new();
+        for i in 1.. {
+            line.clear();
+            if reader
+                .read_until(b'\n', &mut line)
+                .map_err(VocabTxtError::Io)?
+                == 0
+            {
+                break;
+            }
+            let content = line.strip_suffix(b"\n").unwrap_or(&line);
+            ans.push_line(i, content)?;
+        }
+        Ok(ans)
+    }
+
+    fn push_line(&mut self, i: usize, line: &[u8]) -> Result<(), VocabTxtError> {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.trim_ascii().is_empty() || line.starts_with(b"#") {
+            return Ok(());
+        }
+        let piece = line
+            .strip_prefix(b"\"")
+            .and_then(|l| l.strip_suffix(b"\""))
+            .ok_or(VocabTxtError::Malformed { line: i })?;
+        let piece = Box::<[u8]>::from(piece);
+        self.ids
+            .entry(piece.clone())
+            .or_insert(self.pieces.len() as _);
+        self.pieces.push(piece);
+        Ok(())
+    }
+
+    /// 词表的大小。
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// 词表是否为空。
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+
+    /// 按序号排列的所有词。
+    #[inline]
+    pub fn pieces(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        self.pieces.iter().map(|p| &**p)
+    }
+
+    /// 词的字节串。
+    #[inline]
+    pub fn piece(&self, token: utok) -> Option<&[u8]> {
+        self.pieces.get(token as usize).map(|p| &**p)
+    }
+
+    /// 字节串对应的词。
+    #[inline]
+    pub fn token_to_id(&self, piece: &[u8]) -> Option<utok> {
+        self.ids.get(piece).copied()
+    }
+}
+
+#[test]
+fn test_vocab_txt_streaming() {
+    use std::fs;
+
+    let txt = "\"<unk>\"\n# comment\n\n\"a\"\r\n\"b c\"\n\"a\"\n";
+    let eager = VocabTxt::from_bytes(txt.as_bytes()).unwrap();
+    assert_eq!(eager.len(), 4);
+    assert_eq!(eager.piece(2), Some(&b"b c"[..]));
+    // 重复的词保留第一个序号
+    assert_eq!(eager.token_to_id(b"a"), Some(1));
+    assert_eq!(eager.token_to_id(b"missing"), None);
+
+    let dir = std::env::temp_dir().join("infinilm-vocab-txt");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("vocabs.txt");
+    fs::write(&path, txt).unwrap();
+    assert_eq!(VocabTxt::load_streaming(&path).unwrap(), eager);
+
+    fs::write(&path, "\"a\"\n\n\"b\n").unwrap();
+    assert!(matches!(
+        VocabTxt::load_streaming(&path),
+        Err(VocabTxtError::Malformed { line: 3 })
+    ));
+    assert!(matches!(
+        VocabTxt::from_bytes(b"b"),
+        Err(VocabTxtError::Malformed { line: 1 })
+    ));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_vocab_txt_matches_lpe() {
+    use tokeneer::{Lpe, Method};
+
+    // 引号和反斜杠都按原样保留在词中
+    let txt = concat!(
+        "\"<unk>\"\n",
+        "\"\"\"\n",
+        "\"a\"b\"\n",
+        "\"\\n\"\r\n",
+        "\"\\\"\"\n",
+        "\"#\"\n",
+        "\" \"\n",
+        "\"▁你好\"\n",
+        "\"a\"\n",
+    );
+    let vocab = VocabTxt::from_bytes(txt.as_bytes()).unwrap();
+    let expected = Lpe::from_vocabs_txt(txt.as_bytes());
+    let actual = Lpe::new(vocab.pieces(), 0);
+
+    assert_eq!(vocab.len(), expected.vocab_size());
+    assert_eq!(actual.vocab_size(), expected.vocab_size());
+    for i in 0..vocab.len() as utok {
+        assert_eq!(actual.decode(i), expected.decode(i));
+        assert_eq!(vocab.piece(i), Some(expected.decode(i)));
+    }
+    assert_eq!(vocab.piece(2), Some(&br#"a"b"#[..]));
+    assert_eq!(vocab.piece(3), Some(&br"\n"[..]));
+
+    let text = "a\"b\\n# ▁你好\"";
+    assert_eq!(
+        actual.encode(text).into_iter().collect::<Vec<_>>(),
+        expected.encode(text).into_iter().collect::<Vec<_>>(),
+    );
+}
@@ -0,0 +1,125 @@
+use crate::{expand_indices, idx_strides, reslice_mut, Tensor};
+use digit_layout::types::{F16, F32};
+use half::f16;
+use rayon::iter::{IntoParallelIterator, IntoParallelRefMutIterator, ParallelIterator};
+use std::{f32::consts::FRAC_2_PI, iter::zip, ops::DerefMut};
+
+impl<Physical: DerefMut<Target = [u8]>> Tensor<Physical> {
+    /// 原地把每个元素限制在 `[min, max]` 内。
+    pub fn clamp(&mut self, min: f32, max: f32) {
+        assert!(min <= max, "invalid clamp range [{min}, {max}]");
+        self.map_inplace(|x| x.clamp(min, max))
+    }
+
+    /// 原地计算 ReLU，即 `max(x, 0)`。
+    #[inline]
+    pub fn relu(&mut self) {
+        self.map_inplace(|x| x.max(0.))
+    }
+
+    /// 原地计算 SiLU，即 `x * sigmoid(x)`。
+    #[inline]
+    pub fn silu(&mut self) {
+        self.map_inplace(|x| x / (1. + (-x).exp()))
+    }
+
+    /// 原地计算 GELU，使用 tanh 近似。
+    #[inline]
+    pub fn gelu(&mut self) {
+        let k = FRAC_2_PI.sqrt();
+        self.map_inplace(|x| 0.5 * x * (1. + (k * (x + 0.044715 * x * x * x)).tanh()))
+    }
+
+    /// 对每个逻辑元素原地执行 `f`，F16 转换为 f32 计算后舍入。
+    fn map_inplace(&mut self, f: impl Fn(f32) -> f32 + Sync) {
+        match self.layout {
+            F16 => self.map_elements(|x: f16| f16::from_f32(f(x.to_f32()))),
+            F32 => self.map_elements(&f),
+            dt => panic!("unsupported data type: {dt:?}"),
+        }
+    }
+
+    fn map_elements<T: Copy + Send + Sync>(&mut self, f: impl Fn(T) -> T + Sync) {
+        if self.is_contiguous() {
+            reslice_mut::<u8, T>(self.as_mut_slice())
+                .par_iter_mut()
+                .for_each(|x| *x = f(*x));
+            return;
+        }
+        // 广播的维度上多个逻辑元素共享存储，原地修改会重复执行
+        assert!(
+            zip(&*self.shape, self.strides()).all(|(&d, &s)| d == 1 || s != 0),
+            "cannot modify broadcast tensor in place"
+        );
+        // 裸指针不能在线程间传递，以地址形式捕获
+        let base = self.base_mut() as usize;
+        let strides = self.strides();
+        let (n, idx_strides) = idx_strides(&self.shape);
+        (0..n).into_par_iter().for_each(|i| {
+            let indices = expand_indices(i, &idx_strides, &[]);
+            let offset = zip(indices.iter(), strides)
+                .map(|(&i, &s)| i as isize * s as isize)
+                .sum::<isize>();
+            unsafe {
+                let x = (base as *mut T).offset(offset);
+                x.write_unaligned(f(x.read_unaligned()));
+            }
+        });
+    }
+}
+
+#[test]
+fn test() {
+    use crate::reslice;
+
+    let data = [-3.0f32, -0.5, 0., 0.25, 1., 4.];
+    let new = || {
+        let mut t = Tensor::alloc(F32, &[2, 3], |len| vec![0u8; len]);
+        reslice_mut::<u8, f32>(t.physical_mut()).copy_from_slice(&data);
+        t
+    };
+    let values = |t: &Tensor<Vec<u8>>| reslice::<u8, f32>(t.physical()).to_vec();
+
+    let mut t = new();
+    t.silu();
+    for (y, x) in zip(values(&t), data) {
+        assert!((y - x * (1. / (1. + (-x).exp()))).abs() < 1e-6);
+    }
+
+    let mut t = new();
+    t.clamp(-1., 0.5);
+    assert_eq!(values(&t), [-1., -0.5, 0., 0.25, 0.5, 0.5]);
+    assert!(values(&t).iter().all(|x| (-1. ..=0.5).contains(x)));
+
+    let mut t = new();
+    t.relu();
+    assert_eq!(values(&t), [0., 0., 0., 0.25, 1., 4.]);
+
+    let mut t = new();
+    t.gelu();
+    let y = values(&t);
+    assert_eq!(y[2], 0.);
+    assert!((y[4] - 0.841192).abs() < 1e-5);
+    assert!((y[5] - 4.).abs() < 1e-3);
+
+    // 转置后的非连续张量按逻辑元素处理，结果与连续时一致
+    let mut t = new().transpose(&[1, 0]);
+    assert!(!t.is_contiguous());
+    t.clamp(-1., 0.5);
+    assert_eq!(values(&t), [-1., -0.5, 0., 0.25, 0.5, 0.5]);
+
+    let mut t = Tensor::new(
+        F16,
+        &[3],
+        [-2., 0.5, 3.]
+            .map(f16::from_f32)
+            .map(f16::to_bits)
+            .map(u16::to_le_bytes)
+            .concat(),
+    );
+    t.relu();
+    assert_eq!(
+        reslice::<u8, f16>(t.physical()),
+        [0., 0.5, 3.].map(f16::from_f32)
+    );
+}
@@ -0,0 +1,44 @@
+use crate::Tensor;
+use std::ops::DerefMut;
+
+impl<Physical: DerefMut<Target = [u8]>> Tensor<Physical> {
+    /// 原地翻转每个元素的字节序，用于在大小端之间转换数据，单字节类型不变。
+    pub fn byteswap_inplace(&mut self) {
+        let width = self.layout.nbytes();
+        if width <= 1 {
+            return;
+        }
+        assert!(self.is_contiguous(), "byteswap requires contiguous tensor");
+        for x in self.as_mut_slice().chunks_exact_mut(width) {
+            x.reverse();
+        }
+    }
+}
+
+#[test]
+fn test() {
+    use crate::{reslice, reslice_mut};
+    use digit_layout::types::{F16, F32, U8};
+
+    let data = [1.5f32, -2., 3.25e-3, f32::MAX];
+    let mut t = Tensor::alloc(F32, &[2, 2], |len| vec![0u8; len]);
+    reslice_mut::<u8, f32>(t.physical_mut()).copy_from_slice(&data);
+
+    t.byteswap_inplace();
+    let swapped = reslice::<u8, f32>(t.as_slice())
+        .iter()
+        .map(|x| f32::from_bits(x.to_bits().swap_bytes()))
+        .collect::<Vec<_>>();
+    assert_eq!(swapped, data);
+
+    t.byteswap_inplace();
+    assert_eq!(reslice::<u8, f32>(t.as_slice()), data);
+
+    let mut t = Tensor::new(F16, &[2], vec![1u8, 2, 3, 4]);
+    t.byteswap_inplace();
+    assert_eq!(t.as_slice(), [2, 1, 4, 3]);
+
+    let mut t = Tensor::new(U8, &[2], vec![1u8, 2]);
+    t.byteswap_inplace();
+    assert_eq!(t.as_slice(), [1, 2]);
+}
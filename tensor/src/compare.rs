@@ -0,0 +1,131 @@
+use crate::{expand_indices, idx_strides, Tensor};
+use digit_layout::types::{BF16, F16, F32};
+use half::{bf16, f16};
+use std::{iter::zip, ops::Deref, slice::from_raw_parts};
+
+impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
+    /// 形状、数据类型相同，且按逻辑顺序每个元素的值都相同。
+    ///
+    /// 比较的是模式变换后的逻辑元素，转置的张量与其连续存储的副本相等。
+    pub fn logical_eq<U: Deref<Target = [u8]>>(&self, other: &Tensor<U>) -> bool {
+        if self.layout != other.layout || self.shape() != other.shape() {
+            return false;
+        }
+        let size = self.layout.nbytes();
+        let (a, b) = (self.base(), other.base());
+        zip(self.logical_offsets(), other.logical_offsets()).all(|(i, j)| unsafe {
+            from_raw_parts(a.offset(i), size) == from_raw_parts(b.offset(j), size)
+        })
+    }
+
+    /// 形状、数据类型相同，且逐元素满足 `|a - b| <= atol + rtol * |b|`。
+    ///
+    /// 只支持 F16、BF16 和 F32，NaN 与任何值都不相等。
+    pub fn approx_eq<U: Deref<Target = [u8]>>(
+        &self,
+        other: &Tensor<U>,
+        atol: f32,
+        rtol: f32,
+    ) -> bool {
+        if self.layout != other.layout || self.shape() != other.shape() {
+            return false;
+        }
+        zip(self.logical_values(), other.logical_values())
+            .all(|(a, b)| a == b || (a - b).abs() <= atol + rtol * b.abs())
+    }
+
+    /// 按逻辑顺序读出所有元素并转换为 f32，只支持 F16、BF16 和 F32。
+    pub fn to_vec_f32(&self) -> Vec<f32> {
+        self.logical_values().collect()
+    }
+
+    /// 按逻辑顺序读出所有元素。
+    pub(crate) fn logical_values(&self) -> impl Iterator<Item = f32> + '_ {
+        let read: fn(*const u8) -> f32 = match self.layout {
+            F16 => |p| unsafe { p.cast::<f16>().read_unaligned() }.to_f32(),
+            BF16 => |p| unsafe { p.cast::<bf16>().read_unaligned() }.to_f32(),
+            F32 => |p| unsafe { p.cast::<f32>().read_unaligned() },
+            dt => panic!("unsupported data type: {dt:?}"),
+        };
+        let base = self.base();
+        self.logical_offsets()
+            .map(move |offset| read(unsafe { base.offset(offset) }))
+    }
+
+    /// 按逻辑顺序生成每个元素相对 [`base`](Self::base) 的字节偏移。
+    pub(crate) fn logical_offsets(&self) -> impl Iterator<Item = isize> + '_ {
+        let size = self.layout.nbytes() as isize;
+        let strides = self.strides();
+        let (n, idx_strides) = idx_strides(&self.shape);
+        (0..n).map(move |i| {
+            let indices = expand_indices(i, &idx_strides, &[]);
+            zip(indices.iter(), strides)
+                .map(|(&i, &s)| i as isize * s as isize)
+                .sum::<isize>()
+                * size
+        })
+    }
+}
+
+#[test]
+fn test_logical_eq() {
+    use crate::reslice;
+
+    let data = [0.0f32, 1., 2., 3., 4., 5.];
+    let a = Tensor::new(F32, &[2, 3], reslice::<f32, u8>(&data));
+    // 按列存储同样的值，转置后逻辑上与 `a` 相同
+    let data_t = [0.0f32, 3., 1., 4., 2., 5.];
+    let b = Tensor::new(F32, &[3, 2], reslice::<f32, u8>(&data_t)).transpose(&[1, 0]);
+    assert!(a.logical_eq(&b));
+    assert!(b.logical_eq(&a));
+    assert!(a.approx_eq(&b, 0., 0.));
+
+    // 存储相同但逻辑不同
+    let c = Tensor::new(F32, &[2, 3], reslice::<f32, u8>(&data_t));
+    assert!(!a.logical_eq(&c));
+    // 形状或数据类型不同
+    let d = Tensor::new(F32, &[3, 2], reslice::<f32, u8>(&data));
+    assert!(!a.logical_eq(&d));
+    let e = Tensor::new(digit_layout::types::U32, &[2, 3], reslice::<f32, u8>(&data));
+    assert!(!a.logical_eq(&e));
+}
+
+#[test]
+fn test_approx_eq() {
+    use crate::reslice;
+
+    let data = [1.0f32, 2., 3., 4.];
+    let near = [1.0f32, 2.001, 3., 4.];
+    let a = Tensor::new(F32, &[2, 2], reslice::<f32, u8>(&data));
+    let b = Tensor::new(F32, &[2, 2], reslice::<f32, u8>(&near));
+    assert!(!a.logical_eq(&b));
+    assert!(!a.approx_eq(&b, 1e-4, 0.));
+    assert!(a.approx_eq(&b, 1e-2, 0.));
+    assert!(a.approx_eq(&b, 0., 1e-3));
+
+    let nan = [1.0f32, f32::NAN, 3., 4.];
+    let c = Tensor::new(F32, &[2, 2], reslice::<f32, u8>(&nan));
+    assert!(!c.approx_eq(&c, 1., 1.));
+
+    let data = [f16::ONE, f16::from_f32(0.5), f16::ZERO, f16::NEG_ONE];
+    let a = Tensor::new(F16, &[2, 2], reslice::<f16, u8>(&data));
+    let data_t = [data[0], data[2], data[1], data[3]];
+    let b = Tensor::new(F16, &[2, 2], reslice::<f16, u8>(&data_t)).transpose(&[1, 0]);
+    assert!(a.approx_eq(&b, 0., 0.));
+}
+
+#[test]
+fn test_to_vec_f32() {
+    use crate::reslice;
+
+    let data = [0., 1., 2., 3., 4., 5.].map(f16::from_f32);
+    let t = Tensor::new(F16, &[2, 3], reslice::<f16, u8>(&data));
+    assert_eq!(t.to_vec_f32(), [0., 1., 2., 3., 4., 5.]);
+    // 转置后按逻辑顺序而不是存储顺序读出
+    let t = t.transpose(&[1, 0]);
+    assert_eq!(t.to_vec_f32(), [0., 3., 1., 4., 2., 5.]);
+
+    let data = [1.5f32, -2.];
+    let t = Tensor::new(F32, &[2], reslice::<f32, u8>(&data));
+    assert_eq!(t.to_vec_f32(), data);
+}
@@ -0,0 +1,73 @@
+use crate::{reslice_mut, udim, Tensor};
+use digit_layout::types::F32;
+use rayon::{iter::ParallelIterator, slice::ParallelSliceMut};
+use std::{
+    iter::zip,
+    ops::{Deref, DerefMut},
+};
+
+impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
+    /// 沿 `axis` 计算前缀和，只支持 F32，返回新分配的连续张量。
+    ///
+    /// 先把输入整理为连续存储，再按 `axis` 之前的维度并行累加。
+    pub fn cumsum<U>(&self, axis: usize, f: impl FnOnce(usize) -> U) -> Tensor<U>
+    where
+        U: DerefMut<Target = [u8]>,
+    {
+        assert_eq!(self.layout, F32, "unsupported data type: {:?}", self.layout);
+        assert!(axis < self.shape.len(), "axis {axis} out of range");
+
+        let mut ans = Tensor::alloc(self.layout, &self.shape, f);
+        if self.size() == 0 {
+            return ans;
+        }
+        self.reform_to(&mut ans);
+
+        let n = self.shape[axis] as usize;
+        let inner = self.shape[axis + 1..].iter().product::<udim>() as usize;
+        reslice_mut::<u8, f32>(ans.physical_mut())
+            .par_chunks_mut(n * inner)
+            .for_each(|block| {
+                for j in 1..n {
+                    let (prev, next) = block[(j - 1) * inner..].split_at_mut(inner);
+                    for (y, x) in zip(&mut next[..inner], &*prev) {
+                        *y += x;
+                    }
+                }
+            });
+        ans
+    }
+}
+
+#[test]
+fn test() {
+    use crate::reslice;
+
+    let data = [1.0f32, 2., 3., 4.];
+    let t = Tensor::new(F32, &[4], reslice::<f32, u8>(&data));
+    let ans = t.cumsum(0, |len| vec![0u8; len]);
+    assert_eq!(reslice::<u8, f32>(ans.as_slice()), &[1., 3., 6., 10.]);
+
+    let data = (0..24).map(|i| i as f32).collect::<Vec<_>>();
+    let t = Tensor::new(F32, &[2, 3, 4], reslice::<f32, u8>(&data));
+    let ans = t.cumsum(1, |len| vec![0u8; len]);
+    let ans = reslice::<u8, f32>(ans.as_slice());
+    for i in 0..2 {
+        for k in 0..4 {
+            let mut sum = 0.;
+            for j in 0..3 {
+                sum += data[i * 12 + j * 4 + k];
+                assert_eq!(ans[i * 12 + j * 4 + k], sum);
+            }
+        }
+    }
+
+    // 不连续的输入按逻辑顺序累加
+    let t = Tensor::new(F32, &[2, 3], reslice::<f32, u8>(&data[..6])).transpose(&[1, 0]);
+    let ans = t.cumsum(1, |len| vec![0u8; len]);
+    assert_eq!(ans.shape(), &[3, 2]);
+    assert_eq!(
+        reslice::<u8, f32>(ans.as_slice()),
+        &[0., 3., 1., 5., 2., 7.]
+    );
+}
@@ -0,0 +1,63 @@
+use crate::{expand_indices, idx_strides, udim, Shape, Tensor};
+use digit_layout::types::{BF16, F16, F32};
+use half::{bf16, f16};
+use std::{iter::zip, ops::Deref};
+
+impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
+    /// 张量中是否存在 NaN 或无穷大。
+    #[inline]
+    pub fn has_non_finite(&self) -> bool {
+        self.first_non_finite().is_some()
+    }
+
+    /// 按逻辑顺序查找第一个 NaN 或无穷大的元素，返回其坐标。
+    pub fn first_non_finite(&self) -> Option<Shape> {
+        match self.layout {
+            F16 => self.find_first(|x: f16| !x.is_finite()),
+            BF16 => self.find_first(|x: bf16| !x.is_finite()),
+            F32 => self.find_first(|x: f32| !x.is_finite()),
+            dt => panic!("unsupported data type: {dt:?}"),
+        }
+    }
+
+    fn find_first<T: Copy>(&self, pred: impl Fn(T) -> bool) -> Option<Shape> {
+        let base = self.base().cast::<T>();
+        let strides = self.strides();
+        let (n, idx_strides) = idx_strides(&self.shape);
+        (0..n).find_map(|i| {
+            let indices = expand_indices(i, &idx_strides, &[]);
+            let offset = zip(indices.iter(), strides)
+                .map(|(&i, &s)| i as isize * s as isize)
+                .sum::<isize>();
+            let x = unsafe { base.offset(offset).read_unaligned() };
+            pred(x).then(|| indices.iter().map(|&i| i as udim).collect())
+        })
+    }
+}
+
+#[test]
+fn test() {
+    use crate::reslice;
+
+    let mut data = [0.0f32, 1., 2., 3., 4., 5.];
+    let t = Tensor::new(F32, &[2, 3], reslice::<f32, u8>(&data));
+    assert!(!t.has_non_finite());
+    assert_eq!(t.first_non_finite(), None);
+
+    data[5] = f32::NAN;
+    let t = Tensor::new(F32, &[2, 3], reslice::<f32, u8>(&data));
+    assert!(t.has_non_finite());
+    assert_eq!(t.first_non_finite().as_deref(), Some(&[1, 2][..]));
+
+    // 转置后按新的逻辑坐标报告
+    let t = t.transpose(&[1, 0]);
+    assert_eq!(t.first_non_finite().as_deref(), Some(&[2, 1][..]));
+
+    // 切片排除 NaN 所在的列
+    let t = t.slice(&[crate::slice![=>2], crate::slice![=>]]);
+    assert!(!t.has_non_finite());
+
+    let data = [f16::ONE, f16::ZERO, f16::INFINITY, f16::ONE];
+    let t = Tensor::new(F16, &[2, 2], reslice::<f16, u8>(&data)).transpose(&[1, 0]);
+    assert_eq!(t.first_non_finite().as_deref(), Some(&[0, 1][..]));
+}
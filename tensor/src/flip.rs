@@ -0,0 +1,68 @@
+use crate::{pattern::Pattern, udim, Affine, Tensor};
+
+impl<Physical> Tensor<Physical> {
+    /// 沿 `axis` 翻转元素顺序，只修改模式，不复制数据。
+    pub fn flip(self, axis: usize) -> Self {
+        let affine = build(axis, &self.shape);
+        Self {
+            pattern: Pattern(affine * self.pattern.0),
+            ..self
+        }
+    }
+}
+
+/// 第 `axis` 维步长取反，偏移移到这一维的最后一个元素。
+fn build(axis: usize, input: &[udim]) -> Affine {
+    let n = input.len();
+    assert!(axis < n, "axis {axis} out of range for tensor of {n} dims");
+    let last = input[axis].saturating_sub(1) as _;
+    Affine::from_fn(n + 1, n + 1, |r, c| match (r, c) {
+        (r, c) if r == axis && c == axis => -1,
+        (r, c) if r == n && c == axis => last,
+        (r, c) if r == c => 1,
+        _ => 0,
+    })
+}
+
+#[test]
+fn test() {
+    let affine = build(1, &[2, 3]);
+    assert_eq!(
+        affine.as_slice(),
+        &[
+            // column major
+            1, 0, 0, //
+            0, -1, 2, //
+            0, 0, 1, //
+        ]
+    );
+}
+
+#[test]
+fn test_flip() {
+    use digit_layout::types::F32;
+
+    let mut t = Tensor::alloc(F32, &[3, 4], |len| vec![0u8; len]);
+    for (i, x) in crate::reslice_mut::<u8, f32>(t.physical_mut())
+        .iter_mut()
+        .enumerate()
+    {
+        *x = i as _;
+    }
+    let flipped = t.as_ref().flip(0);
+    assert_eq!(flipped.shape(), &[3, 4]);
+    assert_eq!(flipped.strides(), &[-4, 1]);
+
+    let mut dst = Tensor::alloc(F32, &[3, 4], |len| vec![0u8; len]);
+    flipped.map_physical(|u| &**u).reform_to(&mut dst);
+    let src = crate::reslice::<u8, f32>(t.as_slice());
+    let dst = crate::reslice::<u8, f32>(dst.as_slice());
+    for (i, row) in dst.chunks_exact(4).enumerate() {
+        assert_eq!(row, &src[(2 - i) * 4..][..4]);
+    }
+
+    // 翻转两次恢复原样
+    let twice = t.as_ref().flip(1).flip(1);
+    assert_eq!(twice.strides(), t.strides());
+    assert_eq!(twice.bytes_offset(), t.bytes_offset());
+}
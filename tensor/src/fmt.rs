@@ -59,11 +59,103 @@ impl<Physical: Deref<Target = [u8]>> fmt::Display for Tensor<Physical> {
             ty::BF16 => write_tensor!(bf16),
             ty::F32 => write_tensor!(f32),
             ty::F64 => write_tensor!(f64),
-            _ => todo!(),
+            layout => writeln!(f, "<unsupported {layout:?}>"),
         }
     }
 }
 
+impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
+    /// 以嵌套列表的形式显示形状、数据类型、步长和数据，每一维至多显示 `max_elems` 个元素。
+    #[inline]
+    pub fn display(&self, max_elems: usize) -> TensorDisplay<Physical> {
+        TensorDisplay {
+            tensor: self,
+            max_elems: max_elems.max(1),
+        }
+    }
+}
+
+/// [`Tensor::display`] 的返回值。
+pub struct TensorDisplay<'a, Physical> {
+    tensor: &'a Tensor<Physical>,
+    max_elems: usize,
+}
+
+impl<Physical: Deref<Target = [u8]>> fmt::Display for TensorDisplay<'_, Physical> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use digit_layout::types as ty;
+
+        let t = self.tensor;
+        let base = t.base();
+        macro_rules! read {
+            ($ty:ty) => {
+                Box::new(move |offset: isize| -> f64 {
+                    unsafe { base.cast::<$ty>().offset(offset).read_unaligned() }.into()
+                }) as Box<dyn Fn(isize) -> f64>
+            };
+        }
+        let (name, value) = match t.data_layout() {
+            ty::F16 => ("f16", read!(f16)),
+            ty::BF16 => ("bf16", read!(bf16)),
+            ty::F32 => ("f32", read!(f32)),
+            ty::F64 => ("f64", read!(f64)),
+            layout => return writeln!(f, "<unsupported {layout:?}>"),
+        };
+
+        writeln!(
+            f,
+            "shape: {:?}, dtype: {name}, strides: {:?}",
+            t.shape(),
+            t.strides(),
+        )?;
+        write_nested(f, &value, 0, t.shape(), t.strides(), self.max_elems, 0)?;
+        writeln!(f)
+    }
+}
+
+fn write_nested(
+    f: &mut fmt::Formatter,
+    value: &dyn Fn(isize) -> f64,
+    offset: isize,
+    shape: &[udim],
+    strides: &[idim],
+    max: usize,
+    depth: usize,
+) -> fmt::Result {
+    let [n, shape @ ..] = shape else {
+        return write!(f, "{:.4}", value(offset));
+    };
+    let [s, strides @ ..] = strides else {
+        unreachable!()
+    };
+
+    let n = *n as usize;
+    let (head, tail) = if n > max {
+        ((max + 1) / 2, max / 2)
+    } else {
+        (n, 0)
+    };
+    let sep = if shape.is_empty() {
+        ", ".to_string()
+    } else {
+        format!(",\n{}", " ".repeat(depth + 1))
+    };
+
+    write!(f, "[")?;
+    let items = (0..head).chain(n - tail..n).collect::<Vec<_>>();
+    for (k, &i) in items.iter().enumerate() {
+        if k > 0 {
+            write!(f, "{sep}")?;
+        }
+        if k == head && tail > 0 {
+            write!(f, "...{sep}")?;
+        }
+        let offset = offset + i as isize * *s as isize;
+        write_nested(f, value, offset, shape, strides, max, depth + 1)?;
+    }
+    write!(f, "]")
+}
+
 fn write_tensor<T: DataFmt>(
     f: &mut fmt::Formatter,
     ptr: *const T,
@@ -173,3 +265,45 @@ fn test_fmt() {
     t.reform_to(&mut t_);
     println!("{t_}");
 }
+
+#[test]
+fn test_display() {
+    use crate::{reslice, Tensor};
+    use digit_layout::types::F32;
+
+    let data = Vec::from_iter((0..6).map(|x| x as f32));
+    let t = Tensor::new(F32, &[2, 3], reslice::<f32, u8>(&data));
+    assert_eq!(
+        t.display(8).to_string(),
+        "\
+shape: [2, 3], dtype: f32, strides: [3, 1]
+[[0.0000, 1.0000, 2.0000],
+ [3.0000, 4.0000, 5.0000]]
+"
+    );
+    assert_eq!(
+        t.display(2).to_string(),
+        "\
+shape: [2, 3], dtype: f32, strides: [3, 1]
+[[0.0000, ..., 2.0000],
+ [3.0000, ..., 5.0000]]
+"
+    );
+
+    // 不支持的数据类型不会 panic
+    let ids = [1u32, 2, 3];
+    let ids = Tensor::new(digit_layout::types::U32, &[3], reslice::<u32, u8>(&ids));
+    assert!(ids.to_string().starts_with("<unsupported "));
+    assert!(ids.display(8).to_string().starts_with("<unsupported "));
+
+    let t = t.transpose(&[1, 0]);
+    assert_eq!(
+        t.display(2).to_string(),
+        "\
+shape: [3, 2], dtype: f32, strides: [1, 3]
+[[0.0000, 3.0000],
+ ...,
+ [2.0000, 5.0000]]
+"
+    );
+}
@@ -0,0 +1,70 @@
+use crate::{udim, Tensor};
+use std::ops::{Deref, DerefMut};
+
+impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
+    /// 将最后一维的前后两半交错排列：`[a0, a1, b0, b1]` -> `[a0, b0, a1, b1]`。
+    ///
+    /// 用于将 rotate-half 布局的 RoPE 通道转换为交错布局。
+    pub fn interleave_pairs<U>(&self, f: impl FnOnce(usize) -> U) -> Tensor<U>
+    where
+        U: DerefMut<Target = [u8]>,
+    {
+        self.swap_pairs(true, f)
+    }
+
+    /// 将最后一维的交错元素拆回前后两半：`[a0, b0, a1, b1]` -> `[a0, a1, b0, b1]`。
+    ///
+    /// 是 [`interleave_pairs`](Self::interleave_pairs) 的逆变换。
+    pub fn deinterleave_pairs<U>(&self, f: impl FnOnce(usize) -> U) -> Tensor<U>
+    where
+        U: DerefMut<Target = [u8]>,
+    {
+        self.swap_pairs(false, f)
+    }
+
+    fn swap_pairs<U>(&self, interleave: bool, f: impl FnOnce(usize) -> U) -> Tensor<U>
+    where
+        U: DerefMut<Target = [u8]>,
+    {
+        let (&n, head) = self
+            .shape
+            .split_last()
+            .expect("tensor must have at least one dim");
+        assert_eq!(n % 2, 0, "last dim must be even");
+
+        let ndim = self.shape.len() + 1;
+        let (a, b) = if interleave { (2, n / 2) } else { (n / 2, 2) };
+        let mut perm = (0..ndim).collect::<Vec<_>>();
+        perm.swap(ndim - 2, ndim - 1);
+
+        let shape = |a: udim, b: udim| head.iter().copied().chain([a, b]).collect::<Vec<_>>();
+        let src = self
+            .as_ref()
+            .map_physical(|u| &**u)
+            .reshape(&shape(a, b))
+            .transpose(&perm);
+        let mut ans = Tensor::alloc(self.layout, &shape(b, a), f);
+        src.reform_to(&mut ans);
+        ans.reshape(&self.shape)
+    }
+}
+
+#[test]
+fn test() {
+    use crate::reslice;
+    use digit_layout::types::F32;
+
+    let data = [0.0f32, 1., 10., 11.];
+    let t = Tensor::new(F32, &[1, 4], reslice::<f32, u8>(&data));
+
+    let interleaved = t.interleave_pairs(|len| vec![0u8; len]);
+    assert_eq!(interleaved.shape(), &[1, 4]);
+    assert_eq!(
+        reslice::<u8, f32>(interleaved.as_slice()),
+        &[0.0f32, 10., 1., 11.]
+    );
+
+    let restored = interleaved.deinterleave_pairs(|len| vec![0u8; len]);
+    assert_eq!(restored.shape(), &[1, 4]);
+    assert_eq!(reslice::<u8, f32>(restored.as_slice()), &data);
+}
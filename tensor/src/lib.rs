@@ -1,11 +1,26 @@
+mod activation;
 mod broadcast;
+mod byteswap;
+mod compare;
+mod cumsum;
+mod finite;
+mod flip;
 mod fmt;
+mod interleave;
+mod mul;
+mod pad;
 mod pattern;
+mod repeat;
 mod reshape;
+mod roll;
+mod safe_tensors;
+mod select;
 mod slice;
 mod split;
+mod stack;
 mod tensor;
 mod transpose;
+mod where_mask;
 
 #[allow(non_camel_case_types)]
 pub type udim = u32;
@@ -13,11 +28,12 @@ pub type udim = u32;
 #[allow(non_camel_case_types)]
 pub type idim = i32;
 
+pub use fmt::TensorDisplay;
 pub use nalgebra::DVector;
 pub use pattern::{expand_indices, idx_strides, Affine, Shape};
 pub use slice::SliceDim;
 pub use split::{LocalSplitable, Splitable};
-pub use tensor::Tensor;
+pub use tensor::{StridesError, Tensor};
 
 use std::mem::{align_of, size_of, size_of_val};
 
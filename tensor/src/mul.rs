@@ -0,0 +1,96 @@
+use crate::{pattern::broadcast_shape, reslice_mut, Tensor};
+use digit_layout::types::{F16, F32};
+use half::f16;
+use std::{
+    iter::zip,
+    ops::{Deref, DerefMut},
+};
+
+impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
+    /// 逐元素相乘，长度为 1 的维度和缺少的前置维度按广播规则扩展，返回新分配的连续张量。
+    ///
+    /// 只支持 F16 和 F32，F16 转换为 f32 计算后舍入。
+    pub fn mul<T, U>(&self, other: &Tensor<T>, f: impl FnOnce(usize) -> U) -> Tensor<U>
+    where
+        T: Deref<Target = [u8]>,
+        U: DerefMut<Target = [u8]>,
+    {
+        assert_eq!(self.layout, other.layout, "data type mismatch");
+        let shape = broadcast_shape(&[self.shape(), other.shape()]);
+        let a = self.as_ref().map_physical(|u| &**u).broadcast(&shape);
+        let b = other.as_ref().map_physical(|u| &**u).broadcast(&shape);
+        let values = zip(a.logical_values(), b.logical_values()).map(|(a, b)| a * b);
+
+        let mut ans = Tensor::alloc(self.layout, &shape, f);
+        match self.layout {
+            F16 => {
+                for (y, x) in zip(reslice_mut::<u8, f16>(ans.physical_mut()), values) {
+                    *y = f16::from_f32(x);
+                }
+            }
+            F32 => {
+                for (y, x) in zip(reslice_mut::<u8, f32>(ans.physical_mut()), values) {
+                    *y = x;
+                }
+            }
+            dt => panic!("unsupported data type: {dt:?}"),
+        }
+        ans
+    }
+}
+
+#[test]
+fn test_mul() {
+    use crate::reslice;
+
+    let a = (0..12).map(|i| i as f32 - 4.).collect::<Vec<_>>();
+    let row = [0.5f32, -1., 2., 0.];
+    let col = [1.0f32, 2., -3.];
+    let a_ = Tensor::new(F32, &[3, 4], reslice::<f32, u8>(&a));
+    let row_ = Tensor::new(F32, &[1, 4], reslice::<f32, u8>(&row));
+    let col_ = Tensor::new(F32, &[3, 1], reslice::<f32, u8>(&col));
+
+    let ans = a_.mul(&row_, |len| vec![0u8; len]);
+    assert_eq!(ans.shape(), &[3, 4]);
+    let expected = (0..12).map(|i| a[i] * row[i % 4]).collect::<Vec<_>>();
+    assert_eq!(reslice::<u8, f32>(ans.as_slice()), expected);
+
+    let ans = a_.mul(&col_, |len| vec![0u8; len]);
+    assert_eq!(ans.shape(), &[3, 4]);
+    let expected = (0..12).map(|i| a[i] * col[i / 4]).collect::<Vec<_>>();
+    assert_eq!(reslice::<u8, f32>(ans.as_slice()), expected);
+
+    // 两边互相广播即外积
+    let outer = col_.mul(&row_, |len| vec![0u8; len]);
+    assert_eq!(outer.shape(), &[3, 4]);
+    let expected = (0..12).map(|i| col[i / 4] * row[i % 4]).collect::<Vec<_>>();
+    assert_eq!(reslice::<u8, f32>(outer.as_slice()), expected);
+
+    // 不连续的输入按逻辑顺序计算
+    let t = a_.clone().transpose(&[1, 0]);
+    let ans = t.mul(&col_.clone().transpose(&[1, 0]), |len| vec![0u8; len]);
+    assert_eq!(ans.shape(), &[4, 3]);
+    let expected = (0..12)
+        .map(|i| a[i % 3 * 4 + i / 3] * col[i % 3])
+        .collect::<Vec<_>>();
+    assert_eq!(reslice::<u8, f32>(ans.as_slice()), expected);
+
+    let data = [f16::ONE, f16::from_f32(2.), f16::from_f32(-0.5)];
+    let h = Tensor::new(F16, &[3], reslice::<f16, u8>(&data));
+    let ans = h.mul(&h, |len| vec![0u8; len]);
+    assert_eq!(
+        reslice::<u8, f16>(ans.as_slice()),
+        &[f16::ONE, f16::from_f32(4.), f16::from_f32(0.25)]
+    );
+}
+
+#[test]
+#[should_panic(expected = "cannot be broadcast together")]
+fn test_mul_incompatible() {
+    use crate::reslice;
+
+    let data = [0.0f32; 12];
+    let a = Tensor::new(F32, &[3, 4], reslice::<f32, u8>(&data));
+    let b = Tensor::new(F32, &[4, 3], reslice::<f32, u8>(&data));
+    a.mul(&b, |len| vec![0u8; len]);
+}
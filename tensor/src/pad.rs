@@ -0,0 +1,97 @@
+use crate::{slice, udim, Tensor};
+use digit_layout::{types::*, DigitLayout};
+use half::{bf16, f16};
+use std::ops::{Deref, DerefMut};
+
+impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
+    /// 在第 `axis` 维前后分别填充 `before` 和 `after` 个 `value`，返回新分配的连续张量。
+    pub fn pad<U>(
+        &self,
+        axis: usize,
+        before: udim,
+        after: udim,
+        value: f32,
+        f: impl FnOnce(usize) -> U,
+    ) -> Tensor<U>
+    where
+        U: DerefMut<Target = [u8]>,
+    {
+        assert!(axis < self.shape.len(), "axis {axis} out of range");
+
+        let mut shape = self.shape.clone();
+        shape[axis] += before + after;
+        let mut ans = Tensor::alloc(self.layout, &shape, f);
+
+        if before + after > 0 {
+            let value = encode(self.layout, value);
+            for x in ans.physical_mut().chunks_exact_mut(value.len()) {
+                x.copy_from_slice(&value);
+            }
+        }
+        if self.size() > 0 {
+            let dims = (0..shape.len())
+                .map(|i| {
+                    if i == axis {
+                        slice![before =>=> self.shape[axis]]
+                    } else {
+                        slice![=>]
+                    }
+                })
+                .collect::<Vec<_>>();
+            let mut dst = ans.as_mut().map_physical(|u| &mut **u).slice(&dims);
+            self.reform_to(&mut dst);
+        }
+        ans
+    }
+}
+
+/// 把 `value` 编码为 `dt` 类型的字节。
+fn encode(dt: DigitLayout, value: f32) -> Vec<u8> {
+    match dt {
+        F16 => f16::from_f32(value).to_le_bytes().to_vec(),
+        BF16 => bf16::from_f32(value).to_le_bytes().to_vec(),
+        F32 => value.to_le_bytes().to_vec(),
+        F64 => (value as f64).to_le_bytes().to_vec(),
+        I8 => (value as i8).to_le_bytes().to_vec(),
+        I16 => (value as i16).to_le_bytes().to_vec(),
+        I32 => (value as i32).to_le_bytes().to_vec(),
+        I64 => (value as i64).to_le_bytes().to_vec(),
+        U8 => (value as u8).to_le_bytes().to_vec(),
+        U16 => (value as u16).to_le_bytes().to_vec(),
+        U32 => (value as u32).to_le_bytes().to_vec(),
+        U64 => (value as u64).to_le_bytes().to_vec(),
+        _ => panic!("unsupported data type for padding: {dt:?}"),
+    }
+}
+
+#[test]
+fn test() {
+    use crate::reslice;
+
+    let data = [1.0f32, 2., 3., 4., 5., 6.];
+    let t = Tensor::new(F32, &[2, 3], reslice::<f32, u8>(&data));
+
+    let padded = t.pad(1, 1, 1, -1., |len| vec![0u8; len]);
+    assert_eq!(padded.shape(), &[2, 5]);
+    assert_eq!(
+        reslice::<u8, f32>(padded.as_slice()),
+        &[-1., 1., 2., 3., -1., -1., 4., 5., 6., -1.]
+    );
+
+    // 非连续输入
+    let padded = t
+        .as_ref()
+        .map_physical(|u| &**u)
+        .transpose(&[1, 0])
+        .pad(0, 0, 2, 0.5, |len| vec![0u8; len]);
+    assert_eq!(padded.shape(), &[5, 2]);
+    assert_eq!(
+        reslice::<u8, f32>(padded.as_slice()),
+        &[1., 4., 2., 5., 3., 6., 0.5, 0.5, 0.5, 0.5]
+    );
+
+    // 不填充时等价于连续化的复制
+    let copied = t.pad(0, 0, 0, 0., |len| vec![0u8; len]);
+    assert_eq!(copied.shape(), &[2, 3]);
+    assert_eq!(reslice::<u8, f32>(copied.as_slice()), &data);
+}
@@ -1,6 +1,7 @@
 ﻿use crate::{idim, udim};
 use nalgebra::{DMatrix, DVector};
 use smallvec::SmallVec;
+use std::iter::zip;
 
 #[derive(Clone, Debug)]
 pub(crate) struct Pattern(pub DVector<idim>);
@@ -58,3 +59,30 @@ pub fn expand_indices(i: udim, idx_strides: &[udim], tail: &[idim]) -> DVector<i
     ans[idx_strides.len()..].copy_from_slice(tail);
     DVector::from_vec(ans)
 }
+
+/// 右对齐的广播规则下的共同形状，各维长度必须相等或为 1。
+pub(crate) fn broadcast_shape(shapes: &[&[udim]]) -> Shape {
+    let ndim = shapes.iter().map(|s| s.len()).max().unwrap_or(0);
+    let mut ans = Shape::from_elem(1, ndim);
+    for shape in shapes {
+        for (d, &s) in zip(ans.iter_mut().rev(), shape.iter().rev()) {
+            assert!(
+                *d == 1 || s == 1 || *d == s,
+                "shapes {shapes:?} cannot be broadcast together"
+            );
+            if s != 1 {
+                *d = s;
+            }
+        }
+    }
+    ans
+}
+
+#[test]
+fn test_broadcast_shape() {
+    assert_eq!(&*broadcast_shape(&[&[3, 4], &[1, 4]]), &[3, 4]);
+    assert_eq!(&*broadcast_shape(&[&[3, 1], &[1, 4]]), &[3, 4]);
+    assert_eq!(&*broadcast_shape(&[&[2, 3, 4], &[4]]), &[2, 3, 4]);
+    assert_eq!(&*broadcast_shape(&[&[1], &[2, 1, 5]]), &[2, 1, 5]);
+    assert_eq!(&*broadcast_shape(&[&[2, 1], &[4], &[1, 1, 1]]), &[1, 2, 4]);
+}
@@ -0,0 +1,71 @@
+use crate::{udim, Tensor};
+use std::ops::{Deref, DerefMut};
+
+impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
+    /// 把 `axis` 维上的每个切片重复 `repeats` 次，返回新分配的连续张量。
+    ///
+    /// 如 `[2, d]` 的张量沿第 0 维重复 2 次得到 `[4, d]`，各行依次为 `[a, a, b, b]`。
+    pub fn repeat_interleave<U>(
+        &self,
+        axis: usize,
+        repeats: udim,
+        f: impl FnOnce(usize) -> U,
+    ) -> Tensor<U>
+    where
+        U: DerefMut<Target = [u8]>,
+    {
+        assert!(
+            axis < self.shape.len(),
+            "axis {axis} out of range for tensor of {} dims",
+            self.shape.len(),
+        );
+
+        // 在 axis 之后插入一个步长为 0 的维度，广播后连续化
+        let mut shape = self.shape.to_vec();
+        shape.insert(axis + 1, 1);
+        let src = self.as_ref().map_physical(|u| &**u).reshape(&shape);
+        shape[axis + 1] = repeats;
+        let src = src.broadcast(&shape);
+        let mut ans = Tensor::alloc(self.layout, &shape, f);
+        if ans.size() > 0 {
+            src.reform_to(&mut ans);
+        }
+
+        shape[axis] *= repeats;
+        shape.remove(axis + 1);
+        ans.reshape(&shape)
+    }
+}
+
+#[test]
+fn test() {
+    use crate::reslice;
+    use digit_layout::types::F32;
+
+    let data = (0..6).map(|i| i as f32).collect::<Vec<_>>();
+    let t = Tensor::new(F32, &[2, 3], reslice::<f32, u8>(&data));
+
+    let ans = t.repeat_interleave(0, 3, |len| vec![0u8; len]);
+    assert_eq!(ans.shape(), &[6, 3]);
+    assert_eq!(
+        reslice::<u8, f32>(ans.as_slice()),
+        &[0., 1., 2., 0., 1., 2., 0., 1., 2., 3., 4., 5., 3., 4., 5., 3., 4., 5.]
+    );
+
+    let ans = t.repeat_interleave(1, 3, |len| vec![0u8; len]);
+    assert_eq!(ans.shape(), &[2, 9]);
+    assert_eq!(
+        reslice::<u8, f32>(ans.as_slice()),
+        &[0., 0., 0., 1., 1., 1., 2., 2., 2., 3., 3., 3., 4., 4., 4., 5., 5., 5.]
+    );
+
+    // 非连续输入
+    let ans = Tensor::new(F32, &[2, 3], reslice::<f32, u8>(&data))
+        .transpose(&[1, 0])
+        .repeat_interleave(0, 2, |len| vec![0u8; len]);
+    assert_eq!(ans.shape(), &[6, 2]);
+    assert_eq!(
+        reslice::<u8, f32>(ans.as_slice()),
+        &[0., 3., 0., 3., 1., 4., 1., 4., 2., 5., 2., 5.]
+    );
+}
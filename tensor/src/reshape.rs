@@ -3,8 +3,16 @@ use nalgebra::DVector;
 use std::iter::zip;
 
 impl<Physical> Tensor<Physical> {
+    /// 改变形状，元素总数必须不变。
     pub fn reshape(self, shape: &[udim]) -> Self {
-        assert_eq!(self.size() as udim, shape.iter().product::<udim>());
+        let size = shape.iter().product::<udim>();
+        assert_eq!(
+            self.size() as udim,
+            size,
+            "cannot reshape {:?} ({} elements) to {shape:?} ({size} elements)",
+            &*self.shape,
+            self.size(),
+        );
         if self.is_contiguous() {
             // reshape: 张量物理连续，直接修改形状和模式
             assert_eq!(
@@ -146,3 +154,22 @@ impl<Physical> Tensor<Physical> {
         panic!("unsupported reshape");
     }
 }
+
+#[test]
+fn test_reshape_size() {
+    use digit_layout::types::F32;
+    use std::panic::catch_unwind;
+
+    let t = Tensor::new(F32, &[2, 3, 4], ());
+    let t = t.reshape(&[6, 4]);
+    assert_eq!(t.shape(), &[6, 4]);
+    assert_eq!(t.pattern.0.as_slice(), &[4, 1, 0]);
+
+    let e = catch_unwind(|| Tensor::new(F32, &[2, 3, 4], ()).reshape(&[5, 4])).unwrap_err();
+    let msg = e.downcast_ref::<String>().unwrap();
+    assert!(msg.contains("cannot reshape [2, 3, 4] (24 elements) to [5, 4] (20 elements)"));
+
+    // 不连续的张量同样先检查元素总数
+    let t = Tensor::new(F32, &[2, 3, 4], ()).transpose(&[1, 0, 2]);
+    assert!(catch_unwind(|| t.reshape(&[3, 2, 5])).is_err());
+}
@@ -0,0 +1,65 @@
+use crate::{idim, Tensor};
+use std::ops::{Deref, DerefMut};
+
+impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
+    /// 沿 `axis` 循环移动 `shift` 个元素，移出末尾的元素回到开头，负数反向移动。
+    ///
+    /// 返回新分配的连续张量，分两段各复制一次。
+    pub fn roll<U>(&self, axis: usize, shift: idim, f: impl FnOnce(usize) -> U) -> Tensor<U>
+    where
+        U: DerefMut<Target = [u8]>,
+    {
+        assert!(axis < self.shape.len(), "axis {axis} out of range");
+
+        let n = self.shape[axis];
+        let mut ans = Tensor::alloc(self.layout, &self.shape, f);
+        if self.size() == 0 {
+            return ans;
+        }
+        let k = shift.rem_euclid(n as idim) as _;
+        // 输出的 `[k, n)` 来自输入的 `[0, n - k)`，输出的 `[0, k)` 来自输入的 `[n - k, n)`
+        for (dst, src, len) in [(k, 0, n - k), (0, n - k, k)] {
+            if len > 0 {
+                let src = self.as_ref().map_physical(|u| &**u).narrow(axis, src, len);
+                let mut dst = ans
+                    .as_mut()
+                    .map_physical(|u| &mut **u)
+                    .narrow(axis, dst, len);
+                src.reform_to(&mut dst);
+            }
+        }
+        ans
+    }
+}
+
+#[test]
+fn test() {
+    use crate::reslice;
+    use digit_layout::types::F32;
+
+    let data = [0.0f32, 1., 2., 3., 4.];
+    let t = Tensor::new(F32, &[5], reslice::<f32, u8>(&data));
+    let roll = |shift| {
+        let ans = t.roll(0, shift, |len| vec![0u8; len]);
+        reslice::<u8, f32>(ans.as_slice()).to_vec()
+    };
+    assert_eq!(roll(2), [3., 4., 0., 1., 2.]);
+    assert_eq!(roll(-2), [2., 3., 4., 0., 1.]);
+    assert_eq!(roll(0), data);
+    assert_eq!(roll(5), data);
+    assert_eq!(roll(7), roll(2));
+
+    // 只移动指定的维度
+    let data = [0.0f32, 1., 2., 3., 4., 5.];
+    let t = Tensor::new(F32, &[2, 3], reslice::<f32, u8>(&data));
+    let rolled = t.roll(1, 1, |len| vec![0u8; len]);
+    assert_eq!(
+        reslice::<u8, f32>(rolled.as_slice()),
+        &[2., 0., 1., 5., 3., 4.]
+    );
+    let rolled = t.roll(0, 1, |len| vec![0u8; len]);
+    assert_eq!(
+        reslice::<u8, f32>(rolled.as_slice()),
+        &[3., 4., 5., 0., 1., 2.]
+    );
+}
@@ -0,0 +1,148 @@
+use crate::{udim, Tensor};
+use digit_layout::{types::*, DigitLayout};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{self, ErrorKind::InvalidData},
+    ops::{Deref, DerefMut},
+    path::Path,
+};
+
+impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
+    /// 把张量以 `name` 为名保存为只包含这一个张量的 safetensors 文件，不连续的张量先整理为连续。
+    pub fn save_safetensors(&self, path: impl AsRef<Path>, name: &str) -> io::Result<()> {
+        let dtype = dtype_name(self.layout)
+            .ok_or_else(|| invalid(format!("unsupported data type {:?}", self.layout)))?;
+        let contiguous;
+        let data = if self.is_contiguous() {
+            self.as_slice()
+        } else {
+            let mut t = Tensor::alloc(self.layout, &self.shape, |len| vec![0u8; len]);
+            self.reform_to(&mut t);
+            contiguous = t;
+            contiguous.as_slice()
+        };
+
+        let header = json!({
+            name: {
+                "dtype": dtype,
+                "shape": self.shape.as_slice(),
+                "data_offsets": [0, data.len()],
+            }
+        })
+        .to_string();
+        // 数据区按 8 字节对齐
+        let len = header.len().next_multiple_of(8);
+
+        let mut file = Vec::with_capacity(8 + len + data.len());
+        file.extend_from_slice(&(len as u64).to_le_bytes());
+        file.extend_from_slice(header.as_bytes());
+        file.resize(8 + len, b' ');
+        file.extend_from_slice(data);
+        fs::write(path, file)
+    }
+}
+
+impl<Physical: DerefMut<Target = [u8]>> Tensor<Physical> {
+    /// 从 safetensors 文件中加载名为 `name` 的张量。
+    pub fn load_safetensors(
+        path: impl AsRef<Path>,
+        name: &str,
+        f: impl FnOnce(usize) -> Physical,
+    ) -> io::Result<Self> {
+        let file = fs::read(path)?;
+        let len = file
+            .get(..8)
+            .map(|len| u64::from_le_bytes(len.try_into().unwrap()) as usize)
+            .ok_or_else(|| invalid("file too short"))?;
+        let header = file
+            .get(8..)
+            .and_then(|tail| tail.get(..len))
+            .ok_or_else(|| invalid("header out of file"))?;
+        let header: Value = serde_json::from_slice(header).map_err(invalid)?;
+        let data = &file[8 + len..];
+
+        let info = header
+            .get(name)
+            .ok_or_else(|| invalid(format!("tensor {name} not found")))?;
+        let dtype = info["dtype"]
+            .as_str()
+            .and_then(dtype_from_name)
+            .ok_or_else(|| invalid(format!("invalid dtype of tensor {name}")))?;
+        let shape = info["shape"]
+            .as_array()
+            .and_then(|shape| {
+                shape
+                    .iter()
+                    .map(|d| d.as_u64().map(|d| d as udim))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| invalid(format!("invalid shape of tensor {name}")))?;
+        let (start, end) = match info["data_offsets"].as_array().map(Vec::as_slice) {
+            Some([start, end]) => (start.as_u64(), end.as_u64()),
+            _ => (None, None),
+        };
+        let data = start
+            .zip(end)
+            .and_then(|(start, end)| data.get(start as usize..end as usize))
+            .ok_or_else(|| invalid(format!("invalid data offsets of tensor {name}")))?;
+
+        let mut ans = Tensor::alloc(dtype, &shape, f);
+        if ans.bytes_size() != data.len() {
+            return Err(invalid(format!("data size of tensor {name} mismatch")));
+        }
+        ans.physical_mut().copy_from_slice(data);
+        Ok(ans)
+    }
+}
+
+const DTYPES: [(DigitLayout, &str); 13] = [
+    (BOOL, "BOOL"),
+    (U8, "U8"),
+    (I8, "I8"),
+    (U16, "U16"),
+    (I16, "I16"),
+    (F16, "F16"),
+    (BF16, "BF16"),
+    (U32, "U32"),
+    (I32, "I32"),
+    (F32, "F32"),
+    (U64, "U64"),
+    (I64, "I64"),
+    (F64, "F64"),
+];
+
+fn dtype_name(dt: DigitLayout) -> Option<&'static str> {
+    DTYPES.iter().find(|(d, _)| *d == dt).map(|(_, name)| *name)
+}
+
+fn dtype_from_name(name: &str) -> Option<DigitLayout> {
+    DTYPES.iter().find(|(_, n)| *n == name).map(|(d, _)| *d)
+}
+
+#[inline]
+fn invalid(msg: impl ToString) -> io::Error {
+    io::Error::new(InvalidData, msg.to_string())
+}
+
+#[test]
+fn test() {
+    use crate::reslice;
+    use half::f16;
+
+    let data = (0..12).map(|i| f16::from_f32(i as f32)).collect::<Vec<_>>();
+    let t = Tensor::new(F16, &[3, 4], reslice::<f16, u8>(&data)).transpose(&[1, 0]);
+    let path = std::env::temp_dir().join("tensor_test_safetensors.safetensors");
+    t.save_safetensors(&path, "x").unwrap();
+
+    let loaded = Tensor::load_safetensors(&path, "x", |len| vec![0u8; len]).unwrap();
+    assert!(Tensor::load_safetensors(&path, "y", |len| vec![0u8; len]).is_err());
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(loaded.data_layout(), F16);
+    assert_eq!(loaded.shape(), &[4, 3]);
+    let expected = (0..4)
+        .flat_map(|i| (0..3).map(move |j| f16::from_f32((j * 4 + i) as f32)))
+        .collect::<Vec<_>>();
+    assert_eq!(reslice::<u8, f16>(loaded.as_slice()), expected);
+}
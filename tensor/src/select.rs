@@ -0,0 +1,85 @@
+use crate::{slice, udim, Tensor};
+use std::ops::{Deref, DerefMut};
+
+impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
+    /// 按 `indices` 依次选取第 `axis` 维上的元素，返回新分配的连续张量。
+    ///
+    /// 序号可以重复和乱序，越界时 panic。
+    pub fn index_select<U>(
+        &self,
+        axis: usize,
+        indices: &[udim],
+        f: impl FnOnce(usize) -> U,
+    ) -> Tensor<U>
+    where
+        U: DerefMut<Target = [u8]>,
+    {
+        assert!(axis < self.shape.len(), "axis {axis} out of range");
+        let len = self.shape[axis];
+        if let Some(i) = indices.iter().find(|&&i| i >= len) {
+            panic!("index {i} out of range for axis {axis} of length {len}");
+        }
+
+        let mut shape = self.shape.clone();
+        shape[axis] = indices.len() as _;
+        let mut ans = Tensor::alloc(self.layout, &shape, f);
+
+        let dims = |start: udim| {
+            (0..shape.len())
+                .map(|i| {
+                    if i == axis {
+                        slice![start =>=> 1]
+                    } else {
+                        slice![=>]
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        if ans.size() == 0 {
+            return ans;
+        }
+        for (j, &i) in indices.iter().enumerate() {
+            let src = self.as_ref().map_physical(|u| &**u).slice(&dims(i));
+            let mut dst = ans.as_mut().map_physical(|u| &mut **u).slice(&dims(j as _));
+            src.reform_to(&mut dst);
+        }
+        ans
+    }
+}
+
+#[test]
+fn test() {
+    use crate::reslice;
+    use digit_layout::types::F32;
+
+    let data = (0..12).map(|i| i as f32).collect::<Vec<_>>();
+    let t = Tensor::new(F32, &[3, 4], reslice::<f32, u8>(&data));
+
+    let rows = t.index_select(0, &[2, 0, 2], |len| vec![0u8; len]);
+    assert_eq!(rows.shape(), &[3, 4]);
+    assert_eq!(
+        reslice::<u8, f32>(rows.as_slice()),
+        &[8., 9., 10., 11., 0., 1., 2., 3., 8., 9., 10., 11.]
+    );
+
+    // 非连续输入，沿非首维选取
+    let cols = t
+        .as_ref()
+        .map_physical(|u| &**u)
+        .transpose(&[1, 0])
+        .index_select(1, &[1], |len| vec![0u8; len]);
+    assert_eq!(cols.shape(), &[4, 1]);
+    assert_eq!(reslice::<u8, f32>(cols.as_slice()), &[4., 5., 6., 7.]);
+
+    let empty = t.index_select(1, &[], |len| vec![0u8; len]);
+    assert_eq!(empty.shape(), &[3, 0]);
+}
+
+#[test]
+#[should_panic(expected = "index 3 out of range for axis 0 of length 3")]
+fn test_out_of_range() {
+    use digit_layout::types::F32;
+
+    let t = Tensor::alloc(F32, &[3, 4], |len| vec![0u8; len]);
+    t.index_select(0, &[0, 3], |len| vec![0u8; len]);
+}
@@ -10,6 +10,38 @@ impl<Physical> Tensor<Physical> {
             ..self
         }
     }
+
+    /// 取第 `axis` 维的 `[start, start + len)`，其他维度不变。
+    pub fn narrow(self, axis: usize, start: udim, len: udim) -> Self {
+        let ndim = self.shape.len();
+        assert!(
+            axis < ndim,
+            "axis {axis} out of range for tensor of {ndim} dims"
+        );
+        let dim = self.shape[axis];
+        assert!(
+            start.checked_add(len).is_some_and(|end| end <= dim),
+            "range {start}..{start}+{len} out of bounds for axis {axis} of length {dim}",
+        );
+        let dims = (0..ndim)
+            .map(|i| {
+                if i == axis {
+                    SliceDim {
+                        start,
+                        step: 1,
+                        len,
+                    }
+                } else {
+                    SliceDim {
+                        start: 0,
+                        step: 1,
+                        len: self.shape[i],
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        self.slice(&dims)
+    }
 }
 
 fn build(meta: &[SliceDim], input: &[udim]) -> (Shape, Affine) {
@@ -68,7 +100,8 @@ impl SliceDim {
         } else {
             match self.step.cmp(&0) {
                 Ordering::Greater => {
-                    assert!(self.start < len, "{self:?}/{len}");
+                    // 起点可以在末尾，得到空的切片
+                    assert!(self.start <= len, "{self:?}/{len}");
                     Self {
                         start: self.start,
                         step: self.step,
@@ -122,6 +155,9 @@ macro_rules! slice {
     [$start:expr => $end:expr] => {
         slice![$start; 1; $end - $start]
     };
+    [$start:expr => $end:expr, $step:expr] => {
+        slice![$start; $step; ($end - $start + $step - 1) / $step]
+    };
     [$start:expr =>=> $len:expr] => {
         slice![$start; 1; $len]
     };
@@ -153,6 +189,64 @@ fn test_macro() {
     assert_eq!(slice![3=>], slice![3; 1; usize::MAX]);
     assert_eq!(slice![=>5], slice![0; 1; 5]);
     assert_eq!(slice![3 => 5], slice![3; 1; 2]);
+    assert_eq!(slice![0 => 10, 2], slice![0; 2; 5]);
+    assert_eq!(slice![1 => 10, 3], slice![1; 3; 3]);
     assert_eq!(slice![3 =>=> 5], slice![3; 1; 5]);
     assert_eq!(slice![3 => 2 => 5], slice![3; 2; 5]);
 }
+
+#[test]
+fn test_step() {
+    use crate::reslice;
+    use digit_layout::types::F32;
+
+    let data = (0..10).map(|x| x as f32).collect::<Vec<_>>();
+    let t = Tensor::new(F32, &[10], reslice::<f32, u8>(&data));
+
+    let even = t.clone().slice(&[slice![0 => 10, 2]]);
+    assert_eq!(even.shape(), &[5]);
+    assert_eq!(even.strides(), &[2]);
+    assert_eq!(even.contiguous_len(), 0);
+    assert!(!even.is_contiguous());
+
+    let mut buf = vec![0u8; even.bytes_size()];
+    unsafe { even.reform_to_raw(&mut buf) };
+    assert_eq!(reslice::<u8, f32>(&buf), &[0., 2., 4., 6., 8.]);
+
+    // 从打包的 `[n, 2 * dh]` 中无复制地取出交错排列的两组数据
+    let packed = t.reshape(&[5, 2]);
+    let odd = packed.slice(&[slice![=>], slice![1 => 2]]).reshape(&[5]);
+    assert_eq!(odd.strides(), &[2]);
+    assert_eq!(odd.bytes_offset(), 4);
+    let mut buf = vec![0u8; odd.bytes_size()];
+    unsafe { odd.reform_to_raw(&mut buf) };
+    assert_eq!(reslice::<u8, f32>(&buf), &[1., 3., 5., 7., 9.]);
+}
+
+#[test]
+fn test_narrow() {
+    use crate::reslice;
+    use digit_layout::types::F32;
+
+    let data = (0..10).map(|x| x as f32).collect::<Vec<_>>();
+    let t = Tensor::new(F32, &[2, 5], reslice::<f32, u8>(&data));
+
+    let narrowed = t.clone().narrow(1, 2, 2);
+    assert_eq!(narrowed.shape(), &[2, 2]);
+    assert_eq!(narrowed.strides(), &[5, 1]);
+    assert_eq!(narrowed.bytes_offset(), 8);
+    let mut buf = vec![0u8; narrowed.bytes_size()];
+    unsafe { narrowed.reform_to_raw(&mut buf) };
+    assert_eq!(reslice::<u8, f32>(&buf), &[2., 3., 7., 8.]);
+
+    // 末尾的空范围
+    let empty = t.narrow(1, 5, 0);
+    assert_eq!(empty.shape(), &[2, 0]);
+    assert_eq!(empty.bytes_size(), 0);
+}
+
+#[test]
+#[should_panic(expected = "range 4..4+2 out of bounds for axis 1 of length 5")]
+fn test_narrow_out_of_bounds() {
+    Tensor::new(digit_layout::types::F32, &[2, 5], ()).narrow(1, 4, 2);
+}
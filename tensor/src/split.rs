@@ -66,6 +66,17 @@ impl<Physical: Splitable> Tensor<Physical> {
             })
             .collect()
     }
+
+    /// 把第 `axis` 维等分为 `n` 段，维度必须能被 `n` 整除。
+    pub fn chunk(&self, axis: usize, n: udim) -> VecDeque<Self> {
+        assert!(axis < self.shape.len(), "axis {axis} out of range");
+        let len = self.shape[axis];
+        assert!(
+            n > 0 && len % n == 0,
+            "axis {axis} of length {len} cannot be divided into {n} chunks",
+        );
+        self.split(axis, &vec![len / n; n as usize])
+    }
 }
 
 fn build(axis: usize, segments: &[udim], input: &[udim]) -> Vec<(Shape, Affine)> {
@@ -139,6 +150,27 @@ fn test() {
     );
 }
 
+#[test]
+fn test_chunk() {
+    use digit_layout::types::U8;
+
+    let data = (0..6).collect::<Vec<u8>>();
+    let t = Tensor::new(U8, &[6], &*data);
+    let chunks = t.chunk(0, 3);
+    assert_eq!(chunks.len(), 3);
+    for (i, chunk) in chunks.iter().enumerate() {
+        assert_eq!(chunk.shape(), &[2]);
+        assert_eq!(chunk.as_slice(), [2 * i as u8, 2 * i as u8 + 1]);
+    }
+}
+
+#[test]
+#[should_panic(expected = "axis 0 of length 6 cannot be divided into 4 chunks")]
+fn test_chunk_indivisible() {
+    use digit_layout::types::U8;
+    Tensor::new(U8, &[6], ()).chunk(0, 4);
+}
+
 #[macro_export]
 macro_rules! split {
     ($src:expr; [$axis:expr]: $($n:expr),+) => {
@@ -0,0 +1,84 @@
+use crate::{slice, udim, Tensor};
+use std::ops::{Deref, DerefMut};
+
+impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
+    /// 沿新增的第 0 维堆叠形状和数据类型都相同的张量，返回形状为 `[N, ...]` 的新分配的连续张量。
+    ///
+    /// `parts` 为空时 panic。
+    pub fn stack<U>(parts: &[Self], f: impl FnOnce(usize) -> U) -> Tensor<U>
+    where
+        U: DerefMut<Target = [u8]>,
+    {
+        let Some(first) = parts.first() else {
+            panic!("cannot stack an empty list of tensors");
+        };
+        for (i, part) in parts.iter().enumerate().skip(1) {
+            assert_eq!(
+                part.layout, first.layout,
+                "data type of part {i} mismatch: {:?} vs {:?}",
+                part.layout, first.layout,
+            );
+            assert_eq!(
+                part.shape, first.shape,
+                "shape of part {i} mismatch: {:?} vs {:?}",
+                part.shape, first.shape,
+            );
+        }
+
+        let item = [1].into_iter().chain(first.shape.iter().copied());
+        let item = item.collect::<Vec<udim>>();
+        let mut shape = item.clone();
+        shape[0] = parts.len() as _;
+        let mut ans = Tensor::alloc(first.layout, &shape, f);
+        if ans.size() == 0 {
+            return ans;
+        }
+
+        let dims = |i: udim| {
+            [slice![i =>=> 1]]
+                .into_iter()
+                .chain(first.shape.iter().map(|_| slice![=>]))
+                .collect::<Vec<_>>()
+        };
+        for (i, part) in parts.iter().enumerate() {
+            let src = part.as_ref().map_physical(|u| &**u).reshape(&item);
+            let mut dst = ans.as_mut().map_physical(|u| &mut **u).slice(&dims(i as _));
+            src.reform_to(&mut dst);
+        }
+        ans
+    }
+}
+
+#[test]
+fn test() {
+    use crate::reslice;
+    use digit_layout::types::F32;
+
+    let data = (0..18).map(|i| i as f32).collect::<Vec<_>>();
+    let parts = data
+        .chunks_exact(6)
+        .map(|chunk| Tensor::new(F32, &[2, 3], reslice::<f32, u8>(chunk)))
+        .collect::<Vec<_>>();
+
+    let ans = Tensor::stack(&parts, |len| vec![0u8; len]);
+    assert_eq!(ans.shape(), &[3, 2, 3]);
+    assert_eq!(reslice::<u8, f32>(ans.as_slice()), &data[..]);
+
+    // 非连续输入
+    let transposed = parts
+        .iter()
+        .map(|t| t.as_ref().map_physical(|u| &**u).transpose(&[1, 0]))
+        .collect::<Vec<_>>();
+    let ans = Tensor::stack(&transposed, |len| vec![0u8; len]);
+    assert_eq!(ans.shape(), &[3, 3, 2]);
+    assert_eq!(
+        &reslice::<u8, f32>(ans.as_slice())[..6],
+        &[0., 3., 1., 4., 2., 5.]
+    );
+}
+
+#[test]
+#[should_panic(expected = "cannot stack an empty list of tensors")]
+fn test_empty() {
+    Tensor::<&[u8]>::stack(&[], |len| vec![0u8; len]);
+}
@@ -3,8 +3,11 @@ use digit_layout::DigitLayout;
 use nalgebra::DVector;
 use operators::{Argument, Operator, TensorLayout};
 use std::{
+    error, fmt,
+    iter::zip,
     mem::{align_of, size_of},
     ops::{Deref, DerefMut},
+    ptr::copy_nonoverlapping,
 };
 
 #[derive(Clone, Debug)]
@@ -43,6 +46,8 @@ impl<Physical> Tensor<Physical> {
     /// # Safety
     ///
     /// The caller must ensure that the parts are valid.
+    /// `pattern` is the strides followed by the offset, both in elements.
+    /// Use [`with_strides`](Self::with_strides) for a checked version.
     #[inline]
     pub unsafe fn from_raw_parts(
         data_type: DigitLayout,
@@ -109,6 +114,9 @@ impl<Physical> Tensor<Physical> {
     }
 
     /// 连续维度的数量。
+    ///
+    /// 长度为 1 的维度不影响连续性；长度大于 1 的广播（步长为 0）维度
+    /// 多个元素指向同一内存，视为不连续。
     pub fn contiguous_len(&self) -> usize {
         self.pattern
             .strides()
@@ -116,7 +124,7 @@ impl<Physical> Tensor<Physical> {
             .enumerate()
             .rev()
             .scan(1 as idim, |mul, (i, &s)| {
-                if s == *mul || s == 0 {
+                if s == *mul || (s == 0 && self.shape[i] == 1) {
                     *mul *= self.shape[i] as idim;
                     Some(())
                 } else {
@@ -204,6 +212,51 @@ impl<B: Sized, P: DerefMut<Target = [B]>> Tensor<P> {
 }
 
 impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
+    /// 以元素为单位的 `strides` 描述 `physical` 中的张量，检查所有元素都在存储范围内。
+    ///
+    /// 负步长的维度从存储的末端开始，偏移由步长推导得出。允许步长为 0 的广播维度。
+    pub fn with_strides(
+        layout: DigitLayout,
+        shape: &[udim],
+        strides: &[idim],
+        physical: Physical,
+    ) -> Result<Self, StridesError> {
+        if strides.len() != shape.len() {
+            return Err(StridesError::DimMismatch {
+                shape: shape.len(),
+                strides: strides.len(),
+            });
+        }
+        let mut offset = 0i64;
+        let mut end = 1i64;
+        if shape.iter().all(|&d| d > 0) {
+            for (&d, &s) in zip(shape, strides) {
+                let span = (d - 1) as i64 * s as i64;
+                if span < 0 {
+                    offset -= span;
+                }
+                end += span.abs();
+            }
+        } else {
+            end = 0;
+        }
+        let required = end as usize * layout.nbytes();
+        if required > physical.len() || offset > idim::MAX as i64 {
+            return Err(StridesError::OutOfBounds {
+                required,
+                len: physical.len(),
+            });
+        }
+        let mut pattern = strides.to_vec();
+        pattern.push(offset as _);
+        Ok(Self {
+            layout,
+            shape: Shape::from_slice(shape),
+            pattern: Pattern(DVector::from_vec(pattern)),
+            physical,
+        })
+    }
+
     #[inline]
     pub fn as_slice(&self) -> &[u8] {
         debug_assert!(self.is_contiguous());
@@ -272,6 +325,23 @@ impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
             )
             .unwrap();
     }
+
+    /// 与 [`reform_to`](Self::reform_to) 结果相同，但在当前线程按逻辑顺序逐个元素复制。
+    ///
+    /// 不经过并行的算子，用于在 sanitizer 下排查指针运算导致的内存问题。
+    pub fn reform_to_serial<U>(&self, dst: &mut Tensor<U>)
+    where
+        U: DerefMut<Target = [u8]>,
+    {
+        assert_eq!(self.layout, dst.layout);
+        assert_eq!(self.shape(), dst.shape());
+        let size = self.layout.nbytes();
+        let src = self.base();
+        let base = dst.base_mut();
+        for (i, j) in zip(dst.logical_offsets(), self.logical_offsets()) {
+            unsafe { copy_nonoverlapping(src.offset(j), base.offset(i), size) };
+        }
+    }
 }
 
 impl<Physical: DerefMut<Target = [u8]>> Tensor<Physical> {
@@ -284,6 +354,29 @@ impl<Physical: DerefMut<Target = [u8]>> Tensor<Physical> {
     }
 }
 
+/// 用显式步长构造张量时的错误。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum StridesError {
+    /// 步长的数量与形状的维数不同。
+    DimMismatch { shape: usize, strides: usize },
+    /// 访问的元素超出存储范围，`required` 和 `len` 都以字节为单位。
+    OutOfBounds { required: usize, len: usize },
+}
+
+impl error::Error for StridesError {}
+impl fmt::Display for StridesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::DimMismatch { shape, strides } => {
+                write!(f, "{strides} strides for tensor of {shape} dims")
+            }
+            Self::OutOfBounds { required, len } => {
+                write!(f, "strides need {required} bytes, physical has {len}")
+            }
+        }
+    }
+}
+
 #[test]
 fn test() {
     use digit_layout::types::F32;
@@ -318,3 +411,86 @@ fn test() {
     assert_eq!(t.contiguous_len(), 4);
     assert_eq!(t.is_contiguous(), false);
 }
+
+#[test]
+fn test_contiguous_broadcast() {
+    use digit_layout::types::F32;
+
+    let t = Tensor::new(F32, &[1, 4], ());
+    assert_eq!(t.contiguous_len(), 2);
+
+    // 广播出的维度中各行共享内存，只有最后一维连续
+    let t = t.broadcast(&[3, 4]);
+    assert_eq!(t.pattern.0.as_slice(), &[0, 1, 0]);
+    assert_eq!(t.contiguous_len(), 1);
+    assert_eq!(t.is_contiguous(), false);
+
+    // 长度为 1 的零步长维度仍然连续
+    let t = Tensor::new(F32, &[1, 4], ()).broadcast(&[1, 4]);
+    assert_eq!(t.pattern.0.as_slice(), &[0, 1, 0]);
+    assert_eq!(t.contiguous_len(), 2);
+}
+
+#[test]
+fn test_reform_to_serial() {
+    use crate::{reslice, reslice_mut};
+    use digit_layout::types::F32;
+
+    let mut src = Tensor::alloc(F32, &[3, 4, 5], |len| vec![0u8; len]);
+    for (i, x) in reslice_mut::<u8, f32>(src.physical_mut())
+        .iter_mut()
+        .enumerate()
+    {
+        *x = i as _;
+    }
+    let src = src.transpose(&[2, 0, 1]);
+    assert!(!src.is_contiguous());
+
+    let mut parallel = Tensor::alloc(F32, src.shape(), |len| vec![0u8; len]);
+    let mut serial = Tensor::alloc(F32, src.shape(), |len| vec![0u8; len]);
+    src.reform_to(&mut parallel);
+    src.reform_to_serial(&mut serial);
+    assert_eq!(serial.as_slice(), parallel.as_slice());
+    assert!(serial.logical_eq(&src));
+
+    // 目标也可以不连续
+    let mut dst = Tensor::alloc(F32, &[4, 5, 3], |len| vec![0u8; len]).transpose(&[1, 2, 0]);
+    src.reform_to_serial(&mut dst);
+    assert!(dst.logical_eq(&src));
+    let dst = reslice::<u8, f32>(dst.physical());
+    assert_eq!(&dst[..4], &[0., 20., 40., 1.]);
+}
+
+#[test]
+fn test_with_strides() {
+    use digit_layout::types::F32;
+
+    let data = (0..6).map(|i| i as f32).collect::<Vec<_>>();
+    let data = crate::reslice::<f32, u8>(&data);
+    // 按列读取 `[2, 3]` 的存储，得到转置
+    let t = Tensor::with_strides(F32, &[3, 2], &[1, 3], data).unwrap();
+    let transposed = Tensor::new(F32, &[2, 3], data).transpose(&[1, 0]);
+    assert_eq!(t.pattern(), transposed.pattern());
+    assert!(t.logical_eq(&transposed));
+
+    // 负步长从存储末端开始
+    let t = Tensor::with_strides(F32, &[2, 3], &[-3, 1], data).unwrap();
+    let flipped = Tensor::new(F32, &[2, 3], data).flip(0);
+    assert_eq!(t.bytes_offset(), 12);
+    assert!(t.logical_eq(&flipped));
+
+    assert_eq!(
+        Tensor::with_strides(F32, &[3, 2], &[1, 4], data).unwrap_err(),
+        StridesError::OutOfBounds {
+            required: 28,
+            len: 24
+        }
+    );
+    assert_eq!(
+        Tensor::with_strides(F32, &[6], &[1, 1], data).unwrap_err(),
+        StridesError::DimMismatch {
+            shape: 1,
+            strides: 2
+        }
+    );
+}
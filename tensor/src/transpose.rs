@@ -14,7 +14,19 @@ impl<Physical> Tensor<Physical> {
 
 fn build(perm: &[usize], input: &[udim]) -> (Shape, Affine) {
     let n = perm.len();
-    assert_eq!(input.len(), n);
+    assert_eq!(
+        n,
+        input.len(),
+        "permutation {perm:?} has wrong length for tensor of {} dims",
+        input.len(),
+    );
+    // 每个轴序号必须恰好出现一次
+    let mut seen = vec![false; n];
+    for &i in perm {
+        assert!(i < n, "axis {i} in permutation {perm:?} out of range");
+        assert!(!seen[i], "axis {i} repeated in permutation {perm:?}");
+        seen[i] = true;
+    }
     let shape = perm.iter().map(|&i| input[i]).collect();
     let affine = Affine::from_fn(n + 1, n + 1, |r, c| {
         if c == perm.get(r).copied().unwrap_or(r) {
@@ -42,3 +54,16 @@ fn test() {
         ]
     );
 }
+
+#[test]
+#[should_panic(expected = "axis 0 repeated in permutation [0, 0, 1]")]
+fn test_repeated_axis() {
+    build(&[0, 0, 1], &[1, 2, 3]);
+}
+
+#[test]
+#[should_panic(expected = "permutation [1, 0] has wrong length for tensor of 3 dims")]
+fn test_wrong_length() {
+    let t = Tensor::new(digit_layout::types::F32, &[1, 2, 3], ());
+    t.transpose(&[1, 0]);
+}
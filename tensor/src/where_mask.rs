@@ -0,0 +1,105 @@
+use crate::{expand_indices, idim, idx_strides, pattern::broadcast_shape, reslice_mut, Tensor};
+use digit_layout::types::{F16, F32};
+use half::f16;
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefMutIterator, ParallelIterator};
+use std::{
+    iter::zip,
+    ops::{Deref, DerefMut},
+};
+
+impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
+    /// 按字节掩码 `cond` 逐元素选取：非零处取 `a`，零处取 `b`，返回新分配的连续张量。
+    ///
+    /// 三个张量按右对齐规则广播到共同的形状，`a` 和 `b` 的数据类型必须相同，支持 F16 和 F32。
+    pub fn where_mask<C, U>(
+        cond: &Tensor<C>,
+        a: &Self,
+        b: &Self,
+        f: impl FnOnce(usize) -> U,
+    ) -> Tensor<U>
+    where
+        C: Deref<Target = [u8]>,
+        U: DerefMut<Target = [u8]>,
+    {
+        assert_eq!(cond.layout.nbytes(), 1, "mask must be a byte tensor");
+        assert_eq!(
+            a.layout, b.layout,
+            "data type mismatch: {:?} vs {:?}",
+            a.layout, b.layout,
+        );
+        let shape = broadcast_shape(&[cond.shape(), a.shape(), b.shape()]);
+
+        let cond = cond.as_ref().map_physical(|u| &**u).broadcast(&shape);
+        let a_ = a.as_ref().map_physical(|u| &**u).broadcast(&shape);
+        let b_ = b.as_ref().map_physical(|u| &**u).broadcast(&shape);
+        let mut ans = Tensor::alloc(a.layout, &shape, f);
+        match a.layout {
+            F16 => select::<f16>(&cond, &a_, &b_, reslice_mut(ans.as_mut_slice())),
+            F32 => select::<f32>(&cond, &a_, &b_, reslice_mut(ans.as_mut_slice())),
+            dt => panic!("unsupported data type: {dt:?}"),
+        }
+        ans
+    }
+}
+
+fn select<T: Copy + Send + Sync>(
+    cond: &Tensor<&[u8]>,
+    a: &Tensor<&[u8]>,
+    b: &Tensor<&[u8]>,
+    dst: &mut [T],
+) {
+    // 裸指针不能在线程间传递，以地址形式捕获
+    let (cond_base, a_base, b_base) = (cond.base() as usize, a.base() as usize, b.base() as usize);
+    let (_, idx_strides) = idx_strides(&cond.shape);
+    let offset = |indices: &[idim], strides: &[idim]| {
+        zip(indices, strides)
+            .map(|(&i, &s)| i as isize * s as isize)
+            .sum::<isize>()
+    };
+    dst.par_iter_mut().enumerate().for_each(|(i, y)| {
+        let indices = expand_indices(i as _, &idx_strides, &[]);
+        let indices = indices.as_slice();
+        unsafe {
+            let c = (cond_base as *const u8).offset(offset(indices, cond.strides()));
+            let (base, strides) = if c.read() != 0 {
+                (a_base, a.strides())
+            } else {
+                (b_base, b.strides())
+            };
+            *y = (base as *const T)
+                .offset(offset(indices, strides))
+                .read_unaligned();
+        }
+    });
+}
+
+#[test]
+fn test() {
+    use crate::reslice;
+    use digit_layout::types::U8;
+
+    let mask = [1u8, 0, 0, 1];
+    let a = [1.0f32, 2., 3., 4.];
+    let b = [-1.0f32, -2., -3., -4.];
+    let mask = Tensor::new(U8, &[4], &mask[..]);
+    let a = Tensor::new(F32, &[4], reslice::<f32, u8>(&a));
+    let b = Tensor::new(F32, &[4], reslice::<f32, u8>(&b));
+
+    let ans = Tensor::where_mask(&mask, &a, &b, |len| vec![0u8; len]);
+    assert_eq!(ans.shape(), &[4]);
+    assert_eq!(reslice::<u8, f32>(ans.as_slice()), &[1., -2., -3., 4.]);
+
+    // 按行广播的掩码和标量形状的 b
+    let rows = [0u8, 1];
+    let rows = Tensor::new(U8, &[2, 1], &rows[..]);
+    let zero = [f16::ZERO];
+    let zero = Tensor::new(F16, &[1], reslice::<f16, u8>(&zero));
+    let x = (0..8).map(|i| f16::from_f32(i as _)).collect::<Vec<_>>();
+    let x = Tensor::new(F16, &[2, 4], reslice::<f16, u8>(&x));
+    let ans = Tensor::where_mask(&rows, &x, &zero, |len| vec![0u8; len]);
+    assert_eq!(ans.shape(), &[2, 4]);
+    assert_eq!(
+        reslice::<u8, f16>(ans.as_slice()),
+        [0., 0., 0., 0., 4., 5., 6., 7.].map(f16::from_f32)
+    );
+}
@@ -1,8 +1,11 @@
 #![doc = include_str!("../README.md")]
 
 mod manager;
+mod openai;
+mod readiness;
 mod response;
 mod schemas;
+mod websocket;
 
 use causal_lm::CausalLM;
 use http_body_util::{combinators::BoxBody, BodyExt, Empty};
@@ -14,7 +17,8 @@ use hyper::{
 };
 use hyper_util::rt::TokioIo;
 use manager::ServiceManager;
-use response::{error, success, text_stream};
+use readiness::Readiness;
+use response::{error, json, probe, success, text, text_stream};
 use std::{
     future::Future,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
@@ -39,14 +43,29 @@ where
     let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port));
     info!("start service at {addr}");
 
-    let app = App(Arc::new(ServiceManager::new(service, session_capacity)));
+    let app = App {
+        manager: Arc::new(ServiceManager::new(service, session_capacity)),
+        readiness: Default::default(),
+    };
     let listener = TcpListener::bind(addr).await?;
+    // 预热完成之前 `/ready` 返回 503
+    tokio::spawn({
+        let app = app.clone();
+        async move {
+            if app.readiness.warm_up(app.manager.warmup()).await {
+                info!("service is ready");
+            } else {
+                warn!("warmup failed, service is not ready");
+            }
+        }
+    });
     loop {
         let app = app.clone();
         let (stream, _) = listener.accept().await?;
         tokio::spawn(async move {
             if let Err(err) = http1::Builder::new()
                 .serve_connection(TokioIo::new(stream), app)
+                .with_upgrades()
                 .await
             {
                 warn!("Error serving connection: {err:?}");
@@ -55,12 +74,18 @@ where
     }
 }
 
-struct App<M: CausalLM>(Arc<ServiceManager<M>>);
+struct App<M: CausalLM> {
+    manager: Arc<ServiceManager<M>>,
+    readiness: Arc<Readiness>,
+}
 
 impl<M: CausalLM> Clone for App<M> {
     #[inline]
     fn clone(&self) -> Self {
-        Self(self.0.clone())
+        Self {
+            manager: self.manager.clone(),
+            readiness: self.readiness.clone(),
+        }
     }
 }
 
@@ -73,8 +98,8 @@ where
     type Error = hyper::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn call(&self, req: Request<Incoming>) -> Self::Future {
-        let manager = self.0.clone();
+    fn call(&self, mut req: Request<Incoming>) -> Self::Future {
+        let manager = self.manager.clone();
 
         macro_rules! response {
             ($method:ident; $f:expr) => {
@@ -96,8 +121,44 @@ where
             (&Method::POST, "/infer") => {
                 response!(infer; |ret| text_stream(UnboundedReceiverStream::new(ret)))
             }
+            (&Method::GET, "/health") => Box::pin(async { Ok(probe(true)) }),
+            (&Method::GET, "/ready") => {
+                let ready = self.readiness.is_ready();
+                Box::pin(async move { Ok(probe(ready)) })
+            }
+            (&Method::GET, "/metrics") => {
+                let metrics = manager.metrics();
+                Box::pin(async move { Ok(text(metrics)) })
+            }
+            (&Method::GET, "/v1/stream") => match hyper_tungstenite::upgrade(&mut req, None) {
+                Ok((response, websocket)) => {
+                    tokio::spawn(websocket::serve(manager, websocket));
+                    let response = response.map(|b| b.map_err(|never| match never {}).boxed());
+                    Box::pin(async move { Ok(response) })
+                }
+                Err(e) => {
+                    let e =
+                        schemas::Error::InvalidContent(format!("WebSocket upgrade failed: {e}"));
+                    Box::pin(async move { Ok(error(e)) })
+                }
+            },
             (&Method::POST, "/fork") => response!(fork ; success),
             (&Method::POST, "/drop") => response!(drop_; success),
+            (&Method::POST, "/v1/chat/completions") => Box::pin(async move {
+                let whole_body = req.collect().await?.to_bytes();
+                let req = serde_json::from_slice::<openai::ChatCompletions>(&whole_body);
+                Ok(match req {
+                    Ok(req) => {
+                        let stream = req.stream;
+                        match manager.chat_completions(req) {
+                            Ok(completion) if stream => text_stream(completion.into_sse()),
+                            Ok(completion) => json(completion.collect().await),
+                            Err(e) => error(e),
+                        }
+                    }
+                    Err(e) => error(schemas::Error::WrongJson(e)),
+                })
+            }),
             // Return 404 Not Found for other routes.
             _ => Box::pin(async move {
                 Ok(Response::builder()
@@ -112,3 +173,63 @@ where
         }
     }
 }
+
+#[test]
+fn test_chat_completions() {
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        runtime::Builder,
+        time::sleep,
+    };
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    println!("model_dir: {}", model_dir.display());
+
+    const PORT: u16 = 38197;
+    let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+    let _rt = runtime.enter();
+
+    let (service, _handle) = service::Service::<llama_cpu::Transformer>::load(model_dir, ());
+    runtime.spawn(start_infer_service(service, PORT, None));
+
+    let response = runtime.block_on(async {
+        let mut stream = loop {
+            match TcpStream::connect((Ipv4Addr::LOCALHOST, PORT)).await {
+                Ok(stream) => break stream,
+                Err(_) => sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let body = r#"{"messages":[{"role":"user","content":"Hi"}],"max_tokens":8,"stop":"\n\n"}"#;
+        let request = format!(
+            "POST /v1/chat/completions HTTP/1.1\r\n\
+             Host: localhost\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            body.len(),
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    });
+    runtime.shutdown_background();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+    let body: serde_json::Value = serde_json::from_str(body).unwrap();
+    println!("{body:#}");
+    assert_eq!(body["object"], "chat.completion");
+    assert!(body["id"].as_str().unwrap().starts_with("chatcmpl-"));
+    let choice = &body["choices"][0];
+    assert_eq!(choice["message"]["role"], "assistant");
+    assert!(choice["message"]["content"].is_string());
+    assert!(matches!(
+        choice["finish_reason"].as_str(),
+        Some("stop" | "length")
+    ));
+}
@@ -1,5 +1,10 @@
-use crate::schemas::{
-    AnonymousSessionId, DropSuccess, Drop_, Error, Fork, ForkSuccess, Infer, Sentence, SessionId,
+use crate::{
+    openai::{ChatCompletions, Completion, Event, FinishReason, Stop, StopMatcher},
+    schemas::{
+        AnonymousSessionId, DropSuccess, Drop_, Error, Fork, ForkSuccess, Infer, Sentence,
+        SessionId,
+    },
+    websocket::StreamRequest,
 };
 use base64::{engine::general_purpose, Engine};
 use causal_lm::CausalLM;
@@ -20,6 +25,18 @@ impl<M: CausalLM> ServiceManager<M> {
             session_manager: SessionManager::new(capacity),
         }
     }
+
+    /// Prometheus 文本格式的监控指标。
+    #[inline]
+    pub fn metrics(&self) -> String {
+        self.service.metrics().to_string()
+    }
+
+    /// 预热推理服务，见 [`Service::warmup`]。
+    #[inline]
+    pub async fn warmup(&self) -> bool {
+        self.service.warmup().await
+    }
 }
 
 impl<M> ServiceManager<M>
@@ -54,15 +71,14 @@ where
             Some(e) => return Err(Error::InvalidContent(format!("Unknown encoding: {e}"))),
         };
 
-        async fn infer<M: CausalLM>(
-            session_id: &SessionId,
+        /// 设置采样参数并追加消息，对话超出模型的最大序列长度时返回错误。
+        fn prepare<M: CausalLM>(
             session: &mut Session<M>,
-            messages: Vec<Sentence>,
+            messages: &[Sentence],
             temperature: Option<f32>,
             top_k: Option<usize>,
             top_p: Option<f32>,
-            sender: mpsc::UnboundedSender<String>,
-        ) {
+        ) -> Result<(), Error> {
             if let Some(temperature) = temperature {
                 session.sample.temperature = temperature;
             }
@@ -81,12 +97,28 @@ where
                 })
                 .collect::<Vec<_>>();
             session.extend(&messages);
+            session.check_seq_len().map_err(Error::SeqLen)
+        }
+
+        async fn infer<M: CausalLM>(
+            session_id: &SessionId,
+            session: &mut Session<M>,
+            sender: mpsc::UnboundedSender<String>,
+        ) {
             if session.dialog_pos() % 2 == 1 {
+                let mut busy = match session.chat() {
+                    Ok(busy) => busy,
+                    Err(e) => {
+                        warn!("{session_id:?} inference skipped: {e}");
+                        return;
+                    }
+                };
                 info!("{session_id:?} inference started");
-                let mut busy = session.chat();
                 while let Some(s) = busy.decode().await {
                     if let Err(e) = sender.send(s) {
+                        // 连接已断开，停止推理
                         warn!("Failed to send piece to {session_id:?} with error \"{e}\"");
+                        busy.cancel();
                         break;
                     }
                 }
@@ -103,20 +135,16 @@ where
                     .session_manager
                     .take_or_register(session_id.clone(), || self.service.launch())
                     .map_err(Error::Session)?;
+                session.revert(0).unwrap();
+                if let Err(e) = prepare(&mut session, &messages, temperature, top_k, top_p) {
+                    session.revert(0).unwrap();
+                    self.session_manager.restore(&session_id, session);
+                    return Err(e);
+                }
                 let (sender, receiver) = mpsc::unbounded_channel();
                 let self_ = self.clone();
                 tokio::spawn(async move {
-                    session.revert(0).unwrap();
-                    infer(
-                        &session_id,
-                        &mut session,
-                        messages,
-                        temperature,
-                        top_k,
-                        top_p,
-                        sender,
-                    )
-                    .await;
+                    infer(&session_id, &mut session, sender).await;
 
                     self_.session_manager.restore(&session_id, session);
                 });
@@ -136,20 +164,16 @@ where
                     self.session_manager.restore(&session_id, session);
                     return Err(Error::InvalidDialogPos(current));
                 }
+                if let Err(e) = prepare(&mut session, &messages, temperature, top_k, top_p) {
+                    session.revert(p).unwrap();
+                    self.session_manager.restore(&session_id, session);
+                    return Err(e);
+                }
                 let (sender, receiver) = mpsc::unbounded_channel();
                 let self_ = self.clone();
                 tokio::spawn(async move {
                     info!("{session_id:?} reverted to {p}");
-                    infer(
-                        &session_id,
-                        &mut session,
-                        messages,
-                        temperature,
-                        top_k,
-                        top_p,
-                        sender,
-                    )
-                    .await;
+                    infer(&session_id, &mut session, sender).await;
 
                     self_.session_manager.restore(&session_id, session);
                 });
@@ -161,20 +185,15 @@ where
                     .session_manager
                     .take_or_register(session_id.clone(), || self.service.launch())
                     .map_err(Error::Session)?;
+                if let Err(e) = prepare(&mut session, &messages, temperature, top_k, top_p) {
+                    self.session_manager.drop_(&session_id).unwrap();
+                    return Err(e);
+                }
                 let (sender, receiver) = mpsc::unbounded_channel();
                 let self_ = self.clone();
                 if messages.len() % 2 == 1 {
                     tokio::spawn(async move {
-                        infer(
-                            &session_id,
-                            &mut session,
-                            messages,
-                            temperature,
-                            top_k,
-                            top_p,
-                            sender,
-                        )
-                        .await;
+                        infer(&session_id, &mut session, sender).await;
                         self_.session_manager.drop_(&session_id).unwrap();
                     });
                 }
@@ -187,6 +206,114 @@ where
         }
     }
 
+    /// 以匿名会话完成一次 OpenAI 格式的对话补全。
+    pub fn chat_completions(
+        &self,
+        ChatCompletions {
+            model,
+            messages,
+            temperature,
+            top_p,
+            max_tokens,
+            stop,
+            include_stop,
+            stream: _,
+        }: ChatCompletions,
+    ) -> Result<Completion, Error> {
+        if messages.is_empty() {
+            return Err(Error::InvalidContent("Messages must not be empty".into()));
+        }
+
+        let events = self.generate(messages, temperature, top_p, max_tokens, stop, include_stop)?;
+        Ok(Completion::new(model, events))
+    }
+
+    /// 以匿名会话推理 WebSocket 请求，返回生成的事件。
+    pub fn stream(
+        &self,
+        StreamRequest {
+            messages,
+            temperature,
+            top_p,
+            max_tokens,
+            stop,
+            include_stop,
+        }: StreamRequest,
+    ) -> Result<UnboundedReceiver<Event>, Error> {
+        if messages.is_empty() {
+            return Err(Error::InvalidContent("Messages must not be empty".into()));
+        }
+        self.generate(messages, temperature, top_p, max_tokens, stop, include_stop)
+    }
+
+    /// 使用匿名会话连接 `messages` 并推理，逐段发送生成的文本，最后发送结束原因。
+    ///
+    /// 连接后的对话超出模型的最大序列长度时返回错误。接收端关闭后在下一个词的边界停止推理。
+    fn generate(
+        &self,
+        messages: Vec<Sentence>,
+        temperature: Option<f32>,
+        top_p: Option<f32>,
+        max_tokens: Option<usize>,
+        stop: Option<Stop>,
+        include_stop: bool,
+    ) -> Result<UnboundedReceiver<Event>, Error> {
+        let mut session = self.service.launch();
+        session.include_stop = include_stop;
+        // 生成的词数由推理任务计数，达到上限时以长度为原因结束
+        session.max_tokens = max_tokens;
+        if let Some(temperature) = temperature {
+            session.sample.temperature = temperature;
+        }
+        if let Some(top_p) = top_p {
+            session.sample.top_p = top_p;
+        }
+        let mut stop = StopMatcher::new(stop.map_or_else(Vec::new, |s| s.into_vec()), include_stop);
+        let messages = messages
+            .iter()
+            .map(|s| Message {
+                role: &s.role,
+                content: &s.content,
+            })
+            .collect::<Vec<_>>();
+        session.extend(&messages);
+        session.check_seq_len().map_err(Error::SeqLen)?;
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            // 上下文长度已经检查过，启动失败时只能按长度结束
+            let Ok(mut busy) = session.chat() else {
+                let _ = sender.send(Event::Finish(FinishReason::Length));
+                return;
+            };
+            let reason = loop {
+                // 接收端已关闭，如客户端取消或断开
+                if sender.is_closed() {
+                    busy.cancel();
+                    return;
+                }
+                let Some(piece) = busy.decode().await else {
+                    break busy.finish_reason().map_or(FinishReason::Stop, Into::into);
+                };
+                let (text, stopped) = stop.push(&piece);
+                if !text.is_empty() && sender.send(Event::Delta(text)).is_err() {
+                    // 连接已断开，停止推理
+                    busy.cancel();
+                    return;
+                }
+                if stopped {
+                    break FinishReason::Stop;
+                }
+            };
+            let rest = stop.finish();
+            if !rest.is_empty() {
+                let _ = sender.send(Event::Delta(rest));
+            }
+            let _ = sender.send(Event::Finish(reason));
+        });
+        Ok(receiver)
+    }
+
     pub fn fork(
         &self,
         Fork {
@@ -0,0 +1,287 @@
+//! OpenAI 兼容的对话补全接口。
+
+use crate::schemas::Sentence;
+use serde::{Deserialize, Serialize};
+use std::{
+    mem::{replace, take},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream, StreamExt};
+
+#[derive(Deserialize)]
+pub(crate) struct ChatCompletions {
+    pub model: Option<String>,
+    pub messages: Vec<Sentence>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<usize>,
+    pub stop: Option<Stop>,
+    #[serde(default)]
+    pub include_stop: bool,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+/// 停止序列可以是单个字符串或字符串列表。
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Stop {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            Self::One(s) => vec![s],
+            Self::Many(v) => v,
+        }
+    }
+}
+
+/// 推理任务产生的事件。
+pub(crate) enum Event {
+    Delta(String),
+    Finish(FinishReason),
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FinishReason {
+    Stop,
+    Length,
+    /// 客户端在生成过程中取消，只用于 WebSocket 接口。
+    Cancelled,
+}
+
+impl From<service::FinishReason> for FinishReason {
+    fn from(reason: service::FinishReason) -> Self {
+        use service::FinishReason as Reason;
+        match reason {
+            Reason::Eos | Reason::Stop => Self::Stop,
+            Reason::Length => Self::Length,
+            Reason::Cancel => Self::Cancelled,
+        }
+    }
+}
+
+/// 一次对话补全的元信息和事件流。
+pub(crate) struct Completion {
+    id: String,
+    created: u64,
+    model: String,
+    events: UnboundedReceiver<Event>,
+}
+
+impl Completion {
+    pub fn new(model: Option<String>, events: UnboundedReceiver<Event>) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        Self {
+            id: format!("chatcmpl-{}", NEXT.fetch_add(1, Ordering::Relaxed)),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            model: model.unwrap_or_else(|| "infinilm".into()),
+            events,
+        }
+    }
+
+    /// 收集完整的回复。
+    pub async fn collect(mut self) -> String {
+        let mut content = String::new();
+        let mut finish_reason = FinishReason::Stop;
+        while let Some(event) = self.events.recv().await {
+            match event {
+                Event::Delta(s) => content.push_str(&s),
+                Event::Finish(reason) => finish_reason = reason,
+            }
+        }
+
+        #[derive(Serialize)]
+        struct Message {
+            role: &'static str,
+            content: String,
+        }
+        #[derive(Serialize)]
+        struct Choice {
+            index: usize,
+            message: Message,
+            finish_reason: FinishReason,
+        }
+        #[derive(Serialize)]
+        struct Response {
+            id: String,
+            object: &'static str,
+            created: u64,
+            model: String,
+            choices: [Choice; 1],
+        }
+
+        serde_json::to_string(&Response {
+            id: self.id,
+            object: "chat.completion",
+            created: self.created,
+            model: self.model,
+            choices: [Choice {
+                index: 0,
+                message: Message {
+                    role: "assistant",
+                    content,
+                },
+                finish_reason,
+            }],
+        })
+        .unwrap()
+    }
+
+    /// 转换为 SSE 格式的文本流，以 `data: [DONE]` 结束。
+    pub fn into_sse(self) -> impl Stream<Item = String> + Send + Sync + 'static {
+        #[derive(Serialize)]
+        struct Delta {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            role: Option<&'static str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            content: Option<String>,
+        }
+        #[derive(Serialize)]
+        struct Choice {
+            index: usize,
+            delta: Delta,
+            finish_reason: Option<FinishReason>,
+        }
+        #[derive(Serialize)]
+        struct Chunk<'a> {
+            id: &'a str,
+            object: &'static str,
+            created: u64,
+            model: &'a str,
+            choices: [Choice; 1],
+        }
+
+        let Self {
+            id,
+            created,
+            model,
+            events,
+        } = self;
+        let mut first = true;
+        UnboundedReceiverStream::new(events)
+            .map(move |event| {
+                let (content, finish_reason) = match event {
+                    Event::Delta(s) => (Some(s), None),
+                    Event::Finish(reason) => (None, Some(reason)),
+                };
+                let chunk = Chunk {
+                    id: &id,
+                    object: "chat.completion.chunk",
+                    created,
+                    model: &model,
+                    choices: [Choice {
+                        index: 0,
+                        delta: Delta {
+                            role: take(&mut first).then_some("assistant"),
+                            content,
+                        },
+                        finish_reason,
+                    }],
+                };
+                format!("data: {}\n\n", serde_json::to_string(&chunk).unwrap())
+            })
+            .chain(tokio_stream::once("data: [DONE]\n\n".into()))
+    }
+}
+
+/// 在流式文本中查找停止序列。
+///
+/// 可能构成停止序列前缀的尾部会被暂存，直到能确定它不属于停止序列。
+pub(crate) struct StopMatcher {
+    stops: Vec<String>,
+    /// 是否输出遇到的停止序列本身。
+    include: bool,
+    pending: String,
+}
+
+impl StopMatcher {
+    pub fn new(stops: Vec<String>, include: bool) -> Self {
+        Self {
+            stops: stops.into_iter().filter(|s| !s.is_empty()).collect(),
+            include,
+            pending: String::new(),
+        }
+    }
+
+    /// 追加一段文本，返回可以输出的部分以及是否遇到停止序列。
+    pub fn push(&mut self, piece: &str) -> (String, bool) {
+        self.pending.push_str(piece);
+        if let Some((pos, len)) = self
+            .stops
+            .iter()
+            .filter_map(|s| self.pending.find(s.as_str()).map(|pos| (pos, s.len())))
+            .min_by_key(|&(pos, _)| pos)
+        {
+            self.pending
+                .truncate(if self.include { pos + len } else { pos });
+            return (take(&mut self.pending), true);
+        }
+        let keep = self
+            .stops
+            .iter()
+            .map(|s| overlap(&self.pending, s))
+            .max()
+            .unwrap_or(0);
+        let rest = self.pending.split_off(self.pending.len() - keep);
+        (replace(&mut self.pending, rest), false)
+    }
+
+    /// 文本结束，返回暂存的部分。
+    #[inline]
+    pub fn finish(&mut self) -> String {
+        take(&mut self.pending)
+    }
+}
+
+/// `text` 的后缀与 `stop` 的前缀重合的最大长度。
+fn overlap(text: &str, stop: &str) -> usize {
+    (1..stop.len().min(text.len() + 1))
+        .rev()
+        .find(|&k| stop.is_char_boundary(k) && text.ends_with(&stop[..k]))
+        .unwrap_or(0)
+}
+
+#[test]
+fn test_stop_matcher() {
+    let mut matcher = StopMatcher::new(vec!["<end>".into(), "\n\n".into(), String::new()], false);
+    assert_eq!(matcher.push("Hello"), ("Hello".into(), false));
+    assert_eq!(matcher.push(", wor"), (", wor".into(), false));
+    // `<e` 可能是停止序列的开头，暂不输出
+    assert_eq!(matcher.push("ld<e"), ("ld".into(), false));
+    assert_eq!(matcher.push("x"), ("<ex".into(), false));
+    assert_eq!(matcher.push("!\n"), ("!".into(), false));
+    assert_eq!(matcher.finish(), "\n");
+
+    let mut matcher = StopMatcher::new(vec!["<end>".into()], false);
+    assert_eq!(matcher.push("你好<"), ("你好".into(), false));
+    assert_eq!(matcher.push("en"), ("".into(), false));
+    assert_eq!(matcher.push("d>后面"), ("".into(), true));
+    assert_eq!(matcher.finish(), "");
+
+    // 保留停止序列本身，之后的文本仍被丢弃
+    let mut matcher = StopMatcher::new(vec!["<end>".into()], true);
+    assert_eq!(matcher.push("你好<"), ("你好".into(), false));
+    assert_eq!(matcher.push("end>后面"), ("<end>".into(), true));
+    assert_eq!(matcher.finish(), "");
+}
+
+#[test]
+fn test_finish_reason() {
+    use service::FinishReason as Reason;
+
+    let json = |reason: Reason| serde_json::to_value(FinishReason::from(reason)).unwrap();
+    // 结束符和停止词都对应 OpenAI 的 `stop`
+    assert_eq!(json(Reason::Eos), "stop");
+    assert_eq!(json(Reason::Stop), "stop");
+    assert_eq!(json(Reason::Length), "length");
+    assert_eq!(json(Reason::Cancel), "cancelled");
+}
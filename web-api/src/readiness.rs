@@ -0,0 +1,56 @@
+use std::{
+    future::Future,
+    sync::atomic::{
+        AtomicBool,
+        Ordering::{Acquire, Release},
+    },
+};
+
+/// 服务是否可以接收推理请求，预热成功之前不就绪。
+#[derive(Default, Debug)]
+pub(crate) struct Readiness(AtomicBool);
+
+impl Readiness {
+    #[inline]
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Acquire)
+    }
+
+    /// 等待预热完成，成功时标记为就绪。
+    pub async fn warm_up(&self, warmup: impl Future<Output = bool>) -> bool {
+        let ok = warmup.await;
+        if ok {
+            self.0.store(true, Release);
+        }
+        ok
+    }
+}
+
+#[test]
+fn test_readiness() {
+    use crate::response::probe;
+    use hyper::StatusCode;
+    use std::{sync::Arc, time::Duration};
+    use tokio::{runtime::Builder, sync::oneshot, time::sleep};
+
+    let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+    let readiness = Arc::new(Readiness::default());
+    let status = |r: &Readiness| probe(r.is_ready()).status();
+    assert_eq!(status(&readiness), StatusCode::SERVICE_UNAVAILABLE);
+
+    // 预热失败时保持未就绪
+    assert!(!runtime.block_on(readiness.warm_up(async { false })));
+    assert_eq!(status(&readiness), StatusCode::SERVICE_UNAVAILABLE);
+
+    // 模拟的预热完成之前未就绪，完成后就绪
+    let (done, wait) = oneshot::channel();
+    let task = runtime.spawn({
+        let readiness = readiness.clone();
+        async move { readiness.warm_up(async { wait.await.is_ok() }).await }
+    });
+    runtime.block_on(sleep(Duration::from_millis(10)));
+    assert_eq!(status(&readiness), StatusCode::SERVICE_UNAVAILABLE);
+    done.send(()).unwrap();
+    assert!(runtime.block_on(task).unwrap());
+    assert_eq!(status(&readiness), StatusCode::OK);
+}
@@ -36,6 +36,39 @@ pub fn success(success: impl schemas::Success) -> Response<BoxBody<Bytes, hyper:
         .unwrap()
 }
 
+pub fn json(body: String) -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(full(body))
+        .unwrap()
+}
+
+pub fn text(body: String) -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(full(body))
+        .unwrap()
+}
+
+/// 健康检查的响应，`ok` 为 `false` 时返回 503。
+pub fn probe(ok: bool) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let (status, body) = if ok {
+        (StatusCode::OK, r#"{"status":"ok"}"#)
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            r#"{"status":"unavailable"}"#,
+        )
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(full(body))
+        .unwrap()
+}
+
 pub fn error(e: schemas::Error) -> Response<BoxBody<Bytes, hyper::Error>> {
     Response::builder()
         .status(e.status())
@@ -1,3 +1,4 @@
+use causal_lm::SeqLenError;
 use hyper::StatusCode;
 use service::SessionError;
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -84,6 +85,7 @@ pub(crate) enum Error {
     WrongJson(serde_json::Error),
     InvalidContent(String),
     InvalidDialogPos(usize),
+    SeqLen(SeqLenError),
 }
 
 #[derive(serde::Serialize)]
@@ -103,6 +105,7 @@ impl Error {
             Self::Session(Duplicate) => StatusCode::CONFLICT,
             Self::WrongJson(_) => StatusCode::BAD_REQUEST,
             Self::InvalidContent(_) => StatusCode::BAD_REQUEST,
+            Self::SeqLen(_) => StatusCode::BAD_REQUEST,
             Self::InvalidDialogPos(_) => StatusCode::RANGE_NOT_SATISFIABLE,
         }
     }
@@ -131,6 +134,7 @@ impl Error {
             Self::Session(Duplicate) => json(error!(0, "Session ID already exists")),
             Self::WrongJson(e) => json(error!(0, e.to_string())),
             Self::InvalidContent(e) => json(error!(1, e)),
+            Self::SeqLen(e) => json(error!(2, e.to_string())),
             &Self::InvalidDialogPos(current_dialog_pos) => {
                 #[derive(serde::Serialize)]
                 struct ErrorBodyExtra {
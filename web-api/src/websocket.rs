@@ -0,0 +1,177 @@
+use crate::{
+    manager::ServiceManager,
+    openai::{Event, FinishReason, Stop},
+    schemas::{Error, Sentence},
+};
+use causal_lm::CausalLM;
+use futures_util::{Sink, SinkExt, StreamExt};
+use hyper_tungstenite::{
+    tungstenite::{self, Message},
+    HyperWebsocket,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// 客户端发送的第一帧，开始一次推理。
+#[derive(Deserialize)]
+pub(crate) struct StreamRequest {
+    pub messages: Vec<Sentence>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<usize>,
+    pub stop: Option<Stop>,
+    #[serde(default)]
+    pub include_stop: bool,
+}
+
+/// 推理过程中客户端发送的控制帧。
+#[derive(Deserialize)]
+struct Control {
+    #[serde(default)]
+    cancel: bool,
+}
+
+/// 服务端发送的帧，最后一帧带有结束原因。
+#[derive(Serialize)]
+struct Frame<'a> {
+    text: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<FinishReason>,
+}
+
+/// 处理一个 `/v1/stream` 连接：接收推理请求，逐段发送生成的文本，直到结束或客户端取消。
+///
+/// 客户端断开时丢弃事件接收端，推理在下一个词的边界停止。
+pub(crate) async fn serve<M>(manager: Arc<ServiceManager<M>>, websocket: HyperWebsocket)
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+{
+    let mut ws = match websocket.await {
+        Ok(ws) => ws,
+        Err(e) => {
+            warn!("WebSocket handshake failed: {e}");
+            return;
+        }
+    };
+
+    let request = loop {
+        match ws.next().await {
+            Some(Ok(Message::Text(text))) => break serde_json::from_str::<StreamRequest>(&text),
+            Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+            Some(Ok(_)) => {}
+        }
+    };
+    let mut events = match request
+        .map_err(Error::WrongJson)
+        .and_then(|req| manager.stream(req))
+    {
+        Ok(events) => events,
+        Err(e) => {
+            let _ = ws.send(Message::Text(e.body().to_string())).await;
+            let _ = ws.close(None).await;
+            return;
+        }
+    };
+
+    let reason = loop {
+        tokio::select! {
+            // 优先处理控制帧，取消后不再发送新的文本
+            biased;
+            message = ws.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    if serde_json::from_str::<Control>(&text).is_ok_and(|c| c.cancel) {
+                        break FinishReason::Cancelled;
+                    }
+                }
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+            event = events.recv() => match event {
+                Some(Event::Delta(text)) => {
+                    if send(&mut ws, &text, None).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Event::Finish(reason)) => break reason,
+                None => return,
+            },
+        }
+    };
+    drop(events);
+    let _ = send(&mut ws, "", Some(reason)).await;
+    let _ = ws.close(None).await;
+}
+
+async fn send(
+    ws: &mut (impl Sink<Message, Error = tungstenite::Error> + Unpin),
+    text: &str,
+    finish_reason: Option<FinishReason>,
+) -> Result<(), tungstenite::Error> {
+    let frame = Frame {
+        text,
+        finish_reason,
+    };
+    ws.send(Message::Text(serde_json::to_string(&frame).unwrap()))
+        .await
+}
+
+#[test]
+fn test_stream_cancel() {
+    use std::{net::Ipv4Addr, time::Duration};
+    use tokio::{runtime::Builder, time::sleep};
+    use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+    let Some(model_dir) = common::test_model::find() else {
+        return;
+    };
+    println!("model_dir: {}", model_dir.display());
+
+    const PORT: u16 = 38198;
+    let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+    let _rt = runtime.enter();
+
+    let (service, _handle) = service::Service::<llama_cpu::Transformer>::load(model_dir, ());
+    runtime.spawn(crate::start_infer_service(service, PORT, None));
+
+    let frames = runtime.block_on(async {
+        let url = format!("ws://{}:{PORT}/v1/stream", Ipv4Addr::LOCALHOST);
+        let (mut ws, _) = loop {
+            match connect_async(&url).await {
+                Ok(ws) => break ws,
+                Err(_) => sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let request =
+            r#"{"messages":[{"role":"user","content":"Tell me a long story."}],"max_tokens":256}"#;
+        ws.send(Message::Text(request.into())).await.unwrap();
+
+        let mut frames = Vec::new();
+        while let Some(Ok(message)) = ws.next().await {
+            let Message::Text(text) = message else {
+                continue;
+            };
+            let frame: serde_json::Value = serde_json::from_str(&text).unwrap();
+            if frames.is_empty() && frame["finish_reason"].is_null() {
+                // 收到第一段文本后立即取消
+                ws.send(Message::Text(r#"{"cancel":true}"#.into()))
+                    .await
+                    .unwrap();
+            }
+            frames.push(frame);
+        }
+        frames
+    });
+    runtime.shutdown_background();
+
+    println!("{frames:#?}");
+    let (last, deltas) = frames.split_last().unwrap();
+    if deltas.is_empty() {
+        // 模型没有生成任何文本就结束了，无法测试取消
+        return;
+    }
+    assert_eq!(last["finish_reason"], "cancelled");
+    assert_eq!(last["text"], "");
+    assert!(deltas.iter().all(|f| f["finish_reason"].is_null()));
+    assert!(deltas.len() < 256);
+}
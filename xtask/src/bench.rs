@@ -0,0 +1,148 @@
+use crate::{InferenceArgs, Task};
+use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
+use common::{upos, utok};
+use service::Tokenizer;
+use std::{
+    fmt::Debug,
+    time::{Duration, Instant},
+};
+
+#[derive(Args, Default)]
+pub(crate) struct BenchArgs {
+    #[clap(flatten)]
+    pub inference: InferenceArgs,
+    /// Prompt text, repeated or truncated to the prompt length.
+    #[clap(long, short)]
+    pub prompt: Option<String>,
+    /// Number of prompt tokens, 64 by default.
+    #[clap(long)]
+    pub prompt_len: Option<usize>,
+    /// Number of decode steps, 32 by default.
+    #[clap(long)]
+    pub decode_steps: Option<usize>,
+    /// Number of warmup runs, 1 by default.
+    #[clap(long)]
+    pub warmup: Option<usize>,
+    /// Number of measured runs, 5 by default.
+    #[clap(long)]
+    pub runs: Option<usize>,
+}
+
+impl Task for BenchArgs {
+    #[inline]
+    fn inference(&self) -> &InferenceArgs {
+        &self.inference
+    }
+
+    async fn typed<M>(self, meta: M::Meta)
+    where
+        M: CausalLM + Send + Sync + 'static,
+        M::Storage: Send,
+        M::Error: Debug,
+    {
+        let prompt_len = self.prompt_len.unwrap_or(64);
+        let decode_steps = self.decode_steps.unwrap_or(32);
+        let warmup = self.warmup.unwrap_or(1);
+        let runs = self.runs.unwrap_or(5).max(1);
+
+        let time = Instant::now();
+        let model = M::load(&self.inference.model, meta).unwrap();
+        let tokenizer = Tokenizer::load(&self.inference.model).unwrap();
+        println!("load model ... {:?}", time.elapsed());
+        assert!(
+            prompt_len + decode_steps <= model.max_seq_len() as usize,
+            "prompt length + decode steps exceeds max_seq_len {}",
+            model.max_seq_len(),
+        );
+
+        let text = self.prompt.as_deref().unwrap_or("Once upon a time");
+        let tokens = tokenizer
+            .tokenize
+            .encode(&tokenizer.normalizer.encode(text));
+        let prompt = if tokens.is_empty() {
+            vec![model.bos_token(); prompt_len]
+        } else {
+            tokens.iter().copied().cycle().take(prompt_len).collect()
+        };
+
+        let meta = SampleMeta {
+            num_decode: 1,
+            args: self.inference.sample_args(),
+            ..Default::default()
+        };
+        for _ in 0..warmup {
+            run(&model, &prompt, decode_steps, &meta);
+        }
+        let (mut prefill, mut decode): (Vec<_>, Vec<_>) = (0..runs)
+            .map(|_| run(&model, &prompt, decode_steps, &meta))
+            .unzip();
+
+        let prefill = median(&mut prefill);
+        let decode = median(&mut decode);
+        println!("runs: {runs} (warmup: {warmup})");
+        println!(
+            "prefill: {prompt_len} tokens in {prefill:?}, {:.2} tokens/s",
+            prompt_len as f64 / prefill.as_secs_f64(),
+        );
+        println!(
+            "decode:  {decode_steps} tokens in {decode:?}, {:.2} tokens/s",
+            decode_steps as f64 / decode.as_secs_f64(),
+        );
+        match peak_memory() {
+            Some(kib) => println!("peak memory: {:.2} MiB", kib as f64 / 1024.),
+            None => println!("peak memory: unknown"),
+        }
+    }
+}
+
+/// 执行一次预填充和 `steps` 步解码，返回两部分各自的耗时。
+fn run<M>(model: &M, prompt: &[utok], steps: usize, meta: &SampleMeta) -> (Duration, Duration)
+where
+    M: CausalLM,
+{
+    let mut cache = model.new_cache();
+    let mut step = |tokens: &[utok], pos: upos| {
+        let token_embedded = model.token_embed(tokens.iter().copied());
+        let queries = [QueryContext {
+            cache: Some(&mut cache),
+            range: pos..pos + tokens.len() as upos,
+            adapter: None,
+            mask: None,
+        }];
+        let hidden_state = model.forward(queries, token_embedded).unwrap();
+        let decoding = [DecodingMeta {
+            num_query: tokens.len(),
+            num_decode: 1,
+        }];
+        let logits = model.decode(decoding, hidden_state);
+        model.sample([meta.clone()], logits)[0]
+    };
+
+    let time = Instant::now();
+    let mut token = step(prompt, 0);
+    let prefill = time.elapsed();
+
+    let time = Instant::now();
+    for i in 0..steps {
+        token = step(&[token], (prompt.len() + i) as _);
+    }
+    (prefill, time.elapsed())
+}
+
+fn median(times: &mut [Duration]) -> Duration {
+    times.sort_unstable();
+    times[times.len() / 2]
+}
+
+/// 进程的峰值常驻内存，单位 KiB。
+fn peak_memory() -> Option<usize> {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
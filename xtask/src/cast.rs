@@ -1,6 +1,7 @@
 ﻿use std::{fs, path::PathBuf, time::Instant};
 
 use digit_layout::types::{BF16, F16, F32};
+use indicatif::{ProgressBar, ProgressStyle};
 
 #[derive(Args, Default)]
 pub(crate) struct CastArgs {
@@ -12,16 +13,25 @@ pub(crate) struct CastArgs {
     target: Option<String>,
     /// Target model type.
     /// avliable value includes: "f32", "f16", "bf16", "float32", etc.
+    /// "q4" quantizes projections to 4 bits in an f16 model, which runs on CPU only.
     #[clap(long)]
     dt: Option<String>,
+    /// Comma-separated tensor names kept at original precision, `*` matches any characters.
+    /// e.g. "lm_head.weight,model.layers.*.input_layernorm.weight".
+    #[clap(long)]
+    keep_f32: Option<String>,
+    /// Show progress bars while casting and saving.
+    #[clap(long)]
+    progress: bool,
 }
 
 impl CastArgs {
     pub fn invoke(self) {
-        let ty = match self.dt.as_deref() {
-            Some("f32") | Some("float") | Some("float32") | None => F32,
-            Some("f16") | Some("half") | Some("float16") => F16,
-            Some("bf16") | Some("bfloat16") => BF16,
+        let (ty, q4) = match self.dt.as_deref() {
+            Some("f32") | Some("float") | Some("float32") | None => (F32, false),
+            Some("f16") | Some("half") | Some("float16") => (F16, false),
+            Some("bf16") | Some("bfloat16") => (BF16, false),
+            Some("q4") | Some("int4") => (F16, true),
             Some(ty) => panic!("Unknown data type: \"{ty}\""),
         };
         let model_dir = PathBuf::from(self.model);
@@ -35,6 +45,7 @@ impl CastArgs {
                 "{}_{}",
                 model_dir.file_name().unwrap().to_str().unwrap(),
                 match ty {
+                    _ if q4 => "q4",
                     F16 => "f16",
                     F32 => "f32",
                     BF16 => "bf16",
@@ -44,12 +55,61 @@ impl CastArgs {
         });
         fs::create_dir_all(&target).unwrap();
 
+        let num_tensors = model.num_tensors();
+        let bar = |total: u64| {
+            if self.progress {
+                let bar = ProgressBar::new(total);
+                bar.set_style(
+                    ProgressStyle::with_template(
+                        "{bar:40} {bytes}/{total_bytes} ({bytes_per_sec}) {msg}",
+                    )
+                    .unwrap(),
+                );
+                bar
+            } else {
+                ProgressBar::hidden()
+            }
+        };
+        // 只更新计数，绘制由 indicatif 限频
+        let update = |bar: &ProgressBar| {
+            let bar = bar.clone();
+            move |count: usize, bytes: usize| {
+                bar.set_position(bytes as _);
+                bar.set_message(format!("{count}/{num_tensors} tensors"));
+            }
+        };
+
         let time = Instant::now();
-        let model = model.cast(ty);
+        let keep = self
+            .keep_f32
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .collect::<Vec<_>>();
+        let pb = bar(model_bytes(&model));
+        let model = model.cast_with(
+            ty,
+            |name| keep.iter().any(|p| matches(p, name)),
+            update(&pb),
+        );
+        pb.finish_and_clear();
         println!("cast data type ... {:?}", time.elapsed());
 
+        let model = if q4 {
+            let time = Instant::now();
+            let model = llama_cpu::quantize_q4(model);
+            println!("quantize to 4 bits ... {:?}", time.elapsed());
+            model
+        } else {
+            model
+        };
+
         let time = Instant::now();
-        model.save(&target).unwrap();
+        let pb = bar(model_bytes(&model));
+        model.save_with_progress(&target, update(&pb)).unwrap();
+        pb.finish_and_clear();
         println!("save model ... {:?}", time.elapsed());
 
         let copy_file = |name: &str| {
@@ -65,3 +125,64 @@ impl CastArgs {
         copy_file("vocabs.txt");
     }
 }
+
+/// 模型中所有张量的字节数。
+fn model_bytes(model: &llama::Storage) -> u64 {
+    let layers = model.layers.iter().flat_map(|l| {
+        [
+            &l.att_layernorm,
+            &l.att_qkv,
+            &l.att_o,
+            &l.mlp_layernorm,
+            &l.mlp_gate_up,
+            &l.mlp_down,
+        ]
+    });
+    let optional = model
+        .layers
+        .iter()
+        .flat_map(|l| {
+            [
+                &l.att_layernorm_bias,
+                &l.mlp_layernorm_bias,
+                &l.att_q_norm,
+                &l.att_k_norm,
+            ]
+        })
+        .chain([&model.lm_layernorm_bias])
+        .flatten();
+    [&model.embed_tokens, &model.lm_layernorm, &model.lm_head]
+        .into_iter()
+        .chain(layers)
+        .chain(optional)
+        .map(|t| t.bytes_size() as u64)
+        .sum()
+}
+
+/// 简单的通配符匹配，`*` 匹配任意长度的字符。
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((head, tail)) => {
+            let Some(rest) = name.strip_prefix(head) else {
+                return false;
+            };
+            (0..=rest.len())
+                .filter(|&i| rest.is_char_boundary(i))
+                .any(|i| matches(tail, &rest[i..]))
+        }
+    }
+}
+
+#[test]
+fn test_matches() {
+    assert!(matches("lm_head.weight", "lm_head.weight"));
+    assert!(!matches("lm_head.weight", "lm_head.bias"));
+    assert!(matches(
+        "model.layers.*.input_layernorm.weight",
+        "model.layers.12.input_layernorm.weight"
+    ));
+    assert!(matches("*norm*", "model.norm.weight"));
+    assert!(!matches("model.*.mlp.*", "model.norm.weight"));
+    assert!(matches("*", ""));
+}
@@ -231,7 +231,13 @@ impl<M: CausalLM> Chatting<M> {
             role: "user",
             content,
         }]);
-        let mut busy = session.chat();
+        let mut busy = match session.chat() {
+            Ok(busy) => busy,
+            Err(e) => {
+                println!("{e}");
+                return;
+            }
+        };
         while let Some(s) = busy.decode().await {
             match &*s {
                 "\\n" => println!(),
@@ -0,0 +1,598 @@
+use common::safe_tensors::Dtype;
+use safetensors::tensor::TensorView;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, ErrorKind::InvalidData, Read, Seek},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use zip::ZipArchive;
+
+#[derive(Args, Default)]
+pub(crate) struct ConvertPtArgs {
+    /// Model directory containing `pytorch_model*.bin`.
+    #[clap(short, long)]
+    model: String,
+    /// Target model directory.
+    #[clap(short, long)]
+    target: Option<String>,
+}
+
+impl ConvertPtArgs {
+    pub fn invoke(self) {
+        let model_dir = PathBuf::from(self.model);
+        let target = self.target.map(PathBuf::from).unwrap_or_else(|| {
+            model_dir.parent().unwrap().join(format!(
+                "{}_safetensors",
+                model_dir.file_name().unwrap().to_str().unwrap(),
+            ))
+        });
+        fs::create_dir_all(&target).unwrap();
+
+        let mut files = fs::read_dir(&model_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                let name = path.file_name().unwrap().to_string_lossy();
+                name.starts_with("pytorch_model") && name.ends_with(".bin")
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        assert!(!files.is_empty(), "No pytorch_model*.bin found");
+
+        let time = Instant::now();
+        let mut tensors = Vec::new();
+        for path in files {
+            tensors.extend(load_pt(fs::File::open(&path).unwrap()).unwrap());
+            println!("load {} ... {:?}", path.display(), time.elapsed());
+        }
+
+        let time = Instant::now();
+        save(&tensors, target.join("model.safetensors")).unwrap();
+        println!("save {} tensors ... {:?}", tensors.len(), time.elapsed());
+
+        let copy_file = |name: &str| {
+            let src = model_dir.join(name);
+            if src.is_file() {
+                fs::copy(&src, target.join(name)).unwrap();
+                println!("copy {name}");
+            }
+        };
+
+        copy_file("config.json");
+        copy_file("tokenizer.model");
+        copy_file("tokenizer.json");
+        copy_file("tokenizer_config.json");
+        copy_file("vocabs.txt");
+    }
+}
+
+/// 从 pickle 中恢复的张量。
+#[derive(Clone, PartialEq, Debug)]
+struct PtTensor {
+    name: String,
+    dtype: Dtype,
+    shape: Vec<usize>,
+    data: Vec<u8>,
+}
+
+/// 读取 `torch.save` 保存的 zip 格式检查点，按名字顺序返回其中的张量。
+///
+/// 名字映射为 safetensors 中使用的名字，不属于模型的缓冲区会被丢弃。
+fn load_pt(reader: impl Read + Seek) -> io::Result<Vec<PtTensor>> {
+    let mut zip = ZipArchive::new(reader)?;
+    let pkl = zip
+        .file_names()
+        .find(|name| name.ends_with("data.pkl"))
+        .ok_or_else(|| invalid("data.pkl not found"))?
+        .to_string();
+    let prefix = pkl.strip_suffix("data.pkl").unwrap().to_string();
+
+    let mut read = |name: &str| -> io::Result<Vec<u8>> {
+        let mut file = zip.by_name(&format!("{prefix}{name}"))?;
+        let mut buf = Vec::with_capacity(file.size() as _);
+        file.read_to_end(&mut buf)?;
+        Ok(buf)
+    };
+    // 旧版本的检查点不记录字节序，都是小端
+    let big_endian = read("byteorder").is_ok_and(|order| order == b"big");
+
+    let mut state = Unpickler::default().load(&read("data.pkl")?)?;
+    // 训练检查点可能把权重包在 `state_dict` 或 `model` 中
+    loop {
+        let Value::Dict(items) = &state else {
+            return Err(invalid("state dict is not a dict"));
+        };
+        let inner = items.iter().find_map(|(k, v)| match (k, v) {
+            (Value::Str(k), Value::Dict(_)) if k == "state_dict" || k == "model" => Some(v),
+            _ => None,
+        });
+        match inner {
+            Some(inner) => state = inner.clone(),
+            None => break,
+        }
+    }
+    let Value::Dict(items) = state else {
+        unreachable!()
+    };
+
+    let mut storages = HashMap::new();
+    let mut ans = Vec::new();
+    for (name, value) in items {
+        let (Value::Str(name), Value::Tensor(t)) = (name, value) else {
+            continue;
+        };
+        let Some(name) = map_name(&name) else {
+            continue;
+        };
+        if !is_contiguous(&t.shape, &t.stride) {
+            return Err(invalid(format!("tensor {name} is not contiguous")));
+        }
+        if !storages.contains_key(&t.key) {
+            let data = read(&format!("data/{}", t.key))?;
+            storages.insert(t.key.clone(), data);
+        }
+        let storage = &storages[&t.key];
+
+        let size = t.dtype.size();
+        let len = t.shape.iter().product::<usize>() * size;
+        let data = storage
+            .get(t.offset * size..)
+            .and_then(|data| data.get(..len))
+            .ok_or_else(|| invalid(format!("data of tensor {name} out of storage")))?;
+        let mut data = data.to_vec();
+        if big_endian {
+            for x in data.chunks_exact_mut(size) {
+                x.reverse();
+            }
+        }
+        ans.push(PtTensor {
+            name,
+            dtype: t.dtype,
+            shape: t.shape,
+            data,
+        });
+    }
+    ans.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(ans)
+}
+
+/// 把张量名映射为 [`llama::Storage`] 加载时使用的名字，返回 `None` 表示丢弃。
+fn map_name(name: &str) -> Option<String> {
+    // `DataParallel` 保存的权重带有 `module.` 前缀
+    let name = name.strip_prefix("module.").unwrap_or(name);
+    // 旋转位置编码的频率由推理时计算
+    if name.ends_with("rotary_emb.inv_freq") {
+        return None;
+    }
+    Some(name.into())
+}
+
+fn is_contiguous(shape: &[usize], stride: &[usize]) -> bool {
+    let mut expected = 1;
+    for (&d, &s) in shape.iter().zip(stride).rev() {
+        if d != 1 && s != expected {
+            return false;
+        }
+        expected *= d;
+    }
+    true
+}
+
+fn save(tensors: &[PtTensor], path: impl AsRef<Path>) -> io::Result<()> {
+    let views = tensors
+        .iter()
+        .map(|t| {
+            TensorView::new(t.dtype, t.shape.clone(), &t.data)
+                .map(|view| (t.name.clone(), view))
+                .map_err(|e| invalid(format!("{e:?}")))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    safetensors::serialize_to_file(views, &None, path.as_ref())
+        .map_err(|e| invalid(format!("{e:?}")))
+}
+
+#[inline]
+fn invalid(msg: impl ToString) -> io::Error {
+    io::Error::new(InvalidData, msg.to_string())
+}
+
+/// pickle 反序列化得到的值，只包含 `torch.save` 用到的类型，转换时用不到的值不保留内容。
+#[derive(Clone, Debug)]
+enum Value {
+    None,
+    Bool,
+    Int(i64),
+    Float,
+    Str(String),
+    Bytes,
+    Tuple(Vec<Value>),
+    List(Vec<Value>),
+    Dict(Vec<(Value, Value)>),
+    Global(String, String),
+    Storage(Dtype, String),
+    Tensor(TensorRecord),
+    /// 无法识别的对象。
+    Object,
+}
+
+/// `torch._utils._rebuild_tensor_v2` 的参数。
+#[derive(Clone, Debug)]
+struct TensorRecord {
+    dtype: Dtype,
+    key: String,
+    offset: usize,
+    shape: Vec<usize>,
+    stride: Vec<usize>,
+}
+
+/// 解释执行 pickle 操作码的最小虚拟机。
+#[derive(Default)]
+struct Unpickler {
+    stack: Vec<Value>,
+    marks: Vec<usize>,
+    memo: HashMap<u32, Value>,
+}
+
+impl Unpickler {
+    fn load(mut self, mut data: &[u8]) -> io::Result<Value> {
+        let mut take = |n: usize| -> io::Result<&[u8]> {
+            if data.len() < n {
+                return Err(invalid("unexpected end of pickle"));
+            }
+            let (head, tail) = data.split_at(n);
+            data = tail;
+            Ok(head)
+        };
+        macro_rules! read {
+            ($ty:ty) => {
+                <$ty>::from_le_bytes(take(size_of::<$ty>())?.try_into().unwrap())
+            };
+        }
+
+        loop {
+            let op = take(1)?[0];
+            match op {
+                // PROTO
+                0x80 => {
+                    take(1)?;
+                }
+                // FRAME
+                0x95 => {
+                    take(8)?;
+                }
+                // STOP
+                b'.' => return self.pop(),
+                // MARK
+                b'(' => self.marks.push(self.stack.len()),
+                // NONE, NEWTRUE, NEWFALSE
+                b'N' => self.stack.push(Value::None),
+                0x88 | 0x89 => self.stack.push(Value::Bool),
+                // BININT, BININT1, BININT2, LONG1
+                b'J' => self.stack.push(Value::Int(read!(i32) as _)),
+                b'K' => self.stack.push(Value::Int(read!(u8) as _)),
+                b'M' => self.stack.push(Value::Int(read!(u16) as _)),
+                0x8a => {
+                    let n = read!(u8) as usize;
+                    let bytes = take(n)?;
+                    if n > 8 {
+                        return Err(invalid("integer too large"));
+                    }
+                    let fill = if bytes.last().is_some_and(|&b| b & 0x80 != 0) {
+                        0xff
+                    } else {
+                        0
+                    };
+                    let mut buf = [fill; 8];
+                    buf[..n].copy_from_slice(bytes);
+                    self.stack.push(Value::Int(i64::from_le_bytes(buf)));
+                }
+                // BINFLOAT
+                b'G' => {
+                    take(8)?;
+                    self.stack.push(Value::Float);
+                }
+                // BINUNICODE, SHORT_BINUNICODE, BINUNICODE8
+                b'X' | 0x8c | 0x8d => {
+                    let n = match op {
+                        b'X' => read!(u32) as usize,
+                        0x8c => read!(u8) as usize,
+                        _ => read!(u64) as usize,
+                    };
+                    let s = std::str::from_utf8(take(n)?).map_err(invalid)?;
+                    self.stack.push(Value::Str(s.into()));
+                }
+                // BINBYTES, SHORT_BINBYTES
+                b'B' | b'C' => {
+                    let n = if op == b'B' {
+                        read!(u32) as usize
+                    } else {
+                        read!(u8) as usize
+                    };
+                    take(n)?;
+                    self.stack.push(Value::Bytes);
+                }
+                // GLOBAL
+                b'c' => {
+                    let mut line = || -> io::Result<String> {
+                        let mut ans = Vec::new();
+                        loop {
+                            match take(1)?[0] {
+                                b'\n' => break,
+                                b => ans.push(b),
+                            }
+                        }
+                        String::from_utf8(ans).map_err(invalid)
+                    };
+                    let module = line()?;
+                    let name = line()?;
+                    self.stack.push(Value::Global(module, name));
+                }
+                // STACK_GLOBAL
+                0x93 => {
+                    let (Value::Str(name), Value::Str(module)) = (self.pop()?, self.pop()?) else {
+                        return Err(invalid("invalid STACK_GLOBAL"));
+                    };
+                    self.stack.push(Value::Global(module, name));
+                }
+                // BINPUT, LONG_BINPUT, MEMOIZE
+                b'q' | b'r' | 0x94 => {
+                    let key = match op {
+                        b'q' => read!(u8) as u32,
+                        b'r' => read!(u32),
+                        _ => self.memo.len() as u32,
+                    };
+                    let top = self.stack.last().ok_or_else(|| invalid("empty stack"))?;
+                    self.memo.insert(key, top.clone());
+                }
+                // BINGET, LONG_BINGET
+                b'h' | b'j' => {
+                    let key = if op == b'h' {
+                        read!(u8) as u32
+                    } else {
+                        read!(u32)
+                    };
+                    let value = self.memo.get(&key).ok_or_else(|| invalid("memo miss"))?;
+                    self.stack.push(value.clone());
+                }
+                // EMPTY_TUPLE, TUPLE, TUPLE1, TUPLE2, TUPLE3
+                b')' => self.stack.push(Value::Tuple(Vec::new())),
+                b't' => {
+                    let items = self.pop_mark()?;
+                    self.stack.push(Value::Tuple(items));
+                }
+                0x85..=0x87 => {
+                    let n = (op - 0x84) as usize;
+                    if self.stack.len() < n {
+                        return Err(invalid("empty stack"));
+                    }
+                    let items = self.stack.split_off(self.stack.len() - n);
+                    self.stack.push(Value::Tuple(items));
+                }
+                // EMPTY_LIST, APPEND, APPENDS
+                b']' => self.stack.push(Value::List(Vec::new())),
+                b'a' => {
+                    let item = self.pop()?;
+                    self.extend_list([item])?;
+                }
+                b'e' => {
+                    let items = self.pop_mark()?;
+                    self.extend_list(items)?;
+                }
+                // EMPTY_DICT, SETITEM, SETITEMS
+                b'}' => self.stack.push(Value::Dict(Vec::new())),
+                b's' => {
+                    let value = self.pop()?;
+                    let key = self.pop()?;
+                    self.extend_dict(vec![key, value])?;
+                }
+                b'u' => {
+                    let items = self.pop_mark()?;
+                    self.extend_dict(items)?;
+                }
+                // BINPERSID
+                b'Q' => {
+                    let pid = self.pop()?;
+                    self.stack.push(persistent_load(pid)?);
+                }
+                // REDUCE, NEWOBJ
+                b'R' | 0x81 => {
+                    let args = self.pop()?;
+                    let callable = self.pop()?;
+                    self.stack.push(reduce(callable, args)?);
+                }
+                // BUILD，忽略对象的状态
+                b'b' => {
+                    self.pop()?;
+                }
+                _ => return Err(invalid(format!("unsupported pickle opcode 0x{op:02x}"))),
+            }
+        }
+    }
+
+    fn pop(&mut self) -> io::Result<Value> {
+        self.stack.pop().ok_or_else(|| invalid("empty stack"))
+    }
+
+    fn pop_mark(&mut self) -> io::Result<Vec<Value>> {
+        let mark = self.marks.pop().ok_or_else(|| invalid("mark not found"))?;
+        Ok(self.stack.split_off(mark))
+    }
+
+    fn extend_list(&mut self, items: impl IntoIterator<Item = Value>) -> io::Result<()> {
+        match self.stack.last_mut() {
+            Some(Value::List(list)) => {
+                list.extend(items);
+                Ok(())
+            }
+            _ => Err(invalid("append to non-list")),
+        }
+    }
+
+    fn extend_dict(&mut self, items: Vec<Value>) -> io::Result<()> {
+        match self.stack.last_mut() {
+            Some(Value::Dict(dict)) => {
+                let mut items = items.into_iter();
+                while let (Some(k), Some(v)) = (items.next(), items.next()) {
+                    dict.push((k, v));
+                }
+                Ok(())
+            }
+            _ => Err(invalid("set item to non-dict")),
+        }
+    }
+}
+
+/// 从 `('storage', 存储类型, 键, 设备, 元素数)` 恢复存储。
+fn persistent_load(pid: Value) -> io::Result<Value> {
+    let Value::Tuple(pid) = pid else {
+        return Err(invalid("invalid persistent id"));
+    };
+    match &pid[..] {
+        [Value::Str(tag), Value::Global(_, ty), Value::Str(key), ..] if tag == "storage" => {
+            let dtype = match ty.as_str() {
+                "FloatStorage" => Dtype::F32,
+                "HalfStorage" => Dtype::F16,
+                "BFloat16Storage" => Dtype::BF16,
+                "DoubleStorage" => Dtype::F64,
+                "LongStorage" => Dtype::I64,
+                "IntStorage" => Dtype::I32,
+                "ShortStorage" => Dtype::I16,
+                "CharStorage" => Dtype::I8,
+                "ByteStorage" => Dtype::U8,
+                "BoolStorage" => Dtype::BOOL,
+                _ => return Err(invalid(format!("unsupported storage type {ty}"))),
+            };
+            Ok(Value::Storage(dtype, key.clone()))
+        }
+        _ => Err(invalid("invalid persistent id")),
+    }
+}
+
+fn reduce(callable: Value, args: Value) -> io::Result<Value> {
+    let Value::Global(module, name) = callable else {
+        return Err(invalid("callable is not a global"));
+    };
+    let Value::Tuple(args) = args else {
+        return Ok(Value::Object);
+    };
+    let usizes = |v: &Value| match v {
+        Value::Tuple(items) => items
+            .iter()
+            .map(|x| match x {
+                &Value::Int(x) if x >= 0 => Ok(x as usize),
+                _ => Err(invalid("invalid size")),
+            })
+            .collect::<io::Result<Vec<_>>>(),
+        _ => Err(invalid("invalid size")),
+    };
+    match (module.as_str(), name.as_str(), &args[..]) {
+        ("collections", "OrderedDict", _) => Ok(Value::Dict(Vec::new())),
+        (
+            "torch._utils",
+            "_rebuild_tensor_v2",
+            [Value::Storage(dtype, key), Value::Int(offset), shape, stride, ..],
+        ) => Ok(Value::Tensor(TensorRecord {
+            dtype: *dtype,
+            key: key.clone(),
+            offset: *offset as _,
+            shape: usizes(shape)?,
+            stride: usizes(stride)?,
+        })),
+        ("torch._utils", "_rebuild_parameter", [tensor @ Value::Tensor(_), ..]) => {
+            Ok(tensor.clone())
+        }
+        _ => Ok(Value::Object),
+    }
+}
+
+#[test]
+fn test_convert() {
+    use common::safe_tensors::SafeTensors;
+    use std::io::{Cursor, Write};
+    use zip::{write::FileOptions, CompressionMethod::Stored, ZipWriter};
+
+    // 按 `torch.save(OrderedDict(...))` 的格式手工构造 pickle
+    let mut pkl = vec![0x80, 2];
+    let global = |pkl: &mut Vec<u8>, module: &str, name: &str| {
+        pkl.push(b'c');
+        pkl.extend_from_slice(format!("{module}\n{name}\n").as_bytes());
+    };
+    let unicode = |pkl: &mut Vec<u8>, s: &str| {
+        pkl.push(b'X');
+        pkl.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        pkl.extend_from_slice(s.as_bytes());
+    };
+    let ints = |pkl: &mut Vec<u8>, xs: &[u8]| {
+        pkl.push(b'(');
+        for &x in xs {
+            pkl.extend_from_slice(&[b'K', x]);
+        }
+        pkl.push(b't');
+    };
+    global(&mut pkl, "collections", "OrderedDict");
+    pkl.extend_from_slice(&[b'q', 0, b')', b'R', b'q', 1, b'(']);
+    // (名字, 存储类型, 键, 偏移, 形状, 步长)
+    #[rustfmt::skip]
+    let tensors = [
+        ("model.norm.weight", "HalfStorage", "0", 0, &[4][..], &[1][..]),
+        ("module.lm_head.weight", "FloatStorage", "1", 2, &[2, 3], &[3, 1]),
+        ("model.layers.0.self_attn.rotary_emb.inv_freq", "FloatStorage", "1", 0, &[2], &[1]),
+    ];
+    for (name, ty, key, offset, shape, stride) in tensors {
+        unicode(&mut pkl, name);
+        global(&mut pkl, "torch._utils", "_rebuild_tensor_v2");
+        pkl.extend_from_slice(b"((");
+        unicode(&mut pkl, "storage");
+        global(&mut pkl, "torch", ty);
+        unicode(&mut pkl, key);
+        unicode(&mut pkl, "cpu");
+        pkl.extend_from_slice(&[b'K', 8, b't', b'Q', b'K', offset]);
+        ints(&mut pkl, shape);
+        ints(&mut pkl, stride);
+        pkl.push(0x89);
+        pkl.extend_from_slice(&[b'h', 0, b')', b'R', b't', b'R']);
+    }
+    pkl.extend_from_slice(b"u}b.");
+
+    let half = [1., -2., 0.5, 3.].map(|x: f32| common::f16::from_f32(x).to_le_bytes());
+    let float = (0..8).map(|i| (i as f32).to_le_bytes()).collect::<Vec<_>>();
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(Stored);
+    for (name, data) in [
+        ("archive/data.pkl", pkl),
+        ("archive/byteorder", b"little".to_vec()),
+        ("archive/data/0", half.concat()),
+        ("archive/data/1", float.concat()),
+    ] {
+        zip.start_file(name, options).unwrap();
+        zip.write_all(&data).unwrap();
+    }
+    let bin = zip.finish().unwrap().into_inner();
+
+    let tensors = load_pt(Cursor::new(bin)).unwrap();
+    let path = std::env::temp_dir().join("xtask_test_convert_pt.safetensors");
+    save(&tensors, &path).unwrap();
+    let st = SafeTensors::single_file(&path).unwrap();
+
+    // 前缀被去掉，频率缓冲区被丢弃，偏移按元素计算
+    let expected = [
+        (
+            "lm_head.weight",
+            Dtype::F32,
+            vec![2, 3],
+            float[2..].concat(),
+        ),
+        ("model.norm.weight", Dtype::F16, vec![4], half.concat()),
+    ];
+    assert_eq!(st.iter().count(), expected.len());
+    for (name, dtype, shape, data) in expected {
+        let t = st.get(name).unwrap();
+        assert_eq!(t.dtype, dtype);
+        assert_eq!(t.shape, shape);
+        assert_eq!(t.data, data);
+    }
+    drop(st);
+    fs::remove_file(path).unwrap();
+}
@@ -1,9 +1,13 @@
+mod bench;
 mod cast;
 mod chat;
+mod convert_pt;
 mod deploy;
 mod generate;
 mod list_turbo;
+mod perplexity;
 mod service;
+mod shard;
 
 use causal_lm::{CausalLM, SampleArgs};
 use clap::Parser;
@@ -21,7 +25,11 @@ fn main() {
         ListTurbo => list_turbo::list_turbo(),
         Deploy(deploy) => deploy.deploy(),
         Cast(cast) => cast.invoke(),
+        ConvertPt(convert) => convert.invoke(),
+        Shard(shard) => shard.invoke(),
         Generate(args) => args.run(),
+        Bench(args) => args.run(),
+        Perplexity(args) => args.run(),
         Chat(chat) => chat.run(),
         Service(service) => service.run(),
     }
@@ -43,8 +51,16 @@ enum Commands {
     Deploy(DeployArgs),
     /// Cast model
     Cast(cast::CastArgs),
+    /// Convert pytorch_model.bin to safetensors
+    ConvertPt(convert_pt::ConvertPtArgs),
+    /// Split model into shards
+    Shard(shard::ShardArgs),
     /// Generate following text
     Generate(generate::GenerateArgs),
+    /// Measure inference throughput
+    Bench(bench::BenchArgs),
+    /// Evaluate perplexity on a text file
+    Perplexity(perplexity::PerplexityArgs),
     /// Chat locally
     Chat(chat::ChatArgs),
     /// Start the service
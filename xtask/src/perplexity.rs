@@ -0,0 +1,155 @@
+use crate::{InferenceArgs, ModelType};
+use causal_lm::{cross_entropy, CausalLM, DecodingMeta, Model, QueryContext};
+use common::{upos, utok, Blob};
+use service::Tokenizer;
+use std::{fmt::Debug, fs, time::Instant};
+use tensor::Tensor;
+
+#[derive(Args, Default)]
+pub(crate) struct PerplexityArgs {
+    #[clap(flatten)]
+    pub inference: InferenceArgs,
+    /// Text file to evaluate.
+    #[clap(long, short)]
+    pub text: String,
+    /// Sliding window length, at most 1024 tokens by default.
+    #[clap(long)]
+    pub window: Option<usize>,
+    /// Sliding window stride, half of the window by default.
+    #[clap(long)]
+    pub stride: Option<usize>,
+}
+
+impl PerplexityArgs {
+    pub fn run(self) {
+        self.inference.init_log();
+        // 需要在主机上读取 logits，只支持 CPU 模型
+        match self.inference.model_type() {
+            ModelType::Llama => self.typed::<llama_cpu::Transformer>(()),
+            ModelType::Mixtral => self.typed::<mixtral_cpu::MixtralCPU>(()),
+        }
+    }
+
+    fn typed<M>(self, meta: M::Meta)
+    where
+        M: CausalLM<Storage = Blob>,
+        M::Error: Debug,
+    {
+        let time = Instant::now();
+        let model = M::load(&self.inference.model, meta).unwrap();
+        let tokenizer = Tokenizer::load(&self.inference.model).unwrap();
+        println!("load model ... {:?}", time.elapsed());
+
+        let text = fs::read_to_string(&self.text).unwrap();
+        let tokens = tokenizer
+            .tokenize
+            .encode(&tokenizer.normalizer.encode(&text));
+        let window = self
+            .window
+            .unwrap_or(1024)
+            .min(model.max_seq_len() as usize);
+        let stride = self.stride.unwrap_or(window / 2).max(1);
+        println!(
+            "{} tokens, window = {window}, stride = {stride}",
+            tokens.len()
+        );
+
+        let time = Instant::now();
+        let nll = mean_nll(&tokens, window, stride, |input| logits(&model, input));
+        println!("evaluate ... {:?}", time.elapsed());
+        println!("mean nll: {nll:.6}");
+        println!("perplexity: {:.6}", nll.exp());
+    }
+}
+
+/// 对 `tokens` 做一次完整的前向，返回每个位置的 logits。
+fn logits<M: CausalLM<Storage = Blob>>(model: &M, tokens: &[utok]) -> Tensor<Blob> {
+    let mut cache = model.new_cache();
+    let token_embedded = model.token_embed(tokens.iter().copied());
+    let queries = [QueryContext {
+        cache: Some(&mut cache),
+        range: 0..tokens.len() as upos,
+        adapter: None,
+        mask: None,
+    }];
+    let hidden_state = model.forward(queries, token_embedded).unwrap();
+    let decoding = [DecodingMeta {
+        num_query: tokens.len(),
+        num_decode: tokens.len(),
+    }];
+    model.decode(decoding, hidden_state)
+}
+
+/// 以长度为 `window`、步长为 `stride` 的滑动窗口计算平均负对数似然，每个词只计入一次。
+fn mean_nll(
+    tokens: &[utok],
+    window: usize,
+    stride: usize,
+    mut logits: impl FnMut(&[utok]) -> Tensor<Blob>,
+) -> f64 {
+    const IGNORE: utok = utok::MAX;
+    assert!(window > 0 && (1..=window).contains(&stride));
+
+    let n = tokens.len();
+    let mut sum = 0.;
+    let mut count = 0;
+    // 第 0 个词没有上文，不参与计算
+    let mut scored = 1;
+    let mut begin = 0;
+    while scored < n {
+        let end = (begin + window).min(n);
+        // 第 i 个位置的 logits 预测第 begin + i + 1 个词
+        let targets = (begin + 1..=end)
+            .map(|j| {
+                if j >= scored && j < n {
+                    tokens[j]
+                } else {
+                    IGNORE
+                }
+            })
+            .collect::<Vec<_>>();
+        let valid = targets.iter().filter(|&&t| t != IGNORE).count();
+        if valid > 0 {
+            let logits = logits(&tokens[begin..end]);
+            sum += cross_entropy(&logits, &targets, Some(IGNORE)) as f64 * valid as f64;
+            count += valid;
+        }
+        scored = (end + 1).min(n);
+        begin += stride;
+    }
+    if count == 0 {
+        0.
+    } else {
+        sum / count as f64
+    }
+}
+
+#[test]
+fn test_mean_nll() {
+    use digit_layout::types::F32;
+    use tensor::reslice_mut;
+
+    const VOC: usize = 8;
+    let corpus = (0..23).map(|i| i % 5).collect::<Vec<utok>>();
+    let fake = |predict: bool| {
+        move |input: &[utok]| {
+            let mut logits = Tensor::alloc(F32, &[input.len() as _, VOC as _], Blob::new);
+            let data = reslice_mut::<u8, f32>(logits.physical_mut());
+            data.fill(0.);
+            if predict {
+                // 语料循环出现，下一个词总是确定的
+                for (row, &t) in data.chunks_exact_mut(VOC).zip(input) {
+                    row[(t as usize + 1) % 5] = 100.;
+                }
+            }
+            logits
+        }
+    };
+
+    for (window, stride) in [(6, 2), (6, 6), (4, 1), (64, 32)] {
+        let uniform = mean_nll(&corpus, window, stride, fake(false));
+        assert!((uniform.exp() - VOC as f64).abs() < 1e-4);
+        let perfect = mean_nll(&corpus, window, stride, fake(true));
+        assert!((perfect.exp() - 1.).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,59 @@
+use llama::Sharding;
+use std::{fs, path::PathBuf, time::Instant};
+
+#[derive(Args, Default)]
+pub(crate) struct ShardArgs {
+    /// Original model directory.
+    #[clap(short, long)]
+    model: String,
+    /// Target model directory.
+    #[clap(short, long)]
+    target: Option<String>,
+    /// Number of shards.
+    #[clap(long)]
+    shards: Option<usize>,
+    /// Max bytes of each shard.
+    #[clap(long)]
+    max_bytes: Option<usize>,
+}
+
+impl ShardArgs {
+    pub fn invoke(self) {
+        let sharding = match (self.shards, self.max_bytes) {
+            (Some(n), None) => Sharding::Count(n),
+            (None, Some(max)) => Sharding::MaxBytes(max),
+            _ => panic!("Exactly one of --shards and --max-bytes is required"),
+        };
+        let model_dir = PathBuf::from(self.model);
+
+        let time = Instant::now();
+        let model = llama::Storage::load_safetensors(&model_dir).unwrap();
+        println!("load model ... {:?}", time.elapsed());
+
+        let target = self.target.map(PathBuf::from).unwrap_or_else(|| {
+            model_dir.parent().unwrap().join(format!(
+                "{}_sharded",
+                model_dir.file_name().unwrap().to_str().unwrap(),
+            ))
+        });
+        fs::create_dir_all(&target).unwrap();
+
+        let time = Instant::now();
+        model.save_sharded(&target, sharding).unwrap();
+        println!("save shards ... {:?}", time.elapsed());
+
+        let copy_file = |name: &str| {
+            let src = model_dir.join(name);
+            if src.is_file() {
+                let time = Instant::now();
+                fs::copy(&src, target.join(name)).unwrap();
+                println!("copy {name} ... {:?}", time.elapsed());
+            }
+        };
+
+        copy_file("tokenizer.model");
+        copy_file("vocabs.txt");
+        copy_file("tokenizer.json");
+        copy_file("tokenizer_config.json");
+    }
+}